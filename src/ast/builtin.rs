@@ -0,0 +1,355 @@
+use crate::{ast::Expr, parser::ParseErrorKind, stac, stac::DataType};
+
+// Builtins are recognised by the parser rather than declared, so unlike
+// user functions they can accept an argument of any type.
+pub fn is_builtin(name: &str) -> bool {
+    return matches!(
+        name,
+        "typeof"
+            | "isWaiting"
+            | "isNull"
+            | "div"
+            | "make"
+            | "append"
+            | "remove"
+            | "len"
+            | "has"
+            | "str"
+    );
+}
+
+pub fn call(
+    name: &str,
+    mut params: Vec<Box<dyn Expr>>,
+    prog: &stac::Prog,
+) -> Result<Box<dyn Expr>, ParseErrorKind> {
+    let arity = match name {
+        "div" | "append" | "remove" | "has" => 2,
+        _ => 1,
+    };
+    if params.len() != arity {
+        return Err(ParseErrorKind::Type(format!(
+            "{} expects {} argument{}, found {}",
+            name,
+            arity,
+            if arity == 1 { "" } else { "s" },
+            params.len()
+        )));
+    }
+
+    let x = params.remove(0);
+    match name {
+        "typeof" => return Ok(Box::new(TypeOf { x })),
+        "isWaiting" => return Ok(Box::new(IsWaiting { x })),
+        "isNull" => return Ok(Box::new(IsNull { x })),
+        "div" => {
+            let y = params.remove(0);
+            if x.out_type(prog) != DataType::Integer || y.out_type(prog) != DataType::Integer {
+                return Err(ParseErrorKind::Type("div expects int arguments".into()));
+            }
+            return Ok(Box::new(Div { x, y }));
+        }
+        "append" | "remove" => {
+            let y = params.remove(0);
+            let el_type = match x.out_type(prog) {
+                DataType::Array(el_type) => *el_type,
+                t => {
+                    return Err(ParseErrorKind::Type(format!(
+                        "{} expects an array, found {}",
+                        name, t
+                    )))
+                }
+            };
+
+            if name == "append" {
+                if y.out_type(prog) != el_type {
+                    return Err(ParseErrorKind::Type(format!(
+                        "cannot append {} to an array of {}",
+                        y.out_type(prog),
+                        el_type
+                    )));
+                }
+                return Ok(Box::new(Append { arr: x, val: y }));
+            }
+
+            if y.out_type(prog) != DataType::Integer {
+                return Err(ParseErrorKind::Type("remove expects an int index".into()));
+            }
+            return Ok(Box::new(Remove { arr: x, index: y }));
+        }
+        "has" => {
+            let key = params.remove(0);
+            if x.out_type(prog).as_map().is_none() {
+                return Err(ParseErrorKind::Type(format!(
+                    "has expects a map, found {}",
+                    x.out_type(prog)
+                )));
+            }
+            if key.out_type(prog) != DataType::String {
+                return Err(ParseErrorKind::Type("has expects a string key".into()));
+            }
+            return Ok(Box::new(Has { map: x, key }));
+        }
+        "str" => return Ok(Box::new(Str { x })),
+        "len" => match x.out_type(prog) {
+            DataType::Array(_) | DataType::String => return Ok(Box::new(Len { x })),
+            t => {
+                return Err(ParseErrorKind::Type(format!(
+                    "len expects an array or string, found {}",
+                    t
+                )))
+            }
+        },
+        // Its first argument is a type, so the parser constructs it directly
+        "make" => panic!("make expects a type as its first argument"),
+        _ => panic!("unknown builtin: {}", name),
+    }
+}
+
+// Evaluates to the name of the type of x, eg. "int", "[]string" or the struct's name
+pub struct TypeOf {
+    pub x: Box<dyn Expr>,
+}
+
+impl Expr for TypeOf {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        let typ = self.x.out_type(prog);
+        self.x.emit(prog, block);
+        block.add_instr(stac::Instr::TypeOf { typ });
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return DataType::String;
+    }
+}
+
+// Evaluates to whether x is still waiting on an external resource
+pub struct IsWaiting {
+    pub x: Box<dyn Expr>,
+}
+
+impl Expr for IsWaiting {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        self.x.emit(prog, block);
+        block.add_instr(stac::Instr::IsWaiting);
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return DataType::Bool;
+    }
+}
+
+// Evaluates to whether x is null, eg. an optional field a provider left out
+pub struct IsNull {
+    pub x: Box<dyn Expr>,
+}
+
+impl Expr for IsNull {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        self.x.emit(prog, block);
+        block.add_instr(stac::Instr::IsNull);
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return DataType::Bool;
+    }
+}
+
+// Evaluates to whether the map has a value for the key
+pub struct Has {
+    pub map: Box<dyn Expr>,
+    pub key: Box<dyn Expr>,
+}
+
+impl Expr for Has {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        self.map.emit(prog, block);
+        self.key.emit(prog, block);
+        block.add_instr(stac::Instr::MapHas);
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return DataType::Bool;
+    }
+}
+
+// Converts x to an int or float, parsing it if it's a string
+pub struct Cast {
+    pub x: Box<dyn Expr>,
+    pub to: DataType,
+}
+
+impl Expr for Cast {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        self.x.emit(prog, block);
+        block.add_instr(stac::Instr::Cast { to: self.to });
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return self.to.clone();
+    }
+}
+
+// int(x) or float(x), where the name of the function is the type converted to
+pub fn cast(
+    to: DataType,
+    mut params: Vec<Box<dyn Expr>>,
+    prog: &stac::Prog,
+) -> Result<Box<dyn Expr>, ParseErrorKind> {
+    if params.len() != 1 {
+        return Err(ParseErrorKind::Type(format!(
+            "{} expects 1 argument, found {}",
+            to,
+            params.len()
+        )));
+    }
+
+    let x = params.remove(0);
+    match x.out_type(prog) {
+        DataType::Integer | DataType::Float | DataType::String => {
+            return Ok(Box::new(Cast { x, to }))
+        }
+        t => {
+            return Err(ParseErrorKind::Type(format!(
+                "cannot convert {} to {}",
+                t, to
+            )))
+        }
+    }
+}
+
+// Converts x to a string, eg. `42`, `[1, 2]` or `Droplet { name: "web" }`
+pub struct Str {
+    pub x: Box<dyn Expr>,
+}
+
+impl Expr for Str {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        let typ = self.x.out_type(prog);
+        self.x.emit(prog, block);
+        block.add_instr(stac::Instr::ToString { typ });
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return DataType::String;
+    }
+}
+
+// Integer division rounding towards negative infinity, as `/` always produces a float
+pub struct Div {
+    pub x: Box<dyn Expr>,
+    pub y: Box<dyn Expr>,
+}
+
+impl Expr for Div {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        if self.x.out_type(prog) != DataType::Integer || self.y.out_type(prog) != DataType::Integer
+        {
+            panic!("type error: div expects int arguments");
+        }
+
+        self.y.emit(prog, block);
+        self.x.emit(prog, block);
+        block.add_instr(stac::Instr::FloorDiv);
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return DataType::Integer;
+    }
+}
+
+// An array of a length only known at runtime, eg. `make([]string, n)`, with
+// every element set to the fill value or the default for its type
+pub struct Make {
+    pub typ: DataType,
+    pub len: Box<dyn Expr>,
+    pub fill: Option<Box<dyn Expr>>,
+}
+
+impl Expr for Make {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        let el_type = match &self.typ {
+            DataType::Array(el_type) => *el_type.clone(),
+            t => panic!("type error: make expects an array type, found {:?}", t),
+        };
+        if self.len.out_type(prog) != DataType::Integer {
+            panic!("type error: make expects an int length");
+        }
+
+        self.len.emit(prog, block);
+        block.add_instr(stac::Instr::CompoundCreate);
+
+        match self.fill {
+            Some(fill) => {
+                if fill.out_type(prog) != el_type {
+                    panic!(
+                        "type error: make fill value must be {:?}, found {:?}",
+                        el_type,
+                        fill.out_type(prog)
+                    );
+                }
+                fill.emit(prog, block);
+            }
+            None => block.add_instr(stac::Instr::LoadConst {
+                v: stac::DataVal::default_for(el_type, &prog.user_structs),
+            }),
+        }
+        block.add_instr(stac::Instr::CompoundFill);
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return self.typ.clone();
+    }
+}
+
+// A copy of the array with the value added to the end
+pub struct Append {
+    pub arr: Box<dyn Expr>,
+    pub val: Box<dyn Expr>,
+}
+
+impl Expr for Append {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        self.arr.emit(prog, block);
+        self.val.emit(prog, block);
+        block.add_instr(stac::Instr::CompoundPush);
+    }
+
+    fn out_type(&self, prog: &stac::Prog) -> DataType {
+        return self.arr.out_type(prog);
+    }
+}
+
+// A copy of the array without the element at the index
+pub struct Remove {
+    pub arr: Box<dyn Expr>,
+    pub index: Box<dyn Expr>,
+}
+
+impl Expr for Remove {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        self.arr.emit(prog, block);
+        self.index.emit(prog, block);
+        block.add_instr(stac::Instr::CompoundRemove);
+    }
+
+    fn out_type(&self, prog: &stac::Prog) -> DataType {
+        return self.arr.out_type(prog);
+    }
+}
+
+// The number of elements in an array, or characters in a string
+pub struct Len {
+    pub x: Box<dyn Expr>,
+}
+
+impl Expr for Len {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        self.x.emit(prog, block);
+        block.add_instr(stac::Instr::CompoundLen);
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return DataType::Integer;
+    }
+}
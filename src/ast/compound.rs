@@ -2,6 +2,7 @@ use crate::{ast::Const, ast::Expr, ast::Ident, ast::Stmt, stac, stac::DataType,
 
 pub struct ArrayLiteral {
     pub values: Vec<Box<dyn Expr>>,
+    pub el_type: DataType, // the type every value has, so an empty array still has a type
 }
 
 impl Expr for ArrayLiteral {
@@ -34,8 +35,8 @@ impl Expr for ArrayLiteral {
         }
     }
 
-    fn out_type(&self, prog: &stac::Prog) -> DataType {
-        return DataType::Array(Box::new(self.values[0].out_type(prog)));
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return DataType::Array(Box::new(self.el_type.clone()));
     }
 }
 
@@ -46,41 +47,144 @@ pub struct ArrayIndex {
 
 impl Expr for ArrayIndex {
     fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        let typ = self.arr.out_type(prog);
         self.arr.emit(prog, block);
         self.index.emit(prog, block);
-        block.add_instr(stac::Instr::CompoundGet);
+        block.add_instr(get_instr(&typ));
     }
 
     fn out_type(&self, prog: &stac::Prog) -> DataType {
-        return *self.arr.out_type(prog).into_array().unwrap();
+        return el_type(self.arr.out_type(prog));
+    }
+}
+
+// The type of the elements of an array, or the values of a map
+fn el_type(typ: DataType) -> DataType {
+    match typ {
+        DataType::Array(el) | DataType::Map(el) => return *el,
+        t => panic!("cannot index into {}", t),
     }
 }
 
-pub struct AssignArray {
+// Indexing into a map looks up a key rather than a position, and into a struct
+// the position of a field
+fn get_instr(typ: &DataType) -> stac::Instr {
+    match typ {
+        DataType::Map(_) => return stac::Instr::MapGet,
+        DataType::Struct(_) => return stac::Instr::FieldGet,
+        _ => return stac::Instr::CompoundGet,
+    }
+}
+
+fn set_instr(typ: &DataType) -> stac::Instr {
+    match typ {
+        DataType::Map(_) => return stac::Instr::MapSet,
+        DataType::Struct(_) => return stac::Instr::FieldSet,
+        _ => return stac::Instr::CompoundSet,
+    }
+}
+
+pub struct MapLiteral {
+    pub values: Vec<(Box<dyn Expr>, Box<dyn Expr>)>, // the keys and their values
+    pub val_type: DataType,
+}
+
+impl Expr for MapLiteral {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        block.add_instr(stac::Instr::LoadConst {
+            v: DataVal::Map(Default::default()),
+        });
+        for (key, val) in self.values {
+            key.emit(prog, block);
+            val.emit(prog, block);
+            block.add_instr(stac::Instr::MapSet);
+        }
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return DataType::Map(Box::new(self.val_type.clone()));
+    }
+}
+
+// A step along the path to the value being assigned
+pub enum PathStep {
+    Index(Box<dyn Expr>), // into an array or map
+    Field(String),
+}
+
+// An assignment to an element of an array, the value of a key in a map or the
+// field of a struct, nested in each other to any depth, eg. `d.v4[1].ip = "x";`
+pub struct AssignPath {
     pub expr: Box<dyn Expr>,
     pub id: Ident,
-    pub index: Box<dyn Expr>,
+    pub path: Vec<PathStep>, // outermost first
 }
 
-impl Stmt for AssignArray {
-    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
-        // Load the array
+impl Stmt for AssignPath {
+    fn emit(mut self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        // Load the outermost value
         block.add_instr(stac::Instr::LoadIdent { i: self.id.addr });
 
-        // Resolve the index
-        self.index.emit(prog, block);
+        // Get each inner value, keeping the outer one and the key for setting it later
+        let last = self.path.pop().unwrap();
+        let mut types = vec![self.id.data_type.clone()];
+        for step in self.path {
+            let typ = types.last().unwrap().clone();
+            let inner = emit_key(step, &typ, prog, block);
+            block.add_instr(stac::Instr::DupPair);
+            block.add_instr(get_instr(&typ));
+            types.push(inner);
+        }
 
-        // Resolve the expression
+        // Resolve the innermost key and the expression, checking constant
+        // values of a field now and the rest before extern calls
+        let typ = types.last().unwrap().clone();
+        if let (PathStep::Field(field), DataType::Struct(name)) = (&last, &typ) {
+            let idx = prog.user_structs[name].names[field];
+            if let Some(v) = self.expr.const_value() {
+                if let Err(e) = stac::check_field(&prog.user_structs, name, idx, &v) {
+                    panic!("{}", e);
+                }
+            }
+        }
+        emit_key(last, &typ, prog, block);
         self.expr.emit(prog, block);
 
-        // Set the value in the array
-        block.add_instr(stac::Instr::CompoundSet);
+        // Set each value in the one containing it, from the innermost
+        for typ in types.iter().rev() {
+            block.add_instr(set_instr(typ));
+        }
 
-        // Set the id to the array
+        // Set the id to the outermost value
         block.add_instr(stac::Instr::StoreIdent { i: self.id.addr });
     }
 }
 
+// Emits the index or field index of the step into a value of the type,
+// returning the type of the value it leads to
+fn emit_key(
+    step: PathStep,
+    typ: &DataType,
+    prog: &mut stac::Prog,
+    block: &mut stac::Block,
+) -> DataType {
+    match step {
+        PathStep::Index(index) => {
+            index.emit(prog, block);
+            return el_type(typ.clone());
+        }
+        PathStep::Field(field) => {
+            let strct = &prog.user_structs[typ.as_struct().unwrap()];
+            let idx = strct.names[&field];
+            let inner = strct.types[idx].clone();
+            block.add_instr(stac::Instr::LoadConst {
+                v: DataVal::Integer(idx as i64),
+            });
+            return inner;
+        }
+    }
+}
+
 pub struct StructAccess {
     pub expr: Box<dyn Expr>,
     pub field: String,
@@ -102,7 +206,7 @@ impl Expr for StructAccess {
             v: DataVal::Integer(idx as i64),
         });
 
-        block.add_instr(stac::Instr::CompoundGet);
+        block.add_instr(stac::Instr::FieldGet);
     }
 
     fn out_type(&self, prog: &stac::Prog) -> DataType {
@@ -136,6 +240,13 @@ impl Expr for StructLiteral {
                 v: DataVal::Integer(idx as i64),
             });
 
+            // Constant values can be checked now, the rest are checked before extern calls
+            if let Some(v) = value.const_value() {
+                if let Err(e) = stac::check_field(&prog.user_structs, &self.strct, idx, &v) {
+                    panic!("{}", e);
+                }
+            }
+
             let val = std::mem::replace(
                 value,
                 Box::new(Const {
@@ -145,7 +256,7 @@ impl Expr for StructLiteral {
             );
             val.emit(prog, block);
 
-            block.add_instr(stac::Instr::CompoundSet);
+            block.add_instr(stac::Instr::FieldSet);
             remaining_fields.remove(&field);
         }
 
@@ -158,7 +269,7 @@ impl Expr for StructLiteral {
             // Get the default value for the type
             let val = DataVal::default_for(strct.types[idx].clone(), &prog.user_structs);
             block.add_instr(stac::Instr::LoadConst { v: val });
-            block.add_instr(stac::Instr::CompoundSet);
+            block.add_instr(stac::Instr::FieldSet);
         }
     }
 
@@ -166,33 +277,3 @@ impl Expr for StructLiteral {
         return DataType::Struct(self.strct.clone());
     }
 }
-
-pub struct AssignStruct {
-    pub id: Ident,
-    pub field: String,
-    pub expr: Box<dyn Expr>,
-}
-
-impl Stmt for AssignStruct {
-    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
-        // Load the struct
-        block.add_instr(stac::Instr::LoadIdent { i: self.id.addr });
-
-        // Resolve the field to an index
-        let strct = prog
-            .user_structs
-            .get(&self.id.data_type.into_struct().unwrap())
-            .unwrap();
-
-        let idx = *strct.names.get(&self.field).unwrap();
-        block.add_instr(stac::Instr::LoadConst {
-            v: DataVal::Integer(idx as i64),
-        });
-
-        // Resolve the expression
-        self.expr.emit(prog, block);
-
-        // Set the field in the struct
-        block.add_instr(stac::Instr::CompoundSet);
-    }
-}
@@ -6,6 +6,31 @@ pub struct FuncCall {
     pub func: String,
 }
 
+impl FuncCall {
+    fn function<'a>(&self, prog: &'a stac::Prog) -> &'a stac::Function {
+        return prog
+            .user_functions
+            .get(&self.func)
+            .unwrap_or_else(|| panic!("unknown function: {}", self.func));
+    }
+
+    // The label of the function, reserving it if the function hasn't been emitted yet
+    fn label(&self, prog: &mut stac::Prog) -> stac::Label {
+        return reserve_label(prog, &self.func);
+    }
+}
+
+fn reserve_label(prog: &mut stac::Prog, name: &str) -> stac::Label {
+    let label = prog.user_functions[name].label;
+    if label != stac::Label::CONTINUE {
+        return label;
+    }
+
+    let label = prog.add_temp_block();
+    prog.user_functions.get_mut(name).unwrap().label = label;
+    return label;
+}
+
 impl Expr for FuncCall {
     fn emit(mut self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
         // Evaluate all of the parameters
@@ -23,12 +48,12 @@ impl Expr for FuncCall {
 
         // Call the function
         block.add_instr(stac::Instr::Call {
-            label: prog.user_functions.get(&self.func).unwrap().label,
+            label: self.label(prog),
         });
     }
 
     fn out_type(&self, prog: &stac::Prog) -> DataType {
-        let returns = &prog.user_functions.get(&self.func).unwrap().returns;
+        let returns = &self.function(prog).returns;
         if returns.len() == 1 {
             return returns[0].clone();
         } else {
@@ -53,9 +78,9 @@ impl Stmt for FuncCall {
         }
 
         // Call the function
-        let returns_count = prog.user_functions.get(&self.func).unwrap().returns.len();
+        let returns_count = self.function(prog).returns.len();
         block.add_instr(stac::Instr::Call {
-            label: prog.user_functions.get(&self.func).unwrap().label,
+            label: self.label(prog),
         });
 
         // Discard the returns
@@ -74,6 +99,11 @@ pub struct FuncImpl {
 
 impl Stmt for FuncImpl {
     fn emit(self: Box<Self>, prog: &mut stac::Prog, _block: &mut stac::Block) {
+        // Add the label of the function to the program before emitting the body,
+        // so that recursive calls can find it. Calls emitted before the function
+        // have already reserved it.
+        let body_label = reserve_label(prog, &self.name);
+
         let mut body_block = stac::Block::new();
 
         // Load the parameters into their assigned variables
@@ -83,12 +113,7 @@ impl Stmt for FuncImpl {
 
         // Emit the body
         self.body.emit(prog, &mut body_block);
-        let body_label = prog.add_block(body_block);
-
-        // Add the label of the function to the program
-        prog.user_functions
-            .entry(self.name)
-            .and_modify(|f| f.label = body_label);
+        prog.mod_block(body_block, body_label);
     }
 }
 
@@ -102,9 +127,20 @@ impl Stmt for ExternFuncImpl {
     fn emit(self: Box<Self>, prog: &mut stac::Prog, _block: &mut stac::Block) {
         let mut body_block = stac::Block::new();
 
+        // Check the params satisfy their constraints before they're sent anywhere
+        if self
+            .param_types
+            .iter()
+            .any(|t| stac::is_constrained(&prog.user_structs, t))
+        {
+            body_block.add_instr(stac::Instr::Validate {
+                types: self.param_types.clone(),
+            });
+        }
+
         // Add the name of this function to the eval stack
         body_block.add_instr(stac::Instr::LoadConst {
-            v: DataVal::String(self.name.clone()),
+            v: DataVal::String(self.name.as_str().into()),
         });
 
         // Make the extern call
@@ -112,12 +148,14 @@ impl Stmt for ExternFuncImpl {
             param_types: self.param_types,
             return_types: self.return_types,
         });
-        let body_label = prog.add_block(body_block);
-
-        // Add the label of the function to the program
-        prog.user_functions
-            .entry(self.name)
-            .and_modify(|f| f.label = body_label);
+        // Calls emitted before the function have already reserved its label
+        if prog.user_functions[&self.name].label == stac::Label::CONTINUE {
+            let body_label = prog.add_block(body_block);
+            prog.user_functions.get_mut(&self.name).unwrap().label = body_label;
+        } else {
+            let body_label = prog.user_functions[&self.name].label;
+            prog.mod_block(body_block, body_label);
+        }
     }
 }
 
@@ -3,8 +3,10 @@ use crate::{
     stac::{self, DataType, DataVal},
 };
 
+pub mod builtin;
 pub mod compound;
 pub mod func;
+pub mod test;
 
 pub trait Expr {
     // Resolve the expression, potentially adding instructions to the program,
@@ -12,6 +14,43 @@ pub trait Expr {
     fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block);
     // fn in_type(&self, prog: &tac::Prog) -> Vec<DataType>;
     fn out_type(&self, prog: &stac::Prog) -> DataType;
+
+    // The value of the expression, if it is known without executing it
+    fn const_value(&self) -> Option<DataVal> {
+        return None;
+    }
+
+    // Resolve the expression as a condition, branching to one of the labels
+    // depending on its value. Short circuiting operators branch straight to
+    // the labels rather than producing a bool first.
+    fn emit_cond(
+        self: Box<Self>,
+        prog: &mut stac::Prog,
+        block: &mut stac::Block,
+        if_true: stac::Label,
+        if_false: stac::Label,
+    ) {
+        self.emit(prog, block);
+        block.add_instr(stac::Instr::IfExpr { if_true, if_false });
+    }
+}
+
+// Emits a short circuiting expression that produces a value, by branching to
+// blocks that load the result. These are shared by the whole expression tree.
+fn emit_bool_value(expr: Box<dyn Expr>, prog: &mut stac::Prog, block: &mut stac::Block) {
+    let mut true_block = stac::Block::new();
+    true_block.add_instr(stac::Instr::LoadConst {
+        v: DataVal::Bool(true),
+    });
+
+    let mut false_block = stac::Block::new();
+    false_block.add_instr(stac::Instr::LoadConst {
+        v: DataVal::Bool(false),
+    });
+
+    let true_label = prog.add_block(true_block);
+    let false_label = prog.add_block(false_block);
+    expr.emit_cond(prog, block, true_label, false_label);
 }
 
 #[derive(Clone)]
@@ -44,8 +83,8 @@ impl Expr for Arith {
         self.y.emit(prog, block);
         self.x.emit(prog, block);
 
-        match x_type {
-            DataType::String => {
+        match (x_type, &self.op) {
+            (DataType::String, lexer::Token::C('+')) => {
                 block.add_instr(stac::Instr::Concat);
             }
             _ => {
@@ -60,6 +99,8 @@ impl Expr for Arith {
             Token::Eq | Token::Ne | Token::Le | Token::Ge | Token::C('>') | Token::C('<') => {
                 return DataType::Bool
             }
+            // Division always produces a float, see builtin::Div for integer division
+            Token::C('/') => return DataType::Float,
             _ => return self.x.out_type(prog),
         }
     }
@@ -81,6 +122,35 @@ impl Expr for Unary {
     }
 }
 
+// A string literal with expressions interpolated into it
+pub struct Interpolation {
+    pub parts: Vec<Box<dyn Expr>>,
+}
+
+impl Expr for Interpolation {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        // Concat puts the top of the stack first, so build the string from the end
+        for (idx, part) in self.parts.into_iter().rev().enumerate() {
+            match part.out_type(prog) {
+                DataType::String => part.emit(prog, block),
+                typ @ (DataType::Integer | DataType::Float | DataType::Bool) => {
+                    part.emit(prog, block);
+                    block.add_instr(stac::Instr::ToString { typ });
+                }
+                t => panic!("type error: cannot interpolate {:?} into a string", t),
+            }
+
+            if idx > 0 {
+                block.add_instr(stac::Instr::Concat);
+            }
+        }
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return DataType::String;
+    }
+}
+
 pub struct Const {
     pub value: DataVal,
     pub data_type: DataType,
@@ -94,6 +164,10 @@ impl Expr for Const {
     fn out_type(&self, _prog: &stac::Prog) -> DataType {
         return self.data_type.clone();
     }
+
+    fn const_value(&self) -> Option<DataVal> {
+        return Some(self.value.clone());
+    }
 }
 
 pub struct BoolOr {
@@ -103,36 +177,22 @@ pub struct BoolOr {
 
 impl Expr for BoolOr {
     fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
-        self.x.emit(prog, block);
-
-        // Lazy evaluate the second operand
-        let mut true_block = stac::Block::new();
-        let mut initially_false_block = stac::Block::new();
-        let mut finally_false_block = stac::Block::new();
-
-        true_block.add_instr(stac::Instr::LoadConst {
-            v: DataVal::Bool(true),
-        });
-
-        finally_false_block.add_instr(stac::Instr::LoadConst {
-            v: DataVal::Bool(false),
-        });
-
-        let true_label = prog.add_block(true_block);
-        let finally_false_label = prog.add_block(finally_false_block);
-
-        self.y.emit(prog, &mut initially_false_block);
-
-        initially_false_block.add_instr(stac::Instr::IfExpr {
-            if_true: true_label,
-            if_false: finally_false_label,
-        });
+        emit_bool_value(self, prog, block);
+    }
 
-        let initially_false_label = prog.add_block(initially_false_block);
-        block.add_instr(stac::Instr::IfExpr {
-            if_true: true_label,
-            if_false: initially_false_label,
-        })
+    fn emit_cond(
+        self: Box<Self>,
+        prog: &mut stac::Prog,
+        block: &mut stac::Block,
+        if_true: stac::Label,
+        if_false: stac::Label,
+    ) {
+        // Lazy evaluate the second operand, only if the first was false
+        let mut y_block = stac::Block::new();
+        self.y.emit_cond(prog, &mut y_block, if_true, if_false);
+        let y_label = prog.add_block(y_block);
+
+        self.x.emit_cond(prog, block, if_true, y_label);
     }
 
     fn out_type(&self, _prog: &stac::Prog) -> DataType {
@@ -147,36 +207,22 @@ pub struct BoolAnd {
 
 impl Expr for BoolAnd {
     fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
-        self.x.emit(prog, block);
-
-        // Lazy evaluate the second operand
-        let mut false_block = stac::Block::new();
-        let mut initially_true_block = stac::Block::new();
-        let mut finally_true_block = stac::Block::new();
-
-        false_block.add_instr(stac::Instr::LoadConst {
-            v: DataVal::Bool(false),
-        });
-
-        finally_true_block.add_instr(stac::Instr::LoadConst {
-            v: DataVal::Bool(true),
-        });
-
-        let false_label = prog.add_block(false_block);
-        let finally_true_label = prog.add_block(finally_true_block);
-
-        self.y.emit(prog, &mut initially_true_block);
-
-        initially_true_block.add_instr(stac::Instr::IfExpr {
-            if_true: finally_true_label,
-            if_false: false_label,
-        });
+        emit_bool_value(self, prog, block);
+    }
 
-        let initially_true_label = prog.add_block(initially_true_block);
-        block.add_instr(stac::Instr::IfExpr {
-            if_true: initially_true_label,
-            if_false: false_label,
-        })
+    fn emit_cond(
+        self: Box<Self>,
+        prog: &mut stac::Prog,
+        block: &mut stac::Block,
+        if_true: stac::Label,
+        if_false: stac::Label,
+    ) {
+        // Lazy evaluate the second operand, only if the first was true
+        let mut y_block = stac::Block::new();
+        self.y.emit_cond(prog, &mut y_block, if_true, if_false);
+        let y_label = prog.add_block(y_block);
+
+        self.x.emit_cond(prog, block, y_label, if_false);
     }
 
     fn out_type(&self, _prog: &stac::Prog) -> DataType {
@@ -201,6 +247,37 @@ impl Expr for BoolNot {
     }
 }
 
+// Evaluates to x if the condition is true, otherwise y. Each branch stores its
+// value in a temporary, so that a waiting condition marks the result as waiting
+// when its side effects are evaluated.
+pub struct IfValue {
+    pub expr: Box<dyn Expr>,
+    pub tmp: Ident,
+    pub x: Box<dyn Expr>,
+    pub y: Box<dyn Expr>,
+}
+
+impl Expr for IfValue {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        let mut true_block = stac::Block::new();
+        self.x.emit(prog, &mut true_block);
+        true_block.add_instr(stac::Instr::StoreIdent { i: self.tmp.addr });
+        let true_label = prog.add_block(true_block);
+
+        let mut false_block = stac::Block::new();
+        self.y.emit(prog, &mut false_block);
+        false_block.add_instr(stac::Instr::StoreIdent { i: self.tmp.addr });
+        let false_label = prog.add_block(false_block);
+
+        self.expr.emit_cond(prog, block, true_label, false_label);
+        block.add_instr(stac::Instr::LoadIdent { i: self.tmp.addr });
+    }
+
+    fn out_type(&self, _prog: &stac::Prog) -> DataType {
+        return self.tmp.data_type.clone();
+    }
+}
+
 pub trait Stmt {
     fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block);
 }
@@ -212,19 +289,14 @@ pub struct If {
 
 impl Stmt for If {
     fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
-        // Resolve the expr
-        self.expr.emit(prog, block);
-
         // Execute the statement if true
         let mut true_block = stac::Block::new();
         self.stmt.emit(prog, &mut true_block);
         let true_label = prog.add_block(true_block);
 
-        // Point if to correct labels
-        block.add_instr(stac::Instr::IfExpr {
-            if_true: true_label,
-            if_false: stac::Label::CONTINUE,
-        })
+        // Resolve the expr, pointing it to the correct labels
+        self.expr
+            .emit_cond(prog, block, true_label, stac::Label::CONTINUE);
     }
 }
 
@@ -236,9 +308,6 @@ pub struct IfElse {
 
 impl Stmt for IfElse {
     fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
-        // Resolve the expr
-        self.expr.emit(prog, block);
-
         // Create the true block
         let mut true_block = stac::Block::new();
         self.stmt_t.emit(prog, &mut true_block);
@@ -249,17 +318,71 @@ impl Stmt for IfElse {
         self.stmt_f.emit(prog, &mut false_block);
         let false_label = prog.add_block(false_block);
 
-        // Point if to correct labels
-        block.add_instr(stac::Instr::IfExpr {
-            if_true: true_label,
-            if_false: false_label,
-        })
+        // Resolve the expr, pointing it to the correct labels
+        self.expr.emit_cond(prog, block, true_label, false_label);
     }
 }
 
+// Runs the first arm whose value equals the expression, or the default arm if
+// none do. The expression is stored in a temporary so it's only evaluated once.
+pub struct Match {
+    pub expr: Box<dyn Expr>,
+    pub tmp: Ident,
+    pub arms: Vec<(Const, Box<dyn Stmt>)>,
+    pub default: Option<Box<dyn Stmt>>,
+}
+
+impl Stmt for Match {
+    fn emit(mut self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        self.expr.emit(prog, block);
+        block.add_instr(stac::Instr::StoreIdent { i: self.tmp.addr });
+
+        let mut next_label = match self.default {
+            Some(default) => {
+                let mut default_block = stac::Block::new();
+                default.emit(prog, &mut default_block);
+                prog.add_block(default_block)
+            }
+            None => stac::Label::CONTINUE,
+        };
+
+        // Build the chain of comparisons from the last arm, so each can branch to
+        // the next. The first comparison goes in the current block.
+        let first = self.arms.remove(0);
+        for (value, stmt) in self.arms.into_iter().rev() {
+            let mut cmp_block = stac::Block::new();
+            emit_arm(prog, &mut cmp_block, &self.tmp, value, stmt, next_label);
+            next_label = prog.add_block(cmp_block);
+        }
+        emit_arm(prog, block, &self.tmp, first.0, first.1, next_label);
+    }
+}
+
+fn emit_arm(
+    prog: &mut stac::Prog,
+    block: &mut stac::Block,
+    tmp: &Ident,
+    value: Const,
+    stmt: Box<dyn Stmt>,
+    if_false: stac::Label,
+) {
+    let mut arm_block = stac::Block::new();
+    stmt.emit(prog, &mut arm_block);
+    let arm_label = prog.add_block(arm_block);
+
+    let cmp = Box::new(Arith {
+        op: lexer::Token::Eq,
+        x: Box::new(tmp.clone()),
+        y: Box::new(value),
+    });
+    cmp.emit_cond(prog, block, arm_label, if_false);
+}
+
 pub struct While {
     pub expr: Box<dyn Expr>,
     pub stmt: Box<dyn Stmt>,
+    pub post: Option<Box<dyn Stmt>>, // runs after each iteration, even one ended by continue
+    pub completed: Option<Box<dyn Stmt>>, // only runs if the loop wasn't exited by break
 }
 
 impl Stmt for While {
@@ -267,20 +390,73 @@ impl Stmt for While {
         // Resolve the expr, then run the stmt & re-eval if true
         let stmt_label = prog.add_temp_block();
 
+        // A false condition will run the completed clause if there is one, then
+        // continue, automatically unwinding the entire call stack
+        let exit_label = match self.completed {
+            Some(completed) => {
+                let mut completed_block = stac::Block::new();
+                completed.emit(prog, &mut completed_block);
+                prog.add_block(completed_block)
+            }
+            None => stac::Label::CONTINUE,
+        };
+
         let mut expr_block = stac::Block::new();
-        self.expr.emit(prog, &mut expr_block);
-        expr_block.add_instr(stac::Instr::IfExpr {
-            if_true: stmt_label,
-            if_false: stac::Label::CONTINUE, // will automatically unwind the entire call stack
-        });
+        self.expr
+            .emit_cond(prog, &mut expr_block, stmt_label, exit_label);
         let expr_label = prog.add_block(expr_block);
 
+        // Each iteration ends by going to the post statement, if there is one.
+        // Like a continue, this unwinds the frames of the iteration first.
+        let next_label = match self.post {
+            Some(post) => {
+                let mut post_block = stac::Block::new();
+                post.emit(prog, &mut post_block);
+                post_block.add_instr(stac::Instr::Continue {
+                    loops: 1,
+                    label: expr_label,
+                });
+                prog.add_block(post_block)
+            }
+            None => expr_label,
+        };
+
         let mut stmt_block = stac::Block::new();
+        prog.continue_labels.push(next_label);
         self.stmt.emit(prog, &mut stmt_block);
-        stmt_block.add_instr(stac::Instr::Goto { label: expr_label });
+        prog.continue_labels.pop();
+        stmt_block.add_instr(stac::Instr::Continue {
+            loops: 1,
+            label: next_label,
+        });
         prog.mod_block(stmt_block, stmt_label);
 
-        block.add_instr(stac::Instr::Goto { label: expr_label });
+        // Break unwinds to this frame, skipping the completed clause
+        block.add_instr(stac::Instr::Loop { label: expr_label });
+    }
+}
+
+pub struct Break {
+    pub loops: usize, // the number of enclosing loops to exit
+}
+
+impl Stmt for Break {
+    fn emit(self: Box<Self>, _prog: &mut stac::Prog, block: &mut stac::Block) {
+        block.add_instr(stac::Instr::Break { loops: self.loops });
+    }
+}
+
+pub struct Continue {
+    pub loops: usize, // the number of enclosing loops to continue the last of
+}
+
+impl Stmt for Continue {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        let label = prog.continue_labels[prog.continue_labels.len() - self.loops];
+        block.add_instr(stac::Instr::Continue {
+            loops: self.loops,
+            label,
+        });
     }
 }
 
@@ -300,14 +476,14 @@ impl Stmt for Assign {
 }
 
 pub struct Seq {
-    pub stmt1: Box<dyn Stmt>,
-    pub stmt2: Box<dyn Stmt>,
+    pub stmts: Vec<Box<dyn Stmt>>,
 }
 
 impl Stmt for Seq {
     fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
-        self.stmt1.emit(prog, block);
-        self.stmt2.emit(prog, block);
+        for stmt in self.stmts {
+            stmt.emit(prog, block);
+        }
     }
 }
 
@@ -0,0 +1,34 @@
+use crate::{ast::Expr, ast::Stmt, stac, stac::DataType};
+
+// A test declared in the program, which only runs under Prog::run_tests
+pub struct TestBlock {
+    pub name: String,
+    pub body: Box<dyn Stmt>,
+}
+
+impl Stmt for TestBlock {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, _block: &mut stac::Block) {
+        // The body is kept out of the enclosing block, so it can only be entered directly
+        let mut body_block = stac::Block::new();
+        self.body.emit(prog, &mut body_block);
+
+        let label = prog.add_block(body_block);
+        prog.tests.push((self.name, label));
+    }
+}
+
+pub struct Assert {
+    pub expr: Box<dyn Expr>,
+    pub msg: String,
+}
+
+impl Stmt for Assert {
+    fn emit(self: Box<Self>, prog: &mut stac::Prog, block: &mut stac::Block) {
+        if self.expr.out_type(prog) != DataType::Bool {
+            panic!("type error: can only assert a bool");
+        }
+
+        self.expr.emit(prog, block);
+        block.add_instr(stac::Instr::Assert { msg: self.msg });
+    }
+}
@@ -0,0 +1,253 @@
+// Comparing the extern calls a plan would make against the calls recorded in
+// a state store, to show what applying the plan would change.
+//
+// The recorded params are decoded into the plan's struct layout before they're
+// compared, so fields added or reordered since don't show up as changes.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::plan::{ExecutionPlan, PlannedCall};
+use crate::stac::{CallId, DataType, DataVal, Struct};
+use crate::state::{self, RecordedCall, StateSnapshot};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CallChange {
+    Unchanged,
+    Changed { fields: Vec<FieldChange> },
+    Added,   // planned, but never made
+    Removed, // made before, but no longer planned
+}
+
+// A value that differs between the recorded and planned params, eg. the field
+// at "networks.v4[0].ip_address". Each side is shown as it would appear in
+// source, and is None when the element doesn't exist on that side.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub path: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CallDiff {
+    pub id: CallId,
+    pub function: String,
+    pub change: CallChange,
+}
+
+/// How the calls of a plan differ from the calls recorded in a state store,
+/// from `diff`, ordered by id.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StateDiff {
+    pub calls: Vec<CallDiff>,
+}
+
+impl StateDiff {
+    /// Whether applying the plan would change nothing.
+    pub fn is_empty(&self) -> bool {
+        return self.calls.iter().all(|c| c.change == CallChange::Unchanged);
+    }
+}
+
+// Lists the calls that changed, with a line for each of their changed fields,
+// followed by a count of the changes, eg.
+//
+//   ~ createDroplet 3:1:0
+//       size: "s-1vcpu" -> "s-2vcpu"
+//   Diff: 0 to add, 1 to change, 0 to remove.
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |v: &Option<String>| v.clone().unwrap_or_else(|| "(none)".to_string());
+
+        let mut counts = [0; 3];
+        for call in &self.calls {
+            match &call.change {
+                CallChange::Unchanged => continue,
+                CallChange::Added => {
+                    counts[0] += 1;
+                    writeln!(f, "+ {} {}", call.function, call.id)?;
+                }
+                CallChange::Changed { fields } => {
+                    counts[1] += 1;
+                    writeln!(f, "~ {} {}", call.function, call.id)?;
+                    for field in fields {
+                        writeln!(
+                            f,
+                            "    {}: {} -> {}",
+                            field.path,
+                            show(&field.old),
+                            show(&field.new)
+                        )?;
+                    }
+                }
+                CallChange::Removed => {
+                    counts[2] += 1;
+                    writeln!(f, "- {} {}", call.function, call.id)?;
+                }
+            }
+        }
+        return writeln!(
+            f,
+            "Diff: {} to add, {} to change, {} to remove.",
+            counts[0], counts[1], counts[2]
+        );
+    }
+}
+
+/// Classifies each call by its id, as unchanged, changed, added or removed,
+/// by comparing the params it was made with against the params it would be
+/// made with.
+pub fn diff(old: &StateSnapshot, new_plan: &ExecutionPlan) -> StateDiff {
+    let mut recorded: BTreeMap<CallId, &RecordedCall> =
+        old.calls.iter().map(|c| (c.id, c)).collect();
+
+    let mut calls = vec![];
+    for call in &new_plan.calls {
+        let change = match recorded.remove(&call.id) {
+            Some(old) => {
+                let fields = diff_params(old, call, &new_plan.user_structs);
+                if fields.is_empty() {
+                    CallChange::Unchanged
+                } else {
+                    CallChange::Changed { fields }
+                }
+            }
+            None => CallChange::Added,
+        };
+        calls.push(CallDiff {
+            id: call.id,
+            function: call.function.clone(),
+            change,
+        });
+    }
+
+    for (id, old) in recorded {
+        calls.push(CallDiff {
+            id,
+            function: old.function.clone(),
+            change: CallChange::Removed,
+        });
+    }
+
+    calls.sort_by_key(|c| c.id);
+    return StateDiff { calls };
+}
+
+fn diff_params(
+    old: &RecordedCall,
+    new: &PlannedCall,
+    user_structs: &HashMap<String, Struct>,
+) -> Vec<FieldChange> {
+    let mut changes = vec![];
+
+    // The fields of a call's only struct param are named without its position
+    let sole_struct = matches!(new.param_types.as_slice(), [DataType::Struct(_)]);
+    let len = old.params.len().max(new.params.len());
+    for idx in 0..len {
+        let path = if sole_struct {
+            String::new()
+        } else {
+            format!("[{}]", idx)
+        };
+
+        let (old, val, typ) = match (old.params.get(idx), new.params.get(idx)) {
+            (Some(old), Some(val)) => (old, val, &new.param_types[idx]),
+            (old, val) => {
+                changes.push(FieldChange {
+                    path,
+                    old: old.map(|v| v.to_string()),
+                    new: val
+                        .zip(new.param_types.get(idx))
+                        .map(|(v, t)| show(v, t, user_structs)),
+                });
+                continue;
+            }
+        };
+
+        // A param that can't be loaded as its new type has changed as a whole
+        match state::decode(old, typ, user_structs) {
+            Ok(old) => diff_vals(&path, &old, val, typ, user_structs, &mut changes),
+            Err(_) => changes.push(FieldChange {
+                path,
+                old: Some(old.to_string()),
+                new: Some(show(val, typ, user_structs)),
+            }),
+        }
+    }
+    return changes;
+}
+
+// Records the differences between the values, recursing into compounds
+fn diff_vals(
+    path: &str,
+    old: &DataVal,
+    new: &DataVal,
+    typ: &DataType,
+    user_structs: &HashMap<String, Struct>,
+    changes: &mut Vec<FieldChange>,
+) {
+    let change = |path: String, old: Option<&DataVal>, new: Option<&DataVal>, typ| {
+        return FieldChange {
+            path,
+            old: old.map(|v| show(v, typ, user_structs)),
+            new: new.map(|v| show(v, typ, user_structs)),
+        };
+    };
+
+    match (old, new, typ) {
+        (DataVal::Compound(old), DataVal::Compound(new), DataType::Struct(name)) => {
+            let strct = &user_structs[name];
+            for idx in 0..strct.types.len() {
+                let field = strct.field_name(idx);
+                let path = if path.is_empty() {
+                    field.to_string()
+                } else {
+                    format!("{}.{}", path, field)
+                };
+                diff_vals(
+                    &path,
+                    &old[idx],
+                    &new[idx],
+                    &strct.types[idx],
+                    user_structs,
+                    changes,
+                );
+            }
+        }
+        (DataVal::Compound(old), DataVal::Compound(new), DataType::Array(el_typ)) => {
+            for idx in 0..old.len().max(new.len()) {
+                let path = format!("{}[{}]", path, idx);
+                match (old.get(idx), new.get(idx)) {
+                    (Some(old), Some(new)) => {
+                        diff_vals(&path, old, new, el_typ, user_structs, changes)
+                    }
+                    (old, new) => changes.push(change(path, old, new, el_typ)),
+                }
+            }
+        }
+        (DataVal::Map(old), DataVal::Map(new), DataType::Map(val_typ)) => {
+            let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                let path = format!("{}[{:?}]", path, key);
+                match (old.get(key), new.get(key)) {
+                    (Some(old), Some(new)) => {
+                        diff_vals(&path, old, new, val_typ, user_structs, changes)
+                    }
+                    (old, new) => changes.push(change(path, old, new, val_typ)),
+                }
+            }
+        }
+        _ if old == new => {}
+        _ => changes.push(change(path.to_string(), Some(old), Some(new), typ)),
+    }
+}
+
+fn show(val: &DataVal, typ: &DataType, user_structs: &HashMap<String, Struct>) -> String {
+    if val.contains_waiting() {
+        return "(known after apply)".to_string();
+    }
+    return val.display_typed(typ, user_structs);
+}
@@ -0,0 +1,206 @@
+use std::fmt::Write;
+
+use crate::stac::{Addr, Instr, Label};
+
+// The number of trace entries shown either side of an interesting point in a failure
+const EXCERPT_CONTEXT: usize = 5;
+
+// An instruction executed by the interpreter
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    pub ip: (usize, usize),
+    pub instr: Instr,
+    pub side_effect: bool, // whether it was only scanned for side effects
+}
+
+impl TraceEntry {
+    // Whether the interpreter entered a block to execute this instruction
+    pub fn enters_block(&self) -> bool {
+        return self.ip.1 == 0;
+    }
+}
+
+// The name of the instruction's variant, eg. "StoreIdent"
+pub fn instr_kind(instr: &Instr) -> String {
+    let debug = format!("{:?}", instr);
+    return debug
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap()
+        .to_string();
+}
+
+/// Something that can happen during execution, used to constrain the order of events.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// Entering the start of a block
+    Block(Label),
+    /// Executing any instruction of a kind, eg. `"ExternCall"`
+    Instr(&'static str),
+    /// Storing to a variable
+    Store(Addr),
+}
+
+impl Event {
+    fn matches(&self, entry: &TraceEntry) -> bool {
+        match self {
+            Event::Block(label) => entry.ip.0 == label.0 && entry.enters_block(),
+            Event::Instr(kind) => instr_kind(&entry.instr) == *kind,
+            Event::Store(addr) => matches!(entry.instr, Instr::StoreIdent { i } if i == *addr),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Check {
+    BlockVisits(Label, usize),
+    InstrCount(Label, &'static str, usize),
+    Before(Event, Event),
+}
+
+/// Assertions about how a program executes, rather than just its final variables.
+///
+/// Attach them with `Prog::expect` before executing, then check them with
+/// `Prog::verify_expectations`. A failure describes the broken expectation along
+/// with the part of the execution trace it concerns.
+///
+/// ```
+/// use dmcl::expect::{Event, ExecExpectations};
+/// use dmcl::stac::{Addr, Label};
+/// use dmcl::{lexer, parser};
+///
+/// let l = lexer::Lexer::new("p := 0; while p < 3 { p = p + 1; }".chars().collect());
+/// let mut par = parser::Parser::new(l);
+/// let prog = par.program().unwrap();
+///
+/// prog.expect(
+///     ExecExpectations::new()
+///         // The loop body is block 0 (it is reserved before the condition)
+///         .block_visits(Label(0), 3)
+///         .instr_count(Label(0), "StoreIdent", 3)
+///         .before(Event::Store(Addr(0)), Event::Block(Label(0))),
+/// );
+/// prog.execute().unwrap();
+/// prog.verify_expectations().unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ExecExpectations {
+    checks: Vec<Check>,
+}
+
+impl ExecExpectations {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Expect the block to be entered exactly `count` times
+    pub fn block_visits(mut self, label: Label, count: usize) -> Self {
+        self.checks.push(Check::BlockVisits(label, count));
+        return self;
+    }
+
+    /// Expect exactly `count` instructions of a kind to be executed in the block
+    pub fn instr_count(mut self, label: Label, kind: &'static str, count: usize) -> Self {
+        self.checks.push(Check::InstrCount(label, kind, count));
+        return self;
+    }
+
+    /// Expect the first occurrence of `first` to happen before the first occurrence of `then`
+    pub fn before(mut self, first: Event, then: Event) -> Self {
+        self.checks.push(Check::Before(first, then));
+        return self;
+    }
+
+    /// Check every expectation against a trace, describing the first that failed
+    pub fn verify(&self, trace: &[TraceEntry]) -> Result<(), String> {
+        for check in &self.checks {
+            match check {
+                Check::BlockVisits(label, count) => {
+                    let visits: Vec<usize> = (0..trace.len())
+                        .filter(|&i| Event::Block(*label).matches(&trace[i]))
+                        .collect();
+
+                    if visits.len() != *count {
+                        return Err(format!(
+                            "expected block {} to be visited {} times, but it was visited {} times\n{}",
+                            label.0,
+                            count,
+                            visits.len(),
+                            excerpt(trace, visits.last().copied())
+                        ));
+                    }
+                }
+                Check::InstrCount(label, kind, count) => {
+                    let found: Vec<usize> = (0..trace.len())
+                        .filter(|&i| {
+                            trace[i].ip.0 == label.0 && instr_kind(&trace[i].instr) == *kind
+                        })
+                        .collect();
+
+                    if found.len() != *count {
+                        return Err(format!(
+                            "expected {} {} instructions in block {}, but found {}\n{}",
+                            count,
+                            kind,
+                            label.0,
+                            found.len(),
+                            excerpt(trace, found.last().copied())
+                        ));
+                    }
+                }
+                Check::Before(first, then) => {
+                    let first_at = trace.iter().position(|e| first.matches(e));
+                    let then_at = trace.iter().position(|e| then.matches(e));
+
+                    match (first_at, then_at) {
+                        (Some(f), Some(t)) if f < t => {}
+                        (None, _) => {
+                            return Err(format!(
+                                "expected {:?} to happen, but it never did",
+                                first
+                            ));
+                        }
+                        (Some(_), None) => {
+                            return Err(format!("expected {:?} to happen, but it never did", then));
+                        }
+                        (Some(_), Some(t)) => {
+                            return Err(format!(
+                                "expected {:?} to happen before {:?}\n{}",
+                                first,
+                                then,
+                                excerpt(trace, Some(t))
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+// Renders the trace around an index of interest, or the end of the trace
+fn excerpt(trace: &[TraceEntry], around: Option<usize>) -> String {
+    let center = around.unwrap_or(trace.len().saturating_sub(1));
+    let start = center.saturating_sub(EXCERPT_CONTEXT);
+    let end = (center + EXCERPT_CONTEXT + 1).min(trace.len());
+
+    let mut out = String::from("trace:\n");
+    for (idx, entry) in trace.iter().enumerate().take(end).skip(start) {
+        let marker = if Some(idx) == around { ">" } else { " " };
+        let mode = if entry.side_effect {
+            " (side effect)"
+        } else {
+            ""
+        };
+        writeln!(
+            out,
+            "{} {:5} @ {:?} : {:?}{}",
+            marker, idx, entry.ip, entry.instr, mode
+        )
+        .unwrap();
+    }
+
+    return out;
+}
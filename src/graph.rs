@@ -0,0 +1,111 @@
+// The dependencies between extern calls, found by tracking which calls each
+// value was derived from as the program executes or is planned.
+//
+// Rather than tagging the values themselves, the lineage shadows the eval stack
+// and the variables. After each instr, everything it pushed is derived from
+// everything it popped, so a field of a struct returned by a call is derived
+// from the call, as is anything computed from the field. Calls to the stdlib
+// don't create anything, so their returns are derived from their params rather
+// than from the call.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::stac::{CallId, Instr};
+
+type Origins = BTreeSet<CallId>;
+
+#[derive(Default)]
+pub struct Lineage {
+    stack: Vec<Origins>,
+    variables: Vec<Origins>,
+    // The function of every call made, and the origins of the call's returns
+    calls: BTreeMap<CallId, String>,
+    returns: Origins,
+    edges: BTreeSet<(CallId, CallId)>,
+}
+
+impl Lineage {
+    // Records the lineage of the values the instr at the top of the stack
+    // pushed, given the stack's length before and after it. Returns the origins
+    // of the values it popped.
+    pub fn step(&mut self, instr: &Instr, before: usize, after: usize) -> Origins {
+        // The stack can be changed by the host, which only loses lineage
+        self.stack.resize_with(before, Origins::new);
+
+        let remaining = before.saturating_sub(instr.operands());
+        let mut origins: Origins = self.stack.drain(remaining..).flatten().collect();
+        match instr {
+            Instr::LoadIdent { i } => {
+                if let Some(vars) = self.variables.get(i.0) {
+                    origins.extend(vars.iter().copied());
+                }
+            }
+            Instr::StoreIdent { i } => {
+                if self.variables.len() <= i.0 {
+                    self.variables.resize_with(i.0 + 1, Origins::new);
+                }
+                self.variables[i.0] = origins.clone();
+            }
+            _ => {}
+        }
+
+        for _ in remaining..after {
+            self.stack.push(origins.clone());
+        }
+        return origins;
+    }
+
+    // An extern call is being made, with params derived from the origins
+    pub fn call(&mut self, id: CallId, func: &str, origins: Origins, in_stdlib: bool) {
+        if in_stdlib {
+            self.returns = origins;
+            return;
+        }
+
+        for from in origins {
+            if from != id {
+                self.edges.insert((from, id));
+            }
+        }
+        self.calls.insert(id, func.to_string());
+        self.returns = Origins::from([id]);
+    }
+
+    // The returns of the last call were pushed
+    pub fn returned(&mut self, count: usize) {
+        for _ in 0..count {
+            self.stack.push(self.returns.clone());
+        }
+    }
+
+    pub fn edges(&self) -> Vec<(CallId, CallId)> {
+        return self.edges.iter().copied().collect();
+    }
+
+    pub fn calls(&self) -> &BTreeMap<CallId, String> {
+        return &self.calls;
+    }
+}
+
+/// Renders the calls and the edges between them in the DOT language, with
+/// each call labelled by its function and id, eg.
+///
+/// ```text
+/// digraph dependencies {
+///     "3:1:0" [label="createA 3:1:0"];
+///     "4:1:0" [label="createB 4:1:0"];
+///     "3:1:0" -> "4:1:0";
+/// }
+/// ```
+pub fn render_dot(calls: &BTreeMap<CallId, String>, edges: &[(CallId, CallId)]) -> String {
+    let mut out = String::from("digraph dependencies {\n");
+    for (id, func) in calls {
+        writeln!(out, "    \"{}\" [label=\"{} {}\"];", id, func, id).unwrap();
+    }
+    for (from, to) in edges {
+        writeln!(out, "    \"{}\" -> \"{}\";", from, to).unwrap();
+    }
+    out.push_str("}\n");
+    return out;
+}
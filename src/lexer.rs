@@ -1,39 +1,66 @@
 use core::fmt;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, BufRead, BufReader};
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::sync::Arc;
 
 use enum_as_inner::EnumAsInner;
+use serde::{Deserialize, Serialize};
 
 use crate::stac;
 
 pub struct Lexer {
-    source: Vec<char>,
-    index: usize, // index of that first character we have not parsed
+    source: Peekable<Box<dyn Iterator<Item = char>>>, // the characters after peek
     peek: char,
     line: i64,
 
-    word_table: HashMap<String, Token>,
+    // Tokens that have been peeked but not scanned, with the line lexing them ended on
+    peeked: VecDeque<(Result<Token, String>, i64)>,
+    scanned_line: i64, // the line the last scanned token ended on
+
+    // Every identifier seen so far, so that words share their strings
+    identifiers: HashSet<Arc<str>>,
+
+    // Set by a reader source that failed, which ends the characters early
+    read_error: Rc<RefCell<Option<String>>>,
 }
 
-#[derive(Clone, Debug, PartialEq, EnumAsInner)]
+#[derive(Clone, Debug, PartialEq, EnumAsInner, Serialize, Deserialize)]
 pub enum Token {
     C(char), // the character itself
     Integer(i64),
     Float(f64),
-    Word(String),
-    String(String), // a string literal
+    Word(Arc<str>),
+    String(String),             // a string literal
+    Interpolated(Vec<StrPart>), // a string literal containing ${...}
     Type(stac::DataType),
 
     If,
     Else,
     While,
+    For,
     True,
     False,
+    Null,
     Func,
     Return,
     Struct,
     Extern,
+    Break,
+    Continue,
+    Completed,
+    In,
+    Matches,
+    Match,
+    Assert,
+    Var,
+    Import,
+    Input,
 
     DeclAssign,
+    Range, // ..
     BoolOr,
     BoolAnd,
     Eq,
@@ -45,6 +72,13 @@ pub enum Token {
 }
 
 // We need this for a to_string() method
+// A piece of an interpolated string literal
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StrPart {
+    Lit(String),
+    Expr(String), // the source of the expression between ${ and }
+}
+
 impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -53,39 +87,60 @@ impl fmt::Display for Token {
 
 impl Lexer {
     pub fn new(src: Vec<char>) -> Self {
-        // Reserve entries in word table
-        let mut wt = HashMap::new();
-        wt.insert("if".to_string(), Token::If);
-        wt.insert("else".to_string(), Token::Else);
-        wt.insert("while".to_string(), Token::While);
-        wt.insert("true".to_string(), Token::True);
-        wt.insert("false".to_string(), Token::False);
-        wt.insert("int".to_string(), Token::Type(stac::DataType::Integer));
-        wt.insert("float".to_string(), Token::Type(stac::DataType::Float));
-        wt.insert("bool".to_string(), Token::Type(stac::DataType::Bool));
-        wt.insert("string".to_string(), Token::Type(stac::DataType::String));
-        wt.insert("func".to_string(), Token::Func);
-        wt.insert("return".to_string(), Token::Return);
-        wt.insert("extern".to_string(), Token::Extern);
-        wt.insert("struct".to_string(), Token::Struct);
+        return Self::from_chars(Box::new(src.into_iter()), Rc::default());
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(src: &str) -> Self {
+        return Self::new(src.chars().collect());
+    }
+
+    // Reads the source a line at a time, rather than all at once. If it can't
+    // be read, or isn't UTF-8, scanning fails with a lexer error. The parser
+    // peeks at every token of a unit before parsing it, to declare its
+    // functions, so its tokens are still all held at once, but not its text.
+    pub fn from_reader(src: impl io::Read + 'static) -> Self {
+        let error = Rc::default();
+        return Self::from_chars(
+            Box::new(ReaderChars {
+                reader: BufReader::new(src),
+                line: Vec::new().into_iter(),
+                lines: 0,
+                error: Rc::clone(&error),
+            }),
+            error,
+        );
+    }
 
+    fn from_chars(
+        src: Box<dyn Iterator<Item = char>>,
+        read_error: Rc<RefCell<Option<String>>>,
+    ) -> Self {
         let mut l = Lexer {
-            source: src,
-            index: 0,
+            source: src.peekable(),
             peek: 0.into(),
             line: 0,
-            word_table: wt,
+            peeked: VecDeque::new(),
+            scanned_line: 0,
+            identifiers: HashSet::new(),
+            read_error,
         };
         l.read_char();
         return l;
     }
 
+    // The line the last scanned token ended on, starting from 1
+    pub fn line(&self) -> i64 {
+        return self.scanned_line + 1;
+    }
+
+    // The line the lexer has read up to, which may be past peeked tokens
+    fn cur_line(&self) -> i64 {
+        return self.line + 1;
+    }
+
     fn read_char(&mut self) {
-        self.peek = match self.source.get(self.index) {
-            Some(c) => *c,
-            None => '\x00', // indicates EOF
-        };
-        self.index += 1;
+        self.peek = self.source.next().unwrap_or('\x00'); // NUL indicates EOF
     }
 
     fn test_char(&mut self, test: char) -> bool {
@@ -98,13 +153,164 @@ impl Lexer {
         }
     }
 
+    // Lexes a string literal, splitting out any ${...} interpolations
+    fn string(&mut self) -> Result<Token, String> {
+        let start = self.cur_line();
+        self.read_char();
+        let mut parts = Vec::new();
+        let mut collected = String::new();
+        loop {
+            if self.peek == '"' {
+                self.read_char();
+                if parts.is_empty() {
+                    return Ok(Token::String(collected));
+                }
+
+                if !collected.is_empty() {
+                    parts.push(StrPart::Lit(collected));
+                }
+                return Ok(Token::Interpolated(parts));
+            } else if self.peek == '\0' {
+                return Err(format!(
+                    "lexer error: unterminated string starting on line {}",
+                    start
+                ));
+            } else if self.peek == '$' && self.source.peek() == Some(&'{') {
+                let start = self.cur_line();
+                self.read_char();
+                self.read_char();
+
+                // The source of the expression, up to the matching brace
+                let mut expr = String::new();
+                let mut depth = 1;
+                loop {
+                    match self.peek {
+                        '{' => depth += 1,
+                        '}' => depth -= 1,
+                        '\n' => self.line += 1,
+                        '\0' => {
+                            return Err(format!(
+                                "lexer error: unterminated interpolation starting on line {}",
+                                start
+                            ))
+                        }
+                        _ => (),
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+
+                    expr.push(self.peek);
+                    self.read_char();
+                }
+                self.read_char();
+
+                if !collected.is_empty() {
+                    parts.push(StrPart::Lit(std::mem::take(&mut collected)));
+                }
+                parts.push(StrPart::Expr(expr));
+                continue;
+            } else if self.peek == '\r' && self.source.peek() == Some(&'\n') {
+                // Strings get the same contents whatever the line endings
+                self.read_char();
+            } else if self.peek == '\n' {
+                self.line += 1;
+            }
+
+            collected.push(self.peek);
+            self.read_char();
+        }
+    }
+
+    // Skips a block comment, which may contain other block comments
+    fn block_comment(&mut self) -> Result<(), String> {
+        let start = self.cur_line();
+        let mut depth = 0;
+        loop {
+            match (self.peek, self.source.peek()) {
+                ('/', Some('*')) => {
+                    depth += 1;
+                    self.read_char();
+                }
+                ('*', Some('/')) => {
+                    depth -= 1;
+                    self.read_char();
+                }
+                ('\n', _) => self.line += 1,
+                ('\0', _) => {
+                    return Err(format!(
+                        "lexer error: unterminated block comment starting on line {}",
+                        start
+                    ))
+                }
+                _ => {}
+            }
+            self.read_char();
+
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    // The next token, panicking if the source can't be lexed
     pub fn scan(&mut self) -> Token {
+        return self.try_scan().unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    pub fn try_scan(&mut self) -> Result<Token, String> {
+        let (t, line) = match self.peeked.pop_front() {
+            Some(peeked) => peeked,
+            None => (self.lex(), self.line),
+        };
+        self.scanned_line = line;
+        return t;
+    }
+
+    // The token that the next scan will return
+    pub fn peek(&mut self) -> &Token {
+        return self.peek_n(0);
+    }
+
+    // The token n after the next, without consuming any of them
+    pub fn peek_n(&mut self, n: usize) -> &Token {
+        return self.try_peek_n(n).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    pub fn try_peek_n(&mut self, n: usize) -> Result<&Token, String> {
+        while self.peeked.len() <= n {
+            let t = self.lex();
+            self.peeked.push_back((t, self.line));
+        }
+        return self.peeked[n].0.as_ref().map_err(|e| e.clone());
+    }
+
+    fn lex(&mut self) -> Result<Token, String> {
+        let t = self.lex_token();
+
+        // The source ended early, so the token may have been cut short
+        if let Some(e) = self.read_error.borrow().as_ref() {
+            return Err(e.clone());
+        }
+        return t;
+    }
+
+    #[allow(clippy::collapsible_match)] // test_char advances the lexer, so it can't be a guard
+    fn lex_token(&mut self) -> Result<Token, String> {
         loop {
-            if self.peek == ' ' || self.peek == '\t' {
+            if self.peek == ' ' || self.peek == '\t' || self.peek == '\r' {
+                // A \r\n line ending is counted once, by its \n
                 self.read_char();
             } else if self.peek == '\n' {
                 self.read_char();
                 self.line += 1
+            } else if self.peek == '/' && self.source.peek() == Some(&'/') {
+                // Skip a line comment, leaving the newline to be counted
+                while self.peek != '\n' && self.peek != '\0' {
+                    self.read_char();
+                }
+            } else if self.peek == '/' && self.source.peek() == Some(&'*') {
+                self.block_comment()?;
             } else {
                 break;
             }
@@ -113,97 +319,147 @@ impl Lexer {
         match self.peek {
             '&' => {
                 if self.test_char('&') {
-                    return Token::BoolAnd;
+                    return Ok(Token::BoolAnd);
                 }
             }
             '|' => {
                 if self.test_char('|') {
-                    return Token::BoolOr;
+                    return Ok(Token::BoolOr);
                 }
             }
             '>' => {
                 if self.test_char('=') {
-                    return Token::Ge;
+                    return Ok(Token::Ge);
                 } else {
-                    return Token::C('>');
+                    return Ok(Token::C('>'));
                 }
             }
             '<' => {
                 if self.test_char('=') {
-                    return Token::Le;
+                    return Ok(Token::Le);
                 } else {
-                    return Token::C('<');
+                    return Ok(Token::C('<'));
                 }
             }
             '=' => {
                 if self.test_char('=') {
-                    return Token::Eq;
+                    return Ok(Token::Eq);
                 } else {
-                    return Token::C('=');
+                    return Ok(Token::C('='));
                 }
             }
             '!' => {
                 if self.test_char('=') {
-                    return Token::Ne;
+                    return Ok(Token::Ne);
                 } else {
-                    return Token::C('!');
+                    return Ok(Token::C('!'));
                 }
             }
             ':' => {
                 if self.test_char('=') {
-                    return Token::DeclAssign;
+                    return Ok(Token::DeclAssign);
                 } else {
-                    return Token::C(':');
+                    return Ok(Token::C(':'));
                 }
             }
-            '"' => {
-                self.read_char();
-                let mut collected = String::new();
-                loop {
-                    if self.peek == '"' {
-                        self.read_char();
-                        return Token::String(collected);
-                    } else if self.peek == '\0' {
-                        panic!("EOF found before end of string")
-                    }
-
-                    collected.push(self.peek);
+            '.' => {
+                // A lone dot is left for field access
+                if self.source.peek() == Some(&'.') {
                     self.read_char();
+                    self.read_char();
+                    return Ok(Token::Range);
                 }
             }
+            '"' => {
+                return self.string();
+            }
             '\x00' => {
-                return Token::EOF;
+                return Ok(Token::EOF);
             }
             _ => (),
         }
 
-        if self.peek.is_numeric() {
-            let mut v: u64 = 0;
-            while self.peek.is_numeric() {
-                v = 10 * v + self.peek.to_digit(10).unwrap() as u64;
+        if self.peek.is_ascii_digit() {
+            let mut literal = String::new();
+            while self.peek.is_ascii_digit() {
+                literal.push(self.peek);
                 self.read_char();
             }
 
-            if self.peek != '.' {
-                // This is an integer literal
-                return Token::Integer(v as i64);
+            let next = self.source.peek().copied().unwrap_or('\0');
+            let fraction = self.peek == '.' && next != '.';
+            let exponent = self.peek == 'e' || self.peek == 'E';
+            if !fraction && !exponent {
+                // This is an integer literal, possibly the start of a range
+                return match literal.parse() {
+                    Ok(v) => Ok(Token::Integer(v)),
+                    Err(_) => Err(format!(
+                        "lexer error: integer literal {} on line {} is too large",
+                        literal,
+                        self.cur_line()
+                    )),
+                };
             }
 
-            let mut f = v as f64;
-            let mut d = 10.0;
-            self.read_char();
-            while self.peek.is_numeric() {
-                f = f + self.peek.to_digit(10).unwrap() as f64 / d;
-                d *= 10.0;
+            if fraction {
+                if !next.is_ascii_digit() {
+                    return Err(format!(
+                        "lexer error: expected digits after the '.' in {}. on line {}",
+                        literal,
+                        self.cur_line()
+                    ));
+                }
+
+                literal.push('.');
                 self.read_char();
+                while self.peek.is_ascii_digit() {
+                    literal.push(self.peek);
+                    self.read_char();
+                }
+            }
+
+            if self.peek == 'e' || self.peek == 'E' {
+                literal.push('e');
+                self.read_char();
+                if self.peek == '+' || self.peek == '-' {
+                    literal.push(self.peek);
+                    self.read_char();
+                }
+
+                if !self.peek.is_ascii_digit() {
+                    return Err(format!(
+                        "lexer error: expected digits in the exponent of {} on line {}",
+                        literal,
+                        self.cur_line()
+                    ));
+                }
+                while self.peek.is_ascii_digit() {
+                    literal.push(self.peek);
+                    self.read_char();
+                }
             }
 
-            if self.peek != 'f' {
-                panic!("syntax error");
+            // The f suffix is optional
+            if self.peek == 'f' {
+                self.read_char();
             }
-            self.read_char();
 
-            return Token::Float(f);
+            // Precision may be lost, but not the whole value
+            let Ok(v) = literal.parse::<f64>() else {
+                return Err(format!(
+                    "lexer error: invalid float literal {} on line {}",
+                    literal,
+                    self.cur_line()
+                ));
+            };
+            if v.is_infinite() {
+                return Err(format!(
+                    "lexer error: float literal {} on line {} is too large",
+                    literal,
+                    self.cur_line()
+                ));
+            }
+            return Ok(Token::Float(v));
         }
 
         if self.peek.is_alphabetic() {
@@ -213,18 +469,109 @@ impl Lexer {
                 self.read_char();
             }
 
-            match self.word_table.get(&s) {
-                Some(n) => return n.clone(),
+            if let Some(t) = keyword(&s) {
+                return Ok(t);
+            }
+
+            match self.identifiers.get(s.as_str()) {
+                Some(w) => return Ok(Token::Word(w.clone())),
                 None => {
-                    let w = Token::Word(s.clone());
-                    self.word_table.insert(s.clone(), w.clone());
-                    return w;
+                    let w: Arc<str> = s.into();
+                    self.identifiers.insert(w.clone());
+                    return Ok(Token::Word(w));
                 }
             }
         }
 
         let t = Token::C(self.peek);
         self.read_char();
-        return t;
+        return Ok(t);
+    }
+}
+
+// The token for a reserved word
+fn keyword(s: &str) -> Option<Token> {
+    let t = match s {
+        "if" => Token::If,
+        "else" => Token::Else,
+        "while" => Token::While,
+        "for" => Token::For,
+        "true" => Token::True,
+        "false" => Token::False,
+        "null" => Token::Null,
+        "int" => Token::Type(stac::DataType::Integer),
+        "float" => Token::Type(stac::DataType::Float),
+        "bool" => Token::Type(stac::DataType::Bool),
+        "string" => Token::Type(stac::DataType::String),
+        "func" => Token::Func,
+        "return" => Token::Return,
+        "extern" => Token::Extern,
+        "struct" => Token::Struct,
+        "break" => Token::Break,
+        "continue" => Token::Continue,
+        "completed" => Token::Completed,
+        "in" => Token::In,
+        "matches" => Token::Matches,
+        "match" => Token::Match,
+        "assert" => Token::Assert,
+        "var" => Token::Var,
+        "import" => Token::Import,
+        "input" => Token::Input,
+        _ => return None,
+    };
+    return Some(t);
+}
+
+// The tokens up to the end of the source, not including EOF. It's implemented
+// for references so that Iterator::scan doesn't shadow Lexer::scan.
+impl Iterator for &mut Lexer {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        match Lexer::scan(self) {
+            Token::EOF => return None,
+            t => return Some(t),
+        }
+    }
+}
+
+// The characters of a reader, decoded a line at a time
+struct ReaderChars<R> {
+    reader: BufReader<R>,
+    line: std::vec::IntoIter<char>,
+    lines: usize, // how many have been read
+    // Shared with the lexer, which fails once the reader has
+    error: Rc<RefCell<Option<String>>>,
+}
+
+impl<R: io::Read> Iterator for ReaderChars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if let Some(c) = self.line.next() {
+            return Some(c);
+        }
+
+        if self.error.borrow().is_some() {
+            return None;
+        }
+
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => return None,
+            Ok(_) => {
+                self.lines += 1;
+                self.line = line.chars().collect::<Vec<char>>().into_iter();
+                return self.line.next();
+            }
+            Err(e) => {
+                *self.error.borrow_mut() = Some(format!(
+                    "lexer error: failed to read line {} of the source: {}",
+                    self.lines + 1,
+                    e
+                ));
+                return None;
+            }
+        }
     }
 }
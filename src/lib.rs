@@ -1,13 +1,141 @@
+//! DMCL compiles configuration programs that call out to providers, such as
+//! cloud APIs, to create the resources they describe.
+//!
+//! [`compile`] and [`run`] are the simplest way to use it:
+//!
+//! ```
+//! let report = dmcl::run("count := 3;\nsize := count * 2;", &[]).unwrap();
+//! assert_eq!(report.variables["size"], dmcl::stac::DataVal::Integer(6));
+//! ```
+//!
+//! For more control, such as registering externs in Rust or importing other
+//! files, use [`parser::Parser`] and [`stac::Prog`] directly.
+
+#![allow(clippy::needless_return)]
+
+use std::collections::HashMap;
+use std::fmt;
+
 pub mod ast;
+pub mod diff;
+pub mod expect;
+pub mod graph;
 pub mod lexer;
+pub mod native;
+pub mod optimize;
 pub mod parser;
+pub mod plan;
 pub mod provider;
 pub mod scope;
 pub mod stac;
+pub mod state;
+pub mod stdlib;
+pub mod telemetry;
+pub mod trace;
+
+pub use diff::diff;
+
+/// Why a program couldn't be compiled or run
+#[derive(Debug)]
+pub enum Error {
+    Parse(parser::ParseError),
+    Exec(stac::ExecError),
+    Provider(provider::ProviderError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "{}", e),
+            Error::Exec(e) => write!(f, "{}", e),
+            Error::Provider(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<parser::ParseError> for Error {
+    fn from(e: parser::ParseError) -> Self {
+        return Error::Parse(e);
+    }
+}
+
+impl From<stac::ExecError> for Error {
+    fn from(e: stac::ExecError) -> Self {
+        return Error::Exec(e);
+    }
+}
+
+impl From<provider::ProviderError> for Error {
+    fn from(e: provider::ProviderError) -> Self {
+        return Error::Provider(e);
+    }
+}
+
+/// What running a program did
+#[derive(Clone, Debug)]
+pub struct ExecutionReport {
+    // The final value of each variable, by its name in `Prog::vars`
+    pub variables: HashMap<String, stac::DataVal>,
+    pub summary: stac::RunSummary,
+}
+
+/// Compiles the source of a program, along with the prelude.
+pub fn compile(src: &str) -> Result<stac::Prog, Error> {
+    let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+    par.program()?;
+    return Ok(par.into_prog());
+}
+
+/// Compiles and executes a program, with the functions of each HTTP provider
+/// at the given addresses as its externs. Functions whose signatures are in
+/// their provider's schema needn't be declared.
+pub fn run(src: &str, providers: &[&str]) -> Result<ExecutionReport, Error> {
+    // The providers are added first, so the program can use their declarations
+    let mut prog = stac::Prog::new();
+    for addr in providers {
+        prog.add_http_provider(addr.to_string(), provider::ProviderConfig::default())?;
+    }
+    let mut par = parser::Parser::with_prog(lexer::Lexer::from_str(src), prog);
+    par.program()?;
+    let mut prog = par.into_prog();
+    prog.execute()?;
+
+    return Ok(ExecutionReport {
+        variables: prog
+            .vars()
+            .map(|(name, val)| (name.to_string(), val.clone()))
+            .collect(),
+        summary: prog.summary(),
+    });
+}
+
+pub fn print_instructions(blocks: &[stac::Block]) {
+    for (k, block) in blocks.iter().enumerate() {
+        println!("\nBLOCK {:3}:", k);
+        for (k, instr) in block.code.iter().enumerate() {
+            match instr {
+                stac::Instr::LoadConst { v } => println!("{:3}:  LoadConst {}", k, v),
+                _ => println!("{:3}:  {:?}", k, instr),
+            }
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use stac::DataVal;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use expect::{Event, ExecExpectations};
+    use provider::ProviderConfig;
+    use stac::{DataType, DataVal, Label};
+    use state::StateStore;
 
     use self::lexer::Token;
 
@@ -31,14 +159,7 @@ mod tests {
             .collect(),
         );
 
-        let mut tokens = Vec::new();
-        loop {
-            let t = l.scan();
-            if t == Token::EOF {
-                break;
-            }
-            tokens.push(t);
-        }
+        let tokens: Vec<Token> = (&mut l).collect();
 
         assert_eq!(
             tokens,
@@ -94,11 +215,22 @@ mod tests {
         );
 
         let mut par = parser::Parser::new(l);
-        let prog = par.program();
+        let prog = par.program().unwrap();
         print_instructions(&prog.code);
 
-        prog.execute();
+        // The loop body runs once per fibonacci number, the condition once more
+        prog.expect(
+            ExecExpectations::new()
+                .block_visits(Label(0), 13)
+                .block_visits(Label(1), 14)
+                .instr_count(Label(1), "IfExpr", 14)
+                .before(Event::Store(stac::Addr(1)), Event::Block(Label(1)))
+                .before(Event::Block(Label(1)), Event::Instr("Concat")),
+        );
+
+        prog.execute().unwrap();
         println!("{:?}", prog.variables);
+        prog.verify_expectations().unwrap();
 
         assert_eq!(prog.variables[0], stac::DataVal::Integer(233));
         assert_eq!(prog.variables[1], stac::DataVal::Integer(144));
@@ -106,331 +238,6746 @@ mod tests {
             prog.variables[3],
             stac::DataVal::String("helloworld".into())
         );
+
+        let summary = prog.summary();
+        println!("{:?}", summary);
+        assert!(summary.extern_calls.is_empty());
+        assert!(summary.waiting.is_empty());
+        assert_eq!(summary.cycles, 183);
+        assert!(!summary.near_cycle_limit);
     }
 
     #[test]
-    fn scopes() {
-        let l = lexer::Lexer::new(
-            "
-    p := 5;
-    q := 6;
-    if true {
-        p := 7;
-        q := 6.0f;
+    fn trace_hook() {
+        // Counts the events of each kind
+        fn counting(counts: &Arc<Mutex<HashMap<&'static str, usize>>>) -> trace::TraceHook {
+            let counts = counts.clone();
+            return Box::new(move |event| {
+                let kind = match event {
+                    trace::TraceEvent::Instr { .. } => "instr",
+                    trace::TraceEvent::ExternStart { .. } => "extern_start",
+                    trace::TraceEvent::ExternRetry { .. } => "extern_retry",
+                    trace::TraceEvent::ExternFinish { .. } => "extern_finish",
+                    trace::TraceEvent::Store { .. } => "store",
+                    trace::TraceEvent::EnterSideEffects { .. } => "enter",
+                    trace::TraceEvent::ExitSideEffects { .. } => "exit",
+                };
+                *counts.lock().unwrap().entry(kind).or_insert(0) += 1;
+            });
+        }
+
+        let mut prog = compile(
+            r#"
+    p := 0;
+    q := 1;
+    while p < 200 {
+        t := p + q;
+        q = p;
+        p = t;
     }
 
-    r := p;"
-                .chars()
-                .collect(),
-        );
+    k := "hello" + "world";"#,
+        )
+        .unwrap();
+        let counts = Arc::new(Mutex::new(HashMap::new()));
+        prog.set_trace(counting(&counts));
+        prog.execute().unwrap();
 
-        let mut par = parser::Parser::new(l);
-        let prog = par.program();
-        print_instructions(&prog.code);
+        // p and q, three stores in each of the 13 iterations, then k
+        let counts = counts.lock().unwrap().clone();
+        assert_eq!(counts["instr"], prog.summary().cycles);
+        assert_eq!(counts["store"], 2 + 3 * 13 + 1);
+        assert_eq!(counts.len(), 2);
 
-        prog.execute();
-        println!("{:?}", prog.variables);
+        let mut prog = compile(
+            "
+    func extern pending() (int)
 
-        assert_eq!(prog.variables[0], stac::DataVal::Integer(5));
-        assert_eq!(prog.variables[1], stac::DataVal::Integer(6));
-        assert_eq!(prog.variables[2], stac::DataVal::Integer(7));
-        assert_eq!(prog.variables[3], stac::DataVal::Float(6.0));
-        assert_eq!(prog.variables[4], stac::DataVal::Integer(5));
+    n := pending();
+    m := 0;
+    if n > 2 {
+        m = 1;
+    }",
+        )
+        .unwrap();
+        prog.external_functions.insert(
+            "pending".into(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])),
+        );
+        let counts = Arc::new(Mutex::new(HashMap::new()));
+        prog.set_trace(counting(&counts));
+        prog.execute().unwrap();
+
+        // n and m, then side effect mode stores m again as waiting
+        let counts = counts.lock().unwrap().clone();
+        assert_eq!(counts["extern_start"], 1);
+        assert_eq!(counts["extern_finish"], 1);
+        assert_eq!(counts["enter"], 1);
+        assert_eq!(counts["exit"], 1);
+        assert_eq!(counts["store"], 3);
+
+        // The stdout hook prints the format execution used to log in
+        let instr = stac::Instr::Discard;
+        let event = trace::TraceEvent::Instr {
+            ip: (0, 2),
+            instr: &instr,
+        };
+        assert_eq!(event.to_string(), "executing @ (0, 2) : Discard");
     }
 
     #[test]
-    fn functions() {
-        let l = lexer::Lexer::new(
-            "
-    func rand() (int) {
-        p := 4;
-        return p;
+    fn stepping() {
+        let mut prog = compile(
+            r#"
+    p := 0;
+    q := 1;
+    while p < 200 {
+        t := p + q;
+        q = p;
+        p = t;
     }
 
-    p := rand();
-    rand();
+    k := "hello" + "world";"#,
+        )
+        .unwrap();
+        let entry = prog.entrypoint.0;
+        prog.start().unwrap();
+        assert_eq!(prog.ip(), (entry, 0));
 
-    func test() () {
-        idk := 5;
-    }
+        // p := 0, then q's value is on the stack, waiting to be stored
+        for _ in 0..3 {
+            assert_eq!(prog.step().unwrap(), stac::StepResult::Continued);
+        }
+        assert_eq!(prog.ip(), (entry, 3));
+        assert_eq!(prog.eval_stack(), [DataVal::Integer(1)]);
+        assert!(prog.call_stack().is_empty());
+        assert_eq!(prog.side_effect_origin(), None);
 
-    test();
-    
-    func huh(test: int) (int) {
-        test = test + 1;
-        return test;
+        // Stops as the loop is entered
+        let stac::Instr::Loop { label } = prog.code[entry].code[4] else {
+            panic!("expected the loop after p and q");
+        };
+        prog.add_breakpoint((label.0, 0));
+        while prog.step().unwrap() != stac::StepResult::Breakpoint {}
+        assert_eq!(prog.ip(), (label.0, 0));
+        assert_eq!(
+            prog.call_stack(),
+            [stac::Frame {
+                ret: (entry, 4),
+                kind: stac::FrameKind::Loop
+            }]
+        );
+
+        prog.remove_breakpoint((label.0, 0));
+        prog.run_until_complete().unwrap();
+        assert_eq!(prog.get_var("p"), Some(&DataVal::Integer(233)));
+        assert_eq!(prog.get_var("q"), Some(&DataVal::Integer(144)));
+        assert_eq!(
+            prog.get_var("k"),
+            Some(&DataVal::String("helloworld".into()))
+        );
+        assert_eq!(prog.step().unwrap(), stac::StepResult::Finished);
     }
-    
-    q := huh(6);
-    
-    func sub(a: int, b: int) (int) {
-        return a-b;
+
+    #[test]
+    fn execute_twice() {
+        let mut prog = compile(
+            r#"
+    func extern createResource(name: string) (int)
+
+    p := 0;
+    q := 1;
+    while p < 200 {
+        t := p + q;
+        q = p;
+        p = t;
     }
-    
-    r := sub(9, 10);"
-                .chars()
-                .collect(),
+    k := "hello" + "world";
+    r := createResource("fib-${p}");"#,
+        )
+        .unwrap();
+        let ids = Arc::new(Mutex::new(vec![]));
+        let i = ids.clone();
+        prog.external_functions.insert(
+            "createResource".into(),
+            Box::new(move |id, _, _, _, _, _| {
+                i.lock().unwrap().push(id);
+                return Ok(vec![DataVal::Integer(1)]);
+            }),
         );
 
-        let mut par = parser::Parser::new(l);
-        let prog = par.program();
-        print_instructions(&prog.code);
+        prog.execute().unwrap();
+        let first = (prog.variables.clone(), prog.summary());
+        assert_eq!(prog.get_var("p"), Some(&DataVal::Integer(233)));
 
-        prog.execute();
-        println!("{:?}", prog.variables);
+        prog.execute().unwrap();
+        assert_eq!((prog.variables.clone(), prog.summary()), first);
+        assert!(prog.eval_stack().is_empty());
 
-        assert_eq!(prog.variables[0], stac::DataVal::Integer(4));
-        assert_eq!(prog.variables[1], stac::DataVal::Integer(4));
-        assert_eq!(prog.variables[2], stac::DataVal::Integer(5));
-        assert_eq!(prog.variables[4], stac::DataVal::Integer(7));
-        assert_eq!(prog.variables[7], stac::DataVal::Integer(-1));
+        // The second run's call is the same call
+        let ids = ids.lock().unwrap();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids[0], ids[1]);
+
+        prog.reset();
+        assert!(prog.variables.iter().all(|v| *v == DataVal::Uninit));
+        assert_eq!(prog.summary().cycles, 0);
     }
 
     #[test]
-    fn arrays() {
-        let l = lexer::Lexer::new(
-            "
-    p := 5;
-    q := [2, 2, 3, p];
-    q[0] = 1;
-    p = q[0];"
-                .chars()
-                .collect(),
-        );
+    fn line_comments() {
+        let mut prog = compile(
+            r#"
+    // The fibonacci numbers up to 200
+    struct Pair {
+        a: int, // the smaller
+        b: int
+    }
 
-        let mut par = parser::Parser::new(l);
-        let prog = par.program();
-        print_instructions(&prog.code);
+    p := 0; // the current number
+    q := 1;
+    while p < 200 {
+        // Step forward
+        t := p + q;
+        q = p; // the previous number
+        p = t;
+    }
 
-        prog.execute();
-        println!("{:?}", prog.variables);
+    k := "hello" + "world"; // strings can contain "//" though
+    l := "a // b";
+    // A comment at the end without a newline"#,
+        )
+        .unwrap();
+        prog.execute().unwrap();
 
-        assert_eq!(prog.variables[0], stac::DataVal::Integer(1))
+        assert_eq!(prog.variables[0], DataVal::Integer(233));
+        assert_eq!(prog.variables[1], DataVal::Integer(144));
+        assert_eq!(prog.variables[3], DataVal::String("helloworld".into()));
+        assert_eq!(prog.variables[4], DataVal::String("a // b".into()));
     }
 
     #[test]
-    fn structs() {
-        let l = lexer::Lexer::new(
-            "
-    struct Test {
-        n1: int,
-        n2: float
-        n3: float
+    fn block_comments() {
+        let mut prog = compile(
+            r#"
+    /* A pair of numbers,
+       /* with a nested comment */
+       still in the outer comment */
+    struct Pair {
+        a: int, /* the smaller */
+        /* b: float, */
+        b: int
     }
 
-    p := Test{
-        n1: 5,
-        n2: 6.0f
-    };
-    q := p.n1;
-    r := p.n2;
-    w := p.n3;"
+    p := 1 + /* note */ 2;
+    q := Pair { a: p, b: 4 };
+    /*
+    p = 10;
+    */
+    assert p == 3;"#,
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(prog.variables[0], DataVal::Integer(3));
+        assert_eq!(
+            prog.variables[1],
+            DataVal::Compound(vec![DataVal::Integer(3), DataVal::Integer(4)])
+        );
+    }
+
+    #[test]
+    fn block_comment_lines() {
+        // Lines inside comments are still counted
+        let l = lexer::Lexer::new(
+            r#"
+    /* One,
+       /* two */
+       three */
+    p := 1 + /* four
+    */ 2;
+    assert p == 4;"#
                 .chars()
                 .collect(),
         );
 
         let mut par = parser::Parser::new(l);
-        let prog = par.program();
-        print_instructions(&prog.code);
-
-        prog.execute();
-        println!("{:?}", prog.variables);
-
-        assert_eq!(prog.variables[1], stac::DataVal::Integer(5));
-        assert_eq!(prog.variables[2], stac::DataVal::Float(6.));
-        assert_eq!(prog.variables[3], stac::DataVal::Float(0.));
+        let err = par.program().unwrap().execute().unwrap_err();
+        assert_eq!(err.kind, stac::ExecErrorKind::AssertionFailed);
+        assert_eq!(err.message, "assertion on line 7 failed");
     }
 
     #[test]
-    fn extern_func() {
-        let l = lexer::Lexer::new(
-            r#"
-    func extern createResource(name: string) (int)
-    func extern createResourceAsync(name: string) (int)
+    fn malformed_programs() {
+        use parser::ParseErrorKind as K;
 
-    p := createResource("test");
-    q := createResourceAsync("test3");
+        let cases: Vec<(&str, K, i64)> = vec![
+            (
+                "p := ;",
+                K::UnexpectedToken {
+                    found: Token::C(';'),
+                    expected: vec![],
+                },
+                1,
+            ),
+            (
+                "p := 1;\np := (1 + 2;",
+                K::UnexpectedToken {
+                    found: Token::C(';'),
+                    expected: vec![Token::C(')')],
+                },
+                2,
+            ),
+            (
+                "q = 1;",
+                K::UnknownIdentifier {
+                    name: "q".into(),
+                    suggestion: None,
+                },
+                1,
+            ),
+            ("p := nope(1);", K::UnknownFunction("nope".into()), 1),
+            ("p := 1;\n\nnope();", K::UnknownFunction("nope".into()), 3),
+            (
+                "p := Pair { a: 1 };",
+                K::UnknownStruct {
+                    name: "Pair".into(),
+                    known: vec![],
+                },
+                1,
+            ),
+            (
+                "struct Pair { a: int }\np := Pair { b: 1 };",
+                K::UnknownField {
+                    strct: "Pair".into(),
+                    field: "b".into(),
+                    known: vec!["a".into()],
+                },
+                2,
+            ),
+            (
+                "s := \"abc;",
+                K::Lexer("lexer error: unterminated string starting on line 1".into()),
+                1,
+            ),
+            (
+                "p := div(1, 2.0);",
+                K::Type("div expects int arguments".into()),
+                1,
+            ),
+            ("break;", K::Syntax("break outside of loop".into()), 1),
+            (
+                "struct S { n: int in 1..10 }\ns := S { n: 20 };",
+                K::Constraint("constraint violated: field S.n must be in 1..10, but was 20".into()),
+                2,
+            ),
+            (
+                "p := make(int, 3);",
+                K::Type("make expects an array type, found Integer".into()),
+                1,
+            ),
+            (
+                "p := [1];\ns := \"${p}\";",
+                K::Type("cannot interpolate Array(Integer) into a string".into()),
+                2,
+            ),
+            (
+                "outer: if true {}",
+                K::UnexpectedToken {
+                    found: Token::If,
+                    expected: vec![Token::While, Token::For],
+                },
+                1,
+            ),
+        ];
 
-    func test() (int) {
-        p := 1;
-        if p == 1 {
-            p = 2;
+        for (src, kind, line) in cases {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            let err = par.program().err().unwrap();
+            assert_eq!((err.kind, err.line), (kind, line), "{}", src);
         }
-        return p;
     }
 
-    a := 1;
-    if q < 1 {
-        a = 2;
-    } else {
-        a = 3;
+    #[test]
+    fn error_recovery() {
+        let src = "
+    p := 1 +;
+    q := 2;
+    if q > 1 {
+        r := (q;
+    }
+    func f(n: int) (int) {
+        m := n * ;
+        return n;
     }
+    s := f(q);";
 
-    b := test();
-    c := 1;
-    if q < 1 {
-        c = test();
+        let mut par = parser::Parser::with_recovery(lexer::Lexer::from_str(src));
+        let err = par.program().err().unwrap();
+        let lines: Vec<i64> = par.errors().iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![2, 5, 8]);
+        assert_eq!(err, par.errors()[0]);
+
+        // Without recovery only the first is reported
+        let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+        assert_eq!(par.program().err().unwrap().line, 2);
+        assert!(par.errors().is_empty());
     }
-    "#
-            .chars()
-            .collect(),
-        );
 
+    #[test]
+    fn unterminated_block_comment() {
+        let l = lexer::Lexer::new("p := 1;\n\n/* outer /* inner */ p = 2;".chars().collect());
         let mut par = parser::Parser::new(l);
-        let prog = par.program();
-        print_instructions(&prog.code);
-
-        prog.external_functions.insert(
-            "createResource".into(),
-            Box::new(|_ip, _ptype, _rtypes, params, _ustructs| {
-                println!(
-                    "creating resource {}",
-                    params[0].clone().into_string().unwrap()
-                );
-                return vec![DataVal::Integer(6)];
-            }),
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 3: lexer error: unterminated block comment starting on line 3"
         );
+    }
 
-        prog.external_functions.insert(
-            "createResourceAsync".into(),
-            Box::new(|_ip, _ptype, _rtypes, params, _ustructs| {
-                println!(
-                    "creating resource asynchronously {}",
-                    params[0].clone().into_string().unwrap()
-                );
-                return vec![DataVal::Waiting];
-            }),
-        );
+    #[test]
+    fn crlf_line_endings() {
+        let src = "p := 0;\r\nwhile p < 3 {\r\n\tp = p + 1;\r\n}\r\ns := \"a\r\nb\rc\";\r\n";
+        let mut l = lexer::Lexer::from_str(src);
+        let tokens: Vec<Token> = (&mut l).collect();
+        assert!(!tokens.contains(&Token::C('\r')));
+        assert_eq!(l.line(), 6);
 
-        prog.execute();
-        println!("{:?}", prog.variables);
+        let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+        let prog = par.program().unwrap();
+        prog.execute().unwrap();
 
-        assert_eq!(prog.variables[0], stac::DataVal::Integer(6));
-        assert_eq!(prog.variables[1], stac::DataVal::Waiting);
-        assert_eq!(prog.variables[2], stac::DataVal::Waiting);
-        assert_eq!(prog.variables[4], stac::DataVal::Integer(2));
-        assert_eq!(prog.variables[5], stac::DataVal::Waiting);
+        assert_eq!(prog.variables[0], DataVal::Integer(3));
+        // The line ending is normalised, but a lone \r is kept
+        assert_eq!(prog.variables[1], DataVal::String("a\nb\rc".into()));
     }
 
     #[test]
-    fn extern_func2() {
-        let l = lexer::Lexer::new(
-            r#"
-    func extern createResourceAsync(name: string) (int)
+    fn float_literals() {
+        let mut prog = compile(
+            "
+    a := 1.5 + 2.25;
+    b := 1.5e3 * 2.0;
+    c := 2E-4;
+    d := 1e-3 + 1.0f;
+    e := 6.0f - 0.5;
+    f := 2.5e+2f;",
+        )
+        .unwrap();
+        prog.execute().unwrap();
 
-    q := createResourceAsync("test3");
+        assert_eq!(
+            prog.variables[..6],
+            [
+                DataVal::Float(3.75),
+                DataVal::Float(3000.0),
+                DataVal::Float(0.0002),
+                DataVal::Float(1.001),
+                DataVal::Float(5.5),
+                DataVal::Float(250.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn float_literal_without_fraction() {
+        let l = lexer::Lexer::new("p := 1;\nq := 3.x;".chars().collect());
+        let mut par = parser::Parser::new(l);
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 2: lexer error: expected digits after the '.' in 3. on line 2"
+        );
+    }
+
+    #[test]
+    fn peeking() {
+        let src = "p := 1;\nq := Pair { a: p };";
+        let expected: Vec<Token> = (&mut lexer::Lexer::new(src.chars().collect())).collect();
+
+        let mut l = lexer::Lexer::new(src.chars().collect());
+        assert_eq!(*l.peek_n(2), Token::Integer(1));
+        assert_eq!(*l.peek_n(4), Token::Word("q".into()));
+        assert_eq!(*l.peek(), Token::Word("p".into()));
+
+        // The line is of the last token scanned, not the last one peeked
+        assert_eq!(l.scan(), Token::Word("p".into()));
+        assert_eq!(l.line(), 1);
+
+        let mut tokens = vec![Token::Word("p".into())];
+        while *l.peek() != Token::EOF {
+            assert_eq!(l.peek().clone(), *l.peek_n(0));
+            tokens.push(l.scan());
+        }
+        assert_eq!(tokens, expected);
+        assert_eq!(l.line(), 2);
+    }
+
+    #[test]
+    fn lexer_constructors() {
+        let src = "greeting := \"héllo\";\n/* ünïcode */ n := 2.5e1;\nwhile n > 0 { n = n - 1; }";
+        let expected: Vec<Token> = (&mut lexer::Lexer::new(src.chars().collect())).collect();
+
+        let from_str: Vec<Token> = (&mut lexer::Lexer::from_str(src)).collect();
+        assert_eq!(from_str, expected);
+
+        let mut l = lexer::Lexer::from_reader(std::io::Cursor::new(src.as_bytes().to_vec()));
+        let from_reader: Vec<Token> = (&mut l).collect();
+        assert_eq!(from_reader, expected);
+        assert_eq!(l.line(), 3);
+        assert_eq!(expected[2], Token::String("héllo".into()));
+
+        // A source that isn't UTF-8 is an error rather than a panic
+        let bytes = b"a := 1;\nb := \"\xff\";\n".to_vec();
+        let mut l = lexer::Lexer::from_reader(std::io::Cursor::new(bytes.clone()));
+        let msg =
+            "lexer error: failed to read line 2 of the source: stream did not contain valid UTF-8";
+        let mut tokens = vec![];
+        let err = loop {
+            match l.try_scan() {
+                Ok(t) => tokens.push(t),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err, msg);
+        assert_eq!(l.try_peek_n(0).unwrap_err(), msg);
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("a".into()),
+                Token::DeclAssign,
+                Token::Integer(1),
+                Token::C(';')
+            ]
+        );
+
+        let mut par = parser::Parser::new(lexer::Lexer::from_reader(std::io::Cursor::new(bytes)));
+        assert_eq!(
+            par.program().err().unwrap().to_string(),
+            format!("line 2: {}", msg)
+        );
+    }
+
+    #[test]
+    fn interned_identifiers() {
+        let mut l = lexer::Lexer::new("node := 1; node = while_ + node;".chars().collect());
+        let words: Vec<_> = (&mut l).filter_map(|t| t.into_word().ok()).collect();
+
+        // Each use of an identifier shares its string, and keywords only
+        // match whole words
+        assert_eq!(words.len(), 4);
+        assert!(std::sync::Arc::ptr_eq(&words[0], &words[1]));
+        assert!(std::sync::Arc::ptr_eq(&words[0], &words[3]));
+        assert_eq!(&*words[2], "while_");
+    }
+
+    #[test]
+    fn unary_minus() {
+        let l = lexer::Lexer::from_str(
+            "
+    func f(n: int) (int) {
+        return n * 2;
+    }
+    p := -5;
+    q := -f(3);
+    r := -2.5;
+    s := -p;
+    t := -(p + 2) * 2;
+    u := 10 - -p;
+    v := --r;",
+        );
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+
+        // Negative literals don't need a UnaryExpr
+        let p_store = prog.code[prog.entrypoint.0]
+            .code
+            .iter()
+            .position(|i| matches!(i, stac::Instr::StoreIdent { i } if i.0 == 1))
+            .unwrap();
+        assert!(matches!(
+            &prog.code[prog.entrypoint.0].code[p_store - 1],
+            stac::Instr::LoadConst {
+                v: DataVal::Integer(-5)
+            }
+        ));
+
+        prog.execute().unwrap();
+        assert_eq!(
+            prog.variables[1..8],
+            [
+                DataVal::Integer(-5),
+                DataVal::Integer(-6),
+                DataVal::Float(-2.5),
+                DataVal::Integer(5),
+                DataVal::Integer(6),
+                DataVal::Integer(5),
+                DataVal::Float(-2.5),
+            ]
+        );
+
+        let mut par = parser::Parser::new(lexer::Lexer::from_str("b := -true;"));
+        assert_eq!(
+            par.program().err().unwrap().kind,
+            parser::ParseErrorKind::Type("cannot negate bool".into())
+        );
+    }
+
+    #[test]
+    fn literal_overflow() {
+        let lex = |src: &str| {
+            let mut l = lexer::Lexer::new(src.chars().collect());
+            return std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || l.scan()));
+        };
+
+        assert_eq!(
+            lex("9223372036854775807").unwrap(),
+            Token::Integer(i64::MAX)
+        );
+        assert_eq!(lex("1.7e308").unwrap(), Token::Float(1.7e308));
+
+        for (src, msg) in [
+            (
+                "9223372036854775808",
+                "lexer error: integer literal 9223372036854775808 on line 1 is too large",
+            ),
+            (
+                "99999999999999999999",
+                "lexer error: integer literal 99999999999999999999 on line 1 is too large",
+            ),
+            (
+                "1e309",
+                "lexer error: float literal 1e309 on line 1 is too large",
+            ),
+        ] {
+            let err = lex(src).unwrap_err();
+            assert_eq!(err.downcast_ref::<String>().unwrap(), msg);
+        }
+
+        // Only ASCII digits are part of a literal
+        for src in ["a := 1.5²;", "a := 1²;", "a := 1e5²;"] {
+            assert_eq!(
+                compile(src).err().unwrap().to_string(),
+                "line 1: syntax error: expected C(';'), found C('²')"
+            );
+        }
+
+        // Nor are digits that aren't ASCII taken to be a literal at all
+        assert_eq!(lex("²").unwrap(), Token::C('²'));
+        assert_eq!(
+            compile("a := ²;").err().unwrap().to_string(),
+            "line 1: syntax error: unexpected C('²')"
+        );
+    }
+
+    #[test]
+    fn scopes() {
+        let l = lexer::Lexer::new(
+            "
+    p := 5;
+    q := 6;
+    if true {
+        p := 7;
+        q := 6.0f;
+    }
+
+    r := p;"
+                .chars()
+                .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        print_instructions(&prog.code);
+
+        // The if body runs between the outer declarations and the final one
+        prog.expect(
+            ExecExpectations::new()
+                .block_visits(Label(0), 1)
+                .instr_count(Label(0), "StoreIdent", 2)
+                .before(Event::Store(stac::Addr(1)), Event::Block(Label(0)))
+                .before(Event::Block(Label(0)), Event::Store(stac::Addr(4))),
+        );
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+        prog.verify_expectations().unwrap();
+
+        assert_eq!(prog.variables[0], stac::DataVal::Integer(5));
+        assert_eq!(prog.variables[1], stac::DataVal::Integer(6));
+        assert_eq!(prog.variables[2], stac::DataVal::Integer(7));
+        assert_eq!(prog.variables[3], stac::DataVal::Float(6.0));
+        assert_eq!(prog.variables[4], stac::DataVal::Integer(5));
+    }
+
+    #[test]
+    fn redeclaration() {
+        for (src, msg) in [
+            (
+                "p := 1;\np := 2;",
+                "line 2: redeclaration: p is already declared in this scope",
+            ),
+            (
+                "var p: int;\nif true {\n    q := 1;\n    var q: string;\n}",
+                "line 4: redeclaration: q is already declared in this scope",
+            ),
+            (
+                "func f(n: int) () {\n    n := 2;\n}",
+                "line 2: redeclaration: n is already declared in this scope",
+            ),
+            (
+                "func f(n: int, n: string) () {}",
+                "line 1: redeclaration: n is already declared in this scope",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(par.program().err().unwrap().to_string(), msg);
+        }
+
+        // Shadowing in a nested block is fine, including a parameter
+        let mut prog = compile(
+            "
+    func f(n: int) (int) {
+        if true {
+            n := 2;
+        }
+        return n;
+    }
+    p := 1;
+    while p < 2 {
+        p := 10;
+        break;
+    }
+    q := f(5);",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+        assert_eq!(prog.variables[2], DataVal::Integer(1));
+        assert_eq!(prog.variables[4], DataVal::Integer(5));
+    }
+
+    #[test]
+    fn functions() {
+        let mut prog = compile(
+            "
+    func rand() (int) {
+        p := 4;
+        return p;
+    }
+
+    p := rand();
+    rand();
+
+    func test() () {
+        idk := 5;
+    }
+
+    test();
+    
+    func huh(test: int) (int) {
+        test = test + 1;
+        return test;
+    }
+    
+    q := huh(6);
+    
+    func sub(a: int, b: int) (int) {
+        return a-b;
+    }
+    
+    r := sub(9, 10);",
+        )
+        .unwrap();
+        print_instructions(&prog.code);
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+
+        assert_eq!(prog.variables[0], stac::DataVal::Integer(4));
+        assert_eq!(prog.variables[1], stac::DataVal::Integer(4));
+        assert_eq!(prog.variables[2], stac::DataVal::Integer(5));
+        assert_eq!(prog.variables[4], stac::DataVal::Integer(7));
+        assert_eq!(prog.variables[7], stac::DataVal::Integer(-1));
+    }
+
+    #[test]
+    fn early_return() {
+        let mut prog = compile(
+            "
+    reached := 0;
+
+    func clamp(n: int) (int) {
+        if n > 10 {
+            if n > 100 {
+                return 100;
+            }
+            return 10;
+        }
+        reached = reached + 1;
+        return n;
+    }
+
+    func find(target: int) (int) {
+        i := 0;
+        while i < 10 {
+            if (i == target) {
+                return i * 2;
+            }
+            i = i + 1;
+        }
+        return 0 - 1;
+    }
+
+    func log() () {
+        if reached > 0 {
+            return;
+        }
+        reached = 50;
+    }
+
+    a := clamp(500);
+    b := clamp(50);
+    c := clamp(5);
+    d := find(3);
+    e := find(20);
+    log();",
+        )
+        .unwrap();
+        print_instructions(&prog.code);
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+
+        // The remainder of clamp only ran once, and nothing was left on the stack
+        assert_eq!(prog.variables[0], DataVal::Integer(1));
+        assert!(prog.eval_stack.is_empty());
+
+        assert_eq!(
+            prog.variables[4..9],
+            [
+                DataVal::Integer(100),
+                DataVal::Integer(10),
+                DataVal::Integer(5),
+                DataVal::Integer(6),
+                DataVal::Integer(-1),
+            ]
+        );
+    }
+
+    #[test]
+    fn void_return_value() {
+        let l = lexer::Lexer::new(
+            "
+    func log() () {
+        return 1;
+    }"
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 4: type error: function log has no return values"
+        );
+    }
+
+    #[test]
+    fn return_types() {
+        for (src, msg) in [
+            (
+                r#"func f() (int) { return "hello"; }"#,
+                "type error: function f must return (int), found (string)",
+            ),
+            (
+                "func f() (int, bool) { return 1; }",
+                "type error: function f must return (int, bool), found (int)",
+            ),
+            (
+                "func f() (int) { return; }",
+                "type error: function f must return (int), found ()",
+            ),
+            (
+                "func f(n: int) (int) { m := n; }",
+                "type error: function f must return (int), but has no return statement",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(
+                par.program().err().unwrap().kind.to_string(),
+                msg,
+                "{}",
+                src
+            );
+        }
+
+        // Several values are separated by commas
+        let l = lexer::Lexer::from_str(
+            "
+    func f(n: int) (int, bool) {
+        if n > 0 {
+            return n, true;
+        }
+        return 0, false;
+    }
+    x := 1;",
+        );
+        let mut par = parser::Parser::new(l);
+        assert!(par.program().is_ok());
+    }
+
+    #[test]
+    fn embedded_tests() {
+        let l = lexer::Lexer::new(
+            r#"
+    func extern createBucket(name: string) (int)
+
+    func clamp(n: int) (int) {
+        if n > 10 {
+            return 10;
+        }
+        return n;
+    }
+
+    ran := 0;
+    b := createBucket("logs");
+
+    test "clamps large values" {
+        ran = 1;
+        assert clamp(50) == 10;
+        assert clamp(5) == 5, "small values are unchanged";
+        assert b == 4;
+    }
+
+    test "clamps negative values" {
+        assert clamp(0 - 5) == 0, "negative values are clamped to zero";
+        ran = 2;
+    }
+    "#
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        print_instructions(&prog.code);
+
+        prog.test_stubs
+            .insert("createBucket".to_string(), vec![DataVal::Integer(4)]);
+        let results = prog.run_tests();
+        assert_eq!(
+            results,
+            vec![
+                stac::TestResult {
+                    name: "clamps large values".to_string(),
+                    failure: None,
+                },
+                stac::TestResult {
+                    name: "clamps negative values".to_string(),
+                    failure: Some("negative values are clamped to zero failed".to_string()),
+                },
+            ]
+        );
+
+        // Tests don't run as part of the program itself
+        prog.external_functions.insert(
+            "createBucket".into(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Integer(7)])),
+        );
+        prog.execute().unwrap();
+        assert_eq!(prog.variables[1], DataVal::Integer(0));
+        assert_eq!(prog.variables[2], DataVal::Integer(7));
+    }
+
+    #[test]
+    fn embedded_tests_waiting() {
+        let l = lexer::Lexer::new(
+            r#"
+    func extern createBucket(name: string) (int)
+
+    test "needs the bucket" {
+        b := createBucket("logs");
+        assert b == 4;
+    }
+    "#
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+
+        // Without a stub the extern waits, so the assertion can't pass
+        let results = prog.run_tests();
+        assert_eq!(
+            results[0].failure,
+            Some("assertion on line 6 is waiting".to_string())
+        );
+    }
+
+    #[test]
+    fn division() {
+        let mut prog = compile(
+            "
+    a := 7 / 2;
+    b := div(7, 2);
+    c := 7.0f / 2;
+    d := (0 - 7) / 2;
+    e := div(0 - 7, 2);
+    f := div(7, 0 - 2);
+    g := div(0 - 8, 2);
+    h := 6 / 3;
+    types := typeof(a) + typeof(b) + typeof(c) + typeof(h);",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+
+        assert_eq!(
+            prog.variables[..9],
+            [
+                DataVal::Float(3.5),
+                DataVal::Integer(3),
+                DataVal::Float(3.5),
+                DataVal::Float(-3.5),
+                DataVal::Integer(-4),
+                DataVal::Integer(-4),
+                DataVal::Integer(-4),
+                DataVal::Float(2.0),
+                DataVal::String("floatintfloatfloat".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_type_errors() {
+        for (src, msg) in [
+            (
+                r#"p := 5 + "x";"#,
+                "type error: cannot use '+' on int and string",
+            ),
+            (
+                "p := 1 + 2.0f;",
+                "type error: cannot use '+' on int and float",
+            ),
+            (
+                r#"p := "a" - "b";"#,
+                "type error: cannot use '-' on string and string",
+            ),
+            (
+                "p := true * 2;",
+                "type error: cannot use '*' on bool and int",
+            ),
+            (
+                "p := 1 < 2.5;",
+                "type error: cannot use '<' on int and float",
+            ),
+            (
+                "p := true >= false;",
+                "type error: cannot use '>=' on bool and bool",
+            ),
+            (
+                r#"p := 1 == "1";"#,
+                "type error: cannot use '==' on int and string",
+            ),
+            (
+                r#"p := [1] != ["1"];"#,
+                "type error: cannot use '!=' on []int and []string",
+            ),
+            (
+                r#"p := "a" / 2;"#,
+                "type error: cannot use '/' on string and int",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(
+                par.program().err().unwrap().kind.to_string(),
+                msg,
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn modulo() {
+        let mut prog = compile(
+            "
+    a := 10 % 3;
+    b := 10.0f % 3.0f;
+    c := 2 + 7 % 4 * 2;
+    d := (0 - 7) % 3;",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(
+            prog.variables[..4],
+            [
+                DataVal::Integer(1),
+                DataVal::Float(1.0),
+                DataVal::Integer(8),
+                DataVal::Integer(-1),
+            ]
+        );
+
+        let prog = run_waiting(
+            "
+    func extern pending() (int)
+
+    w := pending() % 3;
+    z := 5 % pending();",
+        );
+        assert_eq!(prog.variables[..2], [DataVal::Waiting, DataVal::Waiting]);
+    }
+
+    #[test]
+    fn modulo_by_zero() {
+        let l = lexer::Lexer::new("n := 0; m := 5 % n;".chars().collect());
+        let mut par = parser::Parser::new(l);
+        let err = par.program().unwrap().execute().unwrap_err();
+        assert_eq!(err.kind, stac::ExecErrorKind::DivisionByZero);
+        assert_eq!(err.message, "modulo by zero");
+    }
+
+    #[test]
+    fn execution_errors() {
+        use stac::{ExecErrorKind as K, Instr};
+
+        // Programs the parser wouldn't produce still fail without panicking
+        let cases = vec![
+            (
+                vec![
+                    Instr::LoadConst { v: "a".into() },
+                    Instr::LoadConst { v: 1.into() },
+                    Instr::BinaryExpr { op: Token::C('+') },
+                ],
+                K::Type,
+                r#"cannot use arithmetic on 1 and "a""#,
+            ),
+            (
+                vec![
+                    Instr::LoadConst { v: 1.5.into() },
+                    Instr::LoadConst { v: true.into() },
+                    Instr::BinaryExpr { op: Token::C('<') },
+                ],
+                K::Type,
+                "cannot compare true and 1.5",
+            ),
+            (
+                vec![Instr::UnaryExpr { op: Token::C('-') }],
+                K::StackUnderflow {
+                    needed: 1,
+                    available: 0,
+                    instr: "UnaryExpr".into(),
+                },
+                "stack underflow: UnaryExpr needs 1 values on the eval stack, but there are 0",
+            ),
+            (
+                vec![
+                    Instr::LoadConst { v: 0.into() },
+                    Instr::LoadConst { v: 7.into() },
+                    Instr::FloorDiv,
+                ],
+                K::DivisionByZero,
+                "division by zero",
+            ),
+        ];
+        for (code, kind, message) in cases {
+            let len = code.len();
+            let mut prog = stac::Prog::without_prelude();
+            prog.code = vec![stac::Block { code }];
+
+            let err = prog.execute().unwrap_err();
+            assert_eq!((err.kind, err.message.as_str()), (kind, message));
+            assert_eq!(err.ip, (0, len - 1));
+        }
+
+        // Declared, but never registered
+        let mut prog =
+            compile("func extern createBucket(name: string) (int)\nb := createBucket(\"logs\");")
+                .unwrap();
+        let err = prog.execute().unwrap_err();
+        assert_eq!(err.kind, K::UnknownExtern);
+        assert_eq!(err.message, "unknown external function: createBucket");
+        assert!(matches!(
+            prog.code[err.ip.0].code[err.ip.1],
+            Instr::ExternCall { .. }
+        ));
+    }
+
+    #[test]
+    fn stack_underflow() {
+        use stac::{ExecErrorKind as K, Instr};
+
+        let cases = vec![
+            (
+                vec![Instr::LoadConst { v: 1.into() }, Instr::CompoundSet],
+                3,
+                1,
+                "CompoundSet",
+            ),
+            (
+                vec![
+                    Instr::LoadConst { v: 1.into() },
+                    Instr::LoadConst { v: "f".into() },
+                    Instr::ExternCall {
+                        param_types: vec![DataType::Integer, DataType::Integer],
+                        return_types: vec![],
+                    },
+                ],
+                3,
+                2,
+                "ExternCall",
+            ),
+            (vec![Instr::Discard], 1, 0, "Discard"),
+            (
+                vec![
+                    Instr::LoadConst { v: 1.into() },
+                    Instr::Validate {
+                        types: vec![DataType::Integer, DataType::Integer],
+                    },
+                ],
+                2,
+                1,
+                "Validate",
+            ),
+        ];
+        for (code, needed, available, instr) in cases {
+            let last = code.len() - 1;
+            let mut prog = stac::Prog::without_prelude();
+            let mut block = stac::Block::new();
+            for i in code {
+                block.add_instr(i);
+            }
+            prog.entrypoint = prog.add_block(block);
+
+            let err = prog.execute().unwrap_err();
+            assert_eq!(
+                err.kind,
+                K::StackUnderflow {
+                    needed,
+                    available,
+                    instr: instr.into()
+                }
+            );
+            assert_eq!(err.ip, (0, last));
+            assert_eq!(
+                err.message,
+                format!(
+                    "stack underflow: {} needs {} values on the eval stack, but there are {}",
+                    instr, needed, available
+                )
+            );
+        }
+
+        // An extern called from the entry block has no call site
+        let mut prog = stac::Prog::without_prelude();
+        let mut block = stac::Block::new();
+        block.add_instr(Instr::LoadConst { v: "f".into() });
+        block.add_instr(Instr::ExternCall {
+            param_types: vec![],
+            return_types: vec![],
+        });
+        prog.entrypoint = prog.add_block(block);
+        let err = prog.execute().unwrap_err();
+        assert_eq!(
+            err.kind,
+            K::CallStackUnderflow {
+                instr: "ExternCall".into()
+            }
+        );
+        assert_eq!(
+            err.message,
+            "stack underflow: ExternCall needs a frame, but the call stack is empty"
+        );
+    }
+
+    #[test]
+    fn shared_strings() {
+        let mut prog = compile(
+            r#"
+    struct Server {
+        name: string,
+        user_data: string
+    }
+
+    input user_data: string;
+    copy := user_data;
+    servers := make([]Server, 100);
+    i := 0;
+    while i < 100 {
+        next := copy;
+        copy = next;
+        servers[i] = Server { name: "web", user_data: copy };
+        i = i + 1;
+    }
+    last := servers[99].user_data;"#,
+        )
+        .unwrap();
+
+        // Copying the string around doesn't copy its 1MB
+        let user_data: Arc<str> = "#!/bin/sh\n".repeat(100_000).into();
+        prog.set_input("user_data", DataVal::String(user_data.clone()))
+            .unwrap();
+        prog.execute().unwrap();
+        let last = prog.get_var("last").unwrap().as_string().unwrap();
+        assert!(Arc::ptr_eq(last, &user_data));
+
+        // Ours, the input's, 100 in servers and 4 in variables
+        assert_eq!(Arc::strong_count(&user_data), 106);
+        assert_eq!(prog.get_var("copy"), Some(&DataVal::String(user_data)));
+    }
+
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+
+    #[test]
+    fn threads() {
+        assert_send::<stac::Prog>();
+        assert_sync::<stac::Prog>();
+        assert_send::<DataVal>();
+
+        // Two programs run at once, sharing an extern's ledger between them
+        let created = Arc::new(Mutex::new(vec![]));
+        let handles: Vec<_> = ["web", "db"]
+            .into_iter()
+            .map(|name| {
+                let mut prog = compile(&format!(
+                    r#"
+    func extern createServer(name: string) (int)
+    total := 0;
+    for i := 0; i < 50; i = i + 1 {{
+        total = total + createServer("{}-${{i}}");
+    }}"#,
+                    name
+                ))
+                .unwrap();
+                let created = created.clone();
+                prog.register_extern("createServer", move |name: String| {
+                    let mut created = created.lock().unwrap();
+                    created.push(name);
+                    return created.len() as i64;
+                });
+                return std::thread::spawn(move || {
+                    prog.execute().unwrap();
+                    return prog;
+                });
+            })
+            .collect();
+
+        let mut totals = 0;
+        for handle in handles {
+            let prog = handle.join().unwrap();
+            totals += prog.get_var("total").unwrap().as_integer().unwrap();
+        }
+        let created = created.lock().unwrap();
+        assert_eq!(created.len(), 100);
+        assert!(created.contains(&"web-49".to_string()));
+        assert!(created.contains(&"db-0".to_string()));
+        assert_eq!(totals, (1..=100).sum::<i64>());
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut prog = compile(
+            r#"
+    struct Server {
+        region: string,
+        size: float
+    }
+
+    s := Server { region: "syd", size: 2.5 };
+    idx := 3;
+    a := "web-${s.region}-${idx + 1}";
+    b := "${s.size}x${idx}";
+    c := "{braces} ${"nested"} $ and ${idx > 2}";"#,
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(
+            prog.variables[2..5],
+            [
+                DataVal::String("web-syd-4".into()),
+                DataVal::String("2.5x3".into()),
+                DataVal::String("{braces} nested $ and true".into()),
+            ]
+        );
+
+        let prog = run_waiting(
+            "
+    func extern pending() (int)
+
+    w := \"web-${pending()}-a\";",
+        );
+        assert_eq!(prog.variables[0], DataVal::Waiting);
+    }
+
+    #[test]
+    fn else_if() {
+        let mut prog = compile(
+            "
+    func size(n: int) (string) {
+        if n < 10 {
+            return \"small\";
+        } else if n < 100 {
+            return \"medium\";
+        } else {
+            return \"large\";
+        }
+    }
+
+    a := size(5);
+    b := size(50);
+    c := size(500);
+    count := 0;
+    i := 0;
+    while i < 6 {
+        if i == 0 {
+            count = count + 1;
+        } else if i == 1 {
+            count = count + 10;
+        } else if i == 2 {
+            count = count + 100;
+        }
+        i = i + 1;
+    }",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        // The parameter of size is the first variable
+        assert_eq!(
+            prog.variables[1..5],
+            [
+                DataVal::String("small".into()),
+                DataVal::String("medium".into()),
+                DataVal::String("large".into()),
+                DataVal::Integer(111),
+            ]
+        );
+    }
+
+    #[test]
+    fn for_loops() {
+        let mut prog = compile(
+            "
+    sum := 0;
+    for i := 0; i < 10; i = i + 1 {
+        sum = sum + i;
+    }
+
+    // The loop variable is scoped to the loop, so it can be declared again
+    evens := 0;
+    for i := 0; i < 10; i = i + 2 {
+        evens = evens + 1;
+    }
+
+    n := 3;
+    outer: for ; n > 0; {
+        n = n - 1;
+    } completed {
+        n = 100;
+    }",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(prog.variables[0], DataVal::Integer(45));
+        assert_eq!(prog.variables[2], DataVal::Integer(5));
+        assert_eq!(prog.variables[4], DataVal::Integer(100));
+    }
+
+    #[test]
+    fn var_declarations() {
+        let mut prog = compile(
+            r#"
+    struct Pair { a: int, b: string }
+    var count: int;
+    before := count;
+    count = 3;
+    after := count;
+    var name: string = "x";
+    var p: Pair;
+    var keys: []string;"#,
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(
+            prog.variables[..6],
+            [
+                DataVal::Integer(3),
+                DataVal::Integer(0),
+                DataVal::Integer(3),
+                DataVal::String("x".into()),
+                DataVal::Compound(vec![DataVal::Integer(0), DataVal::String("".into())]),
+                DataVal::Compound(vec![]),
+            ]
+        );
+
+        // The declared type is kept when the variable is assigned
+        for (src, msg) in [
+            (
+                r#"var n: int = "x";"#,
+                "type error: cannot assign string to n of type int",
+            ),
+            (
+                "var n: int;\nn = 1.5;",
+                "type error: cannot assign float to n of type int",
+            ),
+            (
+                "var p: Missing;",
+                "unknown struct: Missing (no structs are declared)",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(par.program().err().unwrap().kind.to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn reassignment_types() {
+        let mut par = parser::Parser::new(lexer::Lexer::from_str("p := 5;\np = \"hello\";"));
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 2: type error: cannot assign string to p of type int"
+        );
+
+        // Extern results are waiting until the resource is created, which
+        // doesn't change the type of the variable they're stored in
+        let prog = run_waiting(
+            r#"
+    func extern pending() (int)
+    p := 5;
+    p = p * 2;
+    p = pending();
+    s := "a";
+    s = s + "b";
+    a := [1];
+    a = [2, 3];"#,
+        );
+        assert_eq!(prog.variables[0], DataVal::Waiting);
+        assert_eq!(prog.variables[1], DataVal::String("ab".into()));
+    }
+
+    #[test]
+    fn call_before_definition() {
+        let mut prog = compile(
+            "
+    even := isEven(10);
+    odd := isEven(7);
+    total := 0;
+    count(3);
+
+    func isEven(n: int) (bool) {
+        if n == 0 {
+            return true;
+        }
+        return isOdd(n - 1);
+    }
+
+    func isOdd(n: int) (bool) {
+        if n == 0 {
+            return false;
+        }
+        return isEven(n - 1);
+    }
+
+    func count(n: int) () {
+        i := 0;
+        while (i < n) {
+            i = i + 1;
+        }
+    }",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(
+            prog.variables[..3],
+            [
+                DataVal::Bool(true),
+                DataVal::Bool(false),
+                DataVal::Integer(0)
+            ]
+        );
+
+        // Including when the source is read from a reader
+        let src = "a := double(4);\nfunc double(n: int) (int) {\n    return n * 2;\n}";
+        let mut par = parser::Parser::new(lexer::Lexer::from_reader(std::io::Cursor::new(
+            src.as_bytes().to_vec(),
+        )));
+        par.program().unwrap();
+        let mut prog = par.into_prog();
+        prog.execute().unwrap();
+        assert_eq!(prog.get_var("a"), Some(&DataVal::Integer(8)));
+    }
+
+    #[test]
+    fn unknown_identifier_suggestions() {
+        for (src, msg) in [
+            (
+                "total := 1;\nn := totl + 1;",
+                "line 2: unknown identifier: totl (did you mean total?)",
+            ),
+            (
+                "count := 1;\nif true {\n    cuont = 2;\n}",
+                "line 3: unknown identifier: cuont (did you mean count?)",
+            ),
+            (
+                "items := [1, 2];\nitem[0] = 3;",
+                "line 2: unknown identifier: item (did you mean items?)",
+            ),
+            (
+                "func f(region: string) () {\n    r := regoin;\n}",
+                "line 2: unknown identifier: regoin (did you mean region?)",
+            ),
+            ("total := 1;\nn := xyz;", "line 2: unknown identifier: xyz"),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(par.program().err().unwrap().to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn for_loop_scope() {
+        let l = lexer::Lexer::new("for i := 0; i < 3; i = i + 1 {} j := i;".chars().collect());
+        let mut par = parser::Parser::new(l);
+        let err = par.program().err().unwrap();
+        assert_eq!(err.to_string(), "line 1: unknown identifier: i");
+    }
+
+    #[test]
+    fn prelude() {
+        let src = r#"
+    a := clamp(15, 0, 10);
+    b := defaultString("", "unnamed");
+    c := defaultString("web", "unnamed");"#;
+
+        let l = lexer::Lexer::new(src.chars().collect());
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+
+        // The prelude's variables come after the program's
+        assert!(prog.variables.len() > 3);
+        assert_eq!(
+            prog.variables[..3],
+            [
+                DataVal::Integer(10),
+                DataVal::String("unnamed".into()),
+                DataVal::String("web".into()),
+            ]
+        );
+
+        // Without it the functions aren't declared
+        let l = lexer::Lexer::new(src.chars().collect());
+        let mut par = parser::Parser::with_prog(l, stac::Prog::without_prelude());
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.kind,
+            parser::ParseErrorKind::UnknownFunction("clamp".into())
+        );
+
+        // The top level code of an earlier unit runs first
+        let l = lexer::Lexer::new("a := 1;".chars().collect());
+        let mut par = parser::Parser::with_prog(l, stac::Prog::without_prelude());
+        par.program().unwrap();
+
+        let l = lexer::Lexer::new("b := 2;".chars().collect());
+        let mut par = parser::Parser::with_prog(l, par.into_prog());
+        let prog = par.program().unwrap();
+        prog.execute().unwrap();
+        assert_eq!(prog.variables, [DataVal::Integer(2), DataVal::Integer(1)]);
+    }
+
+    #[test]
+    fn duplicate() {
+        let l = lexer::Lexer::new(
+            "
+    func extern seed() (int)
+
+    func rand() (int) {
+        p := 4;
+        return p;
+    }
+
+    p := rand();
+
+    func huh(test: int) (int) {
+        test = test + 1;
+        return test;
+    }
+
+    q := huh(seed());
+
+    func sub(a: int, b: int) (int) {
+        return a-b;
+    }
+
+    r := sub(q, 10);"
+                .chars()
+                .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+
+        let mut a = prog.duplicate();
+        let mut b = prog.duplicate();
+
+        // Externs aren't copied
+        assert!(a.external_functions.is_empty());
+
+        a.external_functions.insert(
+            "seed".to_string(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Integer(1)])),
+        );
+        b.external_functions.insert(
+            "seed".to_string(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Integer(20)])),
+        );
+
+        a.execute().unwrap();
+        b.execute().unwrap();
+        println!("{:?}\n{:?}", a.variables, b.variables);
+
+        assert_eq!(a.variables[0], DataVal::Integer(4));
+        assert_eq!(b.variables[0], DataVal::Integer(4));
+        assert_eq!(a.variables[3], DataVal::Integer(2));
+        assert_eq!(b.variables[3], DataVal::Integer(21));
+        assert_eq!(a.variables[6], DataVal::Integer(-8));
+        assert_eq!(b.variables[6], DataVal::Integer(11));
+
+        // A copy of a copy has its own variables
+        let c = a.duplicate();
+        a.variables[3] = DataVal::Integer(100);
+        assert_eq!(b.variables[3], DataVal::Integer(21));
+        assert_eq!(c.variables[3], DataVal::Uninit);
+    }
+
+    #[test]
+    fn serialize_program() {
+        let mut fresh = compile(
+            r#"
+    struct Server {
+        name: string matches "^[a-z]+$",
+        size: float
+    }
+
+    func extern createServer(s: Server) (int)
+
+    func double(n: int) (int) {
+        return n * 2;
+    }
+
+    i := 0;
+    total := 0;
+    while i < 5 {
+        total = total + double(i);
+        i = i + 1;
+    }
+    s := Server { name: "web", size: 1.5 };
+    id := createServer(s);
+    label := "${s.name}-${id}";"#,
+        )
+        .unwrap();
+        let mut loaded = stac::Prog::from_bytes(&fresh.to_bytes()).unwrap();
+
+        // Externs aren't saved
+        assert!(loaded.external_functions.is_empty());
+        for prog in [&mut fresh, &mut loaded] {
+            prog.external_functions.insert(
+                "createServer".into(),
+                Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Integer(42)])),
+            );
+        }
+
+        fresh.execute().unwrap();
+        loaded.execute().unwrap();
+        assert_eq!(loaded.variables, fresh.variables);
+        assert_eq!(loaded.get_var("total"), Some(&DataVal::Integer(20)));
+        assert_eq!(
+            loaded.get_var("label"),
+            Some(&DataVal::String("web-42".into()))
+        );
+
+        // The constraint's regex is compiled again
+        let err = stac::check_constraints(
+            &loaded.user_structs,
+            &DataType::Struct("Server".into()),
+            &DataVal::Compound(vec!["Web".into(), DataVal::Float(1.0)]),
+        );
+        assert!(err.is_err());
+        assert!(stac::Prog::from_bytes(b"{}").is_err());
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut prog = compile(
+            r#"
+    func extern createBucket(name: string) (int)
+
+    id := createBucket("logs");
+    next := id + 1;
+    url := "bucket-${id}";"#,
+        )
+        .unwrap();
+        let saved = prog.to_bytes();
+        prog.external_functions.insert(
+            "createBucket".into(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])),
+        );
+
+        // Stop as soon as the bucket is waiting to be created
+        prog.start().unwrap();
+        while prog.eval_stack().last() != Some(&DataVal::Waiting) {
+            assert_eq!(prog.step().unwrap(), stac::StepResult::Continued);
+        }
+        let snapshot = serde_json::to_string(&prog.snapshot()).unwrap();
+
+        // Hours later, in another process, once the bucket exists
+        let mut resumed = stac::Prog::from_bytes(&saved).unwrap();
+        resumed
+            .restore(serde_json::from_str(&snapshot).unwrap())
+            .unwrap();
+        assert_eq!(resumed.ip(), prog.ip());
+        *resumed.eval_stack.last_mut().unwrap() = DataVal::Integer(42);
+        resumed.run_until_complete().unwrap();
+
+        // createBucket isn't called again
+        assert!(resumed.external_functions.is_empty());
+        assert_eq!(resumed.get_var("next"), Some(&DataVal::Integer(43)));
+        assert_eq!(
+            resumed.get_var("url"),
+            Some(&DataVal::String("bucket-42".into()))
+        );
+
+        let mut other = compile("id := 1;").unwrap();
+        assert_eq!(
+            other
+                .restore(serde_json::from_str(&snapshot).unwrap())
+                .unwrap_err()
+                .to_string(),
+            "restore error: the snapshot was taken of a different program"
+        );
+    }
+
+    #[test]
+    fn use_before_init() {
+        // The program returns before total is declared, but the test still runs
+        let l = lexer::Lexer::new(
+            r#"
+    return;
+    total := 5;
+
+    test "total" {
+        assert total == 5;
+    }"#
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        let results = prog.run_tests();
+        assert_eq!(
+            results[0].failure.as_deref(),
+            Some("use before init: variable total was read before it was assigned")
+        );
+    }
+
+    #[test]
+    fn use_before_init_static() {
+        let l = lexer::Lexer::new(
+            "
+    show();
+    total := 5;
+
+    func show() () {
+        shown := total;
+    }"
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 7: use before init: function show reads variable total before it is declared"
+        );
+    }
+
+    // Deploys to an environment, with an extern to create each resource. The
+    // environment is declared by decl, as either a variable or an input.
+    fn deployment(decl: &str) -> stac::Prog {
+        let src = format!(
+            r#"
+    func extern createDatabase(name: string, replicas: int) (int)
+    func extern createCache(name: string) (int)
+
+    {}
+    replicas := 1;
+    cache := 0;
+    if env == "prod" {{
+        replicas = 3;
+        cache = createCache(env);
+    }} else {{
+        replicas = 1;
+    }}
+    db := createDatabase(env, replicas);
+    "#,
+            decl
+        );
+
+        let l = lexer::Lexer::new(src.chars().collect());
+        let mut par = parser::Parser::new(l);
+        par.program().unwrap();
+        return par.into_prog();
+    }
+
+    fn register_deployment(prog: &mut stac::Prog) {
+        prog.external_functions.insert(
+            "createDatabase".into(),
+            Box::new(|_, _, _, p, _, _| Ok(vec![p[1].clone()])),
+        );
+        prog.external_functions.insert(
+            "createCache".into(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])),
+        );
+    }
+
+    #[test]
+    fn specialize() {
+        let prog = deployment(r#"env := "dev";"#);
+        let inputs = HashMap::from([("env".to_string(), DataVal::String("prod".into()))]);
+        let mut prod = prog.specialize(&inputs).unwrap();
+        print_instructions(&prod.code);
+
+        // The branch for every other environment is gone, along with the
+        // prelude's empty top level block
+        assert_eq!(prod.code.len(), prog.code.len() - 2);
+        let main = &prod.code[prod.entrypoint.0].code;
+        assert!(!main.iter().any(|i| matches!(i, stac::Instr::IfExpr { .. })));
+
+        let mut original = deployment(r#"env := "prod";"#);
+        register_deployment(&mut original);
+        register_deployment(&mut prod);
+        original.execute().unwrap();
+        prod.execute().unwrap();
+        assert_eq!(prod.variables, original.variables);
+        assert_eq!(prod.variables[3], DataVal::Integer(3));
+        assert_eq!(prod.variables[2], DataVal::Waiting);
+
+        // Specializing for another environment drops the prod branch instead
+        let inputs = HashMap::from([("env".to_string(), DataVal::String("staging".into()))]);
+        let mut staging = deployment(r#"env := "prod";"#).specialize(&inputs).unwrap();
+        register_deployment(&mut staging);
+        staging.execute().unwrap();
+        assert_eq!(staging.variables[1], DataVal::Integer(1));
+        assert_eq!(staging.variables[3], DataVal::Integer(1));
+
+        // A declared input no longer needs to be set by the host
+        let decl = "input env: string;";
+        let inputs = HashMap::from([("env".to_string(), DataVal::String("prod".into()))]);
+        let mut prod = deployment(decl).specialize(&inputs).unwrap();
+        let main = &prod.code[prod.entrypoint.0].code;
+        assert!(!main.iter().any(|i| matches!(i, stac::Instr::IfExpr { .. })));
+        let mut original = deployment(decl);
+        original
+            .set_input("env", DataVal::String("prod".into()))
+            .unwrap();
+        register_deployment(&mut original);
+        register_deployment(&mut prod);
+        original.execute().unwrap();
+        prod.execute().unwrap();
+        assert_eq!(prod.variables, original.variables);
+        assert_eq!(prod.get_var("env"), Some(&DataVal::String("prod".into())));
+        assert_eq!(prod.get_var("replicas"), Some(&DataVal::Integer(3)));
+
+        for (decl, name, val, msg) in [
+            (
+                decl,
+                "region",
+                DataVal::String("lon1".into()),
+                "input error: cannot specialize region: there is no such input or variable",
+            ),
+            (
+                decl,
+                "env",
+                DataVal::Integer(1),
+                "input error: cannot specialize env: it must be string, found 1",
+            ),
+            (
+                r#"env := "dev";"#,
+                "replicas",
+                DataVal::Integer(2),
+                "input error: cannot specialize replicas: it is assigned more than once",
+            ),
+            (
+                r#"env := "dev";"#,
+                "env",
+                DataVal::Bool(true),
+                r#"input error: cannot specialize env: it was declared as "dev", not true"#,
+            ),
+            (
+                "env := \"dev\" + \"\";",
+                "env",
+                DataVal::String("prod".into()),
+                "input error: cannot specialize env: it must be declared with a constant",
+            ),
+        ] {
+            let inputs = HashMap::from([(name.to_string(), val)]);
+            let err = deployment(decl).specialize(&inputs).err().unwrap();
+            assert_eq!(err.kind, stac::ExecErrorKind::Input);
+            assert_eq!(err.message, msg);
+        }
+    }
+
+    #[test]
+    fn optimize() {
+        let programs = [
+            "
+    p := 0;
+    q := 1;
+    while p < 200 {
+        t := p + q;
+        q = p;
+        p = t;
+    }",
+            r#"
+    func extern pending() (int)
+    w := pending();
+    a := 0;
+    b := 0;
+    if true {
+        if 1 < 2 {
+            if w > 3 {
+                a = 1;
+            } else {}
+        }
+    } else {
+        a = 2;
+    }
+    if a == 0 && (b == 1 || true) {} else {
+        b = 3;
+    }"#,
+            "
+    func fact(n: int) (int) {
+        if n <= 1 {
+            return 1;
+        }
+        return n * fact(n - 1);
+    }
+    f := fact(6);
+    sum := 0;
+    for i := 0; i < 10; i = i + 1 {
+        if i == 7 {
+            break;
+        }
+        if i % 2 == 0 {
+            continue;
+        }
+        sum = sum + i;
+    }",
+            r#"
+    size := 0;
+    for i := 0; i < 4; i = i + 1 {
+        match i % 3 {
+            0 {
+                size = size + 1;
+            }
+            2 {}
+            _ {
+                size = size + 10;
+            }
+        }
+    }
+    n := 3;
+    outer: for ; n > 0; {
+        if true {}
+        n = n - 1;
+    } completed {
+        n = 100;
+    }"#,
+            // An if whose only branch is empty has nothing to evaluate
+            "
+    func extern pending() (int)
+    w := pending();
+    if w > 3 {}
+    n := 1;",
+        ];
+        for src in programs {
+            let plain = run_waiting(src);
+            let mut optimized = compile(src).unwrap();
+            optimized.optimize();
+            optimized.external_functions.insert(
+                "pending".into(),
+                Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])),
+            );
+            optimized.execute().unwrap();
+            assert_eq!(optimized.variables, plain.variables, "{}", src);
+            assert!(optimized.summary().cycles <= plain.summary().cycles);
+        }
+
+        // Nested ifs on constants and empty branches leave few blocks behind
+        let src = programs[1];
+        let plain = compile(src).unwrap();
+        let mut optimized = compile(src).unwrap();
+        optimized.optimize();
+        print_instructions(&optimized.code);
+        assert_eq!(optimized.code.len(), plain.code.len() - 6);
+        let gotos = optimized
+            .code
+            .iter()
+            .flat_map(|b| &b.code)
+            .filter(|i| matches!(i, stac::Instr::Goto { .. }))
+            .count();
+        assert_eq!(gotos, 1);
+    }
+
+    #[test]
+    fn fold_constants() {
+        let src = r#"
+    size := 1024 * 1024 * 4;
+    version := "10.0." + "0." + "1";
+    half := div(-size, 2);
+    big := 9223372036854775807;
+    x := size + 1;
+    "#;
+        let plain = run_waiting(src);
+        let mut optimized = compile(src).unwrap();
+        optimized.optimize();
+        print_instructions(&optimized.code);
+        let consts: Vec<_> = optimized
+            .code
+            .iter()
+            .flat_map(|b| &b.code)
+            .filter_map(|i| match i {
+                stac::Instr::LoadConst { v } => Some(v.clone()),
+                _ => None,
+            })
+            .collect();
+        assert!(consts.contains(&DataVal::Integer(4194304)));
+        assert!(consts.contains(&DataVal::String("10.0.0.1".into())));
+        assert!(!consts.contains(&DataVal::Integer(1024)));
+        optimized.execute().unwrap();
+        assert_eq!(optimized.variables, plain.variables);
+        assert_eq!(optimized.get_var("half"), Some(&DataVal::Integer(-2097152)));
+
+        // Operations that fail are left to fail at runtime
+        for (src, kind, message) in [
+            (
+                "x := 9223372036854775807 + 1;",
+                stac::ExecErrorKind::IntegerOverflow,
+                "integer overflow: 9223372036854775807 + 1",
+            ),
+            (
+                "x := div(1, 0);",
+                stac::ExecErrorKind::DivisionByZero,
+                "division by zero",
+            ),
+        ] {
+            let mut prog = compile(src).unwrap();
+            prog.optimize();
+            let err = prog.execute().unwrap_err();
+            assert_eq!(err.kind, kind, "{}", src);
+            assert_eq!(err.message, message);
+        }
+    }
+
+    // Executes a program whose extern returns a value that is waiting
+    fn run_waiting(src: &str) -> stac::Prog {
+        let mut prog = compile(src).unwrap();
+        prog.external_functions.insert(
+            "pending".into(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])),
+        );
+        prog.execute().unwrap();
+        println!("{:?}", prog.summary());
+        assert_optimizes(&prog);
+        return prog;
+    }
+
+    // Checks that an executed program, whose externs all return values that are
+    // waiting, leaves the same variables once optimized
+    fn assert_optimizes(prog: &stac::Prog) {
+        let mut optimized = stac::Prog::from_bytes(&prog.to_bytes()).unwrap();
+        optimized.optimize();
+        for name in prog.external_functions.keys() {
+            // The stdlib is registered again when the program is loaded
+            optimized
+                .external_functions
+                .entry(name.clone())
+                .or_insert_with(|| Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])));
+        }
+        optimized.execute().unwrap();
+        assert_eq!(optimized.variables, prog.variables);
+    }
+
+    #[test]
+    fn cycle_limit() {
+        // Well under the default limit, but far more than a thousand cycles
+        let mut prog = compile(
+            "
+    i := 0;
+    while i < 10000 {
+        i = i + 1;
+    }",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+        assert_eq!(prog.get_var("i"), Some(&DataVal::Integer(10000)));
+        assert!(prog.summary().cycles > 10000);
+        assert!(!prog.summary().near_cycle_limit);
+
+        let mut prog = compile("while true {}").unwrap();
+        prog.set_cycle_limit(Some(5000));
+        let err = prog.execute().unwrap_err();
+        assert_eq!(
+            err.kind,
+            stac::ExecErrorKind::CycleLimitExceeded { cycles: 5000 }
+        );
+        assert_eq!(
+            err.message,
+            "cycle limit exceeded: ran more than 5000 instructions"
+        );
+        assert!(prog.summary().near_cycle_limit);
+
+        // It stopped inside the loop, not the main block
+        assert_ne!(err.ip.0, prog.entrypoint.0);
+    }
+
+    #[test]
+    fn loop_call_stack() {
+        let mut prog = compile(
+            "
+    func clamp(x: int) (int) {
+        if x > 10 {
+            return 10;
+        }
+        return x;
+    }
+
+    i := 0;
+    total := 0;
+    for j := 0; j < 3; j = j + 1 {
+        total = total + clamp(j * 6);
+    }
+    while i < 100000 {
+        if i % 2 == 0 {
+            total = total + 1;
+        }
+        i = i + 1;
+    }",
+        )
+        .unwrap();
+        prog.set_cycle_limit(Some(5_000_000));
+        prog.execute().unwrap();
+
+        // Iterations don't leave frames behind, and each call returns into the loop
+        assert_eq!(prog.get_var("i"), Some(&DataVal::Integer(100000)));
+        assert_eq!(prog.get_var("total"), Some(&DataVal::Integer(50016)));
+        assert!(prog.summary().max_call_stack <= 4);
+    }
+
+    #[test]
+    fn recursion() {
+        let src = "
+    func fact(n: int) (int) {
+        if n <= 1 {
+            return 1;
+        }
+        return n * fact(n - 1);
+    }
+
+    // attempt is read after the recursive call, which mustn't overwrite it
+    func backoff(attempt: int) (int) {
+        if attempt == 0 {
+            return 1;
+        }
+        prev := backoff(attempt - 1);
+        return prev + attempt;
+    }
+
+    f := fact(10);
+    b := backoff(5);";
+        let mut prog = compile(src).unwrap();
+        prog.execute().unwrap();
+        assert_eq!(prog.get_var("f"), Some(&DataVal::Integer(3628800)));
+        assert_eq!(prog.get_var("b"), Some(&DataVal::Integer(16)));
+
+        let mut prog = compile(src).unwrap();
+        prog.set_max_call_depth(5);
+        let err = prog.execute().unwrap_err();
+        assert_eq!(err.kind, stac::ExecErrorKind::StackOverflow { depth: 5 });
+        assert_eq!(err.message, "stack overflow: more than 5 calls in progress");
+
+        // Unbounded recursion fails instead of running out of memory
+        let mut prog = compile(
+            "
+    func forever(n: int) (int) {
+        return forever(n + 1);
+    }
+    x := forever(0);",
+        )
+        .unwrap();
+        let err = prog.execute().unwrap_err();
+        assert_eq!(
+            err.kind,
+            stac::ExecErrorKind::StackOverflow {
+                depth: stac::DEFAULT_MAX_CALL_DEPTH
+            }
+        );
+        assert!(matches!(
+            prog.code[err.ip.0].code[err.ip.1],
+            stac::Instr::Call { .. }
+        ));
+    }
+
+    #[test]
+    fn if_continuation() {
+        // A statement after an if in a loop runs once per iteration
+        let prog = run_waiting(
+            "
+    i := 0;
+    n := 0;
+    after := 0;
+    while i < 5 {
+        if (i == 2) {
+            n = n + 10;
+        }
+        after = after + 1;
+        i = i + 1;
+    }",
+        );
+        assert_eq!(
+            prog.variables[..3],
+            [
+                DataVal::Integer(5),
+                DataVal::Integer(10),
+                DataVal::Integer(5)
+            ]
+        );
+        assert_eq!(prog.summary().cycles, 102);
+
+        // An if as the last statement of a function returns to the caller
+        let prog = run_waiting(
+            "
+    hits := 0;
+    func bump(x: int) () {
+        if x > 3 {
+            hits = hits + 1;
+        }
+    }
+    bump(5);
+    bump(1);
+    bump(7);
+    after := hits * 2;",
+        );
+        assert_eq!(prog.variables[0], DataVal::Integer(2));
+        assert_eq!(prog.variables[2], DataVal::Integer(4));
+        assert_eq!(prog.summary().cycles, 40);
+
+        // A nested if whose branch ends in a loop resumes after both ifs
+        let prog = run_waiting(
+            "
+    a := 0;
+    b := 0;
+    c := 0;
+    if a == 0 {
+        if (b == 0) {
+            while c < 3 {
+                c = c + 1;
+            }
+        }
+        b = 5;
+    }
+    a = 7;",
+        );
+        assert_eq!(
+            prog.variables[..3],
+            [
+                DataVal::Integer(7),
+                DataVal::Integer(5),
+                DataVal::Integer(3)
+            ]
+        );
+        assert_eq!(prog.summary().cycles, 53);
+
+        // Compound conditions put the if exprs in blocks of their own
+        let prog = run_waiting(
+            "
+    i := 0;
+    n := 0;
+    while i < 4 && n < 100 {
+        if i == 1 || i == 3 {
+            n = n + 1;
+        }
+        i = i + 1;
+    }
+    after := i + n;",
+        );
+        assert_eq!(
+            prog.variables[..3],
+            [
+                DataVal::Integer(4),
+                DataVal::Integer(2),
+                DataVal::Integer(6)
+            ]
+        );
+        assert_eq!(prog.summary().cycles, 107);
+    }
+
+    #[test]
+    fn if_continuation_waiting() {
+        let prog = run_waiting(
+            "
+    func extern pending() (int)
+
+    w := pending();
+    i := 0;
+    n := 0;
+    after := 0;
+    while i < 3 {
+        if (w == 2) {
+            n = n + 10;
+        }
+        after = after + 1;
+        i = i + 1;
+    }",
+        );
+        assert_eq!(
+            prog.variables[..4],
+            [
+                DataVal::Waiting,
+                DataVal::Integer(3),
+                DataVal::Waiting,
+                DataVal::Integer(3)
+            ]
+        );
+        assert_eq!(prog.summary().cycles, 73);
+
+        let prog = run_waiting(
+            "
+    func extern pending() (int)
+
+    w := pending();
+    hits := 0;
+    func bump(x: int) () {
+        if x > 3 {
+            hits = hits + 1;
+        }
+    }
+    bump(w);
+    bump(1);
+    after := 2;",
+        );
+        assert_eq!(prog.variables[1], DataVal::Waiting);
+        assert_eq!(prog.variables[3], DataVal::Integer(2));
+        assert_eq!(prog.summary().cycles, 30);
+
+        let prog = run_waiting(
+            "
+    func extern pending() (int)
+
+    w := pending();
+    b := 0;
+    c := 0;
+    if w == 0 {
+        if (b == 0) {
+            while c < 3 {
+                c = c + 1;
+            }
+        }
+        b = 5;
+    }
+    a := 7;",
+        );
+        assert_eq!(
+            prog.variables[..4],
+            [
+                DataVal::Waiting,
+                DataVal::Waiting,
+                DataVal::Waiting,
+                DataVal::Integer(7)
+            ]
+        );
+        assert_eq!(prog.summary().cycles, 35);
+    }
+
+    #[test]
+    fn if_values() {
+        let prog = run_waiting(
+            r#"
+    prod := true;
+    size := if prod { "s-4vcpu" } else { "s-1vcpu" };
+    count := 2 * if prod { 3 } else { 1 } + 1;
+    region := if !prod { "lon1" } else if count > 5 { "syd1" } else { "nyc1" };"#,
+        );
+        // Each if value has a temporary before the variable it's assigned to
+        assert_eq!(prog.variables[2], DataVal::String("s-4vcpu".into()));
+        assert_eq!(prog.variables[4], DataVal::Integer(7));
+        assert_eq!(prog.variables[7], DataVal::String("syd1".into()));
+
+        let prog = run_waiting(
+            "
+    func extern pending() (int)
+
+    w := pending();
+    r := (if w > 1 { 10 } else { 20 }) + 1;
+    after := 3;",
+        );
+        assert_eq!(prog.variables[0], DataVal::Waiting);
+        assert_eq!(prog.variables[2], DataVal::Waiting);
+        assert_eq!(prog.variables[3], DataVal::Integer(3));
+
+        for (src, msg) in [
+            (
+                r#"p := if true { 1 } else { "a" };"#,
+                "type error: if branches must have the same type, found int and string",
+            ),
+            (
+                "p := if 1 { 1 } else { 2 };",
+                "type error: if condition must be bool, found int",
+            ),
+            (
+                "p := if true { 1 };",
+                "syntax error: expected Else, found C(';')",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(par.program().err().unwrap().kind.to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn match_statements() {
+        let prog = run_waiting(
+            r#"
+    func describe(state: string) (int) {
+        match state {
+            "active" {
+                return 1;
+            }
+            "off" {
+                return 2;
+            }
+            _ {
+                return 0;
+            }
+        }
+        return -1;
+    }
+    a := describe("active");
+    b := describe("off");
+    c := describe("new");
+
+    size := 0;
+    for i := 0; i < 4; i = i + 1 {
+        match i % 3 {
+            0 {
+                size = size + 1;
+            }
+            -1 {
+                size = size + 100;
+            }
+            2 {
+                size = size + 10;
+            }
+        }
+    }"#,
+        );
+        assert_eq!(
+            prog.variables[2..5],
+            [
+                DataVal::Integer(1),
+                DataVal::Integer(2),
+                DataVal::Integer(0)
+            ]
+        );
+        assert_eq!(prog.variables[5], DataVal::Integer(12));
+
+        // A waiting value could match any arm, so every arm runs for its side effects
+        let prog = run_waiting(
+            "
+    func extern pending() (int)
+
+    w := pending();
+    n := 0;
+    d := 0;
+    after := 0;
+    match w {
+        1 {
+            n = 1;
+        }
+        _ {
+            d = 2;
+        }
+    }
+    after = 3;",
+        );
+        assert_eq!(
+            prog.variables[..4],
+            [
+                DataVal::Waiting,
+                DataVal::Waiting,
+                DataVal::Waiting,
+                DataVal::Integer(3)
+            ]
+        );
+
+        for (src, msg) in [
+            (
+                "match 1.0 { _ {} }",
+                "type error: can only match on int or string, found float",
+            ),
+            (
+                r#"match 1 { "a" {} }"#,
+                r#"type error: match arm String("a") must be int"#,
+            ),
+            (
+                "match 1 { 1 {} 1 {} }",
+                "syntax error: duplicate match arm Integer(1)",
+            ),
+            (
+                "match 1 { _ {} 1 {} }",
+                "syntax error: the _ arm must be the last arm of a match",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(par.program().err().unwrap().kind.to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn make() {
+        let l = lexer::Lexer::new(
+            "
+    struct Rule {
+        port: int,
+        proto: string
+    }
+
+    func extern ruleCount() (int)
+
+    a := make([]string, 3);
+    b := make([]int, 1 + 1, div(9, 2));
+    c := make([]Rule, 2);
+    n := ruleCount();
+    d := make([]Rule, n, c[0]);"
+                .chars()
+                .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        prog.external_functions.insert(
+            "ruleCount".into(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])),
+        );
+        prog.execute().unwrap();
+        assert_optimizes(prog);
+        println!("{:?}", prog.variables);
+
+        let empty = DataVal::String("".into());
+        assert_eq!(prog.variables[0], DataVal::Compound(vec![empty.clone(); 3]));
+        assert_eq!(
+            prog.variables[1],
+            DataVal::Compound(vec![DataVal::Integer(4); 2])
+        );
+
+        let rule = DataVal::Compound(vec![DataVal::Integer(0), empty]);
+        assert_eq!(prog.variables[2], DataVal::Compound(vec![rule; 2]));
+        assert_eq!(prog.variables[4], DataVal::Waiting);
+    }
+
+    #[test]
+    fn arrays() {
+        let mut prog = compile(
+            "
+    p := 5;
+    q := [2, 2, 3, p];
+    q[0] = 1;
+    p = q[0];",
+        )
+        .unwrap();
+        print_instructions(&prog.code);
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+
+        assert_eq!(prog.variables[0], stac::DataVal::Integer(1))
+    }
+
+    #[test]
+    fn structs() {
+        let mut prog = compile(
+            "
+    struct Test {
+        n1: int,
+        n2: float,
+        n3: float,
+    }
+
+    p := Test{
+        n1: 5,
+        n2: 6.0f,
+    };
+    q := p.n1;
+    r := p.n2;
+    w := p.n3;",
+        )
+        .unwrap();
+        print_instructions(&prog.code);
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+
+        assert_eq!(prog.variables[1], stac::DataVal::Integer(5));
+        assert_eq!(prog.variables[2], stac::DataVal::Float(6.));
+        assert_eq!(prog.variables[3], stac::DataVal::Float(0.));
+    }
+
+    #[test]
+    fn list_separators() {
+        // Trailing commas are allowed in every list
+        let mut prog = compile(
+            "
+    struct Pair { a: int, b: int in [1, 2,], }
+    func extern createPairs(n: int,) (int,)
+    func sum(x: int, y: int,) (int) {
+        return x + y;
+    }
+    p := Pair { a: sum(1, 2,), b: 2, };
+    xs := [1, 2, 3,];",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+        assert_eq!(
+            prog.variables[2],
+            DataVal::Compound(vec![DataVal::Integer(3), DataVal::Integer(2)])
+        );
+
+        // The struct in the structs test used to leave out a comma, which is now an error
+        use parser::ParseErrorKind as K;
+        let comma = |end| K::UnexpectedToken {
+            found: lexer::Token::Word("n3".into()),
+            expected: vec![lexer::Token::C(','), lexer::Token::C(end)],
+        };
+        for (src, end) in [
+            ("struct Test { n2: float\n n3: float }", '}'),
+            ("struct Test { n2: int }\nt := Test { n2: 1 n3: 2 };", '}'),
+            ("n3 := 1;\nxs := [1 n3];", ']'),
+            ("n3 := 1;\nfunc f(a: int, b: int) () {}\nf(1 n3);", ')'),
+            ("func f(a: int n3: int) () {}", ')'),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(par.program().err().unwrap().kind, comma(end), "{}", src);
+        }
+    }
+
+    #[test]
+    fn extern_func() {
+        let l = lexer::Lexer::new(
+            r#"
+    func extern createResource(name: string) (int)
+    func extern createResourceAsync(name: string) (int)
+
+    p := createResource("test");
+    q := createResourceAsync("test3");
+
+    func test() (int) {
+        p := 1;
+        if p == 1 {
+            p = 2;
+        }
+        return p;
+    }
+
+    a := 1;
+    if q < 1 {
+        a = 2;
+    } else {
+        a = 3;
+    }
+
+    b := test();
+    c := 1;
+    if q < 1 {
+        c = test();
+    }
+    "#
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        print_instructions(&prog.code);
+
+        prog.register_extern("createResource", |name: String| -> i64 {
+            println!("creating resource {}", name);
+            return 6;
+        });
+
+        prog.register_extern("createResourceAsync", |name: String| -> Option<i64> {
+            println!("creating resource asynchronously {}", name);
+            return None;
+        });
+
+        // Each extern is called once. Both branches of the waiting ifs are scanned
+        // once, including the body of test() and its if.
+        prog.expect(
+            ExecExpectations::new()
+                .instr_count(Label(0), "ExternCall", 1)
+                .instr_count(Label(1), "ExternCall", 1)
+                .block_visits(Label(4), 1)
+                .block_visits(Label(5), 1)
+                .block_visits(Label(2), 2)
+                .block_visits(Label(3), 2)
+                .before(Event::Instr("ExternCall"), Event::Store(stac::Addr(0))),
+        );
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+        prog.verify_expectations().unwrap();
+
+        assert_eq!(prog.variables[0], stac::DataVal::Integer(6));
+        assert_eq!(prog.variables[1], stac::DataVal::Waiting);
+        assert_eq!(prog.variables[2], stac::DataVal::Waiting);
+        assert_eq!(prog.variables[4], stac::DataVal::Integer(2));
+        assert_eq!(prog.variables[5], stac::DataVal::Waiting);
+
+        let summary = prog.summary();
+        println!("{:?}", summary);
+        assert_eq!(summary.waiting, vec!["q", "p", "a", "c"]);
+        assert_eq!(summary.extern_call_count("createResource"), 1);
+        assert_eq!(summary.extern_call_count("createResourceAsync"), 1);
+        assert!(summary.entered_side_effects);
+    }
+
+    #[test]
+    fn waiting_operators() {
+        let mut prog = compile(
+            r#"
+    func extern createServer(name: string) (string)
+    func extern serverReady(id: string) (bool)
+
+    id := createServer("web");
+    ready := serverReady("web-1");
+    name := "web-" + id;
+    label := "${name}-${ready}";
+    pending := !ready;
+
+    a := 1;
+    if !ready && a == 1 {
+        a = 2;
+    }
+    b := 1;
+    if !(name == "web-1") {
+        b = 2;
+    }
+    "#,
+        )
+        .unwrap();
+        prog.register_extern("createServer", |_name: String| -> Option<String> { None });
+        prog.register_extern("serverReady", |_id: String| -> Option<bool> { None });
+
+        prog.execute().unwrap();
+        assert_optimizes(&prog);
+        let summary = prog.summary();
+        assert_eq!(
+            summary.waiting,
+            vec!["id", "ready", "name", "label", "pending", "a", "b"]
+        );
+        assert!(summary.entered_side_effects);
+    }
+
+    #[test]
+    fn resolve_extern() {
+        let mut prog = compile(
+            r#"
+    func extern createResource(name: string) (int)
+    func extern createResourceAsync(name: string) (int)
+
+    p := createResource("test");
+    q := createResourceAsync("test3");
+
+    a := 1;
+    if q < 1 {
+        a = 2;
+    } else {
+        a = 3;
+    }
+    r := q + p;
+    "#,
+        )
+        .unwrap();
+
+        let waiting = Arc::new(Mutex::new(vec![]));
+        prog.register_extern("createResource", |_: String| -> i64 { 6 });
+        let calls = waiting.clone();
+        prog.external_functions.insert(
+            "createResourceAsync".into(),
+            Box::new(move |id, _, _, _, _, _| {
+                calls.lock().unwrap().push(id);
+                return Ok(vec![DataVal::Waiting]);
+            }),
+        );
+
+        prog.execute().unwrap();
+        assert_eq!(prog.get_var("q"), Some(&DataVal::Waiting));
+        assert_eq!(prog.get_var("a"), Some(&DataVal::Waiting));
+        assert_eq!(prog.get_var("r"), Some(&DataVal::Waiting));
+
+        // Once the resource is created, its id stands in for the call
+        let id = waiting.lock().unwrap()[0];
+        prog.resolve_extern(id, vec![DataVal::Integer(42)]);
+        prog.reexecute().unwrap();
+
+        assert_eq!(prog.get_var("q"), Some(&DataVal::Integer(42)));
+        assert_eq!(prog.get_var("a"), Some(&DataVal::Integer(3)));
+        assert_eq!(prog.get_var("r"), Some(&DataVal::Integer(48)));
+        assert!(prog.summary().waiting.is_empty());
+        assert_eq!(waiting.lock().unwrap().len(), 1);
+
+        // The unresolved extern is still called
+        assert_eq!(prog.summary().extern_call_count("createResource"), 1);
+    }
+
+    #[test]
+    fn register_extern() {
+        let mut prog = compile(
+            r#"
+    func extern sum(xs: []int, scale: float) (float)
+    func extern describe(name: string, enabled: bool) (string, int)
+    func extern pending() (int)
+
+    total := sum([1, 2, 3], 1.5);
+    n := pending();
+    later := sum([n], 1.0);
+    describe("bucket", true);
+    "#,
+        )
+        .unwrap();
+
+        let described = Arc::new(Mutex::new(vec![]));
+        let d = described.clone();
+        prog.register_extern("sum", |xs: Vec<i64>, scale: f64| -> f64 {
+            return xs.iter().sum::<i64>() as f64 * scale;
+        });
+        prog.register_extern(
+            "describe",
+            move |name: String, enabled: bool| -> (String, i64) {
+                d.lock().unwrap().push((name.clone(), enabled));
+                return (name, 1);
+            },
+        );
+        prog.register_extern("pending", || -> Option<i64> { None });
+        prog.execute().unwrap();
+
+        assert_eq!(prog.get_var("total"), Some(&DataVal::Float(9.0)));
+        assert_eq!(prog.get_var("n"), Some(&DataVal::Waiting));
+        assert_eq!(
+            *described.lock().unwrap(),
+            vec![("bucket".to_string(), true)]
+        );
+
+        // The closure isn't called with waiting params, and its returns wait
+        assert_eq!(prog.get_var("later"), Some(&DataVal::Waiting));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "extern createResource is declared as (string) (int), but registered as (int) (int)"
+    )]
+    fn register_extern_mismatch() {
+        let mut prog = compile("func extern createResource(name: string) (int)").unwrap();
+        prog.register_extern("createResource", |id: i64| -> i64 { id });
+    }
+
+    #[test]
+    fn failing_extern() {
+        let mut prog = compile(
+            r#"
+    func extern createBucket(name: string) (int)
+
+    a := createBucket("logs");
+    b := createBucket("assets");
+    c := 3;
+    "#,
+        )
+        .unwrap();
+
+        // Fails on the second call, as if the provider's quota ran out
+        let calls = AtomicI64::new(0);
+        prog.register_extern("createBucket", move |name: String| {
+            let calls = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            if calls == 2 {
+                return Err(stac::ExternError::new(format!(
+                    "quota exceeded creating {}",
+                    name
+                )));
+            }
+            return Ok(calls);
+        });
+        let err = prog.execute().unwrap_err();
+
+        // Execution stops at the failing call, before b or c are assigned
+        let stac::ExecErrorKind::Extern { func, error, .. } = &err.kind else {
+            panic!("expected an extern error, found {:?}", err.kind);
+        };
+        assert_eq!(func, "createBucket");
+        assert_eq!(error.message, "quota exceeded creating assets");
+        assert!(matches!(
+            prog.code[err.ip.0].code[err.ip.1],
+            stac::Instr::ExternCall { .. }
+        ));
+        assert_eq!(prog.get_var("a"), Some(&DataVal::Integer(1)));
+        assert_eq!(prog.get_var("b"), Some(&DataVal::Uninit));
+        assert_eq!(prog.get_var("c"), Some(&DataVal::Uninit));
+        assert_eq!(prog.summary().extern_call_count("createBucket"), 1);
+
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "extern error: createBucket failed: quota exceeded creating assets at {:?}",
+                err.ip
+            )
+        );
+    }
+
+    #[test]
+    fn extern_context() {
+        let mut prog = compile(
+            r#"
+    func extern createBucket(name: string) (int)
+    func extern countResources() (int)
+
+    a := createBucket("logs");
+    b := createBucket("assets");
+    n := countResources();
+    "#,
+        )
+        .unwrap();
+
+        // Both externs share the ledger of created resources
+        prog.set_context(vec!["network".to_string()]);
+        prog.external_functions.insert(
+            "createBucket".into(),
+            Box::new(|_, _, _, params, _, ctx| {
+                let ledger = ctx.downcast_mut::<Vec<String>>().unwrap();
+                ledger.push(params[0].clone().try_into().unwrap());
+                return Ok(vec![DataVal::Integer(ledger.len() as i64)]);
+            }),
+        );
+        prog.external_functions.insert(
+            "countResources".into(),
+            Box::new(|_, _, _, _, _, ctx| {
+                let ledger = ctx.downcast_ref::<Vec<String>>().unwrap();
+                return Ok(vec![DataVal::Integer(ledger.len() as i64)]);
+            }),
+        );
+        prog.execute().unwrap();
+
+        assert_eq!(prog.get_var("a"), Some(&DataVal::Integer(2)));
+        assert_eq!(prog.get_var("b"), Some(&DataVal::Integer(3)));
+        assert_eq!(prog.get_var("n"), Some(&DataVal::Integer(3)));
+        assert_eq!(
+            prog.context::<Vec<String>>().unwrap(),
+            &vec!["network", "logs", "assets"]
+        );
+
+        prog.context_mut::<Vec<String>>().unwrap().clear();
+        assert!(prog.context::<Vec<String>>().unwrap().is_empty());
+        assert!(prog.context::<String>().is_none());
+    }
+
+    // Lets a test read what a program printed
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            return self.0.lock().unwrap().write(buf);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn stdlib() {
+        let l = lexer::Lexer::from_str(
+            r#"
+    words := split("create the bucket", " ");
+    n := len(words);
+    shout := to_upper(join(words, "-"));
+    found := contains(shout, "BUCKET");
+    letters := split("abc", "");
+
+    a := abs(0 - 7);
+    lo := min(3, 9);
+    hi := max(3, 9);
+    down := floor(2.7);
+    up := ceil(2.2);
+    neg := floor(0.0 - 2.5);
+
+    print(shout);
+    print("${n} words, max ${hi}");
+
+    test "stdlib runs in tests" {
+        assert max(1, 2) == 2;
+        print("testing");
+    }
+    "#,
+        );
+        let mut par = parser::Parser::with_prog(l, stac::Prog::with_stdlib());
+        par.program().unwrap();
+        let mut prog = par.into_prog();
+
+        let out = SharedBuf::default();
+        prog.set_output(Box::new(out.clone()));
+        prog.execute().unwrap();
+
+        let strings =
+            |v: &[&str]| DataVal::from(v.iter().map(|&s| DataVal::from(s)).collect::<Vec<_>>());
+        assert_eq!(
+            prog.get_var("words"),
+            Some(&strings(&["create", "the", "bucket"]))
+        );
+        assert_eq!(prog.get_var("n"), Some(&DataVal::Integer(3)));
+        assert_eq!(
+            prog.get_var("shout"),
+            Some(&DataVal::from("CREATE-THE-BUCKET"))
+        );
+        assert_eq!(prog.get_var("found"), Some(&DataVal::Bool(true)));
+        assert_eq!(prog.get_var("letters"), Some(&strings(&["a", "b", "c"])));
+        assert_eq!(prog.get_var("a"), Some(&DataVal::Integer(7)));
+        assert_eq!(prog.get_var("lo"), Some(&DataVal::Integer(3)));
+        assert_eq!(prog.get_var("hi"), Some(&DataVal::Integer(9)));
+        assert_eq!(prog.get_var("down"), Some(&DataVal::Integer(2)));
+        assert_eq!(prog.get_var("up"), Some(&DataVal::Integer(3)));
+        assert_eq!(prog.get_var("neg"), Some(&DataVal::Integer(-3)));
+
+        // Tests still call the stdlib rather than stubbing it
+        let results = prog.run_tests();
+        assert_eq!(results[0].failure, None);
+
+        let printed = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            printed,
+            "CREATE-THE-BUCKET\n3 words, max 9\nCREATE-THE-BUCKET\n3 words, max 9\ntesting\n"
+        );
+
+        // Calls are type checked against the declarations
+        for (src, msg) in [
+            (
+                r#"x := max("a", 1);"#,
+                "type error: argument 1 of max must be int, found string",
+            ),
+            (
+                r#"print("a", "b");"#,
+                "type error: print expects 1 argument, found 2",
+            ),
+        ] {
+            let mut par =
+                parser::Parser::with_prog(lexer::Lexer::from_str(src), stac::Prog::with_stdlib());
+            assert_eq!(
+                par.program().err().unwrap().to_string(),
+                format!("line 1: {}", msg)
+            );
+        }
+
+        // And the stdlib is opt in
+        let err = compile(r#"print("hi");"#).err().unwrap();
+        assert_eq!(err.to_string(), "line 1: unknown function: print");
+    }
+
+    #[tokio::test]
+    async fn async_externs() {
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createBucket"]}"#),
+            ("/createBucket", "[4]"),
+        ]);
+        let mut prog = compile(
+            r#"
+    func extern createBucket(name: string) (int)
+    func extern approve(bucket: int) (bool)
+    func extern pending() (int)
+
+    b := createBucket("logs");
+    ok := approve(b);
+    n := pending();
+    "#,
+        )
+        .unwrap();
+
+        // A blocking provider would panic inside the runtime
+        prog.add_http_provider_async(addr, ProviderConfig::default())
+            .await
+            .unwrap();
+
+        // Approval comes from another task on the same thread, so the call can
+        // only complete if execution yields to it rather than blocking
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let rx = Mutex::new(Some(rx));
+        prog.async_external_functions.insert(
+            "approve".into(),
+            Box::new(move |_, _, _, params, _| {
+                assert_eq!(params, vec![DataVal::Integer(4)]);
+                let rx = rx.lock().unwrap().take().unwrap();
+                return Box::pin(async move { Ok(vec![DataVal::Bool(rx.await.unwrap())]) });
+            }),
+        );
+
+        // Sync externs are still called directly
+        prog.external_functions.insert(
+            "pending".into(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])),
+        );
+
+        let (result, _) = tokio::join!(prog.execute_async(), async {
+            tx.send(true).unwrap();
+        });
+        result.unwrap();
+
+        assert_eq!(prog.get_var("b"), Some(&DataVal::Integer(4)));
+        assert_eq!(prog.get_var("ok"), Some(&DataVal::Bool(true)));
+        assert_eq!(prog.get_var("n"), Some(&DataVal::Waiting));
+        assert_eq!(received.lock().unwrap().len(), 2);
+        assert_eq!(prog.summary().extern_call_count("approve"), 1);
+    }
+
+    #[test]
+    fn extern_func2() {
+        let l = lexer::Lexer::new(
+            r#"
+    func extern createResourceAsync(name: string) (int)
+
+    q := createResourceAsync("test3");
+
+    a := 1;
+    if q == 0 {
+        if q == 0 {
+            a = 2;
+        }
+        a = 3;
+    }
+
+    b := 2;
+    while a == 1 {
+        b = 3;
+    }
+    "#
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        print_instructions(&prog.code);
+
+        prog.external_functions.insert(
+            "createResourceAsync".into(),
+            Box::new(|_ip, _ptype, _rtypes, params, _ustructs, _ctx| {
+                println!(
+                    "creating resource asynchronously {}",
+                    params[0].clone().into_string().unwrap()
+                );
+                return Ok(vec![DataVal::Waiting]);
+            }),
+        );
+
+        // The while loop under the waiting value is only scanned once
+        prog.expect(
+            ExecExpectations::new()
+                .block_visits(Label(1), 1)
+                .block_visits(Label(3), 1)
+                .block_visits(Label(4), 2)
+                .before(Event::Block(Label(2)), Event::Block(Label(1))),
+        );
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+        prog.verify_expectations().unwrap();
+
+        assert_eq!(prog.variables[0], stac::DataVal::Waiting);
+        assert_eq!(prog.variables[1], stac::DataVal::Waiting);
+        assert_eq!(prog.variables[2], stac::DataVal::Waiting);
+
+        // Each waiting if expr was only reached once
+        assert!(prog.report.converged.is_empty());
+
+        let summary = prog.summary();
+        assert_eq!(summary.waiting, vec!["q", "a", "b"]);
+        assert_eq!(
+            summary.extern_calls["createResourceAsync"]
+                .values()
+                .sum::<usize>(),
+            1
+        );
+    }
+
+    #[test]
+    fn deferred_calls() {
+        let mut prog = compile(
+            r#"
+    func extern createResource(name: string) (int)
+    func extern pendingId() (int)
+
+    pending := pendingId();
+    if pending == 0 {
+        a := createResource("db");
+    }
+    b := createResource("web");
+    "#,
+        )
+        .unwrap();
+
+        let ids = Arc::new(Mutex::new(vec![]));
+        let i = ids.clone();
+        prog.external_functions.insert(
+            "createResource".into(),
+            Box::new(move |id, _, _, _, _, _| {
+                i.lock().unwrap().push(id);
+                return Ok(vec![DataVal::Integer(1)]);
+            }),
+        );
+        prog.register_extern("pendingId", || -> Option<i64> { None });
+        prog.execute().unwrap();
+
+        // The call in the waiting branch isn't made, but the host can see it
+        let deferred = &prog.report.deferred_calls;
+        assert_eq!(deferred.len(), 1);
+        assert_eq!(deferred[0].func, "createResource");
+        assert_eq!(deferred[0].id.ordinal, 0);
+        assert!(matches!(
+            prog.code[deferred[0].call_site.0].code[deferred[0].call_site.1],
+            stac::Instr::Call { .. }
+        ));
+        assert_eq!(prog.summary().extern_call_count("createResource"), 1);
+
+        // The call after the branch is from another site, with its own ordinals
+        let ids = ids.lock().unwrap();
+        assert_eq!(ids.len(), 1);
+        assert_eq!(ids[0].ordinal, 0);
+        assert_ne!(ids[0], deferred[0].id);
+        assert_eq!(prog.get_var("b"), Some(&DataVal::Integer(1)));
+    }
+
+    #[test]
+    fn stable_call_ids() {
+        fn ids(src: &str) -> HashMap<String, stac::CallId> {
+            let mut prog = compile(src).unwrap();
+            let ids = Arc::new(Mutex::new(HashMap::new()));
+            let i = ids.clone();
+            prog.external_functions.insert(
+                "createResource".into(),
+                Box::new(move |id, _, _, params, _, _| {
+                    let name = params[0].clone().into_string().unwrap().to_string();
+                    i.lock().unwrap().insert(name, id);
+                    return Ok(vec![DataVal::Integer(1)]);
+                }),
+            );
+            prog.execute().unwrap();
+            return ids.lock().unwrap().clone();
+        }
+
+        let before = ids(r#"
+    func extern createResource(name: string) (int)
+    func setup() () {
+        a := createResource("a");
+        i := 0;
+        while i < 2 {
+            b := createResource("b${i}");
+            i = i + 1;
+        }
+    }
+    setup();"#);
+        let after = ids(r#"
+    func extern createResource(name: string) (int)
+    func setup() () {
+        a := createResource("a");
+        i := 0;
+        while i < 2 {
+            b := createResource("b${i}");
+            i = i + 1;
+        }
+    }
+    z := createResource("z");
+    setup();"#);
+
+        assert_eq!(after.len(), 4);
+        for name in ["a", "b0", "b1"] {
+            assert_eq!(before[name], after[name], "{}", name);
+        }
+
+        // Each site counts its own calls
+        assert_eq!(after["a"].ordinal, 0);
+        assert_eq!(after["b0"].ordinal, 0);
+        assert_eq!(after["b1"].ordinal, 1);
+        assert_eq!(after["z"].ordinal, 0);
+
+        // A name can stand in for the ordinal
+        let b1 = after["b1"];
+        assert_eq!(b1.with_name("b1"), format!("{}:{}:b1", b1.block, b1.instr));
+        assert_eq!(b1.to_string(), format!("{}:{}:1", b1.block, b1.instr));
+
+        // Ids are sent to providers, and kept in snapshots, as tuples
+        assert_eq!(
+            serde_json::to_string(&b1).unwrap(),
+            format!("[{},{},1]", b1.block, b1.instr)
+        );
+    }
+
+    #[test]
+    fn side_effect_converged() {
+        let l = lexer::Lexer::new(
+            r#"
+    func extern createResourceAsync(name: string) (int)
+
+    q := createResourceAsync("test3");
 
     a := 1;
-    if q == 0 {
+    b := 2;
+    i := 0;
+    while i < 50 {
         if q == 0 {
-            a = 2;
+            b = 3;
+        }
+        a = a + 1;
+        i = i + 1;
+    }
+    "#
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        print_instructions(&prog.code);
+
+        prog.external_functions.insert(
+            "createResourceAsync".into(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])),
+        );
+
+        // The body of the if is only scanned the first time around
+        prog.expect(ExecExpectations::new().block_visits(Label(0), 1));
+
+        prog.execute().unwrap();
+        assert_optimizes(prog);
+        println!("{:?}", prog.variables);
+        prog.verify_expectations().unwrap();
+
+        assert_eq!(prog.variables[2], DataVal::Waiting);
+        assert_eq!(prog.variables[1], DataVal::Integer(51));
+        assert_eq!(prog.variables[3], DataVal::Integer(50));
+        assert_eq!(prog.report.speculative_stores.len(), 1);
+        assert_eq!(prog.report.converged.len(), 1);
+    }
+
+    #[test]
+    fn struct_types() {
+        // A struct can be used as a type before it is declared
+        let l = lexer::Lexer::from_str(
+            "
+    struct Droplet { id: int, networks: DropletNetworking }
+    func extern createDroplet(name: string) (Droplet)
+    func networksOf(d: Droplet) (DropletNetworking) {
+        return d.networks;
+    }
+    struct DropletNetworking { v4: []string }",
+        );
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        assert_eq!(
+            prog.user_functions["networksOf"].params,
+            vec![DataType::Struct("Droplet".into())]
+        );
+
+        let l = lexer::Lexer::from_str(
+            "
+    struct Droplet { id: int }
+    struct Network { ip_address: string }
+
+    func extern createDroplet(req: DropletRequest) (Droplet)",
+        );
+        let mut par = parser::Parser::new(l);
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 5: unknown struct: DropletRequest (declared structs are Droplet, Network)"
+        );
+    }
+
+    #[test]
+    fn struct_literals() {
+        // Fields left out of a literal get the default for their type
+        let mut prog = compile(
+            r#"
+    struct Droplet { name: string, size: int, tags: []string }
+    d := Droplet { size: 2 };
+    n := d.name;"#,
+        )
+        .unwrap();
+        prog.execute().unwrap();
+        assert_eq!(
+            prog.variables[0],
+            DataVal::Compound(vec![
+                DataVal::String("".into()),
+                DataVal::Integer(2),
+                DataVal::Compound(vec![])
+            ])
+        );
+
+        let decl = "struct Droplet { name: string, size: int }\n";
+        for (src, msg) in [
+            (
+                "d := Dorplet { size: 2 };",
+                "line 2: unknown struct: Dorplet (declared structs are Droplet)",
+            ),
+            (
+                "d := Droplet { sise: 2 };",
+                "line 2: type error: struct Droplet has no field sise (its fields are name, size)",
+            ),
+            (
+                "d := Droplet { size: 2, name: \"a\", size: 3 };",
+                "line 2: type error: field size is given twice in literal of struct Droplet",
+            ),
+            (
+                "d := Droplet { size: 2 };\nn := d.nmae;",
+                "line 3: type error: struct Droplet has no field nmae (its fields are name, size)",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(&(decl.to_string() + src)));
+            assert_eq!(par.program().err().unwrap().to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn displaying_values() {
+        let mut prog = compile(
+            r#"
+    struct Droplet { name: string, size: float, networks: DropletNetworking }
+    struct DropletNetworking { v4: []Network, v6: []Network }
+    struct Network { ip_address: string, port: int, public: bool }
+
+    d := Droplet {
+        name: "web",
+        size: 2.0,
+        networks: DropletNetworking {
+            v4: [Network { ip_address: "10.0.0.2", port: 80, public: true }, Network { ip_address: "10.0.0.3" }]
+        }
+    };"#,
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        let d = prog.get_var("d").unwrap();
+        assert_eq!(
+            d.to_string(),
+            r#"["web", 2.0, [[["10.0.0.2", 80, true], ["10.0.0.3", 0, false]], []]]"#
+        );
+        assert_eq!(
+            d.display_typed(&DataType::Struct("Droplet".into()), &prog.user_structs),
+            concat!(
+                r#"Droplet { name: "web", size: 2.0, networks: DropletNetworking { "#,
+                r#"v4: [Network { ip_address: "10.0.0.2", port: 80, public: true }, "#,
+                r#"Network { ip_address: "10.0.0.3", port: 0, public: false }], v6: [] } }"#
+            )
+        );
+
+        assert_eq!(DataVal::Waiting.to_string(), "waiting");
+        assert_eq!(
+            DataType::Array(Box::new(DataType::Struct("Network".into()))).to_string(),
+            "[]Network"
+        );
+    }
+
+    #[test]
+    fn nested_struct_literals() {
+        let l = lexer::Lexer::from_str(
+            r#"
+    struct CreateDropletRequest { name: string, networks: DropletNetworking }
+    struct DropletNetworking { v4: []Network, v6: []Network }
+    struct Network { ip_address: string, netmask: string }
+    func extern createDroplet(req: CreateDropletRequest) (int)
+
+    d := createDroplet(CreateDropletRequest {
+        name: "web",
+        networks: DropletNetworking {
+            v4: [Network { ip_address: "10.0.0.2", netmask: "255.0.0.0" }, Network { ip_address: "10.0.0.3" }]
+        }
+    });"#,
+        );
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createDroplet"]}"#),
+            ("/createDroplet", "[1]"),
+        ]);
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.execute().unwrap();
+
+        // The partially initialized network and networking get default fields
+        let received = received.lock().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&received.last().unwrap().body).unwrap();
+        assert_eq!(
+            body["params"][0],
+            serde_json::json!({
+                "name": "web",
+                "networks": {
+                    "v4": [
+                        {"ip_address": "10.0.0.2", "netmask": "255.0.0.0"},
+                        {"ip_address": "10.0.0.3", "netmask": ""}
+                    ],
+                    "v6": []
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn struct_literals_in_conditions() {
+        // A variable named like a struct is a condition, not the start of a literal
+        let mut prog = compile(
+            "
+    struct ready { n: int }
+    ready := true;
+    count := 0;
+    if ready {
+        count = 1;
+    }
+    i := 0;
+    while i < 3 {
+        i = i + 1;
+    }
+    for j := 0; j < 2; j = j + 1 {
+        count = count + 1;
+    }
+    if (ready { n: 4 }).n == 4 {
+        count = count + 10;
+    }
+    r := ready { n: 5 };",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+        assert_eq!(prog.get_var("count"), Some(&DataVal::Integer(13)));
+        assert_eq!(prog.get_var("i"), Some(&DataVal::Integer(3)));
+        assert_eq!(
+            prog.get_var("r"),
+            Some(&DataVal::Compound(vec![DataVal::Integer(5)]))
+        );
+
+        let l = lexer::Lexer::from_str(
+            "
+    struct Droplet { size: int }
+    if Droplet { size: 2 }.size == 2 {}",
+        );
+        let mut par = parser::Parser::new(l);
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 3: syntax error: a Droplet literal in a condition must be in parentheses"
+        );
+    }
+
+    #[test]
+    fn variable_names() {
+        let mut prog = compile(
+            "
+    func double(x: int) (int) {
+        y := x * 2;
+        return y;
+    }
+    x := 1;
+    if x == 1 {
+        x := 5;
+        z := double(x);
+    }
+    w := clamp(20, 0, 10);",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        // A name shadowed in a block is the outer variable, and variables in
+        // functions are qualified by them. Those of the prelude aren't listed.
+        let vars: Vec<(&str, &DataVal)> = prog.vars().collect();
+        assert_eq!(
+            vars,
+            [
+                ("double.x", &DataVal::Integer(5)),
+                ("double.y", &DataVal::Integer(10)),
+                ("x", &DataVal::Integer(1)),
+                ("z", &DataVal::Integer(10)),
+                ("w", &DataVal::Integer(10)),
+            ]
+        );
+        assert_eq!(prog.get_var("nope"), None);
+        assert_eq!(prog.get_var("clamp.n"), None);
+    }
+
+    #[test]
+    fn inputs() {
+        let src = r#"
+    struct Env { name: string, replicas: int }
+    input region: string;
+    input count: int;
+    input env: Env;
+
+    size := "s-1vcpu";
+    if region == "syd1" {
+        size = "s-4vcpu";
+    }
+    total := count * env.replicas;
+    name := env.name + "-" + region;"#;
+
+        let mut prog = compile(src).unwrap();
+        prog.set_input("region", DataVal::String("syd1".into()))
+            .unwrap();
+        prog.set_input("count", DataVal::Integer(3)).unwrap();
+        let env = prog
+            .struct_val("Env", &[("replicas", DataVal::Integer(2))])
+            .unwrap();
+        prog.set_input("env", env).unwrap();
+        prog.execute().unwrap();
+        assert_eq!(
+            prog.get_var("size"),
+            Some(&DataVal::String("s-4vcpu".into()))
+        );
+        assert_eq!(prog.get_var("total"), Some(&DataVal::Integer(6)));
+        assert_eq!(prog.get_var("name"), Some(&DataVal::String("-syd1".into())));
+
+        let mut par = parser::Parser::new(lexer::Lexer::from_str("if true {\n input x: int;\n}"));
+        assert_eq!(
+            par.program().err().unwrap().to_string(),
+            "line 2: syntax error: inputs must be declared at the top level"
+        );
+
+        // Names the program doesn't have are errors, rather than ignored
+        let mut prog = compile(src).unwrap();
+        for (err, msg) in [
+            (
+                prog.set_input("regoin", DataVal::String("syd1".into())),
+                "input error: the program has no input regoin",
+            ),
+            (
+                prog.struct_val("Envs", &[]).map(drop),
+                "input error: unknown struct Envs",
+            ),
+            (
+                prog.struct_val("Env", &[("replica", DataVal::Integer(2))])
+                    .map(drop),
+                "input error: struct Env has no field replica",
+            ),
+        ] {
+            let err = err.unwrap_err();
+            assert_eq!(err.kind, stac::ExecErrorKind::Input);
+            assert_eq!(err.message, msg);
+        }
+    }
+
+    #[test]
+    fn mistyped_input() {
+        let mut prog = compile("input region: string;\ninput count: int;").unwrap();
+        prog.set_input("region", DataVal::String("syd1".into()))
+            .unwrap();
+        prog.set_input("count", DataVal::String("3".into()))
+            .unwrap();
+        let err = prog.execute().unwrap_err();
+        assert_eq!(err.kind, stac::ExecErrorKind::Input);
+        assert_eq!(
+            err.message,
+            r#"input error: input count must be int, found "3""#
+        );
+    }
+
+    #[test]
+    fn native_conversions() {
+        assert_eq!(DataVal::from(3), DataVal::Integer(3));
+        assert_eq!(DataVal::from("web"), DataVal::String("web".into()));
+        assert_eq!(i64::try_from(DataVal::Integer(3)), Ok(3));
+        assert_eq!(
+            String::try_from(DataVal::Integer(3))
+                .err()
+                .unwrap()
+                .to_string(),
+            "conversion error: expected string, found 3"
+        );
+
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct DropletNetworking {
+            v4: Vec<String>,
+        }
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct CreateDropletRequest {
+            name: String,
+            size: i64,
+            networks: DropletNetworking,
+        }
+
+        // Fields are matched by name, whatever order they're declared in
+        let prog = compile(
+            "
+    struct CreateDropletRequest { networks: DropletNetworking, size: int, name: string }
+    struct DropletNetworking { v4: []string }",
+        )
+        .unwrap();
+        let typ = DataType::Struct("CreateDropletRequest".into());
+        let req = CreateDropletRequest {
+            name: "web".into(),
+            size: 2,
+            networks: DropletNetworking {
+                v4: vec!["10.0.0.2".into()],
+            },
+        };
+
+        let val = DataVal::from_serde(&req, &typ, &prog.user_structs).unwrap();
+        assert_eq!(
+            val,
+            DataVal::Compound(vec![
+                DataVal::Compound(vec![DataVal::Compound(vec!["10.0.0.2".into()])]),
+                2.into(),
+                "web".into(),
+            ])
+        );
+        let back: CreateDropletRequest = val.to_serde(&typ, &prog.user_structs).unwrap();
+        assert_eq!(back, req);
+
+        let err = DataVal::from(1)
+            .to_serde::<CreateDropletRequest>(&typ, &prog.user_structs)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "conversion error: expected CreateDropletRequest, found 1"
+        );
+    }
+
+    #[test]
+    fn run_with_providers() {
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createDroplet"]}"#),
+            ("/createDroplet", "[7]"),
+        ]);
+        let report = run(
+            r#"
+    func extern createDroplet(name: string) (int)
+    id := createDroplet("web");
+    next := id + 1;"#,
+            &[&addr],
+        )
+        .unwrap();
+        assert_eq!(
+            report.variables,
+            HashMap::from([
+                ("id".to_string(), DataVal::Integer(7)),
+                ("next".to_string(), DataVal::Integer(8)),
+            ])
+        );
+        assert_eq!(report.summary.extern_call_count("createDroplet"), 1);
+        assert_eq!(received.lock().unwrap().len(), 2);
+
+        let err = run("x := ;", &[]).err().unwrap();
+        assert_eq!(err.to_string(), "line 1: syntax error: unexpected C(';')");
+    }
+
+    #[test]
+    fn imports() {
+        let files = HashMap::from([
+            (
+                "droplets.dmcl",
+                "
+    import \"networking.dmcl\";
+
+    struct CreateDropletRequest { name: string, networks: DropletNetworking }
+    func extern createDroplet(req: CreateDropletRequest) (int)",
+            ),
+            (
+                "networking.dmcl",
+                "struct DropletNetworking { v4: []string }",
+            ),
+            ("a.dmcl", "import \"b.dmcl\";"),
+            ("b.dmcl", "\nimport \"a.dmcl\";"),
+            (
+                "dup.dmcl",
+                "import \"droplets.dmcl\";\n\nstruct DropletNetworking { v6: []string }",
+            ),
+        ]);
+        let loader = move || -> Box<parser::Loader> {
+            let files = files.clone();
+            return Box::new(move |path| match files.get(path) {
+                Some(src) => Ok(src.to_string()),
+                None => Err("no such file".into()),
+            });
+        };
+
+        // Files imported more than once are only compiled once
+        let l = lexer::Lexer::from_str(
+            r#"
+    import "droplets.dmcl";
+    import "networking.dmcl";
+
+    d := createDroplet(CreateDropletRequest {
+        name: "web",
+        networks: DropletNetworking { v4: ["10.0.0.2"] }
+    });"#,
+        );
+        let mut par = parser::Parser::with_loader(l, loader());
+        let prog = par.program().unwrap();
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createDroplet"]}"#),
+            ("/createDroplet", "[1]"),
+        ]);
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.execute().unwrap();
+        assert_eq!(prog.variables[0], DataVal::Integer(1));
+        let received = received.lock().unwrap();
+        assert!(received
+            .last()
+            .unwrap()
+            .body
+            .contains(r#""networks":{"v4":["10.0.0.2"]}"#));
+
+        for (src, msg) in [
+            (
+                r#"import "a.dmcl";"#,
+                "line 1: in a.dmcl: line 1: in b.dmcl: line 2: import error: import cycle: a.dmcl -> b.dmcl -> a.dmcl",
+            ),
+            (
+                r#"import "dup.dmcl";"#,
+                "line 1: in dup.dmcl: line 3: duplicate definition: struct DropletNetworking is already defined at networking.dmcl line 1",
+            ),
+            (
+                "import \"networking.dmcl\";\nstruct DropletNetworking { v6: []string }",
+                "line 2: duplicate definition: struct DropletNetworking is already defined at networking.dmcl line 1",
+            ),
+            (
+                r#"import "missing.dmcl";"#,
+                "line 1: import error: cannot import missing.dmcl: no such file",
+            ),
+        ] {
+            let mut par = parser::Parser::with_loader(lexer::Lexer::from_str(src), loader());
+            assert_eq!(par.program().err().unwrap().to_string(), msg);
+        }
+
+        let mut par = parser::Parser::new(lexer::Lexer::from_str(r#"import "a.dmcl";"#));
+        assert_eq!(
+            par.program().err().unwrap().to_string(),
+            "line 1: import error: cannot import a.dmcl, as the parser has no loader"
+        );
+    }
+
+    #[test]
+    fn array_types() {
+        let mut prog = compile(
+            r#"
+    struct Net { ip: string }
+    struct Server { tags: []string, grid: [][]int, nets: []Net }
+
+    func extern createServers(names: []string) ([]int)
+    func transpose(g: [][]int) ([][]int) {
+        return [[g[0][0], g[1][0]], [g[0][1], g[1][1]]];
+    }
+
+    s := Server {
+        tags: ["a", "b"],
+        grid: transpose([[1, 2], [3, 4]]),
+        nets: [Net { ip: "10.0.0.1" }]
+    };"#,
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        let grid = DataType::Array(Box::new(DataType::Array(Box::new(DataType::Integer))));
+        assert_eq!(prog.user_structs["Server"].types[1], grid);
+        assert_eq!(prog.user_functions["transpose"].params, vec![grid.clone()]);
+        assert_eq!(prog.user_functions["transpose"].returns, vec![grid]);
+        assert_eq!(
+            prog.user_functions["createServers"].params,
+            vec![DataType::Array(Box::new(DataType::String))]
+        );
+
+        let json = serde_json::to_string(&provider::TypeAndVal {
+            // The function's parameter is variable 0
+            val: prog.variables[1].clone(),
+            typ: DataType::Struct("Server".into()),
+            user_structs: &prog.user_structs,
+            compat: None,
+            path: String::new(),
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            r#"{"tags":["a","b"],"grid":[[1,3],[2,4]],"nets":[{"ip":"10.0.0.1"}]}"#
+        );
+    }
+
+    #[test]
+    fn null_fields() {
+        let mut prog = compile(
+            r#"
+    struct Droplet { name: string, vpc_uuid: string, size: int }
+    func extern createDroplet(name: string) (Droplet)
+    func extern tagDroplet(d: Droplet) (int)
+
+    d := createDroplet("web");
+    vpc := "default";
+    if !isNull(d.vpc_uuid) {
+        vpc = d.vpc_uuid;
+    }
+    named := d.name != null;
+    sized := d.size == null;
+    t := tagDroplet(d);
+    label := "size ${d.size}";"#,
+        )
+        .unwrap();
+
+        // vpc_uuid is null and size is left out
+        let (addr, received) = mock_provider(vec![
+            (
+                "/provider_schema",
+                r#"{"functions": ["createDroplet", "tagDroplet"]}"#,
+            ),
+            ("/createDroplet", r#"[{"name": "web", "vpc_uuid": null}]"#),
+            ("/tagDroplet", "[1]"),
+        ]);
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(
+            prog.get_var("d"),
+            Some(&DataVal::Compound(vec![
+                DataVal::String("web".into()),
+                DataVal::Null,
+                DataVal::Null
+            ]))
+        );
+        assert_eq!(
+            prog.get_var("vpc"),
+            Some(&DataVal::String("default".into()))
+        );
+        assert_eq!(prog.get_var("named"), Some(&DataVal::Bool(true)));
+        assert_eq!(prog.get_var("sized"), Some(&DataVal::Bool(true)));
+        assert_eq!(
+            prog.get_var("label"),
+            Some(&DataVal::String("size null".into()))
+        );
+        let received = received.lock().unwrap();
+        assert!(received
+            .last()
+            .unwrap()
+            .body
+            .contains(r#"{"name":"web","vpc_uuid":null,"size":null}"#));
+        drop(received);
+
+        // Arithmetic on null fails rather than panicking
+        let mut prog = compile(
+            r#"
+    struct Droplet { name: string, size: int }
+    func extern createDroplet(name: string) (Droplet)
+    d := createDroplet("web");
+    s := d.size + 1;"#,
+        )
+        .unwrap();
+        let (addr, _) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createDroplet"]}"#),
+            ("/createDroplet", r#"[{"name": "web", "size": null}]"#),
+        ]);
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        let err = prog.execute().unwrap_err();
+        assert_eq!(err.kind, stac::ExecErrorKind::Type);
+        assert_eq!(err.message, "cannot use arithmetic on null and 1");
+
+        let mut par = parser::Parser::new(lexer::Lexer::from_str("x := null;"));
+        assert_eq!(
+            par.program().err().unwrap().to_string(),
+            "line 1: syntax error: null can only be compared against, with == or !="
+        );
+    }
+
+    #[test]
+    fn casts() {
+        let mut prog = compile(
+            r#"
+    func extern pending() (int)
+    count := 5;
+    cpuFraction := 0.6;
+    half := float(count) / 2.0f;
+    cores := int(cpuFraction * 4.0f);
+    negative := int(-2.9);
+    same := int(7) + 1;
+    port := int("8080");
+    ratio := float("0.25");
+    w := float(pending());
+    "#,
+        )
+        .unwrap();
+        prog.external_functions.insert(
+            "pending".into(),
+            Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])),
+        );
+        prog.execute().unwrap();
+        assert_optimizes(&prog);
+        assert_eq!(prog.get_var("half"), Some(&DataVal::Float(2.5)));
+        assert_eq!(prog.get_var("cores"), Some(&DataVal::Integer(2)));
+        assert_eq!(prog.get_var("negative"), Some(&DataVal::Integer(-2)));
+        assert_eq!(prog.get_var("same"), Some(&DataVal::Integer(8)));
+        assert_eq!(prog.get_var("port"), Some(&DataVal::Integer(8080)));
+        assert_eq!(prog.get_var("ratio"), Some(&DataVal::Float(0.25)));
+        assert_eq!(prog.get_var("w"), Some(&DataVal::Waiting));
+
+        for (src, msg) in [
+            ("x := int(1e19);", "cannot convert 1e19 to int"),
+            ("x := int(-1e19);", "cannot convert -1e19 to int"),
+            (
+                "x := float(9007199254740993);",
+                "cannot convert 9007199254740993 to float",
+            ),
+            (r#"x := int("8080a");"#, r#"cannot convert "8080a" to int"#),
+            (r#"x := int("2.5");"#, r#"cannot convert "2.5" to int"#),
+            (r#"x := float("inf");"#, r#"cannot convert "inf" to float"#),
+            (r#"x := float(" 3");"#, r#"cannot convert " 3" to float"#),
+        ] {
+            let mut prog = compile(src).unwrap();
+            prog.optimize();
+            let err = prog.execute().unwrap_err();
+            assert_eq!(err.kind, stac::ExecErrorKind::Conversion, "{}", src);
+            assert_eq!(err.message, msg);
+        }
+
+        for (src, msg) in [
+            ("x := int(true);", "type error: cannot convert bool to int"),
+            (
+                "x := float(1, 2);",
+                "type error: float expects 1 argument, found 2",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(
+                par.program().err().unwrap().to_string(),
+                format!("line 1: {}", msg)
+            );
+        }
+    }
+
+    #[test]
+    fn str() {
+        let mut prog = compile(
+            r#"
+    struct Network { ip: string, ports: []int }
+    struct Droplet { name: string, size: float, public: bool, net: Network }
+    func extern createDroplet(name: string) (Droplet)
+    func extern pending() (int)
+
+    n := 3;
+    count := "count=" + str(n);
+    size := str(2.5) + "/" + str(1.0) + "/" + str(false);
+    same := str("web");
+    ports := str([80, 443]);
+    tags := str({"env": "prod"});
+    d := createDroplet("web");
+    droplet := str(d);
+    w := str([1, pending()]);"#,
+        )
+        .unwrap();
+
+        let (addr, _) = mock_provider(vec![
+            (
+                "/provider_schema",
+                r#"{"functions": ["createDroplet", "pending"]}"#,
+            ),
+            (
+                "/createDroplet",
+                r#"[{"name": "web-1", "size": 2.0, "public": true, "net": {"ip": "10.0.0.2", "ports": [22]}}]"#,
+            ),
+            ("/pending", r#"[{"$waiting": true}]"#),
+        ]);
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.execute().unwrap();
+
+        let var = |name| prog.get_var(name).unwrap().to_string();
+        assert_eq!(var("count"), r#""count=3""#);
+        assert_eq!(var("size"), r#""2.5/1/false""#);
+        assert_eq!(var("same"), r#""web""#);
+        assert_eq!(var("ports"), r#""[80, 443]""#);
+        assert_eq!(var("tags"), r#""{\"env\": \"prod\"}""#);
+        assert_eq!(
+            prog.get_var("droplet"),
+            Some(&DataVal::String(
+                r#"Droplet { name: "web-1", size: 2.0, public: true, net: Network { ip: "10.0.0.2", ports: [22] } }"#.into()
+            ))
+        );
+        assert_eq!(prog.get_var("w"), Some(&DataVal::Waiting));
+    }
+
+    #[test]
+    fn maps() {
+        let mut prog = compile(
+            r#"
+    struct Bucket { name: string, tags: map[string]string }
+    func extern createBucket(b: Bucket) (int)
+    func withOwner(tags: map[string]string, owner: string) (map[string]string) {
+        tags["owner"] = owner;
+        return tags;
+    }
+
+    env := "prod";
+    tags := {"env": env, "team": "infra"};
+    tags["team"] = "platform";
+    tags = withOwner(tags, "ops");
+    team := tags["team"];
+    hasOwner := has(tags, "owner");
+    hasCost := has(tags, "cost");
+
+    ports := map[string][]int{};
+    ports["web"] = [80, 443];
+    ports["web"][1] = 8443;
+    sizes := {"web": 2, "db": 4};
+    same := sizes == {"db": 4, "web": 2};
+
+    b := createBucket(Bucket { name: "assets", tags: tags });"#,
+        )
+        .unwrap();
+
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createBucket"]}"#),
+            ("/createBucket", "[1]"),
+        ]);
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.execute().unwrap();
+
+        let tags = prog.get_var("tags").unwrap().as_map().unwrap();
+        let keys: Vec<&str> = tags.keys().map(|k| k.as_str()).collect();
+        assert_eq!(keys, vec!["env", "owner", "team"]);
+        assert_eq!(
+            prog.get_var("team"),
+            Some(&DataVal::String("platform".into()))
+        );
+        assert_eq!(prog.get_var("hasOwner"), Some(&DataVal::Bool(true)));
+        assert_eq!(prog.get_var("hasCost"), Some(&DataVal::Bool(false)));
+        assert_eq!(
+            prog.get_var("ports").unwrap().to_string(),
+            r#"{"web": [80, 8443]}"#
+        );
+        assert_eq!(prog.get_var("same"), Some(&DataVal::Bool(true)));
+
+        // Keys are serialized in order
+        let received = received.lock().unwrap();
+        assert!(received.last().unwrap().body.contains(
+            r#"{"name":"assets","tags":{"env":"prod","owner":"ops","team":"platform"}}"#
+        ));
+        drop(received);
+
+        let mut prog = compile(r#"m := {"a": 1}; x := m["b"];"#).unwrap();
+        let err = prog.execute().unwrap_err();
+        assert_eq!(
+            err.kind,
+            stac::ExecErrorKind::MissingKey { key: "b".into() }
+        );
+        assert_eq!(err.message, r#"missing key: the map has no key "b""#);
+
+        for (src, msg) in [
+            (
+                "m := {};",
+                "type error: an empty map needs a value type, eg. map[string]int{}",
+            ),
+            (
+                r#"m := {"a": 1, "b": "c"};"#,
+                "type error: map value 1 must be int, found string",
+            ),
+            (
+                r#"m := {"a": 1}; x := m[0];"#,
+                "type error: map keys must be strings, found int",
+            ),
+            (
+                "m := map[int]int{};",
+                "type error: map keys must be strings, found int",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(
+                par.program().err().unwrap().to_string(),
+                format!("line 1: {}", msg)
+            );
+        }
+    }
+
+    #[test]
+    fn empty_arrays() {
+        let l = lexer::Lexer::from_str(
+            r#"
+    struct CreateDropletRequest { name: string, ssh_keys: []string }
+    func extern createDroplet(req: CreateDropletRequest) (int)
+
+    keys := []string{};
+    ports := []int{80, 443};
+    grid := [][]int{};
+    d := createDroplet(CreateDropletRequest { name: "web", ssh_keys: keys });"#,
+        );
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createDroplet"]}"#),
+            ("/createDroplet", "[1]"),
+        ]);
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(prog.variables[0], DataVal::Compound(vec![]));
+        assert_eq!(
+            prog.variables[1],
+            DataVal::Compound(vec![DataVal::Integer(80), DataVal::Integer(443)])
+        );
+        let received = received.lock().unwrap();
+        assert!(received.last().unwrap().body.contains(r#""ssh_keys":[]"#));
+
+        for (src, msg) in [
+            (
+                "p := [];",
+                "type error: an empty array needs an element type, eg. []string{}",
+            ),
+            (
+                r#"p := [1, "a"];"#,
+                "type error: array element 1 must be int, found string",
+            ),
+            (
+                "p := []string{1};",
+                "type error: array element 0 must be string, found int",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(par.program().err().unwrap().kind.to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn array_builtins() {
+        let mut prog = compile(
+            r#"
+    ports := []int{};
+    i := 0;
+    while (i < 4) {
+        ports = append(ports, 8000 + i);
+        i = i + 1;
+    }
+    n := len(ports);
+    ports = remove(ports, 1);
+    m := len(ports);
+    c := len("héllo");"#,
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(
+            prog.variables[0],
+            DataVal::Compound(vec![
+                DataVal::Integer(8000),
+                DataVal::Integer(8002),
+                DataVal::Integer(8003)
+            ])
+        );
+        assert_eq!(prog.variables[2], DataVal::Integer(4));
+        assert_eq!(prog.variables[3], DataVal::Integer(3));
+        assert_eq!(prog.variables[4], DataVal::Integer(5));
+
+        let prog = run_waiting(
+            r#"
+    func extern pending() (int)
+    keys := make([]int, pending());
+    keys = append(keys, 1);
+    n := len(keys);"#,
+        );
+        assert_eq!(prog.variables[0], DataVal::Waiting);
+        assert_eq!(prog.variables[1], DataVal::Waiting);
+
+        for (src, msg) in [
+            (
+                r#"p := append([]int{}, "a");"#,
+                "type error: cannot append string to an array of int",
+            ),
+            (
+                r#"p := remove([]int{1}, "a");"#,
+                "type error: remove expects an int index",
+            ),
+            (
+                "p := len(1);",
+                "type error: len expects an array or string, found int",
+            ),
+        ] {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(src));
+            assert_eq!(par.program().err().unwrap().kind.to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn fake_provider() {
+        let l = lexer::Lexer::new(
+            r#"
+    struct CreateDropletRequest {
+        name: string,
+        region: string,
+        size: string,
+        image: string,
+        ssh_keys: []string,
+        backups: bool,
+        ipv6: bool,
+        monitoring: bool,
+        tags: []string,
+        user_data: string,
+        volumes: []string,
+        vpc_uuid: string,
+        with_droplet_agent: bool
+    }
+
+    struct DropletNetwork {
+        ip_address: string,
+        netmask: string,
+        gateway: string,
+        type: string
+    }
+
+    struct DropletNetworking {
+        v4: []DropletNetwork,
+        v6: []DropletNetwork
+    }
+
+    struct Droplet {
+        id: int,
+        state: string,
+        name: string,
+        networks: DropletNetworking
+    }
+
+    func extern createDroplet(req: CreateDropletRequest) (Droplet)
+
+    q := createDroplet(CreateDropletRequest {
+        name: "hello-world",
+        region: "syd1",
+        size: "s-1vcpu-512mb-10gb",
+        image: "ubuntu-20-04-x64"
+    });
+    c := q.state;
+    "#
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        print_instructions(&prog.code);
+
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createDroplet"]}"#),
+            ("/createDroplet", r#"[{"$waiting": true}]"#),
+        ]);
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+
+        assert_eq!(prog.variables[1], stac::DataVal::Waiting);
+
+        let received = received.lock().unwrap();
+        let call = received.last().unwrap();
+        assert_eq!(call.path, "/createDroplet");
+        assert!(call.body.contains(r#""name":"hello-world""#));
+
+        // Trace context is only propagated with the otel feature
+        #[cfg(not(feature = "otel"))]
+        assert!(!call.headers.iter().any(|h| h.0 == "traceparent"));
+    }
+
+    #[test]
+    fn plan() {
+        let mut prog = compile(
+            r#"
+    struct CreateDropletRequest {
+        name: string,
+        region: string,
+        size: string,
+        image: string,
+        ssh_keys: []string,
+        backups: bool,
+        ipv6: bool,
+        monitoring: bool,
+        tags: []string,
+        user_data: string,
+        volumes: []string,
+        vpc_uuid: string,
+        with_droplet_agent: bool
+    }
+
+    struct DropletNetwork {
+        ip_address: string,
+        netmask: string,
+        gateway: string,
+        type: string
+    }
+
+    struct DropletNetworking {
+        v4: []DropletNetwork,
+        v6: []DropletNetwork
+    }
+
+    struct Droplet {
+        id: int,
+        state: string,
+        name: string,
+        networks: DropletNetworking
+    }
+
+    func extern createDroplet(req: CreateDropletRequest) (Droplet)
+
+    q := createDroplet(CreateDropletRequest {
+        name: "hello-world",
+        region: "syd1",
+        size: "s-1vcpu-512mb-10gb",
+        image: "ubuntu-20-04-x64"
+    });
+    c := q.state;
+    "#,
+        )
+        .unwrap();
+
+        // No provider is needed, as nothing is called
+        let plan = prog.plan().unwrap();
+        assert_eq!(plan.calls.len(), 1);
+        let call = &plan.calls[0];
+        assert_eq!(call.function, "createDroplet");
+        assert_eq!(
+            call.param_types,
+            vec![DataType::Struct("CreateDropletRequest".into())]
+        );
+        let req = call.params[0].display_typed(&call.param_types[0], &prog.user_structs);
+        assert_eq!(
+            req,
+            r#"CreateDropletRequest { name: "hello-world", region: "syd1", size: "s-1vcpu-512mb-10gb", image: "ubuntu-20-04-x64", ssh_keys: [], backups: false, ipv6: false, monitoring: false, tags: [], user_data: "", volumes: [], vpc_uuid: "", with_droplet_agent: false }"#
+        );
+        assert_eq!(prog.get_var("c"), Some(&DataVal::Waiting));
+        assert_eq!(
+            plan.to_string(),
+            format!("would call createDroplet with {}\n", req)
+        );
+
+        let json = serde_json::to_string(&plan).unwrap();
+        let plan: plan::ExecutionPlan = serde_json::from_str(&json).unwrap();
+        assert_eq!(plan.calls[0].params, call.params.clone());
+        assert!(plan.render().contains(r#"region             = "syd1""#));
+
+        // Execution still makes the call
+        let err = prog.execute().unwrap_err();
+        assert_eq!(err.message, "unknown external function: createDroplet");
+    }
+
+    #[test]
+    fn plan_possible_calls() {
+        let mut prog = compile(
+            "
+    func extern createA() (int)
+    func extern createB(a: int) (int)
+
+    a := createA();
+    if a == 1 {
+        b := createB(a);
+    }
+    ",
+        )
+        .unwrap();
+
+        // The branch depends on createA, so createB may or may not be called
+        let plan = prog.plan().unwrap();
+        assert_eq!(plan.calls.len(), 1);
+        assert_eq!(plan.possible_calls.len(), 1);
+        let possible = &plan.possible_calls[0];
+        assert_eq!(possible.function, "createB");
+        assert_eq!(possible.id, prog.report.deferred_calls[0].id);
+        assert_eq!(possible.depends_on, prog.report.deferred_calls[0].origin);
+
+        assert_eq!(
+            plan.to_string(),
+            format!(
+                "would call createA\nmay call createB (depends on if at {:?})\n",
+                possible.depends_on
+            )
+        );
+        let out = plan.render();
+        assert!(out.contains(&format!(
+            "# {}\n? createB(...) depends on if at {:?}\n",
+            possible.id, possible.depends_on
+        )));
+        assert!(out.ends_with("Plan: 1 to create, 1 that may be created.\n"));
+
+        // They're kept when the plan is saved
+        let json = serde_json::to_string(&plan).unwrap();
+        let saved: plan::ExecutionPlan = serde_json::from_str(&json).unwrap();
+        assert_eq!(saved.possible_calls, plan.possible_calls);
+    }
+
+    #[test]
+    fn state() {
+        let path = std::env::temp_dir().join(format!("dmcl-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let src = r#"
+    func extern createBucket(name: string) (int)
+    func extern pending() (int)
+
+    b := createBucket("logs");
+    n := pending();
+    "#;
+
+        let pending = Arc::new(AtomicI64::new(0));
+        let mut hits = Vec::new();
+        for _ in 0..2 {
+            let mut prog = compile(src).unwrap();
+            let (addr, received) = mock_provider(vec![
+                ("/provider_schema", r#"{"functions": ["createBucket"]}"#),
+                ("/createBucket", "[4]"),
+            ]);
+            prog.add_http_provider(addr, ProviderConfig::default())
+                .unwrap();
+            let p = pending.clone();
+            prog.external_functions.insert(
+                "pending".into(),
+                Box::new(move |_, _, _, _, _, _| {
+                    p.fetch_add(1, Ordering::SeqCst);
+                    return Ok(vec![DataVal::Waiting]);
+                }),
+            );
+            prog.with_state(Box::new(state::JsonFileStore::open(&path).unwrap()));
+            prog.execute().unwrap();
+
+            assert_eq!(prog.get_var("b"), Some(&DataVal::Integer(4)));
+            assert_eq!(prog.get_var("n"), Some(&DataVal::Waiting));
+            let created = received
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|r| r.path == "/createBucket")
+                .count();
+            hits.push(created);
+        }
+
+        // The bucket is only created once, but the waiting call is made again
+        assert_eq!(hits, vec![1, 0]);
+        assert_eq!(pending.load(Ordering::SeqCst), 2);
+
+        let store = state::JsonFileStore::open(&path).unwrap();
+        let all = store.all();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].1, vec![DataVal::Integer(4)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn state_diff() {
+        let path = std::env::temp_dir().join(format!("dmcl-diff-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let src = |size: &str, ip: &str, rest: &str| {
+            return format!(
+                r#"
+    struct Network {{
+        ip_address: string,
+        netmask: string
+    }}
+
+    struct CreateDropletRequest {{
+        name: string,
+        size: string,
+        tags: []string,
+        networks: []Network
+    }}
+
+    func extern createDroplet(req: CreateDropletRequest) (int)
+    func extern createBucket(name: string) (int)
+
+    d := createDroplet(CreateDropletRequest {{
+        name: "web",
+        size: "{}",
+        tags: ["prod"],
+        networks: [Network {{ ip_address: "{}" }}]
+    }});
+    {}
+    "#,
+                size, ip, rest
+            );
+        };
+        let plan = |src: String| {
+            let mut prog = compile(&src).unwrap();
+            prog.with_state(Box::new(state::JsonFileStore::open(&path).unwrap()));
+            return prog.plan().unwrap();
+        };
+
+        let mut prog = compile(&src("s-1vcpu", "10.0.0.2", "")).unwrap();
+        let (addr, _) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createDroplet"]}"#),
+            ("/createDroplet", "[1]"),
+        ]);
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.with_state(Box::new(state::JsonFileStore::open(&path).unwrap()));
+        prog.execute().unwrap();
+        let snapshot = state::JsonFileStore::open(&path).unwrap().snapshot();
+        assert_eq!(snapshot.calls.len(), 1);
+
+        // Only the field that was modified is named
+        let d = diff(&snapshot, &plan(src("s-2vcpu", "10.0.0.2", "")));
+        assert_eq!(d.calls.len(), 1);
+        assert_eq!(
+            d.calls[0].change,
+            diff::CallChange::Changed {
+                fields: vec![diff::FieldChange {
+                    path: "size".into(),
+                    old: Some(r#""s-1vcpu""#.into()),
+                    new: Some(r#""s-2vcpu""#.into()),
+                }]
+            }
+        );
+        assert_eq!(
+            d.to_string(),
+            format!(
+                "~ createDroplet {}\n    size: \"s-1vcpu\" -> \"s-2vcpu\"\nDiff: 0 to add, 1 to change, 0 to remove.\n",
+                d.calls[0].id
+            )
+        );
+        let json = serde_json::to_string(&d).unwrap();
+        assert_eq!(serde_json::from_str::<diff::StateDiff>(&json).unwrap(), d);
+
+        // Nested structs are recursed into
+        let d = diff(&snapshot, &plan(src("s-1vcpu", "10.0.0.3", "")));
+        match &d.calls[0].change {
+            diff::CallChange::Changed { fields } => {
+                assert_eq!(fields.len(), 1);
+                assert_eq!(fields[0].path, "networks[0].ip_address");
+            }
+            change => panic!("expected a change, found {:?}", change),
+        }
+
+        let d = diff(
+            &snapshot,
+            &plan(src("s-1vcpu", "10.0.0.2", r#"b := createBucket("logs");"#)),
+        );
+        assert_eq!(d.calls[0].change, diff::CallChange::Unchanged);
+        assert_eq!(d.calls[1].change, diff::CallChange::Added);
+        assert_eq!(d.calls[1].function, "createBucket");
+        assert!(!d.is_empty());
+
+        let d = diff(&snapshot, &plan("a := 1;".into()));
+        assert_eq!(d.calls[0].change, diff::CallChange::Removed);
+        assert_eq!(d.calls[0].function, "createDroplet");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dependency_graph() {
+        let mut prog = compile(
+            r#"
+    func extern createA() (int)
+    func extern createB(a: int) (int)
+    func extern createC(name: string) (int)
+
+    id := createA();
+    b := createB(id);
+    n := "c-${b + 1}";
+    c := createC(n);
+    "#,
+        )
+        .unwrap();
+        prog.plan().unwrap();
+
+        // The name of C is derived from B, though it's computed from it first
+        let graph = prog.dependency_graph();
+        assert_eq!(graph.len(), 2);
+        let (a, b) = graph[0];
+        assert_eq!(graph[1].0, b);
+        let c = graph[1].1;
+        assert_eq!(
+            prog.dependency_dot(),
+            format!(
+                "digraph dependencies {{\n    \"{a}\" [label=\"createA {a}\"];\n    \"{b}\" [label=\"createB {b}\"];\n    \"{c}\" [label=\"createC {c}\"];\n    \"{a}\" -> \"{b}\";\n    \"{b}\" -> \"{c}\";\n}}\n"
+            )
+        );
+
+        // Execution finds the same edges as planning
+        let mut prog = compile(
+            r#"
+    func extern createA() (int)
+    func extern createB(a: int) (int)
+
+    id := createA();
+    b := createB(id);
+    "#,
+        )
+        .unwrap();
+        prog.register_extern("createA", || 1);
+        prog.register_extern("createB", |a: i64| a + 1);
+        prog.execute().unwrap();
+        assert_eq!(prog.dependency_graph().len(), 1);
+
+        let mut prog = compile(
+            r#"
+    func extern createA() (int)
+    func extern createB(a: int) (int)
+
+    a := createA();
+    b := createB(2);
+    "#,
+        )
+        .unwrap();
+        prog.plan().unwrap();
+        assert_eq!(prog.dependency_graph(), vec![]);
+    }
+
+    #[test]
+    fn typed_schema() {
+        let schema = r#"{"functions": [
+            {
+                "name": "createBucket",
+                "params": [{"struct": "Bucket"}],
+                "returns": [{"struct": "BucketInfo"}],
+                "structs": {
+                    "Bucket": [
+                        {"name": "name", "type": "string"},
+                        {"name": "tags", "type": {"array": "string"}}
+                    ],
+                    "BucketInfo": [{"name": "id", "type": "int"}]
+                }
+            },
+            "deleteBucket"
+        ]}"#;
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", schema),
+            ("/createBucket", r#"[{"id": 4}]"#),
+        ]);
+
+        // Neither the function nor its structs are declared
+        let report = run(
+            r#"
+    b := createBucket(Bucket { name: "logs", tags: ["prod"] });
+    id := b.id;
+    "#,
+            &[&addr],
+        )
+        .unwrap();
+        assert_eq!(report.variables["id"], DataVal::Integer(4));
+        let received = received.lock().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&received[1].body).unwrap();
+        assert_eq!(
+            body["params"][0],
+            serde_json::json!({"name": "logs", "tags": ["prod"]})
+        );
+        drop(received);
+
+        // Declarations must agree with the schema, in either order
+        let err = run(
+            r#"
+    func extern createBucket(name: string) (int)
+    b := createBucket("logs");
+    "#,
+            &[&addr],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: schema drift: createBucket is declared as (string) (int), but the provider's schema has (Bucket) (BucketInfo)"
+        );
+
+        let err = run(
+            "struct BucketInfo { id: string }
+a := 1;",
+            &[&addr],
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse(parser::ParseError {
+                kind: parser::ParseErrorKind::SchemaDrift(_),
+                ..
+            })
+        ));
+
+        // Matching declarations are fine, even with their fields reordered
+        let mut prog = compile(
+            r#"
+    struct Bucket { tags: []string, name: string }
+    struct BucketInfo { id: int }
+    func extern createBucket(b: Bucket) (BucketInfo)
+    func extern deleteBucket(id: int) ()
+    "#,
+        )
+        .unwrap();
+        prog.add_http_provider(addr.clone(), ProviderConfig::default())
+            .unwrap();
+
+        let mut prog = compile("func extern createBucket(b: int) (int)").unwrap();
+        let err = prog
+            .add_http_provider(addr.clone(), ProviderConfig::default())
+            .unwrap_err();
+        assert!(matches!(err, provider::ProviderError::Drift { .. }));
+
+        let (addr, _) = mock_provider(vec![(
+            "/provider_schema",
+            r#"{"functions": [{"name": "f", "params": [{"struct": "Missing"}], "returns": []}]}"#,
+        )]);
+        let err = compile("a := 1;")
+            .unwrap()
+            .add_http_provider(addr.clone(), ProviderConfig::default())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            provider::ProviderError::UnknownStruct {
+                url: format!("{}/provider_schema", addr),
+                name: "Missing".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn provider_retries() {
+        let src = r#"
+    func extern createBucket(name: string) (int)
+    b := createBucket("logs");
+    "#;
+        let (addr, received) = mock_provider_with(vec![
+            (
+                "/provider_schema",
+                "200 OK",
+                r#"{"functions": ["createBucket"]}"#,
+            ),
+            ("/createBucket", "503 Service Unavailable", "starting"),
+            (
+                "/createBucket",
+                "429 Too Many Requests\r\nRetry-After: 0",
+                "slow down",
+            ),
+            ("/createBucket", "200 OK", "[4]"),
+        ]);
+        let mut prog = compile(src).unwrap();
+        let config = ProviderConfig {
+            backoff: Duration::from_millis(5),
+            ..Default::default()
+        };
+        prog.add_http_provider(addr, config).unwrap();
+
+        let retries = Arc::new(Mutex::new(vec![]));
+        let r = retries.clone();
+        prog.set_trace(Box::new(move |event| {
+            if let trace::TraceEvent::ExternRetry { retry, .. } = event {
+                r.lock().unwrap().push((retry.attempt, retry.delay));
+            }
+        }));
+        prog.execute().unwrap();
+        assert_eq!(prog.get_var("b"), Some(&DataVal::Integer(4)));
+
+        let attempts = received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.path == "/createBucket")
+            .count();
+        assert_eq!(attempts, 3);
+
+        // The second retry waits as long as the provider asked, not the backoff
+        assert_eq!(
+            *retries.lock().unwrap(),
+            vec![(1, Duration::from_millis(5)), (2, Duration::ZERO)]
+        );
+
+        // Failures are surfaced once the retries run out
+        let (addr, received) = mock_provider_with(vec![
+            (
+                "/provider_schema",
+                "200 OK",
+                r#"{"functions": ["createBucket"]}"#,
+            ),
+            ("/createBucket", "502 Bad Gateway", "down"),
+        ]);
+        let mut prog = compile(src).unwrap();
+        let config = ProviderConfig {
+            retries: 1,
+            backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        prog.add_http_provider(addr, config).unwrap();
+        let err = prog.execute().unwrap_err();
+        assert!(err.message.ends_with("responded with status 502: down"));
+        assert_eq!(received.lock().unwrap().len(), 3);
+
+        // The backoff doubles up to the most a retry may wait
+        let (addr, _) = mock_provider_with(vec![
+            (
+                "/provider_schema",
+                "200 OK",
+                r#"{"functions": ["createBucket"]}"#,
+            ),
+            ("/createBucket", "502 Bad Gateway", "down"),
+        ]);
+        let mut prog = compile(src).unwrap();
+        let config = ProviderConfig {
+            backoff: Duration::from_millis(5),
+            max_backoff: Duration::from_millis(8),
+            ..Default::default()
+        };
+        prog.add_http_provider(addr, config).unwrap();
+        let retries = Arc::new(Mutex::new(vec![]));
+        let r = retries.clone();
+        prog.set_trace(Box::new(move |event| {
+            if let trace::TraceEvent::ExternRetry { retry, .. } = event {
+                r.lock().unwrap().push(retry.delay);
+            }
+        }));
+        prog.execute().unwrap_err();
+        assert_eq!(
+            *retries.lock().unwrap(),
+            vec![
+                Duration::from_millis(5),
+                Duration::from_millis(8),
+                Duration::from_millis(8)
+            ]
+        );
+
+        // A provider asking to wait longer than that fails the call instead
+        let (addr, received) = mock_provider_with(vec![
+            (
+                "/provider_schema",
+                "200 OK",
+                r#"{"functions": ["createBucket"]}"#,
+            ),
+            (
+                "/createBucket",
+                "429 Too Many Requests\r\nRetry-After: 86400",
+                "slow down",
+            ),
+        ]);
+        let mut prog = compile(src).unwrap();
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        let start = std::time::Instant::now();
+        let err = prog.execute().unwrap_err();
+        assert!(err
+            .message
+            .ends_with("responded with status 429: slow down"));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn async_provider_retries() {
+        let (addr, received) = mock_provider_with(vec![
+            ("/provider_schema", "503 Service Unavailable", "starting"),
+            (
+                "/provider_schema",
+                "200 OK",
+                r#"{"functions": ["createBucket"]}"#,
+            ),
+            ("/createBucket", "503 Service Unavailable", "starting"),
+            (
+                "/createBucket",
+                "429 Too Many Requests\r\nRetry-After: 0",
+                "slow down",
+            ),
+            ("/createBucket", "200 OK", "[4]"),
+        ]);
+        let mut prog = compile(
+            r#"
+    func extern createBucket(name: string) (int)
+    b := createBucket("logs");
+    "#,
+        )
+        .unwrap();
+        let config = ProviderConfig {
+            backoff: Duration::from_millis(5),
+            ..Default::default()
+        };
+        prog.add_http_provider_async(addr, config).await.unwrap();
+
+        // Retries are traced the same way as those of blocking providers
+        let retries = Arc::new(Mutex::new(vec![]));
+        let r = retries.clone();
+        prog.set_trace(Box::new(move |event| {
+            if let trace::TraceEvent::ExternRetry { retry, .. } = event {
+                r.lock().unwrap().push((retry.attempt, retry.delay));
+            }
+        }));
+        prog.execute_async().await.unwrap();
+        assert_eq!(prog.get_var("b"), Some(&DataVal::Integer(4)));
+        assert_eq!(received.lock().unwrap().len(), 5);
+        assert_eq!(
+            *retries.lock().unwrap(),
+            vec![(1, Duration::from_millis(5)), (2, Duration::ZERO)]
+        );
+
+        // A provider that doesn't respond in time fails the call
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut prog = compile("a := 1;").unwrap();
+        let config = ProviderConfig {
+            request_timeout: Duration::from_millis(50),
+            retries: 0,
+            ..Default::default()
+        };
+        let err = prog
+            .add_http_provider_async(format!("http://{}", listener.local_addr().unwrap()), config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, provider::ProviderError::Unreachable { .. }));
+        drop(listener);
+    }
+
+    #[test]
+    fn provider_errors() {
+        use provider::ProviderError;
+
+        // Nothing listens on a port once its listener is dropped
+        let addr = format!(
+            "http://{}",
+            TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        );
+        let mut prog = compile("a := 1;").unwrap();
+        let config = ProviderConfig {
+            retries: 0,
+            ..Default::default()
+        };
+        match prog.add_http_provider(addr.clone(), config) {
+            Err(ProviderError::Unreachable { url, .. }) => {
+                assert_eq!(url, format!("{}/provider_schema", addr))
+            }
+            r => panic!("expected the provider to be unreachable, found {:?}", r),
+        }
+
+        let (addr, _) = mock_provider(vec![("/provider_schema", "functions: createBucket")]);
+        let err = prog
+            .add_http_provider(addr, ProviderConfig::default())
+            .unwrap_err();
+        assert!(matches!(err, ProviderError::InvalidJson { .. }));
+        assert!(err.to_string().ends_with(": functions: createBucket"));
+
+        let (addr, _) = mock_provider(vec![("/provider_schema", r#"{"name": "buckets"}"#)]);
+        assert_eq!(
+            prog.add_http_provider(addr.clone(), ProviderConfig::default()),
+            Err(ProviderError::MissingFunctions {
+                url: format!("{}/provider_schema", addr),
+                body: r#"{"name": "buckets"}"#.into(),
+            })
+        );
+
+        let (addr, _) = mock_provider(vec![]);
+        assert!(matches!(
+            prog.add_http_provider(addr, ProviderConfig::default()),
+            Err(ProviderError::Status { status: 404, .. })
+        ));
+
+        // Failed calls stop execution, rather than the process
+        let src = r#"
+    func extern createBucket(name: string) (int)
+    b := createBucket("logs");
+    "#;
+        let (addr, _) = mock_provider_with(vec![
+            (
+                "/provider_schema",
+                "200 OK",
+                r#"{"functions": ["createBucket"]}"#,
+            ),
+            (
+                "/createBucket",
+                "500 Internal Server Error",
+                "bucket quota exceeded",
+            ),
+        ]);
+        let mut prog = compile(src).unwrap();
+        prog.add_http_provider(addr.clone(), ProviderConfig::default())
+            .unwrap();
+        let err = prog.execute().unwrap_err();
+        assert!(matches!(err.kind, stac::ExecErrorKind::Extern { .. }));
+        assert_eq!(
+            err.message,
+            format!(
+                "extern error: createBucket failed: provider error: {}/createBucket responded with status 500: bucket quota exceeded",
+                addr
+            )
+        );
+
+        let (addr, _) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createBucket"]}"#),
+            ("/createBucket", r#"["four"]"#),
+        ]);
+        let mut prog = compile(src).unwrap();
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        let err = prog.execute().unwrap_err();
+        assert!(err
+            .message
+            .contains("returned values that don't match the declared returns"));
+
+        // A call whose params are waiting isn't made, and its returns wait too
+        let (addr, received) = mock_provider(vec![
+            (
+                "/provider_schema",
+                r#"{"functions": ["createBucket", "tagBucket"]}"#,
+            ),
+            ("/createBucket", r#"[{"$waiting": true}]"#),
+            ("/tagBucket", "[1]"),
+        ]);
+        let mut prog = compile(
+            r#"
+    func extern createBucket(name: string) (int)
+    func extern tagBucket(bucket: int, tag: string) (int)
+    b := createBucket("logs");
+    t := tagBucket(b, "prod");
+    "#,
+        )
+        .unwrap();
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.execute().unwrap();
+        assert_eq!(prog.get_var("b"), Some(&DataVal::Waiting));
+        assert_eq!(prog.get_var("t"), Some(&DataVal::Waiting));
+        let received = received.lock().unwrap();
+        assert!(!received.iter().any(|r| r.path == "/tagBucket"));
+        drop(received);
+
+        // Params that don't match their types can't be sent
+        let user_structs = HashMap::new();
+        let rpc = provider::DMCLRPC::new(
+            stac::CallId {
+                block: 0,
+                instr: 0,
+                ordinal: 0,
+            },
+            &[DataType::Integer],
+            vec![DataVal::String("four".into())],
+            &user_structs,
+        );
+        assert_eq!(
+            rpc.encode("http://provider/createBucket"),
+            Err(ProviderError::InvalidParams {
+                url: "http://provider/createBucket".into(),
+                error: r#"expected int, found "four""#.into(),
+            })
+        );
+    }
+
+    #[test]
+    fn chained_access() {
+        let l = lexer::Lexer::new(
+            r#"
+    struct DropletNetwork {
+        ip_address: string,
+        type: string
+    }
+
+    struct DropletNetworking {
+        v4: []DropletNetwork,
+        v6: []DropletNetwork
+    }
+
+    struct Droplet {
+        id: int,
+        ssh_keys: []string,
+        networks: DropletNetworking
+    }
+
+    func extern getDroplet(id: int) (Droplet)
+
+    d := getDroplet(1);
+    ip := d.networks.v4[0].ip_address;
+    key := d.ssh_keys[1];
+    kind := d.networks.v4[1].type + "/" + d.networks.v6[0].type;
+    grid := [[1, 2], [3, 4]];
+    corner := grid[1][1];
+    "#
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+
+        let (addr, _) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["getDroplet"]}"#),
+            (
+                "/getDroplet",
+                r#"[{
+                    "id": 1,
+                    "ssh_keys": ["a", "b"],
+                    "networks": {
+                        "v4": [
+                            {"ip_address": "10.0.0.1", "type": "private"},
+                            {"ip_address": "1.2.3.4", "type": "public"}
+                        ],
+                        "v6": [{"ip_address": "::1", "type": "public"}]
+                    }
+                }]"#,
+            ),
+        ]);
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(
+            prog.variables[1..4],
+            [
+                DataVal::String("10.0.0.1".into()),
+                DataVal::String("b".into()),
+                DataVal::String("public/public".into()),
+            ]
+        );
+        assert_eq!(prog.variables[5], DataVal::Integer(4));
+    }
+
+    #[test]
+    fn field_of_non_struct() {
+        let l = lexer::Lexer::new(
+            r#"
+    struct Droplet { ssh_keys: []string }
+    d := Droplet { ssh_keys: ["a"] };
+    n := d.ssh_keys.len;"#
+                .chars()
+                .collect(),
+        );
+        let mut par = parser::Parser::new(l);
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 4: type error: cannot access field len of non-struct type Array(String)"
+        );
+    }
+
+    #[test]
+    fn nested_index_assignment() {
+        let mut prog = compile(
+            "
+    a := [[1, 2], [3, 4]];
+    a[1][0] = 9;
+    i := 0;
+    a[i][i + 1] = a[1][0] + 1;
+
+    cube := [[[0, 0], [0, 0]], [[0, 0], [0, 0]]];
+    cube[1][0][1] = 7;
+    flat := [5, 6];
+    flat[1] = 8;",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        let ints = |v: &[i64]| DataVal::Compound(v.iter().map(|i| DataVal::Integer(*i)).collect());
+        assert_eq!(
+            prog.variables[0],
+            DataVal::Compound(vec![ints(&[1, 10]), ints(&[9, 4])])
+        );
+        assert_eq!(
+            prog.variables[2],
+            DataVal::Compound(vec![
+                DataVal::Compound(vec![ints(&[0, 0]), ints(&[0, 0])]),
+                DataVal::Compound(vec![ints(&[0, 7]), ints(&[0, 0])]),
+            ])
+        );
+        assert_eq!(prog.variables[3], ints(&[5, 8]));
+    }
+
+    #[test]
+    fn nested_path_assignment() {
+        let mut prog = compile(
+            r#"
+    struct Net { ip: string, ports: []int }
+    struct Droplet { name: string, i: Net, v4: []int, nets: map[string]Net }
+
+    a := [Net { ip: "a" }, Net { ip: "b" }];
+    a[0].ip = "x";
+    d := Droplet { v4: [1, 2], i: Net { ports: [0] } };
+    d.i.ip = "y";
+    d.v4[1] = 5;
+    d.i.ports[0] = 80;
+    d.nets = {"public": Net { ip: "c" }};
+    d.nets["public"].ip = "z";
+    ips := [a[0].ip, a[1].ip, d.i.ip, d.nets["public"].ip];"#,
+        )
+        .unwrap();
+        prog.execute().unwrap();
+        assert_eq!(
+            prog.get_var("ips"),
+            Some(&DataVal::Compound(vec![
+                "x".into(),
+                "b".into(),
+                "y".into(),
+                "z".into()
+            ]))
+        );
+        let d = prog.get_var("d").unwrap().as_compound().unwrap();
+        assert_eq!(d[2], DataVal::Compound(vec![1.into(), 5.into()]));
+        let i = d[1].as_compound().unwrap();
+        assert_eq!(i[1], DataVal::Compound(vec![80.into()]));
+
+        for (src, msg) in [
+            (
+                r#"d.i.ip = 1;"#,
+                "type error: cannot assign int to d.i.ip of type string",
+            ),
+            (
+                r#"d.v4[0] = "a";"#,
+                "type error: cannot assign string to d.v4[] of type int",
+            ),
+            (
+                r#"d.i.port = 1;"#,
+                "type error: struct Net has no field port (its fields are ip, ports)",
+            ),
+        ] {
+            let src = format!(
+                "struct Net {{ ip: string, ports: []int }}\nstruct Droplet {{ i: Net, v4: []int }}\nd := Droplet {{}};\n{}",
+                src
+            );
+            let err = compile(&src).err().unwrap();
+            assert_eq!(err.to_string(), format!("line 4: {}", msg), "{}", src);
+        }
+    }
+
+    #[test]
+    fn waiting_compound_set() {
+        let mut prog = compile(
+            r#"
+    struct Droplet {
+        name: string,
+        size: int
+    }
+
+    func extern pendingName() (string)
+    func extern pendingIndex() (int)
+    func extern pendingDroplet() (Droplet)
+
+    name := pendingName();
+    idx := pendingIndex();
+
+    // A waiting value
+    d := Droplet { name: "web", size: 1 };
+    d.name = name;
+    sizes := [1, 2, 3];
+    sizes[1] = idx;
+
+    // A waiting index
+    droplets := [d, Droplet { name: "db", size: 2 }];
+    droplets[idx] = Droplet { name: "cache", size: 3 };
+    grid := [[1, 2], [3, 4]];
+    grid[idx][0] = 5;
+
+    // A waiting container
+    e := pendingDroplet();
+    e.size = 4;
+    f := [e];
+    f[0] = d;
+    "#,
+        )
+        .unwrap();
+        for name in ["pendingName", "pendingIndex", "pendingDroplet"] {
+            prog.external_functions.insert(
+                name.into(),
+                Box::new(|_, _, _, _, _, _| Ok(vec![DataVal::Waiting])),
+            );
+        }
+        prog.execute().unwrap();
+        assert_optimizes(&prog);
+
+        let w = DataVal::Waiting;
+        assert_eq!(
+            prog.get_var("d"),
+            Some(&DataVal::Compound(vec![w.clone(), 1.into()]))
+        );
+        assert_eq!(
+            prog.get_var("sizes"),
+            Some(&DataVal::Compound(vec![1.into(), w.clone(), 3.into()]))
+        );
+
+        assert_eq!(
+            prog.get_var("droplets"),
+            Some(&DataVal::Compound(vec![w.clone(), w.clone()]))
+        );
+        assert_eq!(
+            prog.get_var("grid"),
+            Some(&DataVal::Compound(vec![w.clone(), w.clone()]))
+        );
+
+        assert_eq!(prog.get_var("e"), Some(&w));
+        assert_eq!(
+            prog.get_var("f"),
+            Some(&DataVal::Compound(vec![DataVal::Compound(vec![
+                w.clone(),
+                1.into()
+            ])]))
+        );
+
+        // Field assignment stores the struct
+        let mut prog = compile(
+            r#"
+    struct Droplet {
+        name: string,
+        size: int
+    }
+    d := Droplet { name: "web", size: 1 };
+    d.size = d.size + 1;
+    "#,
+        )
+        .unwrap();
+        prog.execute().unwrap();
+        assert_eq!(
+            prog.get_var("d"),
+            Some(&DataVal::Compound(vec!["web".into(), 2.into()]))
+        );
+    }
+
+    #[test]
+    fn compound_equality() {
+        let mut prog = compile(
+            r#"
+    struct Droplet {
+        name: string,
+        tags: []string
+    }
+
+    func extern createDroplet(d: Droplet) (Droplet)
+
+    desired := Droplet { name: "web", tags: ["env:dev", "team:a"] };
+    same := desired == Droplet { name: "web", tags: ["env:dev", "team:a"] };
+    drifted := Droplet { name: "web", tags: ["env:dev"] } != desired;
+
+    a := [[1, 2], [3, 4]];
+    b := a;
+    b[1][0] = 5;
+    copied := a == [[1, 2], [3, 4]];
+    modified := a == b;
+
+    actual := createDroplet(desired);
+    converged := actual == desired;"#,
+        )
+        .unwrap();
+
+        // The provider hasn't assigned the tags yet
+        prog.external_functions.insert(
+            "createDroplet".into(),
+            Box::new(|_, _, _, _, _, _| {
+                return Ok(vec![DataVal::Compound(vec![
+                    "web".into(),
+                    DataVal::Waiting,
+                ])]);
+            }),
+        );
+        prog.execute().unwrap();
+
+        assert_eq!(prog.get_var("same"), Some(&DataVal::Bool(true)));
+        assert_eq!(prog.get_var("drifted"), Some(&DataVal::Bool(true)));
+        assert_eq!(prog.get_var("copied"), Some(&DataVal::Bool(true)));
+        assert_eq!(prog.get_var("modified"), Some(&DataVal::Bool(false)));
+        assert_eq!(prog.get_var("converged"), Some(&DataVal::Waiting));
+
+        // Compounds still can't be ordered
+        let l = lexer::Lexer::new("a := [1]; b := a < [2];".chars().collect());
+        let err = parser::Parser::new(l).program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 1: type error: cannot use '<' on []int and []int"
+        );
+
+        let mut prog = stac::Prog::without_prelude();
+        let arr = DataVal::Compound(vec![DataVal::Integer(1)]);
+        prog.code = vec![stac::Block {
+            code: vec![
+                stac::Instr::LoadConst { v: arr.clone() },
+                stac::Instr::LoadConst { v: arr },
+                stac::Instr::BinaryExpr { op: Token::Le },
+            ],
+        }];
+        let err = prog.execute().unwrap_err();
+        assert_eq!(err.kind, stac::ExecErrorKind::Type);
+        assert_eq!(err.message, "cannot compare [1] and [1]");
+    }
+
+    #[test]
+    fn nested_index_out_of_range() {
+        let l = lexer::Lexer::new("a := [[1, 2], [3, 4]]; a[0][2] = 9;".chars().collect());
+        let mut par = parser::Parser::new(l);
+        let err = par.program().unwrap().execute().unwrap_err();
+        assert_eq!(
+            err.kind,
+            stac::ExecErrorKind::IndexOutOfBounds { index: 2, len: 2 }
+        );
+        assert_eq!(
+            err.message,
+            "index out of range: 2 is not an index of an array of length 2"
+        );
+    }
+
+    #[test]
+    fn index_bounds() {
+        let cases = vec![
+            ("a := [1, 2, 3, 4]; b := a[-1];", -1, 4),
+            ("a := [1, 2, 3, 4]; b := a[10];", 10, 4),
+            ("a := [1, 2, 3, 4]; i := 2 - 3; a[i] = 5;", -1, 4),
+            ("a := [1, 2, 3, 4]; a[4] = 5;", 4, 4),
+        ];
+        for (src, index, len) in cases {
+            let l = lexer::Lexer::new(src.chars().collect());
+            let mut par = parser::Parser::new(l);
+            let prog = par.program().unwrap();
+            let err = prog.execute().unwrap_err();
+            assert_eq!(
+                err.kind,
+                stac::ExecErrorKind::IndexOutOfBounds { index, len },
+                "{}",
+                src
+            );
+            let is_compound = matches!(
+                prog.code[err.ip.0].code[err.ip.1],
+                stac::Instr::CompoundGet | stac::Instr::CompoundSet
+            );
+            assert!(is_compound, "{}", src);
+        }
+
+        // The length is only known at runtime
+        let l = lexer::Lexer::new("n := 2 - 5; a := make([]int, n);".chars().collect());
+        let mut par = parser::Parser::new(l);
+        let err = par.program().unwrap().execute().unwrap_err();
+        assert_eq!(err.kind, stac::ExecErrorKind::NegativeLength { len: -3 });
+        assert_eq!(err.message, "cannot create an array of negative length -3");
+
+        // As is a length too long to allocate, eg. from an extern
+        let mut prog = compile(
+            "
+    func extern ruleCount() (int)
+    a := make([]int, ruleCount());",
+        )
+        .unwrap();
+        prog.register_extern("ruleCount", || -> i64 { 100000000000000 });
+        let err = prog.execute().unwrap_err();
+        assert_eq!(
+            err.kind,
+            stac::ExecErrorKind::LengthLimitExceeded {
+                len: 100000000000000
+            }
+        );
+        assert_eq!(
+            err.message,
+            "cannot create an array of length 100000000000000: arrays may have at most 1048576 elements"
+        );
+
+        // The limit can be changed
+        let mut prog = compile("a := make([]int, 11);").unwrap();
+        prog.set_max_array_length(10);
+        let err = prog.execute().unwrap_err();
+        assert_eq!(
+            err.kind,
+            stac::ExecErrorKind::LengthLimitExceeded { len: 11 }
+        );
+        prog.set_max_array_length(11);
+        prog.reset();
+        prog.execute().unwrap();
+
+        // And making arrays uses a cycle for each element, so making many of
+        // them is bounded by the cycle limit
+        let mut prog = compile(
+            "
+    i := 0;
+    while i < 100 {
+        a := make([]int, 1000000);
+        i = i + 1;
+    }",
+        )
+        .unwrap();
+        let err = prog.execute().unwrap_err();
+        assert!(matches!(
+            err.kind,
+            stac::ExecErrorKind::CycleLimitExceeded { .. }
+        ));
+        assert!(prog.get_var("i").unwrap().as_integer().unwrap() < &10);
+
+        // A field the compiler didn't resolve is a bug, not an indexing error
+        let mut prog = stac::Prog::without_prelude();
+        prog.code = vec![stac::Block {
+            code: vec![
+                stac::Instr::LoadConst {
+                    v: DataVal::Compound(vec![DataVal::Integer(1), DataVal::Integer(2)]),
+                },
+                stac::Instr::LoadConst {
+                    v: DataVal::Integer(2),
+                },
+                stac::Instr::FieldGet,
+            ],
+        }];
+        let err = prog.execute().unwrap_err();
+        assert_eq!(err.kind, stac::ExecErrorKind::Internal);
+        assert_eq!(err.message, "struct with 2 fields has no field 2");
+    }
+
+    const TAGGED_DROPLET: &str = r#"
+    struct CreateDropletRequest {
+        name: string,
+        tags: []string
+    }
+
+    func extern createDroplet(req: CreateDropletRequest) (int)
+
+    d := createDroplet(CreateDropletRequest { name: "web", tags: ["env:dev"] });
+    "#;
+
+    // Sets a field of the first param, a struct, using the struct's metadata
+    fn update_field(
+        params: &mut [DataVal],
+        ctx: &stac::ExternCtx,
+        field: &str,
+        f: impl Fn(&mut DataVal),
+    ) {
+        let strct = &ctx.user_structs[ctx.param_types[0].as_struct().unwrap()];
+        let fields = params[0].as_compound_mut().unwrap();
+        f(&mut fields[strct.names[field]]);
+    }
+
+    #[test]
+    fn extern_middleware() {
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createDroplet"]}"#),
+            ("/createDroplet", "[1]"),
+        ]);
+
+        let l = lexer::Lexer::new(TAGGED_DROPLET.chars().collect());
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+
+        // Middlewares run in the order they were added
+        prog.add_extern_middleware(Box::new(|_, mut params, ctx| {
+            update_field(&mut params, ctx, "name", |name| {
+                *name = DataVal::String(format!("prod-{}", name.as_string().unwrap()).into());
+            });
+            return Ok(params);
+        }));
+        prog.add_extern_middleware(Box::new(|func, mut params, ctx| {
+            assert_eq!(func, "createDroplet");
+            let name = params[0].as_compound().unwrap()[0].clone();
+            update_field(&mut params, ctx, "tags", |tags| {
+                let tag = format!("name:{}", name.as_string().unwrap());
+                tags.as_compound_mut()
+                    .unwrap()
+                    .push(DataVal::String(tag.into()));
+            });
+            return Ok(params);
+        }));
+        prog.execute().unwrap();
+
+        assert_eq!(prog.variables[0], DataVal::Integer(1));
+        let received = received.lock().unwrap();
+        let call = received.last().unwrap();
+        assert!(call
+            .body
+            .contains(r#""name":"prod-web","tags":["env:dev","name:prod-web"]"#));
+    }
+
+    #[test]
+    fn extern_middleware_rejects() {
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createDroplet"]}"#),
+            ("/createDroplet", "[1]"),
+        ]);
+
+        let l = lexer::Lexer::new(TAGGED_DROPLET.chars().collect());
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.add_extern_middleware(Box::new(|_, _, _| {
+            return Err(stac::ExternError::new(
+                "droplets must be tagged with a team",
+            ));
+        }));
+
+        let err = prog.execute().unwrap_err();
+        assert!(
+            matches!(err.kind, stac::ExecErrorKind::Rejected { ref func, .. } if func == "createDroplet")
+        );
+        assert_eq!(
+            err.message,
+            "extern call to createDroplet rejected: droplets must be tagged with a team"
+        );
+
+        let received = received.lock().unwrap();
+        assert!(received.iter().all(|r| r.path == "/provider_schema"));
+    }
+
+    const CONSTRAINED_STRUCT: &str = r#"
+    struct CreateServerRequest {
+        name: string matches "^[a-z0-9-]+$",
+        size: string in ["small", "large"],
+        port: int in 1..65535
+    }
+
+    func extern createServer(req: CreateServerRequest) (int)
+    "#;
+
+    #[test]
+    fn constraints_constant() {
+        let src = CONSTRAINED_STRUCT.to_string()
+            + r#"
+    q := createServer(CreateServerRequest { name: "web", size: "huge", port: 80 });
+    "#;
+
+        let l = lexer::Lexer::new(src.chars().collect());
+        let mut par = parser::Parser::new(l);
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            r#"line 10: constraint violated: field CreateServerRequest.size must be one of ["small", "large"], but was "huge""#
+        );
+    }
+
+    #[test]
+    fn constraints_runtime() {
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createServer"]}"#),
+            ("/createServer", "[1]"),
+        ]);
+
+        let src = CONSTRAINED_STRUCT.to_string()
+            + r#"
+    p := 70000;
+    q := createServer(CreateServerRequest { name: "web", size: "small", port: p });
+    "#;
+
+        let l = lexer::Lexer::new(src.chars().collect());
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+
+        let err = prog.execute().unwrap_err();
+        assert_eq!(err.kind, stac::ExecErrorKind::Constraint);
+        assert_eq!(
+            err.message,
+            "constraint violated: field CreateServerRequest.port must be in 1..65535, but was 70000"
+        );
+
+        // The provider was never called
+        let received = received.lock().unwrap();
+        assert!(received.iter().all(|r| r.path == "/provider_schema"));
+    }
+
+    #[test]
+    fn constraints_pass() {
+        let (addr, received) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createServer"]}"#),
+            ("/createServer", "[1]"),
+        ]);
+
+        let src = CONSTRAINED_STRUCT.to_string()
+            + r#"
+    n := "api-2";
+    req := CreateServerRequest { name: n, size: "large", port: 65535 };
+    q := createServer(req);
+    "#;
+
+        let l = lexer::Lexer::new(src.chars().collect());
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        prog.add_http_provider(addr, ProviderConfig::default())
+            .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(prog.variables[2], DataVal::Integer(1));
+
+        let received = received.lock().unwrap();
+        let call = received.last().unwrap();
+        assert_eq!(call.path, "/createServer");
+        assert!(call.body.contains(r#""name":"api-2""#));
+        assert!(call.body.contains(r#""size":"large""#));
+    }
+
+    const DRIFTED_SERVER: &str = r#"
+    struct Server {
+        id: int,
+        cpus: int,
+        load: float
+    }
+
+    func extern createServer(name: string) (Server)
+
+    s := createServer("web");
+    "#;
+
+    // Executes DRIFTED_SERVER against a provider returning the server
+    fn drifted(mode: provider::ProviderMode, server: &'static str) -> stac::Prog {
+        let (addr, _) = mock_provider(vec![
+            ("/provider_schema", r#"{"functions": ["createServer"]}"#),
+            ("/createServer", server),
+        ]);
+
+        let l = lexer::Lexer::new(DRIFTED_SERVER.chars().collect());
+        let mut par = parser::Parser::new(l);
+        par.program().unwrap();
+        let mut prog = par.into_prog();
+        prog.add_http_provider_with(addr, ProviderConfig::default(), mode)
+            .unwrap();
+        prog.execute().unwrap();
+        return prog;
+    }
+
+    #[test]
+    fn provider_compat() {
+        use provider::{ProviderMode, ProviderWarning};
+
+        let prog = drifted(
+            ProviderMode::Compatible,
+            r#"[{"id": 3.0, "cpus": "4", "load": 0.5}]"#,
+        );
+        assert_eq!(
+            prog.variables[0],
+            DataVal::Compound(vec![
+                DataVal::Integer(3),
+                DataVal::Integer(4),
+                DataVal::Float(0.5)
+            ])
+        );
+        assert_eq!(
+            prog.provider_warnings(),
+            vec![
+                ProviderWarning {
+                    path: "createServer[0].id".into(),
+                    expected: DataType::Integer,
+                    received: "float 3".into(),
+                },
+                ProviderWarning {
+                    path: "createServer[0].cpus".into(),
+                    expected: DataType::Integer,
+                    received: r#"string "4""#.into(),
+                },
+            ]
+        );
+
+        // Impossible coercions still fail
+        for server in [
+            r#"[{"id": {"n": 3}, "cpus": 4, "load": 0.5}]"#,
+            r#"[{"id": 3, "cpus": "four", "load": 0.5}]"#,
+        ] {
+            let res = std::panic::catch_unwind(|| drifted(ProviderMode::Compatible, server));
+            assert!(res.is_err());
+        }
+
+        // Strict mode is unchanged, and never records warnings
+        let prog = drifted(
+            ProviderMode::Strict,
+            r#"[{"id": 3.0, "cpus": 4, "load": 1}]"#,
+        );
+        assert_eq!(
+            prog.variables[0],
+            DataVal::Compound(vec![
+                DataVal::Integer(3),
+                DataVal::Integer(4),
+                DataVal::Float(1.0)
+            ])
+        );
+        assert!(prog.provider_warnings().is_empty());
+
+        let res = std::panic::catch_unwind(|| {
+            drifted(
+                ProviderMode::Strict,
+                r#"[{"id": 3, "cpus": "4", "load": 0.5}]"#,
+            )
+        });
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn plan_render() {
+        let l = lexer::Lexer::new(
+            "
+    struct Network {
+        vpc: string,
+        ports: []int
+    }
+
+    struct Server {
+        name: string,
+        replicas: int,
+        network: Network
+    }"
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+
+        let calls = vec![
+            plan::PlannedCall {
+                id: (7, 2, 0).into(),
+                function: "createServer".to_string(),
+                params: vec![DataVal::Compound(vec![
+                    DataVal::String("web".into()),
+                    DataVal::Integer(2),
+                    DataVal::Compound(vec![
+                        DataVal::Waiting,
+                        DataVal::Compound(vec![DataVal::Integer(80), DataVal::Integer(443)]),
+                    ]),
+                ])],
+                param_types: vec![DataType::Struct("Server".to_string())],
+            },
+            plan::PlannedCall {
+                id: (3, 1, 0).into(),
+                function: "createVpc".to_string(),
+                params: vec![DataVal::String("prod".into()), DataVal::Bool(true)],
+                param_types: vec![DataType::String, DataType::Bool],
+            },
+        ];
+
+        let out = plan::render(&calls, &prog.user_structs);
+        println!("{}", out);
+        assert_eq!(
+            out,
+            r#"# 3:1:0
++ createVpc(
+    "prod",
+    true,
+)
+
+# 7:2:0
++ createServer(
+    Server {
+        name     = "web"
+        replicas = 2
+        network  = Network {
+            vpc   = (known after apply)
+            ports = [80, 443]
         }
-        a = 3;
+    },
+)
+
+Plan: 2 to create.
+"#
+        );
+
+        let colored = plan::render_with(&calls, &prog.user_structs, true);
+        assert!(colored.starts_with("\x1b[1m# 3:1:0\x1b[0m\n\x1b[32m+\x1b[0m createVpc("));
     }
 
-    b := 2;
-    while a == 1 {
-        b = 3;
+    fn structs_of(src: &str) -> HashMap<String, stac::Struct> {
+        let l = lexer::Lexer::new(src.chars().collect());
+        let mut par = parser::Parser::new(l);
+        return par.program().unwrap().user_structs.clone();
+    }
+
+    #[test]
+    fn state_migration() {
+        let old = structs_of(
+            "
+    struct Network { ip: string, private: bool }
+    struct Droplet { id: int, name: string, tags: []string, net: Network }",
+        );
+        let reordered = structs_of(
+            "
+    struct Network { private: bool, ip: string }
+    struct Droplet { net: Network, region: string, tags: []string, name: string, id: int }",
+        );
+
+        let droplet = DataType::Struct("Droplet".to_string());
+        let val = DataVal::Compound(vec![
+            DataVal::Integer(42),
+            DataVal::String("web".into()),
+            DataVal::Compound(vec![]),
+            DataVal::Compound(vec![DataVal::String("10.0.0.1".into()), DataVal::Waiting]),
+        ]);
+
+        // Persist under the old layout, then load under the reordered one
+        let persisted = state::encode(&val, &droplet, &old).to_string();
+        println!("{}", persisted);
+        let loaded = serde_json::from_str(&persisted).unwrap();
+
+        assert_eq!(
+            state::decode(&loaded, &droplet, &reordered),
+            Ok(DataVal::Compound(vec![
+                DataVal::Compound(vec![DataVal::Waiting, DataVal::String("10.0.0.1".into())]),
+                DataVal::String("".into()),
+                DataVal::Compound(vec![]),
+                DataVal::String("web".into()),
+                DataVal::Integer(42),
+            ]))
+        );
+
+        // Removing a field without data is fine, but not one with data
+        let removed = structs_of(
+            "
+    struct Network { ip: string, private: bool }
+    struct Droplet { id: int, net: Network }",
+        );
+        let err = state::decode(&loaded, &droplet, &removed).unwrap_err();
+        assert_eq!(
+            err,
+            state::MigrationError::RemovedField {
+                strct: "Droplet".to_string(),
+                field: "name".to_string(),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "migration error: field Droplet.name was removed, but the state has data for it"
+        );
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn otel_spans() {
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+
+        type Spans = Arc<Mutex<Vec<(String, HashMap<String, String>)>>>;
+
+        // Records the name and fields of every span
+        struct Recorder(Spans);
+
+        struct Fields<'a>(&'a mut HashMap<String, String>);
+
+        impl Visit for Fields<'_> {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.insert(field.name().to_string(), value.to_string());
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0
+                    .insert(field.name().to_string(), format!("{:?}", value));
+            }
+        }
+
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _: &tracing::Metadata) -> bool {
+                return true;
+            }
+
+            fn new_span(&self, span: &Attributes) -> Id {
+                let mut fields = HashMap::new();
+                span.record(&mut Fields(&mut fields));
+
+                let mut spans = self.0.lock().unwrap();
+                spans.push((span.metadata().name().to_string(), fields));
+                return Id::from_u64(spans.len() as u64);
+            }
+
+            fn record(&self, span: &Id, values: &Record) {
+                let mut spans = self.0.lock().unwrap();
+                values.record(&mut Fields(&mut spans[span.into_u64() as usize - 1].1));
+            }
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &tracing::Event) {}
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let (addr, received) = mock_provider(vec![
+            (
+                "/provider_schema",
+                r#"{"functions": ["createBucket", "createUser"]}"#,
+            ),
+            ("/createBucket", "[4]"),
+            ("/createUser", r#"[{"$waiting": true}]"#),
+        ]);
+
+        let spans = Spans::default();
+        tracing::subscriber::with_default(Recorder(spans.clone()), || {
+            let l = lexer::Lexer::new(
+                r#"
+        func extern createBucket(name: string) (int)
+        func extern createUser(name: string, bucket: int) (int)
+
+        b := createBucket("logs");
+        u := createUser("ci", b);
+        "#
+                .chars()
+                .collect(),
+            );
+
+            let mut par = parser::Parser::new(l);
+            let prog = par.program().unwrap();
+            prog.add_http_provider(addr, ProviderConfig::default())
+                .unwrap();
+            prog.execute().unwrap();
+        });
+
+        let spans = spans.lock().unwrap();
+        let names: Vec<_> = spans.iter().map(|s| s.0.as_str()).collect();
+        // The prelude is parsed before the program
+        assert_eq!(
+            names,
+            vec![
+                "dmcl.parse",
+                "dmcl.parse",
+                "dmcl.execute",
+                "dmcl.extern_call",
+                "dmcl.extern_call"
+            ]
+        );
+
+        let calls: Vec<_> = spans.iter().filter(|s| s.0 == "dmcl.extern_call").collect();
+        assert_eq!(calls[0].1["function"], "createBucket");
+        assert_eq!(calls[0].1["params_size"], "1");
+        assert_eq!(calls[0].1["outcome"], "ok");
+        assert_eq!(calls[1].1["function"], "createUser");
+        assert_eq!(calls[1].1["params_size"], "2");
+        assert_eq!(calls[1].1["outcome"], "waiting");
+        assert_ne!(calls[0].1["resource"], calls[1].1["resource"]);
+
+        // Every span belongs to the same trace
+        let trace_id = &spans[0].1["trace_id"];
+        assert!(spans.iter().all(|s| &s.1["trace_id"] == trace_id));
+
+        // Each provider request carries the context of its extern call span
+        let received = received.lock().unwrap();
+        let requests: Vec<_> = received
+            .iter()
+            .filter(|r| r.path != "/provider_schema")
+            .collect();
+        assert_eq!(requests.len(), 2);
+        for (req, span) in requests.iter().zip(&calls) {
+            let traceparent = &req.headers.iter().find(|h| h.0 == "traceparent").unwrap().1;
+            assert_eq!(
+                *traceparent,
+                format!("00-{}-{}-01", span.1["trace_id"], span.1["span_id"])
+            );
+        }
+    }
+
+    #[test]
+    fn side_effect_recursion() {
+        let l = lexer::Lexer::new(
+            r#"
+    func extern createResourceAsync(name: string) (int)
+
+    func countdown(n: int) (int) {
+        if n > 0 {
+            n = countdown(n - 1);
+        }
+        return n;
+    }
+
+    q := createResourceAsync("test");
+    a := 1;
+    if q == 0 {
+        a = countdown(3);
     }
+    b := 2;
     "#
             .chars()
             .collect(),
         );
 
         let mut par = parser::Parser::new(l);
-        let prog = par.program();
+        let prog = par.program().unwrap();
         print_instructions(&prog.code);
 
         prog.external_functions.insert(
             "createResourceAsync".into(),
-            Box::new(|_ip, _ptype, _rtypes, params, _ustructs| {
-                println!(
-                    "creating resource asynchronously {}",
-                    params[0].clone().into_string().unwrap()
-                );
-                return vec![DataVal::Waiting];
+            Box::new(|_ip, _ptype, _rtypes, _params, _ustructs, _ctx| {
+                return Ok(vec![DataVal::Waiting]);
             }),
         );
 
-        prog.execute();
+        prog.execute().unwrap();
         println!("{:?}", prog.variables);
 
-        assert_eq!(prog.variables[0], stac::DataVal::Waiting);
-        assert_eq!(prog.variables[1], stac::DataVal::Waiting);
+        // The walk through countdown terminated, so execution carried on after the if
         assert_eq!(prog.variables[2], stac::DataVal::Waiting);
+        assert_eq!(prog.variables[3], stac::DataVal::Integer(2));
+
+        // Both the assignment in the if and the one inside countdown were speculative
+        let marked: Vec<_> = prog
+            .report
+            .speculative_stores
+            .iter()
+            .map(|s| s.addr)
+            .collect();
+        assert!(marked.contains(&stac::Addr(2)));
+        assert!(marked.contains(&stac::Addr(0)));
+        assert!(marked
+            .iter()
+            .all(|a| *a == stac::Addr(0) || *a == stac::Addr(2)));
     }
 
     #[test]
-    fn fake_provider() {
+    fn type_predicates() {
         let l = lexer::Lexer::new(
             r#"
-    struct CreateDropletRequest {
-        name: string,
-        region: string,
-        size: string,
-        image: string,
-        ssh_keys: []string,
-        backups: bool,
-        ipv6: bool,
-        monitoring: bool,
-        tags: []string,
-        user_data: string,
-        volumes: []string,
-        vpc_uuid: string,
-        with_droplet_agent: bool
+    func extern createResourceAsync(name: string) (int)
+
+    struct Point {
+        x: int,
+        y: int
     }
 
-    struct DropletNetwork {
-        ip_address: string,
-        netmask: string,
-        gateway: string,
-        type: string
+    a := typeof(1);
+    b := typeof(1.5f);
+    c := typeof(true);
+    d := typeof("hello");
+    p := Point{ x: 1 };
+    e := typeof(p);
+    f := typeof([1, 2]);
+
+    q := createResourceAsync("test");
+    g := isWaiting(q);
+    h := isWaiting(a);
+    i := typeof(q);
+
+    j := 0;
+    if typeof(d) == "string" {
+        j = 1;
     }
+    "#
+            .chars()
+            .collect(),
+        );
 
-    struct DropletNetworking {
-        v4: []DropletNetwork,
-        v6: []DropletNetwork
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        print_instructions(&prog.code);
+
+        prog.external_functions.insert(
+            "createResourceAsync".into(),
+            Box::new(|_ip, _ptype, _rtypes, _params, _ustructs, _ctx| {
+                return Ok(vec![DataVal::Waiting]);
+            }),
+        );
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+
+        assert_eq!(prog.variables[0], DataVal::String("int".into()));
+        assert_eq!(prog.variables[1], DataVal::String("float".into()));
+        assert_eq!(prog.variables[2], DataVal::String("bool".into()));
+        assert_eq!(prog.variables[3], DataVal::String("string".into()));
+        assert_eq!(prog.variables[5], DataVal::String("Point".into()));
+        assert_eq!(prog.variables[6], DataVal::String("[]int".into()));
+        assert_eq!(prog.variables[7], DataVal::Waiting);
+        assert_eq!(prog.variables[8], DataVal::Bool(true));
+        assert_eq!(prog.variables[9], DataVal::Bool(false));
+        assert_eq!(prog.variables[10], DataVal::Waiting);
+        assert_eq!(prog.variables[11], DataVal::Integer(1));
     }
 
-    struct Droplet {
-        id: int,
-        state: string,
-        name: string,
-        networks: DropletNetworking
+    // An || chain of n constants that are all false, except the one at `truthy`
+    fn or_chain(n: usize, truthy: Option<usize>) -> String {
+        let terms: Vec<&str> = (0..n)
+            .map(|i| if Some(i) == truthy { "true" } else { "false" })
+            .collect();
+        return terms.join(" || ");
     }
 
-    func extern createDroplet(req: CreateDropletRequest) (Droplet)
+    #[test]
+    fn long_bool_chains() {
+        for (truthy, expected) in [(Some(0), true), (Some(199), true), (None, false)] {
+            let l = lexer::Lexer::new(format!("r := {};", or_chain(200, truthy)).chars().collect());
 
-    q := createDroplet(CreateDropletRequest {
-        name: "hello-world",
-        region: "syd1",
-        size: "s-1vcpu-512mb-10gb",
-        image: "ubuntu-20-04-x64"
-    });
-    c := q.state;
+            let mut par = parser::Parser::with_prog(l, stac::Prog::without_prelude());
+            let prog = par.program().unwrap();
+
+            // One block per right hand operand, plus the shared true/false blocks and the entrypoint
+            assert_eq!(prog.code.len(), 199 + 3);
+
+            prog.execute().unwrap();
+            assert_eq!(prog.variables[0], DataVal::Bool(expected));
+        }
+    }
+
+    #[test]
+    fn bool_lazy_evaluation() {
+        let l = lexer::Lexer::new(
+            r#"
+    func extern sideEffect() (bool)
+
+    a := true || sideEffect();
+    b := false && sideEffect();
+    c := false || sideEffect();
+    d := 0;
+    if true || sideEffect() {
+        d = 1;
+    }
     "#
             .chars()
             .collect(),
         );
 
         let mut par = parser::Parser::new(l);
-        let prog = par.program();
+        let prog = par.program().unwrap();
         print_instructions(&prog.code);
 
-        prog.add_http_provider("http://localhost:8080".into());
+        let calls = Arc::new(AtomicI64::new(0));
+        let c = calls.clone();
+        prog.external_functions.insert(
+            "sideEffect".into(),
+            Box::new(move |_ip, _ptype, _rtypes, _params, _ustructs, _ctx| {
+                c.fetch_add(1, Ordering::SeqCst);
+                return Ok(vec![DataVal::Bool(true)]);
+            }),
+        );
 
-        prog.execute();
+        prog.execute().unwrap();
         println!("{:?}", prog.variables);
 
-        assert_eq!(prog.variables[1], stac::DataVal::Waiting)
+        // Only the right hand side of c needed evaluating
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(prog.variables[0], DataVal::Bool(true));
+        assert_eq!(prog.variables[1], DataVal::Bool(false));
+        assert_eq!(prog.variables[2], DataVal::Bool(true));
+        assert_eq!(prog.variables[3], DataVal::Integer(1));
     }
-}
 
-pub fn print_instructions(blocks: &Vec<stac::Block>) {
-    for (k, block) in blocks.iter().enumerate() {
-        println!("\nBLOCK {:3}:", k);
-        for (k, instr) in block.code.iter().enumerate() {
-            println!("{:3}:  {:?}", k, instr)
+    #[test]
+    fn nesting_limit() {
+        // Long flat programs and else if chains within the limit compile
+        let mut src = String::from("x := 0;\n");
+        for i in 0..8000 {
+            src += &format!("if x == {} {{ x = x + 1; }}\n", i);
+        }
+        let mut prog = compile(&src).unwrap();
+        prog.execute().unwrap();
+        assert_eq!(prog.get_var("x"), Some(&DataVal::Integer(8000)));
+
+        let mut src = String::from("x := 3; y := 0;\nif x == 0 { y = 1; }");
+        for i in 1..30 {
+            src += &format!(" else if x == {} {{ y = {}; }}", i, i * 2);
+        }
+        let mut prog = compile(&src).unwrap();
+        prog.execute().unwrap();
+        assert_eq!(prog.get_var("y"), Some(&DataVal::Integer(6)));
+
+        // Deeper nesting is an error rather than overflowing the stack
+        let msg = "line 1: compilation limit exceeded: expressions and blocks may be nested at most 32 deep";
+        let deep = [
+            format!("x := {}1{};", "(".repeat(500), ")".repeat(500)),
+            format!("x := {}1;", "-".repeat(500)),
+            format!(
+                "x := 0; {} x = 1; {}",
+                "if true { ".repeat(500),
+                "}".repeat(500)
+            ),
+            format!("x := 0; if x == 0 {{}}{}", " else if x == 1 {}".repeat(500)),
+            format!(
+                "x := if false {{ 0 }}{} else {{ 1 }};",
+                " else if false { 0 }".repeat(500)
+            ),
+        ];
+        for src in deep {
+            let mut par = parser::Parser::new(lexer::Lexer::from_str(&src));
+            assert_eq!(par.program().err().unwrap().to_string(), msg);
+        }
+
+        // As is chaining too many operators, though not for separate chains
+        let mut prog = compile(&format!("x := 0{};", " + 1 * 2".repeat(999))).unwrap();
+        prog.execute().unwrap();
+        assert_eq!(prog.get_var("x"), Some(&DataVal::Integer(1998)));
+
+        let mut par = parser::Parser::new(lexer::Lexer::from_str(&format!(
+            "x := 0{};",
+            " + 1".repeat(1001)
+        )));
+        assert_eq!(
+            par.program().err().unwrap().to_string(),
+            "line 1: compilation limit exceeded: expressions may chain at most 1000 operators"
+        );
+    }
+
+    #[test]
+    fn block_limit() {
+        // Each if body is a block of its own
+        let src = "if true {}\n".repeat(stac::BLOCK_LIMIT);
+        let mut par = parser::Parser::new(lexer::Lexer::from_str(&src));
+        assert_eq!(
+            par.program().err().unwrap().to_string(),
+            format!(
+                "line {}: compilation limit exceeded: a program may have at most {} blocks",
+                stac::BLOCK_LIMIT + 1,
+                stac::BLOCK_LIMIT
+            )
+        );
+    }
+
+    #[test]
+    fn while_completed() {
+        let mut prog = compile(
+            "
+    i := 0;
+    natural := 0;
+    while i < 3 {
+        i = i + 1;
+    } completed {
+        natural = 1;
+    }
+
+    j := 0;
+    broke := 1;
+    while j < 10 {
+        j = j + 1;
+        if j == 2 {
+            break;
+        }
+    } completed {
+        broke = 0;
+    }
+
+    a := 0;
+    count := 0;
+    inner_done := 0;
+    outer_done := 0;
+    while a < 2 {
+        a = a + 1;
+        b := 0;
+        while b < 5 {
+            b = b + 1;
+            count = count + 1;
+            if b == 2 {
+                break;
+            }
+        } completed {
+            inner_done = inner_done + 1;
+        }
+    } completed {
+        outer_done = 1;
+    }
+
+    x := 0;
+    labelled_done := 0;
+    outer: while x < 5 {
+        x = x + 1;
+        y := 0;
+        while y < 2 {
+            y = y + 1;
+            if x == 2 {
+                break outer;
+            }
+        }
+    } completed {
+        labelled_done = 1;
+    }",
+        )
+        .unwrap();
+        print_instructions(&prog.code);
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+
+        // Natural exit runs the clause
+        assert_eq!(prog.variables[0], DataVal::Integer(3));
+        assert_eq!(prog.variables[1], DataVal::Integer(1));
+
+        // Break skips it
+        assert_eq!(prog.variables[2], DataVal::Integer(2));
+        assert_eq!(prog.variables[3], DataVal::Integer(1));
+
+        // The inner loop always breaks, but the outer one completes
+        assert_eq!(prog.variables[4], DataVal::Integer(2));
+        assert_eq!(prog.variables[5], DataVal::Integer(4));
+        assert_eq!(prog.variables[6], DataVal::Integer(0));
+        assert_eq!(prog.variables[7], DataVal::Integer(1));
+
+        // Breaking the labelled outer loop from the inner one skips the outer clause
+        assert_eq!(prog.variables[9], DataVal::Integer(2));
+        assert_eq!(prog.variables[10], DataVal::Integer(0));
+    }
+
+    #[test]
+    fn continue_loops() {
+        let mut prog = compile(
+            "
+    i := 0;
+    odd := 0;
+    while i < 10 {
+        i = i + 1;
+        if i % 2 == 0 {
+            continue;
+        }
+        odd = odd + i;
+    } completed {
+        odd = odd + 1000;
+    }
+
+    // The post statement still runs when an iteration is continued
+    sum := 0;
+    for j := 0; j < 10; j = j + 1 {
+        if j < 5 {
+            continue;
+        }
+        sum = sum + j;
+    }
+
+    pairs := 0;
+    outer: for x := 0; x < 3; x = x + 1 {
+        for y := 0; y < 3; y = y + 1 {
+            if (y > x) {
+                continue outer;
+            }
+            pairs = pairs + 1;
         }
+    }",
+        )
+        .unwrap();
+        prog.execute().unwrap();
+
+        assert_eq!(prog.variables[0], DataVal::Integer(10));
+        assert_eq!(prog.variables[1], DataVal::Integer(1025));
+        assert_eq!(prog.variables[2], DataVal::Integer(35));
+        assert_eq!(prog.variables[4], DataVal::Integer(6));
+    }
+
+    #[test]
+    fn continue_outside_loop() {
+        let l = lexer::Lexer::new(
+            "func f() () { continue; } while true { f(); }"
+                .chars()
+                .collect(),
+        );
+        let mut par = parser::Parser::new(l);
+        let err = par.program().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 1: syntax error: continue outside of loop"
+        );
+    }
+
+    #[test]
+    fn while_completed_waiting() {
+        let l = lexer::Lexer::new(
+            r#"
+    func extern createResourceAsync(name: string) (int)
+
+    q := createResourceAsync("test");
+    a := 1;
+    b := 1;
+    while q == 0 {
+        a = 2;
+    } completed {
+        b = 2;
+    }
+    c := 3;
+    "#
+            .chars()
+            .collect(),
+        );
+
+        let mut par = parser::Parser::new(l);
+        let prog = par.program().unwrap();
+        print_instructions(&prog.code);
+
+        prog.external_functions.insert(
+            "createResourceAsync".into(),
+            Box::new(|_ip, _ptype, _rtypes, _params, _ustructs, _ctx| {
+                return Ok(vec![DataVal::Waiting]);
+            }),
+        );
+
+        prog.execute().unwrap();
+        println!("{:?}", prog.variables);
+
+        // Both the body and the clause might run
+        assert_eq!(prog.variables[1], DataVal::Waiting);
+        assert_eq!(prog.variables[2], DataVal::Waiting);
+        assert_eq!(prog.variables[3], DataVal::Integer(3));
+    }
+
+    // A request received by a mock provider, with lowercased header names
+    struct MockRequest {
+        path: String,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    type Received = Arc<Mutex<Vec<MockRequest>>>;
+
+    // Serves canned responses for each path on a local port, returning the
+    // address of the server and the requests it receives.
+    fn mock_provider(routes: Vec<(&'static str, &'static str)>) -> (String, Received) {
+        return mock_provider_with(routes.into_iter().map(|(p, r)| (p, "200 OK", r)).collect());
+    }
+
+    // Like mock_provider, with the status of each response, which may include
+    // headers after it. A path with several routes responds with each in turn,
+    // and then with the last.
+    fn mock_provider_with(
+        routes: Vec<(&'static str, &'static str, &'static str)>,
+    ) -> (String, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let recv = received.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                // Read the request line and headers
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split_whitespace().nth(1).unwrap_or("").to_string();
+
+                let mut content_len = 0;
+                let mut headers = vec![];
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((k, v)) = header.split_once(':') {
+                        let (k, v) = (k.trim().to_lowercase(), v.trim().to_string());
+                        if k == "content-length" {
+                            content_len = v.parse().unwrap();
+                        }
+                        headers.push((k, v));
+                    }
+                }
+
+                let mut body = vec![0; content_len];
+                reader.read_exact(&mut body).unwrap();
+                let mut recv = recv.lock().unwrap();
+                recv.push(MockRequest {
+                    path: path.clone(),
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                });
+                let seen = recv.iter().filter(|r| r.path == path).count();
+                drop(recv);
+
+                let matching: Vec<_> = routes.iter().filter(|r| r.0 == path).collect();
+                let (status, resp) = match matching.get(seen - 1).or(matching.last()) {
+                    Some(r) => (r.1, r.2),
+                    None => ("404 Not Found", ""),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    resp.len(),
+                    resp
+                )
+                .unwrap();
+            }
+        });
+
+        return (addr, received);
     }
 }
@@ -0,0 +1,152 @@
+// Rust closures that can be registered as externs with Prog::register_extern,
+// converting their params and returns to and from DataVals.
+
+use crate::stac::{DataType, DataVal, ExternError};
+
+// A Rust type that an extern can take or return
+pub trait NativeType: Sized {
+    fn data_type() -> DataType;
+    fn from_val(v: DataVal) -> Self;
+    fn into_val(self) -> DataVal;
+}
+
+macro_rules! native_type {
+    ($t:ty, $data_type:expr) => {
+        impl NativeType for $t {
+            fn data_type() -> DataType {
+                return $data_type;
+            }
+
+            fn from_val(v: DataVal) -> Self {
+                // The types were checked against the declaration when registered
+                return Self::try_from(v).unwrap();
+            }
+
+            fn into_val(self) -> DataVal {
+                return self.into();
+            }
+        }
+    };
+}
+
+native_type!(i64, DataType::Integer);
+native_type!(f64, DataType::Float);
+native_type!(bool, DataType::Bool);
+native_type!(String, DataType::String);
+
+impl<T: NativeType> NativeType for Vec<T> {
+    fn data_type() -> DataType {
+        return DataType::Array(Box::new(T::data_type()));
+    }
+
+    fn from_val(v: DataVal) -> Self {
+        return v
+            .into_compound()
+            .unwrap()
+            .into_iter()
+            .map(T::from_val)
+            .collect();
+    }
+
+    fn into_val(self) -> DataVal {
+        return DataVal::Compound(self.into_iter().map(T::into_val).collect());
+    }
+}
+
+// What an extern returns: nothing, one value, a pair of values, None if
+// they are still waiting, or an error if the call failed
+pub trait NativeReturns {
+    fn data_types() -> Vec<DataType>;
+    fn into_vals(self) -> Result<Vec<DataVal>, ExternError>;
+}
+
+impl NativeReturns for () {
+    fn data_types() -> Vec<DataType> {
+        return vec![];
+    }
+
+    fn into_vals(self) -> Result<Vec<DataVal>, ExternError> {
+        return Ok(vec![]);
+    }
+}
+
+impl<T: NativeType> NativeReturns for T {
+    fn data_types() -> Vec<DataType> {
+        return vec![T::data_type()];
+    }
+
+    fn into_vals(self) -> Result<Vec<DataVal>, ExternError> {
+        return Ok(vec![self.into_val()]);
+    }
+}
+
+impl<A: NativeType, B: NativeType> NativeReturns for (A, B) {
+    fn data_types() -> Vec<DataType> {
+        return vec![A::data_type(), B::data_type()];
+    }
+
+    fn into_vals(self) -> Result<Vec<DataVal>, ExternError> {
+        return Ok(vec![self.0.into_val(), self.1.into_val()]);
+    }
+}
+
+impl<R: NativeReturns> NativeReturns for Option<R> {
+    fn data_types() -> Vec<DataType> {
+        return R::data_types();
+    }
+
+    fn into_vals(self) -> Result<Vec<DataVal>, ExternError> {
+        match self {
+            Some(r) => return r.into_vals(),
+            None => return Ok(vec![DataVal::Waiting; R::data_types().len()]),
+        }
+    }
+}
+
+impl<R: NativeReturns> NativeReturns for Result<R, ExternError> {
+    fn data_types() -> Vec<DataType> {
+        return R::data_types();
+    }
+
+    fn into_vals(self) -> Result<Vec<DataVal>, ExternError> {
+        return self?.into_vals();
+    }
+}
+
+// A closure taking up to four params, where Args is the tuple of their types
+pub trait NativeFn<Args> {
+    fn param_types() -> Vec<DataType>;
+    fn return_types() -> Vec<DataType>;
+    fn call(&self, params: Vec<DataVal>) -> Result<Vec<DataVal>, ExternError>;
+}
+
+macro_rules! native_fn {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> NativeFn<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R,
+            R: NativeReturns,
+            $($arg: NativeType),*
+        {
+            fn param_types() -> Vec<DataType> {
+                return vec![$($arg::data_type()),*];
+            }
+
+            fn return_types() -> Vec<DataType> {
+                return R::data_types();
+            }
+
+            #[allow(unused_mut, unused_variables)]
+            fn call(&self, params: Vec<DataVal>) -> Result<Vec<DataVal>, ExternError> {
+                let mut params = params.into_iter();
+                return self($($arg::from_val(params.next().unwrap())),*).into_vals();
+            }
+        }
+    };
+}
+
+native_fn!();
+native_fn!(A1);
+native_fn!(A1, A2);
+native_fn!(A1, A2, A3);
+native_fn!(A1, A2, A3, A4);
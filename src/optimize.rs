@@ -0,0 +1,207 @@
+// Passes that simplify a compiled program without changing what it does
+
+use std::collections::{HashMap, HashSet};
+
+use crate::stac::{Addr, Block, DataVal, ExecError, Instr, Label, Prog, Struct};
+
+// Replaces every load of a variable with a constant value. The variable's only
+// store must be of a constant of the same type, which is replaced too.
+pub fn bind_constant(prog: &mut Prog, var: Addr, val: &DataVal) -> Result<(), String> {
+    let mut stores = vec![];
+    for (b, block) in prog.code.iter().enumerate() {
+        for (i, instr) in block.code.iter().enumerate() {
+            if matches!(instr, Instr::StoreIdent { i: a } if *a == var) {
+                stores.push((b, i));
+            }
+        }
+    }
+
+    let decl = match stores[..] {
+        [(b, i)] if i > 0 => match &mut prog.code[b].code[i - 1] {
+            Instr::LoadConst { v } => v,
+            _ => return Err("it must be declared with a constant".to_string()),
+        },
+        [] => return Err("it is never assigned".to_string()),
+        _ => return Err("it is assigned more than once".to_string()),
+    };
+    if std::mem::discriminant(decl) != std::mem::discriminant(val) {
+        return Err(format!("it was declared as {}, not {}", decl, val));
+    }
+    *decl = val.clone();
+
+    replace_loads(prog, var, val);
+    return Ok(());
+}
+
+// Replaces an input with a constant value, stored before anything else runs as
+// though it were declared with the value
+pub fn bind_input(prog: &mut Prog, var: Addr, val: &DataVal) {
+    let entry = &mut prog.code[prog.entrypoint.0].code;
+    entry.splice(
+        0..0,
+        [
+            Instr::LoadConst { v: val.clone() },
+            Instr::StoreIdent { i: var },
+        ],
+    );
+    replace_loads(prog, var, val);
+}
+
+fn replace_loads(prog: &mut Prog, var: Addr, val: &DataVal) {
+    for block in &mut prog.code {
+        for instr in &mut block.code {
+            if matches!(instr, Instr::LoadIdent { i } if *i == var) {
+                *instr = Instr::LoadConst { v: val.clone() };
+            }
+        }
+    }
+}
+
+// Evaluates the operations on constants, replacing each with its result. Since
+// a result is a constant too, chains like `1024 * 1024 * 4` fold completely.
+// Operations that fail, such as an overflowing add, are left to fail at runtime.
+pub fn fold_constants(prog: &mut Prog) {
+    let user_structs = prog.user_structs.clone();
+    for block in &mut prog.code {
+        let mut code: Vec<Instr> = vec![];
+        for instr in block.code.drain(..) {
+            let operands = match instr {
+                Instr::BinaryExpr { .. }
+                | Instr::Concat
+                | Instr::FloorDiv
+                | Instr::UnaryExpr { .. }
+                | Instr::ToString { .. }
+                | Instr::Cast { .. } => instr.operands(),
+                _ => 0,
+            };
+            let foldable = operands > 0
+                && code.len() >= operands
+                && code[code.len() - operands..]
+                    .iter()
+                    .all(|i| matches!(i, Instr::LoadConst { v } if !v.is_waiting()));
+            if !foldable {
+                code.push(instr);
+                continue;
+            }
+
+            let mut ops = code.split_off(code.len() - operands);
+            ops.push(instr);
+            match eval(ops.clone(), &user_structs) {
+                Ok(v) => code.push(Instr::LoadConst { v }),
+                Err(_) => code.extend(ops),
+            }
+        }
+        block.code = code;
+    }
+}
+
+// Replaces ifs on a constant with a goto to the branch that is always taken
+pub fn fold_branches(prog: &mut Prog) {
+    for block in &mut prog.code {
+        let mut code: Vec<Instr> = vec![];
+        for instr in block.code.drain(..) {
+            match (&instr, &code[..]) {
+                (
+                    Instr::IfExpr { if_true, if_false },
+                    [.., Instr::LoadConst {
+                        v: DataVal::Bool(b),
+                    }],
+                ) => {
+                    let label = if *b { *if_true } else { *if_false };
+                    code.pop();
+                    if label != Label::CONTINUE {
+                        code.push(Instr::Goto { label });
+                    }
+                }
+                _ => code.push(instr),
+            }
+        }
+        block.code = code;
+    }
+}
+
+// Executes instructions in an empty program, for the value they leave
+fn eval(code: Vec<Instr>, user_structs: &HashMap<String, Struct>) -> Result<DataVal, ExecError> {
+    let mut prog = Prog::without_prelude();
+    prog.code = vec![Block { code }];
+    prog.user_structs = user_structs.clone();
+    prog.execute()?;
+    return prog.pop();
+}
+
+// Where a jump to the label can go instead: past any blocks that only goto
+// another, or nowhere if the block is empty
+fn jump_target(code: &[Block], mut label: Label) -> Label {
+    let mut seen = HashSet::new();
+    while label != Label::CONTINUE && seen.insert(label.0) {
+        match &code[label.0].code[..] {
+            [] => return Label::CONTINUE,
+            [Instr::Goto { label: next }] => label = *next,
+            _ => break,
+        }
+    }
+    return label;
+}
+
+// Points ifs and gotos at where the blocks they jump to would go, leaving those
+// blocks for remove_unreachable. Calls are left alone, as the label of a
+// function is how its calls are tracked.
+pub fn collapse_gotos(prog: &mut Prog) {
+    let original = prog.code.clone();
+    for block in &mut prog.code {
+        let mut code = vec![];
+        for instr in block.code.drain(..) {
+            match instr {
+                Instr::Goto { label } => match jump_target(&original, label) {
+                    // Jumping to a block that does nothing is the same as not jumping
+                    Label::CONTINUE => {}
+                    label => code.push(Instr::Goto { label }),
+                },
+                Instr::IfExpr { if_true, if_false } => code.push(Instr::IfExpr {
+                    if_true: jump_target(&original, if_true),
+                    if_false: jump_target(&original, if_false),
+                }),
+                instr => code.push(instr),
+            }
+        }
+        block.code = code;
+    }
+}
+
+// Removes the blocks that can't be reached from the entrypoint, a function or a
+// test, numbering the rest compactly
+pub fn remove_unreachable(prog: &mut Prog) {
+    let mut reachable = vec![false; prog.code.len()];
+    let mut queue = vec![prog.entrypoint];
+    queue.extend(prog.user_functions.values().map(|f| f.label));
+    queue.extend(prog.tests.iter().map(|t| t.1));
+
+    while let Some(label) = queue.pop() {
+        if label == Label::CONTINUE || reachable[label.0] {
+            continue;
+        }
+        reachable[label.0] = true;
+
+        for instr in &prog.code[label.0].code {
+            match instr {
+                Instr::IfExpr { if_true, if_false } => queue.extend([*if_true, *if_false]),
+                Instr::Goto { label }
+                | Instr::Call { label }
+                | Instr::Loop { label }
+                | Instr::Continue { label, .. } => queue.push(*label),
+                _ => {}
+            }
+        }
+    }
+
+    let mut renumbered = HashMap::new();
+    let mut code = vec![];
+    for (idx, block) in std::mem::take(&mut prog.code).into_iter().enumerate() {
+        if reachable[idx] {
+            renumbered.insert(idx, code.len());
+            code.push(block);
+        }
+    }
+    prog.code = code;
+    prog.relabel(|l| renumbered[&l]);
+}
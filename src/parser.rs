@@ -1,161 +1,905 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
 
 use crate::{
     ast::{self, NullStmt},
-    lexer::{Lexer, Token},
+    lexer::{Lexer, StrPart, Token},
     scope,
     stac::{self, DataType},
+    telemetry,
 };
 
+/// An error in the source of a program, which stops it from being compiled
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub line: i64, // the line of the token the error was found at
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    // The source couldn't be split into tokens, eg. an unterminated string
+    Lexer(String),
+    // A token that doesn't fit the grammar, with the tokens that would have if
+    // there are only a few of them
+    UnexpectedToken {
+        found: Token,
+        expected: Vec<Token>,
+    },
+    // A variable that isn't in scope, and the closest name to it that is
+    UnknownIdentifier {
+        name: String,
+        suggestion: Option<String>,
+    },
+    // A variable declared twice in the same scope
+    Redeclared(String),
+    UnknownFunction(String),
+    // A struct that isn't declared, along with those that are
+    UnknownStruct {
+        name: String,
+        known: Vec<String>,
+    },
+    // A field that the struct doesn't have, along with those it does
+    UnknownField {
+        strct: String,
+        field: String,
+        known: Vec<String>,
+    },
+    // A field given a value twice in the same struct literal
+    DuplicateField {
+        strct: String,
+        field: String,
+    },
+    // A constant that breaks the constraint of the struct field it is assigned to
+    Constraint(String),
+    // A function called before a top level variable it reads is declared
+    UseBeforeInit {
+        func: String,
+        var: String,
+    },
+    // A struct or function defined twice, along with the file and line it was
+    // first defined at, where the file is empty for the file being parsed
+    Duplicate {
+        name: String,
+        first: (String, i64),
+    },
+    // A file that couldn't be imported, eg. because it couldn't be loaded
+    Import(String),
+    // An error in an imported file, at a line of that file
+    Imported {
+        path: String,
+        error: Box<ParseError>,
+    },
+    Type(String),
+    Syntax(String),
+    // A declaration that disagrees with the schema of a provider added to the program
+    SchemaDrift(String),
+    // The program is too large or too deeply nested to compile
+    Limit(String),
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::Lexer(msg) | ParseErrorKind::Constraint(msg) => write!(f, "{}", msg),
+            ParseErrorKind::UnexpectedToken { found, expected } if expected.is_empty() => {
+                write!(f, "syntax error: unexpected {}", found)
+            }
+            ParseErrorKind::UnexpectedToken { found, expected } => {
+                let expected: Vec<String> = expected.iter().map(|t| t.to_string()).collect();
+                write!(
+                    f,
+                    "syntax error: expected {}, found {}",
+                    expected.join(" or "),
+                    found
+                )
+            }
+            ParseErrorKind::UnknownIdentifier {
+                name,
+                suggestion: Some(s),
+            } => write!(f, "unknown identifier: {} (did you mean {}?)", name, s),
+            ParseErrorKind::UnknownIdentifier { name, .. } => {
+                write!(f, "unknown identifier: {}", name)
+            }
+            ParseErrorKind::Redeclared(name) => {
+                write!(
+                    f,
+                    "redeclaration: {} is already declared in this scope",
+                    name
+                )
+            }
+            ParseErrorKind::UnknownFunction(name) => write!(f, "unknown function: {}", name),
+            ParseErrorKind::UnknownStruct { name, known } if known.is_empty() => {
+                write!(f, "unknown struct: {} (no structs are declared)", name)
+            }
+            ParseErrorKind::UnknownStruct { name, known } => write!(
+                f,
+                "unknown struct: {} (declared structs are {})",
+                name,
+                known.join(", ")
+            ),
+            ParseErrorKind::UnknownField {
+                strct,
+                field,
+                known,
+            } => write!(
+                f,
+                "type error: struct {} has no field {} (its fields are {})",
+                strct,
+                field,
+                known.join(", ")
+            ),
+            ParseErrorKind::DuplicateField { strct, field } => write!(
+                f,
+                "type error: field {} is given twice in literal of struct {}",
+                field, strct
+            ),
+            ParseErrorKind::UseBeforeInit { func, var } => write!(
+                f,
+                "use before init: function {} reads variable {} before it is declared",
+                func, var
+            ),
+            ParseErrorKind::Duplicate { name, first } if first.0.is_empty() => write!(
+                f,
+                "duplicate definition: {} is already defined at line {}",
+                name, first.1
+            ),
+            ParseErrorKind::Duplicate { name, first } => write!(
+                f,
+                "duplicate definition: {} is already defined at {} line {}",
+                name, first.0, first.1
+            ),
+            ParseErrorKind::Import(msg) => write!(f, "import error: {}", msg),
+            ParseErrorKind::Imported { path, error } => write!(f, "in {}: {}", path, error),
+            ParseErrorKind::Type(msg) => write!(f, "type error: {}", msg),
+            ParseErrorKind::Syntax(msg) => write!(f, "syntax error: {}", msg),
+            ParseErrorKind::SchemaDrift(msg) => write!(f, "schema drift: {}", msg),
+            ParseErrorKind::Limit(msg) => write!(f, "compilation limit exceeded: {}", msg),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// How deeply expressions and blocks may nest. They're parsed recursively, so
+// this keeps a program from overflowing the stack of the thread parsing it.
+pub const NESTING_LIMIT: usize = 32;
+
+// How many binary operators may be chained in an expression, eg. `a + b + c`,
+// since they're compiled recursively too
+pub const CHAIN_LIMIT: usize = 1000;
+
+/// Reads the source of an imported file from the path it is imported by
+pub type Loader = dyn Fn(&str) -> Result<String, String>;
+
 pub struct Parser {
     lexer: Lexer,
     lookahead: Token,
 
     cur_scope: scope::Scope,
     prog: stac::Prog,
+
+    // The labels of the loops enclosing the current statement, innermost last
+    loops: Vec<Option<String>>,
+    // The name and return types of the function being parsed, if any, and
+    // whether it has a return statement
+    func: Option<(String, Vec<DataType>)>,
+    returned: bool,
+    // The variables and blocks of the program before this unit was parsed
+    prev_unit: (usize, usize),
+
+    // The top level variables read by each function, and the functions called
+    // by top level code along with the number of variables declared before them
+    global_reads: HashMap<String, Vec<ast::Ident>>,
+    top_level_calls: Vec<(String, usize)>,
+    // Struct names used as types, which may be declared later, and the line of each use
+    struct_types: Vec<(String, i64)>,
+
+    // Whether to carry on after a statement fails to parse, and the errors found so far
+    recover: bool,
+    errors: Vec<ParseError>,
+
+    // Set while parsing a condition, where `x {` starts a block rather than a
+    // struct literal, until the next bracketed expression
+    no_struct_literal: bool,
+    // How many expressions and blocks enclose the one being parsed, and how
+    // many operators are chained before it
+    depth: usize,
+    chain: usize,
+
+    // How imported files are read, the path of this unit if it was imported,
+    // and the files being imported to get to this one
+    loader: Option<Rc<Loader>>,
+    file: String,
+    importing: Vec<String>,
+    // The files imported into the program so far, and the file and line each
+    // struct and function was defined at
+    imported: HashSet<String>,
+    definitions: HashMap<String, (String, i64)>,
+    // The name of each variable declared in this unit, see Prog::vars
+    var_addrs: HashMap<String, stac::Addr>,
 }
 
 impl Parser {
     pub fn new(lexer: Lexer) -> Parser {
-        let mut p = Parser {
+        return Parser::with_prog(lexer, stac::Prog::new());
+    }
+
+    // Parses another unit into an existing program, such as one containing the
+    // prelude. Its functions can be called, and its top level code runs first.
+    pub fn with_prog(lexer: Lexer, prog: stac::Prog) -> Parser {
+        // The first token is scanned by program, so that errors lexing it are returned
+        return Parser {
             lexer,
-            prog: stac::Prog::new(),
+            prev_unit: (prog.variables.len(), prog.code.len()),
+            prog,
             cur_scope: scope::Scope::new(None),
-            lookahead: Token::C(' '),
+            lookahead: Token::EOF,
+            loops: vec![],
+            func: None,
+            returned: false,
+            global_reads: HashMap::new(),
+            top_level_calls: vec![],
+            struct_types: vec![],
+            recover: false,
+            errors: vec![],
+            no_struct_literal: false,
+            depth: 0,
+            chain: 0,
+            loader: None,
+            file: String::new(),
+            importing: vec![],
+            imported: HashSet::new(),
+            definitions: HashMap::new(),
+            var_addrs: HashMap::new(),
         };
-        p.next_tok();
-        return p;
     }
 
-    fn match_tok(&mut self, t: Token) {
+    // A parser that can import other files, reading them with the loader. Each
+    // is only imported once, however many units import it.
+    pub fn with_loader(lexer: Lexer, loader: Box<Loader>) -> Parser {
+        let mut par = Parser::new(lexer);
+        par.loader = Some(Rc::from(loader));
+        return par;
+    }
+
+    // A parser that carries on after an error in a statement, skipping to the
+    // end of it, so that every error in the program can be reported at once
+    pub fn with_recovery(lexer: Lexer) -> Parser {
+        let mut par = Parser::new(lexer);
+        par.recover = true;
+        return par;
+    }
+
+    // Every error found by program when recovering, in the order they were found
+    pub fn errors(&self) -> &[ParseError] {
+        return &self.errors;
+    }
+
+    pub fn into_prog(self) -> stac::Prog {
+        return self.prog;
+    }
+
+    // An error at the current token
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        return ParseError {
+            kind,
+            line: self.lexer.line(),
+        };
+    }
+
+    // An error for the current token, which isn't any of the expected ones
+    fn unexpected(&self, expected: Vec<Token>) -> ParseError {
+        return self.error(ParseErrorKind::UnexpectedToken {
+            found: self.lookahead.clone(),
+            expected,
+        });
+    }
+
+    fn match_tok(&mut self, t: Token) -> Result<(), ParseError> {
         if self.lookahead == t {
-            self.next_tok();
+            return self.next_tok();
         } else {
-            panic!(
-                "syntax error: next token didn't match: {:?} where {:?} expected",
-                self.lookahead, t,
-            );
+            return Err(self.unexpected(vec![t]));
         }
     }
 
-    fn next_tok(&mut self) {
-        self.lookahead = self.lexer.scan();
+    fn next_tok(&mut self) -> Result<(), ParseError> {
+        match self.lexer.try_scan() {
+            Ok(t) => {
+                self.lookahead = t;
+                return Ok(());
+            }
+            Err(msg) => return Err(self.error(ParseErrorKind::Lexer(msg))),
+        }
     }
 
-    pub fn program(&mut self) -> &mut stac::Prog {
-        let s = self.stmts();
+    pub fn program(&mut self) -> Result<&mut stac::Prog, ParseError> {
+        let _phase = telemetry::PhaseSpan::enter(telemetry::Phase::Parse, self.prog.trace_id);
+        self.next_tok()?;
+        self.imports()?;
+        self.declare_functions();
+        let s = self.stmts()?;
+        if self.recover {
+            // Statements that failed have already been skipped
+            let checks = self
+                .check_struct_types()
+                .and_then(|_| self.check_global_reads());
+            if let Err(e) = checks {
+                self.errors.push(e);
+            }
+            if !self.errors.is_empty() {
+                return Err(self.errors[0].clone());
+            }
+        } else {
+            self.check_struct_types()?;
+            self.check_global_reads()?;
+        }
         let mut block = stac::Block::new();
+
+        // Run the previous unit's top level code before this one's
+        let (prev_vars, prev_blocks) = self.prev_unit;
+        if prev_blocks > 0 && !self.prog.code[self.prog.entrypoint.0].code.is_empty() {
+            block.add_instr(stac::Instr::Call {
+                label: self.prog.entrypoint,
+            });
+        }
+
         s.emit(&mut self.prog, &mut block);
         self.prog.entrypoint = self.prog.add_block(block);
+        if self.prog.blocks_exceeded() {
+            return Err(self.error(ParseErrorKind::Limit(format!(
+                "a program may have at most {} blocks",
+                stac::BLOCK_LIMIT
+            ))));
+        }
+        for (name, addr) in std::mem::take(&mut self.var_addrs) {
+            self.prog.name_var(name, addr);
+        }
+
+        // Keep the indices of this unit's variables and blocks starting from 0
+        self.prog.move_to_end(prev_vars, prev_blocks);
+
+        return Ok(&mut self.prog);
+    }
+
+    // The imports at the start of the unit, eg. `import "droplets.dmcl";`. Each
+    // is compiled as a unit before this one, so its structs and functions can
+    // be used and its top level code runs first.
+    fn imports(&mut self) -> Result<(), ParseError> {
+        while self.lookahead == Token::Import {
+            self.next_tok()?;
+            let path = match self.lookahead.clone() {
+                Token::String(path) => path,
+                t => {
+                    return Err(self.error(ParseErrorKind::Syntax(format!(
+                        "import path must be a string, found {}",
+                        t
+                    ))))
+                }
+            };
+            self.next_tok()?;
+            if let Err(e) = self.import(&path) {
+                if self.recover {
+                    self.errors.push(e.clone());
+                }
+                return Err(e);
+            }
+            self.match_tok(Token::C(';'))?;
+        }
+
+        // The imported units come before this one, like the prelude
+        self.prev_unit = (self.prog.variables.len(), self.prog.code.len());
+        return Ok(());
+    }
+
+    fn import(&mut self, path: &str) -> Result<(), ParseError> {
+        if self.importing.iter().any(|p| p == path) {
+            return Err(self.error(ParseErrorKind::Import(format!(
+                "import cycle: {} -> {}",
+                self.importing.join(" -> "),
+                path
+            ))));
+        }
+        if self.imported.contains(path) {
+            return Ok(());
+        }
+
+        let loader = match &self.loader {
+            Some(loader) => loader.clone(),
+            None => {
+                return Err(self.error(ParseErrorKind::Import(format!(
+                    "cannot import {}, as the parser has no loader",
+                    path
+                ))))
+            }
+        };
+        let src = loader(path).map_err(|e| {
+            self.error(ParseErrorKind::Import(format!(
+                "cannot import {}: {}",
+                path, e
+            )))
+        })?;
+
+        // The imported unit is parsed into this program, then handed back
+        let prog = std::mem::replace(&mut self.prog, stac::Prog::without_prelude());
+        let mut par = Parser::with_prog(Lexer::from_str(&src), prog);
+        par.loader = Some(loader);
+        par.file = path.to_string();
+        par.importing = self.importing.clone();
+        par.importing.push(path.to_string());
+        par.imported = std::mem::take(&mut self.imported);
+        par.definitions = std::mem::take(&mut self.definitions);
+
+        let res = par.program().map(|_| ());
+        self.prog = par.prog;
+        self.imported = par.imported;
+        self.definitions = par.definitions;
+        self.imported.insert(path.to_string());
+
+        return res.map_err(|e| {
+            self.error(ParseErrorKind::Imported {
+                path: path.to_string(),
+                error: Box::new(e),
+            })
+        });
+    }
+
+    // Records where a struct or function is defined, if it hasn't been already
+    fn define(&mut self, name: String) -> Result<(), ParseError> {
+        if let Some(first) = self.definitions.get(&name) {
+            return Err(self.error(ParseErrorKind::Duplicate {
+                name,
+                first: first.clone(),
+            }));
+        }
+        self.definitions
+            .insert(name, (self.file.clone(), self.lexer.line()));
+        return Ok(());
+    }
+
+    // Declares the signature of every function in the unit before parsing it,
+    // so that functions can be called before they are defined. This lexes the
+    // whole unit ahead of parsing it, and the lexer keeps its tokens until
+    // they're parsed, so even a unit read with from_reader is held in full.
+    // Only the tokens of each signature are copied. Anything malformed is left
+    // for the parser to report when it gets there.
+    fn declare_functions(&mut self) {
+        let mut idx = 0;
+        while let Some(t) = self.token_at(idx) {
+            if *t == Token::EOF {
+                break;
+            }
+            idx += 1;
+            if *t != Token::Func {
+                continue;
+            }
 
-        return &mut self.prog;
+            let mut tokens = vec![];
+            while let Some(t) = self.token_at(idx + tokens.len()) {
+                if matches!(t, Token::EOF | Token::Func | Token::C('{') | Token::C(';')) {
+                    break;
+                }
+                tokens.push(t.clone());
+            }
+            if let Some((name, f)) = signature(&tokens) {
+                self.prog.user_functions.insert(name, f);
+            }
+        }
     }
 
-    fn block(&mut self) -> Box<dyn ast::Stmt> {
-        self.match_tok(Token::C('{'));
+    // The lookahead, or the token idx after it, if it can be lexed
+    fn token_at(&mut self, idx: usize) -> Option<&Token> {
+        if idx == 0 {
+            return Some(&self.lookahead);
+        }
+        return self.lexer.try_peek_n(idx - 1).ok();
+    }
+
+    // Catches struct types that are never declared
+    fn check_struct_types(&self) -> Result<(), ParseError> {
+        for (name, line) in &self.struct_types {
+            if !self.prog.user_structs.contains_key(name) {
+                return Err(ParseError {
+                    kind: self.unknown_struct(name),
+                    line: *line,
+                });
+            }
+        }
+        return Ok(());
+    }
 
+    fn unknown_struct(&self, name: &str) -> ParseErrorKind {
+        let mut known: Vec<String> = self.prog.user_structs.keys().cloned().collect();
+        known.sort();
+        return ParseErrorKind::UnknownStruct {
+            name: name.to_string(),
+            known,
+        };
+    }
+
+    fn unknown_field(&self, strct: String, field: String) -> ParseErrorKind {
+        let names = &self.prog.user_structs[&strct].names;
+        let mut known: Vec<&String> = names.keys().collect();
+        known.sort_by_key(|n| names[*n]);
+        return ParseErrorKind::UnknownField {
+            known: known.into_iter().cloned().collect(),
+            strct,
+            field,
+        };
+    }
+
+    // Catches functions called before a top level variable they read is declared
+    fn check_global_reads(&self) -> Result<(), ParseError> {
+        for (func, declared) in &self.top_level_calls {
+            for id in self.global_reads.get(func).into_iter().flatten() {
+                if id.addr.0 >= *declared {
+                    return Err(self.error(ParseErrorKind::UseBeforeInit {
+                        func: func.clone(),
+                        var: id.name.as_word().unwrap().to_string(),
+                    }));
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Consumes an identifier, such as the name of a variable or struct
+    fn word(&mut self) -> Result<String, ParseError> {
+        match self.lookahead.clone() {
+            Token::Word(w) => {
+                self.next_tok()?;
+                return Ok(w.to_string());
+            }
+            t => {
+                return Err(self.error(ParseErrorKind::Syntax(format!(
+                    "expected a name, found {}",
+                    t
+                ))))
+            }
+        }
+    }
+
+    // Adds a variable to the current scope, unless it already has one of that name
+    fn declare(&mut self, id: ast::Ident) -> Result<(), ParseError> {
+        let func = self.func.as_ref().map(|f| f.0.clone());
+        return self.declare_in(id, func);
+    }
+
+    // Declares a variable of the function, or the top level if there is none.
+    // When a name is declared again in a nested block, the first keeps the name.
+    fn declare_in(&mut self, id: ast::Ident, func: Option<String>) -> Result<(), ParseError> {
+        let name = id.name.as_word().unwrap().to_string();
+        if !self.cur_scope.declare(id.name.clone(), id.clone()) {
+            return Err(self.error(ParseErrorKind::Redeclared(name)));
+        }
+
+        let qualified = match func {
+            Some(func) => format!("{}.{}", func, name),
+            None => name,
+        };
+        self.var_addrs.entry(qualified).or_insert(id.addr);
+        return Ok(());
+    }
+
+    fn lookup(&self, id_tok: &Token) -> Result<ast::Ident, ParseError> {
+        match self.cur_scope.get(id_tok.clone()) {
+            Some(id) => return Ok(id),
+            None => {
+                let name = id_tok.as_word().unwrap();
+                return Err(self.error(ParseErrorKind::UnknownIdentifier {
+                    name: name.to_string(),
+                    suggestion: self.cur_scope.closest(name),
+                }));
+            }
+        }
+    }
+
+    // Checks the arguments of a call match the params of the function
+    fn check_args(&self, func: &str, args: &[Box<dyn ast::Expr>]) -> Result<(), ParseError> {
+        let params = &self.prog.user_functions[func].params;
+        if args.len() != params.len() {
+            return Err(self.error(ParseErrorKind::Type(format!(
+                "{} expects {} argument{}, found {}",
+                func,
+                params.len(),
+                if params.len() == 1 { "" } else { "s" },
+                args.len()
+            ))));
+        }
+
+        for (idx, (arg, param)) in args.iter().zip(params).enumerate() {
+            let typ = arg.out_type(&self.prog);
+            if typ != *param {
+                return Err(self.error(ParseErrorKind::Type(format!(
+                    "argument {} of {} must be {}, found {}",
+                    idx + 1,
+                    func,
+                    param,
+                    typ
+                ))));
+            }
+        }
+        return Ok(());
+    }
+
+    fn note_call(&mut self, func: &str) {
+        if self.func.is_none() {
+            self.top_level_calls
+                .push((func.to_string(), self.prog.variables.len()));
+        }
+    }
+
+    fn block(&mut self) -> Result<Box<dyn ast::Stmt>, ParseError> {
+        self.match_tok(Token::C('{'))?;
+
+        self.push_scope();
+        let s = self.nested(|par| par.stmts())?;
+        self.pop_scope();
+
+        self.match_tok(Token::C('}'))?;
+
+        return Ok(s);
+    }
+
+    // Parses an expression or block inside the current one, unless they're
+    // already nested as deeply as they may be
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        if self.depth >= NESTING_LIMIT {
+            return Err(self.error(ParseErrorKind::Limit(format!(
+                "expressions and blocks may be nested at most {} deep",
+                NESTING_LIMIT
+            ))));
+        }
+        self.depth += 1;
+        let res = parse(self);
+        self.depth -= 1;
+        return res;
+    }
+
+    // Matches a binary operator, chaining the operand after it onto the ones
+    // before it, unless the chain is already as long as it may be
+    fn operator(&mut self) -> Result<(), ParseError> {
+        if self.chain >= CHAIN_LIMIT {
+            return Err(self.error(ParseErrorKind::Limit(format!(
+                "expressions may chain at most {} operators",
+                CHAIN_LIMIT
+            ))));
+        }
+        self.chain += 1;
+        return self.next_tok();
+    }
+
+    fn push_scope(&mut self) {
         // Replace the current scope with a null one, then set the current scope to a new one
         // containing the previous.
         let prev = std::mem::replace(&mut self.cur_scope, scope::Scope::new(None));
         self.cur_scope = scope::Scope::new(Some(Box::new(prev)));
+    }
 
-        let s = self.stmts();
-
+    fn pop_scope(&mut self) {
         // Replace the current scope with a null one, then set the current scope to the previous one.
         let cur = std::mem::replace(&mut self.cur_scope, scope::Scope::new(None));
         self.cur_scope = cur.take_prev();
+    }
 
-        self.match_tok(Token::C('}'));
+    // The statements up to the end of the block or program, parsed in a loop
+    // so that long programs don't need a deep stack
+    fn stmts(&mut self) -> Result<Box<dyn ast::Stmt>, ParseError> {
+        let mut stmts = vec![];
+        while self.lookahead != Token::C('}') && self.lookahead != Token::EOF {
+            if self.recover {
+                stmts.push(self.recovering_stmt()?);
+            } else {
+                stmts.push(self.stmt()?);
+            }
+        }
+        return Ok(Box::new(ast::Seq { stmts }));
+    }
+
+    // Parses a statement, or records the error in it and skips past it
+    fn recovering_stmt(&mut self) -> Result<Box<dyn ast::Stmt>, ParseError> {
+        let depth = self.cur_scope.depth();
+        let loops = self.loops.clone();
+        let func = self.func.clone();
+        let (nesting, chain) = (self.depth, self.chain);
+
+        match self.stmt() {
+            Ok(stmt) => return Ok(stmt),
+            Err(e) => {
+                self.errors.push(e);
+
+                // Leave any scopes, loops and functions the statement was in the middle of
+                while self.cur_scope.depth() > depth {
+                    self.pop_scope();
+                }
+                self.loops = loops;
+                self.func = func;
+                self.depth = nesting;
+                self.chain = chain;
 
-        return s;
+                self.synchronize()?;
+                return Ok(Box::new(NullStmt {}));
+            }
+        }
     }
 
-    fn stmts(&mut self) -> Box<dyn ast::Stmt> {
-        if self.lookahead == Token::C('}') || self.lookahead == Token::EOF {
-            return Box::new(ast::NullStmt {});
-        } else {
-            return Box::new(ast::Seq {
-                stmt1: self.stmt(),
-                stmt2: self.stmts(),
-            });
+    // Skips to the end of the current statement: the next ';', or the '}'
+    // closing a block within it. The '}' ending an enclosing block is left for
+    // it to match.
+    fn synchronize(&mut self) -> Result<(), ParseError> {
+        let mut depth = 0;
+        loop {
+            match self.lookahead {
+                Token::EOF => return Ok(()),
+                Token::C(';') if depth == 0 => return self.next_tok(),
+                Token::C('{') => depth += 1,
+                Token::C('}') if depth == 0 => {
+                    if self.cur_scope.prev.is_some() {
+                        return Ok(());
+                    }
+                    // A stray '}' at the top level would end the program
+                    return self.next_tok();
+                }
+                Token::C('}') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return self.next_tok();
+                    }
+                }
+                _ => (),
+            }
+            self.next_tok()?;
         }
     }
 
-    fn stmt(&mut self) -> Box<dyn ast::Stmt> {
+    fn stmt(&mut self) -> Result<Box<dyn ast::Stmt>, ParseError> {
         match self.lookahead {
             Token::C(';') => {
-                self.next_tok();
-                return Box::new(NullStmt {});
+                self.next_tok()?;
+                return Ok(Box::new(NullStmt {}));
             }
             Token::If => {
-                self.next_tok();
-                let e = self.bool();
-                let s_t = self.block();
+                self.next_tok()?;
+                let e = self.cond()?;
+                let s_t = self.block()?;
                 if self.lookahead != Token::Else {
-                    return Box::new(ast::If { expr: e, stmt: s_t });
+                    return Ok(Box::new(ast::If { expr: e, stmt: s_t }));
                 }
 
-                self.next_tok();
-                let s_f = self.block();
-                return Box::new(ast::IfElse {
+                self.next_tok()?;
+                let s_f = if self.lookahead == Token::If {
+                    // An else if chain, nesting the rest of the chain in the else
+                    self.nested(|par| par.stmt())?
+                } else {
+                    self.block()?
+                };
+                return Ok(Box::new(ast::IfElse {
                     expr: e,
                     stmt_t: s_t,
                     stmt_f: s_f,
-                });
+                }));
             }
             Token::While => {
-                self.next_tok();
-                let e = self.bool();
-                let body = self.block();
-                return Box::new(ast::While {
-                    expr: e,
-                    stmt: body,
-                });
+                self.next_tok()?;
+                return self.while_loop(None);
+            }
+            Token::Match => {
+                self.next_tok()?;
+                return self.match_stmt();
+            }
+            Token::For => {
+                self.next_tok()?;
+                return self.for_loop(None);
+            }
+            Token::Var => {
+                self.next_tok()?;
+                let stmt = self.var_decl()?;
+                self.match_tok(Token::C(';'))?;
+
+                return Ok(stmt);
+            }
+            Token::Input => {
+                // A variable set by the host before execution, eg. `input region: string;`
+                if self.func.is_some() || self.cur_scope.depth() > 0 {
+                    return Err(self.error(ParseErrorKind::Syntax(
+                        "inputs must be declared at the top level".into(),
+                    )));
+                }
+                self.next_tok()?;
+                let name = self.word()?;
+                self.match_tok(Token::C(':'))?;
+                let data_type = self.data_type()?;
+                self.next_tok()?;
+                self.check_type_exists(&data_type)?;
+                self.match_tok(Token::C(';'))?;
+
+                let id = ast::Ident {
+                    addr: self.prog.allocate_var(&name),
+                    name: Token::Word(name.as_str().into()),
+                    data_type: data_type.clone(),
+                };
+                self.declare(id)?;
+                self.prog.declare_input(name, data_type);
+                return Ok(Box::new(ast::NullStmt {}));
+            }
+            Token::Break => {
+                self.next_tok()?;
+                let loops = self.loop_target("break")?;
+                self.match_tok(Token::C(';'))?;
+
+                return Ok(Box::new(ast::Break { loops }));
+            }
+            Token::Continue => {
+                self.next_tok()?;
+                let loops = self.loop_target("continue")?;
+                self.match_tok(Token::C(';'))?;
+
+                return Ok(Box::new(ast::Continue { loops }));
             }
             Token::Func => {
-                self.next_tok();
+                self.next_tok()?;
 
                 match self.lookahead {
                     Token::Extern => {
                         // Extern function
-                        self.next_tok();
+                        self.next_tok()?;
 
-                        let name = self.lookahead.clone();
-                        self.next_tok();
+                        let name = self.word()?;
+                        let line = self.lexer.line();
+                        self.define(format!("function {}", name))?;
 
-                        self.match_tok(Token::C('('));
+                        self.match_tok(Token::C('('))?;
                         let params: Vec<stac::DataType> = self
-                            .decl_list(Token::C(')'))
+                            .decl_list(Token::C(')'))?
                             .iter()
                             .map(|p| {
                                 return p.1.clone();
                             })
                             .collect();
-                        self.match_tok(Token::C(')'));
-
-                        let returns = self.type_list();
+                        self.match_tok(Token::C(')'))?;
+
+                        let returns = self.type_list()?;
+                        if let Err(msg) = self.prog.check_schema_function(&name, &params, &returns)
+                        {
+                            return Err(ParseError {
+                                kind: ParseErrorKind::SchemaDrift(msg),
+                                line,
+                            });
+                        }
 
                         // Assign the func to the name
                         self.prog.user_functions.insert(
-                            name.clone().into_word().unwrap(),
+                            name.clone(),
                             stac::Function {
                                 label: stac::Label::CONTINUE,
                                 params: params.clone(),
                                 returns: returns.clone(),
+                                locals: vec![],
                             },
                         );
 
-                        return Box::new(ast::func::ExternFuncImpl {
-                            name: name.into_word().unwrap(),
+                        return Ok(Box::new(ast::func::ExternFuncImpl {
+                            name,
                             param_types: params,
                             return_types: returns,
-                        });
+                        }));
                     }
                     _ => {
                         // Regular function
 
-                        let name = self.lookahead.clone();
-                        self.next_tok();
+                        let name = self.word()?;
+                        self.define(format!("function {}", name))?;
 
                         // Create new scope from previous
                         self.cur_scope = scope::Scope::new(Some(Box::new(std::mem::replace(
@@ -164,469 +908,1515 @@ impl Parser {
                         ))));
 
                         // Parse the function signature
-                        self.match_tok(Token::C('('));
-                        let params: Vec<ast::Ident> = self
-                            .decl_list(Token::C(')'))
-                            .iter()
-                            .map(|p| {
-                                let ident = ast::Ident {
-                                    name: p.0.clone(),
-                                    data_type: p.1.clone(),
-                                    addr: self.prog.allocate_var(),
-                                };
-                                self.cur_scope.put(p.0.clone(), ident.clone());
-                                return ident;
-                            })
-                            .collect();
-                        self.match_tok(Token::C(')'));
-
-                        let returns = self.type_list();
+                        let first_local = self.prog.variables.len();
+                        self.match_tok(Token::C('('))?;
+                        let mut params: Vec<ast::Ident> = vec![];
+                        for (param, data_type) in self.decl_list(Token::C(')'))? {
+                            let ident = ast::Ident {
+                                addr: self.prog.allocate_var(param.as_word().unwrap()),
+                                name: param,
+                                data_type,
+                            };
+                            self.declare_in(ident.clone(), Some(name.clone()))?;
+                            params.push(ident);
+                        }
+                        self.match_tok(Token::C(')'))?;
 
-                        // Parse the function body
-                        let body = self.block();
+                        let returns = self.type_list()?;
 
                         // Create the data type for the function
                         let param_types: Vec<DataType> =
                             params.iter().map(|p| p.data_type.clone()).collect();
 
-                        // pop the func scope
-                        self.cur_scope =
-                            std::mem::replace(&mut self.cur_scope, scope::Scope::new(None))
-                                .take_prev();
-
-                        // Assign the func to the name
+                        // Assign the func to the name before the body, so it can call itself
                         self.prog.user_functions.insert(
-                            name.clone().into_word().unwrap(),
+                            name.clone(),
                             stac::Function {
                                 label: stac::Label::CONTINUE,
                                 params: param_types,
-                                returns,
+                                returns: returns.clone(),
+                                locals: vec![],
                             },
                         );
 
+                        // Parse the function body, which can't break out of loops around it.
+                        // It shares the scope of the parameters, so they can't be redeclared.
+                        let outer_loops = std::mem::take(&mut self.loops);
+                        let outer_func = self.func.replace((name.clone(), returns.clone()));
+                        let outer_returned = std::mem::replace(&mut self.returned, false);
+                        self.match_tok(Token::C('{'))?;
+                        let body = self.stmts()?;
+                        self.match_tok(Token::C('}'))?;
+                        let returned = std::mem::replace(&mut self.returned, outer_returned);
+                        self.loops = outer_loops;
+                        self.func = outer_func;
+
+                        // Every variable allocated since the params is local to the function
+                        self.prog.user_functions.get_mut(&name).unwrap().locals = (first_local
+                            ..self.prog.variables.len())
+                            .map(stac::Addr)
+                            .collect();
+
+                        if !returns.is_empty() && !returned {
+                            return Err(self.error(ParseErrorKind::Type(format!(
+                                "function {} must return ({}), but has no return statement",
+                                name,
+                                type_names(&returns)
+                            ))));
+                        }
+
+                        // pop the func scope
+                        self.cur_scope =
+                            std::mem::replace(&mut self.cur_scope, scope::Scope::new(None))
+                                .take_prev();
+
                         // Return the function
-                        return Box::new(ast::func::FuncImpl {
-                            name: name.into_word().unwrap(),
-                            body,
-                            params,
-                        });
+                        return Ok(Box::new(ast::func::FuncImpl { name, body, params }));
                     }
                 }
             }
             Token::Return => {
-                self.next_tok();
+                self.next_tok()?;
+
+                // Collect the return values
+                let values = self.bool_list(Token::C(';'))?;
+                self.next_tok()?;
+
+                if let Some((name, returns)) = &self.func {
+                    if returns.is_empty() && !values.is_empty() {
+                        return Err(self.error(ParseErrorKind::Type(format!(
+                            "function {} has no return values",
+                            name
+                        ))));
+                    }
 
-                // Collect the parameters
-                let mut values = vec![];
-                while self.lookahead.clone() != Token::C(';') {
-                    values.push(self.bool());
+                    let types: Vec<DataType> =
+                        values.iter().map(|v| v.out_type(&self.prog)).collect();
+                    if types != *returns {
+                        return Err(self.error(ParseErrorKind::Type(format!(
+                            "function {} must return ({}), found ({})",
+                            name,
+                            type_names(returns),
+                            type_names(&types)
+                        ))));
+                    }
+                    self.returned = true;
                 }
-                self.next_tok();
 
-                return Box::new(ast::func::Return { values });
+                return Ok(Box::new(ast::func::Return { values }));
             }
-            Token::Struct => {
-                self.next_tok();
-                let name = self.lookahead.clone();
-                self.next_tok();
+            Token::Assert => {
+                let line = self.lexer.line();
+                self.next_tok()?;
+                let expr = self.bool()?;
+                if expr.out_type(&self.prog) != DataType::Bool {
+                    return Err(self.error(ParseErrorKind::Type("can only assert a bool".into())));
+                }
+
+                // An optional message describing the assertion
+                let msg = if self.lookahead == Token::C(',') {
+                    self.next_tok()?;
+                    let msg = match self.lookahead.clone() {
+                        Token::String(msg) => msg,
+                        t => {
+                            return Err(self.error(ParseErrorKind::Syntax(format!(
+                                "assert message must be a string, found {}",
+                                t
+                            ))))
+                        }
+                    };
+                    self.next_tok()?;
+                    msg
+                } else {
+                    format!("assertion on line {}", line)
+                };
+                self.match_tok(Token::C(';'))?;
 
-                self.match_tok(Token::C('{'));
-                let fields = self.decl_list(Token::C('}'));
-                self.match_tok(Token::C('}'));
+                return Ok(Box::new(ast::test::Assert { expr, msg }));
+            }
+            Token::Struct => {
+                self.next_tok()?;
+                let name = self.word()?;
+                let line = self.lexer.line();
+                self.define(format!("struct {}", name))?;
 
+                self.match_tok(Token::C('{'))?;
                 let mut types = vec![];
                 let mut names = HashMap::new();
-                for (idx, field) in fields.iter().enumerate() {
-                    types.push(field.1.clone());
-                    names.insert(field.0.clone().into_word().unwrap(), idx);
+                let mut constraints = vec![];
+                let mut ids = vec![];
+                while self.lookahead != Token::C('}') {
+                    let field = self.word()?;
+                    self.match_tok(Token::C(':'))?;
+
+                    let data_type = self.data_type()?;
+                    self.next_tok()?;
+
+                    ids.push(stac::field_id(&field));
+                    names.insert(field, types.len());
+                    constraints.push(self.constraint(&data_type)?);
+                    types.push(data_type);
+                    self.separator(&Token::C('}'))?;
                 }
+                self.match_tok(Token::C('}'))?;
 
-                self.prog
-                    .user_structs
-                    .insert(name.into_word().unwrap(), stac::Struct { types, names });
+                let strct = stac::Struct {
+                    types,
+                    names,
+                    constraints,
+                    ids,
+                };
+                if let Err(msg) = self.prog.check_schema_struct(&name, &strct) {
+                    return Err(ParseError {
+                        kind: ParseErrorKind::SchemaDrift(msg),
+                        line,
+                    });
+                }
+                self.prog.user_structs.insert(name, strct);
 
-                return Box::new(ast::NullStmt {});
+                return Ok(Box::new(ast::NullStmt {}));
             }
             Token::C('{') => return self.block(),
             _ => return self.assign(),
         }
     }
 
+    // A declaration with an explicit type, eg. `var count: int` or
+    // `var name: string = "x"`. Caller is responsible for the var token.
+    fn var_decl(&mut self) -> Result<Box<dyn ast::Stmt>, ParseError> {
+        let name = Token::Word(self.word()?.into());
+        self.match_tok(Token::C(':'))?;
+        let data_type = self.data_type()?;
+        self.next_tok()?;
+        self.check_type_exists(&data_type)?;
+
+        let expr: Box<dyn ast::Expr> = if self.lookahead == Token::C('=') {
+            self.next_tok()?;
+            let expr = self.bool()?;
+            self.check_assign(name.as_word().unwrap(), &data_type, &*expr)?;
+            expr
+        } else {
+            Box::new(ast::Const {
+                value: stac::DataVal::default_for(data_type.clone(), &self.prog.user_structs),
+                data_type: data_type.clone(),
+            })
+        };
+
+        let id = ast::Ident {
+            addr: self.prog.allocate_var(name.as_word().unwrap()),
+            name: name.clone(),
+            data_type,
+        };
+        self.declare(id.clone())?;
+
+        return Ok(Box::new(ast::Assign { id, expr }));
+    }
+
+    // Catches a value being stored in a variable of another type
+    fn check_assign(
+        &self,
+        name: &str,
+        data_type: &DataType,
+        expr: &dyn ast::Expr,
+    ) -> Result<(), ParseError> {
+        // Values waiting on an external resource take the type of the variable
+        let found = expr.out_type(&self.prog);
+        if found != *data_type && found != DataType::Waiting {
+            return Err(self.error(ParseErrorKind::Type(format!(
+                "cannot assign {} to {} of type {}",
+                found, name, data_type
+            ))));
+        }
+        return Ok(());
+    }
+
+    // Catches struct types that haven't been declared yet, for when their
+    // fields are needed straight away
+    fn check_type_exists(&self, data_type: &DataType) -> Result<(), ParseError> {
+        match data_type {
+            DataType::Struct(name) if !self.prog.user_structs.contains_key(name) => {
+                return Err(self.error(self.unknown_struct(name)))
+            }
+            DataType::Array(el) | DataType::Map(el) => return self.check_type_exists(el),
+            _ => return Ok(()),
+        }
+    }
+
+    // A match on an int or string, eg. `match state { "active" { ... } _ { ... } }`.
+    // Caller is responsible for the match token.
+    fn match_stmt(&mut self) -> Result<Box<dyn ast::Stmt>, ParseError> {
+        let expr = self.cond()?;
+        let data_type = expr.out_type(&self.prog);
+        if data_type != DataType::Integer && data_type != DataType::String {
+            return Err(self.error(ParseErrorKind::Type(format!(
+                "can only match on int or string, found {}",
+                data_type
+            ))));
+        }
+        let tmp = ast::Ident {
+            addr: self.prog.allocate_var("match"),
+            name: Token::Match,
+            data_type: data_type.clone(),
+        };
+
+        self.match_tok(Token::C('{'))?;
+        let mut arms: Vec<(ast::Const, Box<dyn ast::Stmt>)> = vec![];
+        let mut default = None;
+        while self.lookahead != Token::C('}') {
+            if default.is_some() {
+                return Err(self.error(ParseErrorKind::Syntax(
+                    "the _ arm must be the last arm of a match".into(),
+                )));
+            }
+            if self.lookahead == Token::C('_') {
+                self.next_tok()?;
+                default = Some(self.block()?);
+                continue;
+            }
+
+            let (value, typ) = self.constant()?;
+            if typ != data_type {
+                return Err(self.error(ParseErrorKind::Type(format!(
+                    "match arm {:?} must be {}",
+                    value, data_type
+                ))));
+            }
+            if arms.iter().any(|(c, _)| c.value == value) {
+                return Err(self.error(ParseErrorKind::Syntax(format!(
+                    "duplicate match arm {:?}",
+                    value
+                ))));
+            }
+            let stmt = self.block()?;
+            arms.push((
+                ast::Const {
+                    value,
+                    data_type: typ,
+                },
+                stmt,
+            ));
+        }
+        self.next_tok()?;
+
+        if arms.is_empty() {
+            return Err(self.error(ParseErrorKind::Syntax(
+                "a match needs at least one arm other than _".into(),
+            )));
+        }
+        return Ok(Box::new(ast::Match {
+            expr,
+            tmp,
+            arms,
+            default,
+        }));
+    }
+
+    // Caller is responsible for the while token, and any label before it
+    fn while_loop(&mut self, label: Option<String>) -> Result<Box<dyn ast::Stmt>, ParseError> {
+        let e = self.cond()?;
+
+        self.loops.push(label);
+        let body = self.block()?;
+        self.loops.pop();
+
+        let completed = if self.lookahead == Token::Completed {
+            self.next_tok()?;
+            Some(self.block()?)
+        } else {
+            None
+        };
+
+        return Ok(Box::new(ast::While {
+            expr: e,
+            stmt: body,
+            post: None,
+            completed,
+        }));
+    }
+
+    // The number of enclosing loops up to the innermost loop, or the loop with the label
+    fn loop_target(&mut self, stmt: &str) -> Result<usize, ParseError> {
+        match self.lookahead.clone() {
+            Token::Word(label) => {
+                self.next_tok()?;
+                match self
+                    .loops
+                    .iter()
+                    .rev()
+                    .position(|l| l.as_deref() == Some(&*label))
+                {
+                    Some(idx) => return Ok(idx + 1),
+                    None => {
+                        return Err(self.error(ParseErrorKind::Syntax(format!(
+                            "no enclosing loop labelled {}",
+                            label
+                        ))))
+                    }
+                }
+            }
+            _ => {
+                if self.loops.is_empty() {
+                    return Err(
+                        self.error(ParseErrorKind::Syntax(format!("{} outside of loop", stmt)))
+                    );
+                }
+                return Ok(1);
+            }
+        }
+    }
+
+    // A for loop is a while loop in its own scope, with the init statement
+    // before it and the post statement after each iteration
+    fn for_loop(&mut self, label: Option<String>) -> Result<Box<dyn ast::Stmt>, ParseError> {
+        self.push_scope();
+
+        let init = if self.lookahead == Token::C(';') {
+            Box::new(NullStmt {})
+        } else {
+            self.simple_stmt()?
+        };
+        self.match_tok(Token::C(';'))?;
+
+        let e = self.bool()?;
+        self.match_tok(Token::C(';'))?;
+
+        let post = if self.lookahead == Token::C('{') {
+            None
+        } else {
+            // The post statement is followed by the body, like a condition
+            let prev = std::mem::replace(&mut self.no_struct_literal, true);
+            let post = self.simple_stmt();
+            self.no_struct_literal = prev;
+            Some(post?)
+        };
+
+        self.loops.push(label);
+        let body = self.block()?;
+        self.loops.pop();
+
+        let completed = if self.lookahead == Token::Completed {
+            self.next_tok()?;
+            Some(self.block()?)
+        } else {
+            None
+        };
+
+        self.pop_scope();
+
+        return Ok(Box::new(ast::Seq {
+            stmts: vec![
+                init,
+                Box::new(ast::While {
+                    expr: e,
+                    stmt: body,
+                    post,
+                    completed,
+                }),
+            ],
+        }));
+    }
+
     // Caller is responsible for the start and end token ()/[]
-    fn bool_list(&mut self, end_tok: Token) -> Vec<Box<dyn ast::Expr>> {
+    fn bool_list(&mut self, end_tok: Token) -> Result<Vec<Box<dyn ast::Expr>>, ParseError> {
         let mut list = vec![];
 
         while self.lookahead != end_tok {
-            if self.lookahead == Token::C(',') {
-                self.next_tok();
-            }
-
-            list.push(self.bool());
+            list.push(self.nested_bool()?);
+            self.separator(&end_tok)?;
         }
 
-        return list;
+        return Ok(list);
     }
 
     // Caller is responsible for the start and end token ()/[]
-    fn decl_list(&mut self, end_tok: Token) -> Vec<(Token, DataType)> {
+    fn decl_list(&mut self, end_tok: Token) -> Result<Vec<(Token, DataType)>, ParseError> {
         let mut list = Vec::new();
 
         while self.lookahead != end_tok {
-            if self.lookahead == Token::C(',') {
-                self.next_tok();
+            let name = Token::Word(self.word()?.into());
+            self.match_tok(Token::C(':'))?;
+
+            let data_type = self.data_type()?;
+            self.next_tok()?;
+
+            list.push((name, data_type));
+            self.separator(&end_tok)?;
+        }
+
+        return Ok(list);
+    }
+
+    // Consumes the comma after an element of a list, which can only be left
+    // out after the last element
+    fn separator(&mut self, end_tok: &Token) -> Result<(), ParseError> {
+        if self.lookahead == Token::C(',') {
+            return self.next_tok();
+        } else if self.lookahead != *end_tok {
+            return Err(self.unexpected(vec![Token::C(','), end_tok.clone()]));
+        }
+        return Ok(());
+    }
+
+    // An optional constraint following the type of a struct field, eg.
+    // `in ["a", "b"]`, `in 1..10` or `matches "^[a-z]+$"`
+    fn constraint(&mut self, data_type: &DataType) -> Result<Option<stac::Constraint>, ParseError> {
+        match self.lookahead {
+            Token::In => {
+                self.next_tok()?;
+                if self.lookahead == Token::C('[') {
+                    self.next_tok()?;
+                    let mut vals = vec![];
+                    while self.lookahead != Token::C(']') {
+                        let (val, typ) = self.constant()?;
+                        if typ != *data_type {
+                            return Err(self.error(ParseErrorKind::Type(format!(
+                                "constraint value {:?} must be {}",
+                                val, data_type
+                            ))));
+                        }
+                        vals.push(val);
+                        self.separator(&Token::C(']'))?;
+                    }
+                    self.next_tok()?;
+
+                    return Ok(Some(stac::Constraint::OneOf(vals)));
+                }
+
+                if *data_type != DataType::Integer {
+                    return Err(self.error(ParseErrorKind::Type(
+                        "only int fields can be constrained to a range".into(),
+                    )));
+                }
+                let lo = self.int_constant()?;
+                self.match_tok(Token::Range)?;
+                let hi = self.int_constant()?;
+
+                return Ok(Some(stac::Constraint::Range(lo, hi)));
             }
+            Token::Matches => {
+                self.next_tok()?;
+                if *data_type != DataType::String {
+                    return Err(self.error(ParseErrorKind::Type(
+                        "only string fields can be matched against a regex".into(),
+                    )));
+                }
 
-            let name = match self.lookahead.clone() {
-                Token::Word(w) => Token::Word(w),
-                _ => panic!("syntax error: decl must have identifier"),
-            };
-            self.next_tok();
-            self.match_tok(Token::C(':'));
+                let pattern = match self.lookahead.clone() {
+                    Token::String(s) => s,
+                    _ => {
+                        return Err(self.error(ParseErrorKind::Syntax(
+                            "matches must be followed by a string".into(),
+                        )))
+                    }
+                };
 
-            let data_type = self.data_type();
-            self.next_tok();
+                match regex::Regex::new(&pattern) {
+                    Ok(re) => {
+                        self.next_tok()?;
+                        return Ok(Some(stac::Constraint::Matches(re)));
+                    }
+                    Err(e) => {
+                        return Err(self.error(ParseErrorKind::Syntax(format!(
+                            "invalid regex {:?}: {}",
+                            pattern, e
+                        ))))
+                    }
+                }
+            }
+            _ => return Ok(None),
+        }
+    }
 
-            list.push((name, data_type));
+    // A literal value, with an optional minus for numbers
+    fn constant(&mut self) -> Result<(stac::DataVal, DataType), ParseError> {
+        let negative = self.lookahead == Token::C('-');
+        if negative {
+            self.next_tok()?;
         }
 
-        return list;
+        let c = match self.lookahead.clone() {
+            Token::Integer(i) if negative => (stac::DataVal::Integer(-i), DataType::Integer),
+            Token::Integer(i) => (stac::DataVal::Integer(i), DataType::Integer),
+            Token::Float(f) if negative => (stac::DataVal::Float(-f), DataType::Float),
+            Token::Float(f) => (stac::DataVal::Float(f), DataType::Float),
+            Token::String(s) if !negative => (stac::DataVal::String(s.into()), DataType::String),
+            Token::True if !negative => (stac::DataVal::Bool(true), DataType::Bool),
+            Token::False if !negative => (stac::DataVal::Bool(false), DataType::Bool),
+            t => {
+                return Err(self.error(ParseErrorKind::Syntax(format!(
+                    "expected a constant, found {}",
+                    t
+                ))))
+            }
+        };
+        self.next_tok()?;
+
+        return Ok(c);
+    }
+
+    fn int_constant(&mut self) -> Result<i64, ParseError> {
+        match self.constant()? {
+            (stac::DataVal::Integer(i), _) => return Ok(i),
+            (v, _) => {
+                return Err(self.error(ParseErrorKind::Type(format!(
+                    "range bound {:?} must be int",
+                    v
+                ))))
+            }
+        }
     }
 
-    fn type_list(&mut self) -> Vec<DataType> {
-        self.match_tok(Token::C('('));
+    fn type_list(&mut self) -> Result<Vec<DataType>, ParseError> {
+        self.match_tok(Token::C('('))?;
 
         let mut list = Vec::new();
 
         while self.lookahead != Token::C(')') {
-            if self.lookahead == Token::C(',') {
-                self.next_tok();
-            }
+            list.push(self.data_type()?);
+            self.next_tok()?;
+            self.separator(&Token::C(')'))?;
+        }
+        self.next_tok()?;
 
-            list.push(self.data_type());
-            self.next_tok();
+        return Ok(list);
+    }
+
+    // The arguments of make([]T, n) or make([]T, n, fill), after the '('
+    fn make(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let typ = self.data_type()?;
+        let el_type = match &typ {
+            DataType::Array(el_type) => *el_type.clone(),
+            t => {
+                return Err(self.error(ParseErrorKind::Type(format!(
+                    "make expects an array type, found {:?}",
+                    t
+                ))))
+            }
+        };
+        self.next_tok()?;
+        self.match_tok(Token::C(','))?;
+        let len = self.bool()?;
+        if len.out_type(&self.prog) != DataType::Integer {
+            return Err(self.error(ParseErrorKind::Type("make expects an int length".into())));
         }
-        self.next_tok();
 
-        return list;
+        let fill = if self.lookahead == Token::C(',') {
+            self.next_tok()?;
+            let fill = self.bool()?;
+            if fill.out_type(&self.prog) != el_type {
+                return Err(self.error(ParseErrorKind::Type(format!(
+                    "make fill value must be {:?}, found {:?}",
+                    el_type,
+                    fill.out_type(&self.prog)
+                ))));
+            }
+            Some(fill)
+        } else {
+            None
+        };
+        self.match_tok(Token::C(')'))?;
+
+        return Ok(Box::new(ast::builtin::Make { typ, len, fill }));
     }
 
-    fn data_type(&mut self) -> DataType {
+    fn data_type(&mut self) -> Result<DataType, ParseError> {
         match self.lookahead.clone() {
-            Token::Type(s) => s,
-            Token::Word(s) => DataType::Struct(s),
+            Token::Type(s) => Ok(s),
+            Token::Word(s) if &*s == "map" => {
+                self.next_tok()?;
+                self.map_type()
+            }
+            Token::Word(s) => {
+                self.struct_types.push((s.to_string(), self.lexer.line()));
+                Ok(DataType::Struct(s.to_string()))
+            }
             Token::C('[') => {
-                self.next_tok();
-                self.match_tok(Token::C(']'));
-                DataType::Array(Box::new(self.data_type()))
+                self.next_tok()?;
+                self.match_tok(Token::C(']'))?;
+                Ok(DataType::Array(Box::new(self.data_type()?)))
             }
-            _ => panic!("syntax error: must have a type"),
+            t => Err(self.error(ParseErrorKind::Syntax(format!(
+                "expected a type, found {}",
+                t
+            )))),
         }
     }
 
-    fn assign(&mut self) -> Box<dyn ast::Stmt> {
-        match self.lookahead {
-            Token::Word(_) => (),
-            _ => panic!(
-                "syntax error: assignment must have identifier as lhs, found {:?}",
-                self.lookahead
-            ),
+    // The rest of a map type after the map keyword, eg. `[string]int`
+    fn map_type(&mut self) -> Result<DataType, ParseError> {
+        self.match_tok(Token::C('['))?;
+        let key_type = self.data_type()?;
+        if key_type != DataType::String {
+            return Err(self.error(ParseErrorKind::Type(format!(
+                "map keys must be strings, found {}",
+                key_type
+            ))));
+        }
+        self.next_tok()?;
+        self.match_tok(Token::C(']'))?;
+        return Ok(DataType::Map(Box::new(self.data_type()?)));
+    }
+
+    fn assign(&mut self) -> Result<Box<dyn ast::Stmt>, ParseError> {
+        let id_tok = match self.lookahead.clone() {
+            Token::Word(w) => Token::Word(w),
+            _ => return Err(self.unexpected(vec![])),
         };
 
-        let id_tok = self.lookahead.clone();
+        let peeked = match self.lexer.try_peek_n(0) {
+            Ok(t) => t.clone(),
+            Err(msg) => return Err(self.error(ParseErrorKind::Lexer(msg))),
+        };
+        match peeked {
+            Token::String(name) if id_tok == Token::Word("test".into()) => {
+                // Test declaration. test isn't a keyword, so it can still be used as a name
+                if self.cur_scope.prev.is_some() {
+                    return Err(self.error(ParseErrorKind::Syntax(
+                        "tests must be declared at the top level".into(),
+                    )));
+                }
+                self.next_tok()?;
+                self.next_tok()?;
 
-        self.next_tok();
+                return Ok(Box::new(ast::test::TestBlock {
+                    name,
+                    body: self.block()?,
+                }));
+            }
+            Token::C(':') => {
+                // Labelled loop
+                self.next_tok()?;
+                self.next_tok()?;
+                let label = Some(id_tok.into_word().unwrap().to_string());
+                match self.lookahead {
+                    Token::While => {
+                        self.next_tok()?;
+                        return self.while_loop(label);
+                    }
+                    Token::For => {
+                        self.next_tok()?;
+                        return self.for_loop(label);
+                    }
+                    _ => return Err(self.unexpected(vec![Token::While, Token::For])),
+                }
+            }
+            _ => (),
+        }
 
-        let stmt: Box<dyn ast::Stmt>;
-        match self.lookahead {
+        let stmt = self.simple_stmt()?;
+        self.match_tok(Token::C(';'))?;
+
+        return Ok(stmt);
+    }
+
+    // A declaration, assignment or call, without the semicolon after it
+    fn simple_stmt(&mut self) -> Result<Box<dyn ast::Stmt>, ParseError> {
+        let id_tok = match self.lookahead.clone() {
+            Token::Word(w) => Token::Word(w),
+            _ => return Err(self.unexpected(vec![])),
+        };
+
+        self.next_tok()?;
+
+        match self.lookahead.clone() {
             Token::DeclAssign => {
                 // Declare and assign
-                self.next_tok();
-                let expr = self.bool();
+                self.next_tok()?;
+                let expr = self.bool()?;
 
                 let id = ast::Ident {
-                    addr: self.prog.allocate_var(),
+                    addr: self.prog.allocate_var(id_tok.as_word().unwrap()),
                     name: id_tok.clone(),
                     data_type: expr.out_type(&self.prog),
                 };
 
-                self.cur_scope.put(id_tok, id.clone());
+                self.declare(id.clone())?;
 
-                stmt = Box::new(ast::Assign { id, expr })
+                return Ok(Box::new(ast::Assign { id, expr }));
             }
             Token::C('=') => {
                 // Assignment
-                self.next_tok();
-                let id = self
-                    .cur_scope
-                    .get(id_tok.clone())
-                    .expect(&format!("unknown identifier: {}", id_tok));
-
-                stmt = Box::new(ast::Assign {
-                    id: id,
-                    expr: self.bool(),
-                });
+                self.next_tok()?;
+                let id = self.lookup(&id_tok)?;
+                let expr = self.bool()?;
+                self.check_assign(id_tok.as_word().unwrap(), &id.data_type, &*expr)?;
+
+                return Ok(Box::new(ast::Assign { id, expr }));
             }
             Token::C('(') => {
                 // Function call (returns ignored)
-                self.next_tok();
-                let params = self.bool_list(Token::C(')'));
-                self.next_tok();
+                self.next_tok()?;
+                let params = self.bool_list(Token::C(')'))?;
+                self.next_tok()?;
 
-                stmt = Box::new(ast::func::FuncCall {
-                    func: id_tok.into_word().unwrap(),
+                let name = id_tok.as_word().unwrap();
+                if !self.prog.user_functions.contains_key(&**name) {
+                    return Err(self.error(ParseErrorKind::UnknownFunction(name.to_string())));
+                }
+                self.check_args(name, &params)?;
+                self.note_call(name);
+                return Ok(Box::new(ast::func::FuncCall {
+                    func: id_tok.into_word().unwrap().to_string(),
                     params,
-                });
+                }));
             }
-            Token::C('[') => {
-                // Array index
-                self.next_tok();
-                let index = self.bool();
-                self.match_tok(Token::C(']'));
-
-                self.match_tok(Token::C('='));
-
-                let id = self
-                    .cur_scope
-                    .get(id_tok.clone())
-                    .expect(&format!("unknown identifier: {}", id_tok));
-
-                let stmt = Box::new(ast::compound::AssignArray {
-                    id: id,
-                    index,
-                    expr: self.bool(),
-                });
+            Token::C('[') | Token::C('.') => {
+                // An element or field, possibly nested in others
+                let id = self.lookup(&id_tok)?;
+                let mut name = id_tok.as_word().unwrap().to_string();
+                let mut typ = id.data_type.clone();
+                let mut path = vec![];
+                loop {
+                    if self.lookahead == Token::C('.') {
+                        self.next_tok()?;
+                        let field = self.word()?;
+                        typ = match &typ {
+                            DataType::Struct(strct_name) => {
+                                let strct = &self.prog.user_structs[strct_name];
+                                match strct.names.get(&field) {
+                                    Some(&idx) => strct.types[idx].clone(),
+                                    None => {
+                                        return Err(self
+                                            .error(self.unknown_field(strct_name.clone(), field)))
+                                    }
+                                }
+                            }
+                            t => {
+                                return Err(self.error(ParseErrorKind::Type(format!(
+                                    "cannot access field {} of non-struct type {:?}",
+                                    field, t
+                                ))))
+                            }
+                        };
+                        name = format!("{}.{}", name, field);
+                        path.push(ast::compound::PathStep::Field(field));
+                    } else if self.lookahead == Token::C('[') {
+                        self.next_tok()?;
+                        let index = self.nested_bool()?;
+                        self.match_tok(Token::C(']'))?;
+                        self.check_index(&typ, &*index)?;
+                        typ = match typ {
+                            DataType::Array(el) | DataType::Map(el) => *el,
+                            _ => unreachable!(),
+                        };
+                        name += "[]";
+                        path.push(ast::compound::PathStep::Index(index));
+                    } else {
+                        break;
+                    }
+                }
 
-                self.match_tok(Token::C(';'));
+                self.match_tok(Token::C('='))?;
+                let expr = self.bool()?;
+                self.check_assign(&name, &typ, &*expr)?;
 
-                return stmt;
+                return Ok(Box::new(ast::compound::AssignPath { id, path, expr }));
+            }
+            _ => {
+                return Err(self.unexpected(vec![Token::DeclAssign, Token::C('='), Token::C('(')]))
             }
-            _ => panic!("unknown statement"),
         }
+    }
 
-        self.match_tok(Token::C(';'));
+    // An arithmetic or comparison expression, if the operator can be used on the types
+    fn binary(
+        &self,
+        op: Token,
+        x: Box<dyn ast::Expr>,
+        y: Box<dyn ast::Expr>,
+    ) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let (x_type, y_type) = (x.out_type(&self.prog), y.out_type(&self.prog));
+        let allowed = match op {
+            Token::C('+') => matches!(
+                x_type,
+                DataType::Integer | DataType::Float | DataType::String
+            ),
+            // Division converts both sides to floats, so they can be mixed
+            Token::C('/') => {
+                let numeric = |t: &DataType| matches!(t, DataType::Integer | DataType::Float);
+                numeric(&x_type) && numeric(&y_type)
+            }
+            Token::C('-') | Token::C('*') | Token::C('%') => {
+                matches!(x_type, DataType::Integer | DataType::Float)
+            }
+            // Arrays and structs are compared element by element
+            Token::Eq | Token::Ne => matches!(
+                x_type,
+                DataType::Integer
+                    | DataType::Float
+                    | DataType::String
+                    | DataType::Bool
+                    | DataType::Array(_)
+                    | DataType::Map(_)
+                    | DataType::Struct(_)
+            ),
+            _ => matches!(
+                x_type,
+                DataType::Integer | DataType::Float | DataType::String
+            ),
+        };
 
-        return stmt;
+        if !allowed || (x_type != y_type && op != Token::C('/')) {
+            let symbol = match &op {
+                Token::C(c) => c.to_string(),
+                Token::Eq => "==".into(),
+                Token::Ne => "!=".into(),
+                Token::Le => "<=".into(),
+                Token::Ge => ">=".into(),
+                t => t.to_string(),
+            };
+            return Err(self.error(ParseErrorKind::Type(format!(
+                "cannot use '{}' on {} and {}",
+                symbol, x_type, y_type
+            ))));
+        }
+
+        return Ok(Box::new(ast::Arith { op, x, y }));
     }
 
     // This part specifies the order of operations through the heirarchy
-    fn bool(&mut self) -> Box<dyn ast::Expr> {
-        let mut x = self.join();
+    // An expression before a block, which can't contain an unbracketed struct literal
+    fn cond(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let prev = std::mem::replace(&mut self.no_struct_literal, true);
+        let res = self.bool();
+        self.no_struct_literal = prev;
+        return res;
+    }
+
+    // An expression inside brackets, where struct literals are allowed again
+    fn nested_bool(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let prev = std::mem::replace(&mut self.no_struct_literal, false);
+        let res = self.bool();
+        self.no_struct_literal = prev;
+        return res;
+    }
+
+    fn bool(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let chain = self.chain;
+        let mut x = self.join()?;
         while self.lookahead == Token::BoolOr {
-            self.next_tok();
-            x = Box::new(ast::BoolOr { x, y: self.join() });
+            self.operator()?;
+            x = Box::new(ast::BoolOr { x, y: self.join()? });
         }
-        return x;
+        self.chain = chain;
+        return Ok(x);
     }
 
-    fn join(&mut self) -> Box<dyn ast::Expr> {
-        let mut x = self.equality();
+    fn join(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let chain = self.chain;
+        let mut x = self.equality()?;
         while self.lookahead == Token::BoolAnd {
-            self.next_tok();
+            self.operator()?;
             x = Box::new(ast::BoolAnd {
                 x,
-                y: self.equality(),
+                y: self.equality()?,
             });
         }
-        return x;
+        self.chain = chain;
+        return Ok(x);
     }
 
-    fn equality(&mut self) -> Box<dyn ast::Expr> {
-        let mut x = self.rel();
+    fn equality(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let chain = self.chain;
+        let mut x = self.rel()?;
         while self.lookahead == Token::Eq || self.lookahead == Token::Ne {
             let tok = self.lookahead.clone();
-            self.next_tok();
-            x = Box::new(ast::Arith {
-                op: tok,
-                x,
-                y: self.rel(),
-            });
+            self.operator()?;
+
+            // Null has no type, so comparing against it checks for absence instead
+            if self.lookahead == Token::Null {
+                self.next_tok()?;
+                x = Box::new(ast::builtin::IsNull { x });
+                if tok == Token::Ne {
+                    x = Box::new(ast::BoolNot { x });
+                }
+                continue;
+            }
+
+            let y = self.rel()?;
+            x = self.binary(tok, x, y)?;
         }
-        return x;
+        self.chain = chain;
+        return Ok(x);
     }
 
-    fn rel(&mut self) -> Box<dyn ast::Expr> {
-        let mut x = self.expr();
-        while match self.lookahead {
-            Token::Ge | Token::Le | Token::C('<') | Token::C('>') => true,
-            _ => false,
-        } {
+    fn rel(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let chain = self.chain;
+        let mut x = self.expr()?;
+        while matches!(
+            self.lookahead,
+            Token::Ge | Token::Le | Token::C('<') | Token::C('>')
+        ) {
             let tok = self.lookahead.clone();
-            self.next_tok();
-            x = Box::new(ast::Arith {
-                op: tok,
-                x,
-                y: self.expr(),
-            });
+            self.operator()?;
+            let y = self.expr()?;
+            x = self.binary(tok, x, y)?;
         }
-        return x;
+        self.chain = chain;
+        return Ok(x);
     }
 
-    fn expr(&mut self) -> Box<dyn ast::Expr> {
-        let mut x = self.term();
+    fn expr(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let chain = self.chain;
+        let mut x = self.term()?;
         while self.lookahead == Token::C('-') || self.lookahead == Token::C('+') {
             let tok = self.lookahead.clone();
-            self.next_tok();
-            x = Box::new(ast::Arith {
-                op: tok,
-                x,
-                y: self.term(),
-            });
+            self.operator()?;
+            let y = self.term()?;
+            x = self.binary(tok, x, y)?;
         }
-        return x;
+        self.chain = chain;
+        return Ok(x);
     }
 
-    fn term(&mut self) -> Box<dyn ast::Expr> {
-        let mut x = self.unary();
-        while self.lookahead == Token::C('*') || self.lookahead == Token::C('/') {
+    fn term(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let chain = self.chain;
+        let mut x = self.unary()?;
+        while self.lookahead == Token::C('*')
+            || self.lookahead == Token::C('/')
+            || self.lookahead == Token::C('%')
+        {
             let tok = self.lookahead.clone();
-            self.next_tok();
-            x = Box::new(ast::Arith {
-                op: tok,
-                x,
-                y: self.unary(),
-            });
+            self.operator()?;
+            let y = self.unary()?;
+            x = self.binary(tok, x, y)?;
         }
-        return x;
+        self.chain = chain;
+        return Ok(x);
     }
 
-    fn unary(&mut self) -> Box<dyn ast::Expr> {
+    // Every nested expression, eg. in brackets or after a prefix operator,
+    // starts again from here
+    fn unary(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        return self.nested(|par| par.prefixed());
+    }
+
+    fn prefixed(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
         if self.lookahead == Token::C('-') {
-            self.next_tok();
-            return Box::new(ast::Unary {
-                op: self.lookahead.clone(),
-                x: self.unary(),
-            });
+            let op = self.lookahead.clone();
+            self.next_tok()?;
+            let x = self.unary()?;
+
+            // Negative literals are constants
+            match x.const_value() {
+                Some(stac::DataVal::Integer(i)) => {
+                    return Ok(Box::new(ast::Const {
+                        value: stac::DataVal::Integer(-i),
+                        data_type: DataType::Integer,
+                    }))
+                }
+                Some(stac::DataVal::Float(f)) => {
+                    return Ok(Box::new(ast::Const {
+                        value: stac::DataVal::Float(-f),
+                        data_type: DataType::Float,
+                    }))
+                }
+                _ => (),
+            }
+
+            let t = x.out_type(&self.prog);
+            if t != DataType::Integer && t != DataType::Float {
+                return Err(self.error(ParseErrorKind::Type(format!("cannot negate {}", t))));
+            }
+            return Ok(Box::new(ast::Unary { op, x }));
         } else if self.lookahead == Token::C('!') {
-            self.next_tok();
-            return Box::new(ast::BoolNot { x: self.unary() });
+            self.next_tok()?;
+            return Ok(Box::new(ast::BoolNot { x: self.unary()? }));
         } else {
             return self.field();
         }
     }
 
-    fn field(&mut self) -> Box<dyn ast::Expr> {
-        let x = self.factor();
-        if self.lookahead == Token::C('.') {
-            self.next_tok();
-            let field = self.lookahead.clone().into_word().unwrap();
-            self.next_tok();
-            return Box::new(ast::compound::StructAccess { expr: x, field });
+    // A factor followed by any number of .field and [index] suffixes
+    fn field(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let mut x = self.factor()?;
+        loop {
+            if self.lookahead == Token::C('.') {
+                self.next_tok()?;
+                let field = self.word()?;
+
+                match x.out_type(&self.prog) {
+                    DataType::Struct(name) => {
+                        if !self.prog.user_structs[&name].names.contains_key(&field) {
+                            return Err(self.error(self.unknown_field(name, field)));
+                        }
+                    }
+                    t => {
+                        return Err(self.error(ParseErrorKind::Type(format!(
+                            "cannot access field {} of non-struct type {:?}",
+                            field, t
+                        ))))
+                    }
+                }
+
+                x = Box::new(ast::compound::StructAccess { expr: x, field });
+            } else if self.lookahead == Token::C('[') {
+                self.next_tok()?;
+                let index = self.nested_bool()?;
+                self.match_tok(Token::C(']'))?;
+                self.check_index(&x.out_type(&self.prog), &*index)?;
+
+                x = Box::new(ast::compound::ArrayIndex { arr: x, index });
+            } else {
+                return Ok(x);
+            }
+        }
+    }
+
+    // Checks that the type can be indexed, and that maps are indexed by strings
+    fn check_index(&self, typ: &DataType, index: &dyn ast::Expr) -> Result<(), ParseError> {
+        match typ {
+            DataType::Array(_) => return Ok(()),
+            DataType::Map(_) if index.out_type(&self.prog) == DataType::String => return Ok(()),
+            DataType::Map(_) => {
+                return Err(self.error(ParseErrorKind::Type(format!(
+                    "map keys must be strings, found {}",
+                    index.out_type(&self.prog)
+                ))))
+            }
+            t => {
+                return Err(self.error(ParseErrorKind::Type(format!(
+                    "cannot index into non-array type {:?}",
+                    t
+                ))))
+            }
+        }
+    }
+
+    // A map literal like `{"env": "prod"}`, from its opening brace. Without a
+    // value type, it's the type of the first value.
+    fn map_literal(
+        &mut self,
+        mut val_type: Option<DataType>,
+    ) -> Result<Box<dyn ast::Expr>, ParseError> {
+        self.match_tok(Token::C('{'))?;
+        let mut values = vec![];
+        while self.lookahead != Token::C('}') {
+            let key = self.nested_bool()?;
+            if key.out_type(&self.prog) != DataType::String {
+                return Err(self.error(ParseErrorKind::Type(format!(
+                    "map keys must be strings, found {}",
+                    key.out_type(&self.prog)
+                ))));
+            }
+            self.match_tok(Token::C(':'))?;
+
+            let val = self.nested_bool()?;
+            let t = val.out_type(&self.prog);
+            match &val_type {
+                Some(val_type) if t != *val_type => {
+                    return Err(self.error(ParseErrorKind::Type(format!(
+                        "map value {} must be {}, found {}",
+                        values.len(),
+                        val_type,
+                        t
+                    ))))
+                }
+                Some(_) => {}
+                None => val_type = Some(t),
+            }
+
+            values.push((key, val));
+            self.separator(&Token::C('}'))?;
+        }
+        self.next_tok()?;
+
+        let Some(val_type) = val_type else {
+            return Err(self.error(ParseErrorKind::Type(
+                "an empty map needs a value type, eg. map[string]int{}".into(),
+            )));
+        };
+        return Ok(Box::new(ast::compound::MapLiteral { values, val_type }));
+    }
+
+    // An array literal, if all of its values have the element type
+    fn array_literal(
+        &self,
+        values: Vec<Box<dyn ast::Expr>>,
+        el_type: DataType,
+    ) -> Result<Box<dyn ast::Expr>, ParseError> {
+        for (idx, v) in values.iter().enumerate() {
+            let t = v.out_type(&self.prog);
+            if t != el_type {
+                return Err(self.error(ParseErrorKind::Type(format!(
+                    "array element {} must be {}, found {}",
+                    idx, el_type, t
+                ))));
+            }
+        }
+
+        return Ok(Box::new(ast::compound::ArrayLiteral { values, el_type }));
+    }
+
+    // Parses the source of an expression interpolated into a string
+    fn interpolated(&mut self, src: &str) -> Result<Box<dyn ast::Expr>, ParseError> {
+        // Errors are reported at the line of the string rather than within the interpolation
+        let line = self.lexer.line();
+        let outer = std::mem::replace(&mut self.lexer, Lexer::from_str(src));
+        let next = std::mem::replace(&mut self.lookahead, Token::EOF);
+
+        let res = self
+            .next_tok()
+            .and_then(|_| self.nested_bool())
+            .and_then(|x| {
+                if self.lookahead != Token::EOF {
+                    return Err(self.unexpected(vec![]));
+                }
+                return Ok(x);
+            });
+
+        self.lexer = outer;
+        self.lookahead = next;
+        return res.map_err(|e| ParseError { line, ..e });
+    }
+
+    // An if in an expression, eg. `if prod { "s-4vcpu" } else { "s-1vcpu" }`,
+    // which must have an else. Caller is responsible for the if token.
+    fn if_value(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
+        let expr = self.cond()?;
+        let cond_type = expr.out_type(&self.prog);
+        if cond_type != DataType::Bool {
+            return Err(self.error(ParseErrorKind::Type(format!(
+                "if condition must be bool, found {}",
+                cond_type
+            ))));
+        }
+
+        self.match_tok(Token::C('{'))?;
+        let x = self.nested_bool()?;
+        self.match_tok(Token::C('}'))?;
+        self.match_tok(Token::Else)?;
+        let y = if self.lookahead == Token::If {
+            self.next_tok()?;
+            self.nested(|par| par.if_value())?
         } else {
-            return x;
+            self.match_tok(Token::C('{'))?;
+            let y = self.nested_bool()?;
+            self.match_tok(Token::C('}'))?;
+            y
+        };
+
+        let (x_type, y_type) = (x.out_type(&self.prog), y.out_type(&self.prog));
+        if x_type != y_type {
+            return Err(self.error(ParseErrorKind::Type(format!(
+                "if branches must have the same type, found {} and {}",
+                x_type, y_type
+            ))));
         }
+        return Ok(Box::new(ast::IfValue {
+            expr,
+            tmp: ast::Ident {
+                addr: self.prog.allocate_var("if"),
+                name: Token::If,
+                data_type: x_type,
+            },
+            x,
+            y,
+        }));
     }
 
-    fn factor(&mut self) -> Box<dyn ast::Expr> {
+    fn factor(&mut self) -> Result<Box<dyn ast::Expr>, ParseError> {
         match self.lookahead.clone() {
             Token::C('(') => {
-                self.next_tok();
-                let x = self.bool();
-                self.match_tok(Token::C(')'));
-                return x;
+                self.next_tok()?;
+                let x = self.nested_bool()?;
+                self.match_tok(Token::C(')'))?;
+                return Ok(x);
+            }
+            Token::If => {
+                self.next_tok()?;
+                return self.if_value();
             }
             Token::C('[') => {
-                // Array literal
-                self.next_tok();
-                let array: Vec<Box<dyn ast::Expr>> = self.bool_list(Token::C(']'));
-                self.next_tok();
+                // Array literal, either [a, b] or typed like []string{a, b}
+                self.next_tok()?;
+                if self.lookahead != Token::C(']') {
+                    let values = self.bool_list(Token::C(']'))?;
+                    self.next_tok()?;
+
+                    let el_type = values[0].out_type(&self.prog);
+                    return self.array_literal(values, el_type);
+                }
 
-                return Box::new(ast::compound::ArrayLiteral { values: array });
+                self.next_tok()?;
+                if matches!(
+                    self.lookahead,
+                    Token::Type(_) | Token::Word(_) | Token::C('[')
+                ) {
+                    let el_type = self.data_type()?;
+                    self.next_tok()?;
+                    self.match_tok(Token::C('{'))?;
+                    let values = self.bool_list(Token::C('}'))?;
+                    self.next_tok()?;
+
+                    return self.array_literal(values, el_type);
+                }
+
+                return Err(self.error(ParseErrorKind::Type(
+                    "an empty array needs an element type, eg. []string{}".into(),
+                )));
+            }
+            Token::C('{') => return self.map_literal(None),
+            Token::Type(to @ (DataType::Integer | DataType::Float)) => {
+                // Conversions are called like functions, but named after their type
+                self.next_tok()?;
+                self.match_tok(Token::C('('))?;
+                let params = self.bool_list(Token::C(')'))?;
+                self.next_tok()?;
+                return ast::builtin::cast(to, params, &self.prog).map_err(|kind| self.error(kind));
             }
             Token::String(s) => {
                 // String literal
-                self.next_tok();
-                return Box::new(ast::Const {
-                    value: stac::DataVal::String(s),
+                self.next_tok()?;
+                return Ok(Box::new(ast::Const {
+                    value: stac::DataVal::String(s.into()),
                     data_type: DataType::String,
-                });
+                }));
+            }
+            Token::Interpolated(parts) => {
+                self.next_tok()?;
+                let mut exprs: Vec<Box<dyn ast::Expr>> = vec![];
+                for part in parts {
+                    match part {
+                        StrPart::Lit(s) => exprs.push(Box::new(ast::Const {
+                            value: stac::DataVal::String(s.into()),
+                            data_type: DataType::String,
+                        })),
+                        StrPart::Expr(src) => {
+                            let x = self.interpolated(&src)?;
+                            match x.out_type(&self.prog) {
+                                DataType::String
+                                | DataType::Integer
+                                | DataType::Float
+                                | DataType::Bool => (),
+                                t => {
+                                    return Err(self.error(ParseErrorKind::Type(format!(
+                                        "cannot interpolate {:?} into a string",
+                                        t
+                                    ))))
+                                }
+                            }
+                            exprs.push(x);
+                        }
+                    }
+                }
+                return Ok(Box::new(ast::Interpolation { parts: exprs }));
             }
             Token::Integer(i) => {
                 let x = Box::new(ast::Const {
                     value: stac::DataVal::Integer(i),
                     data_type: stac::DataType::Integer,
                 });
-                self.next_tok();
-                return x;
+                self.next_tok()?;
+                return Ok(x);
             }
             Token::Float(f) => {
                 let x = Box::new(ast::Const {
                     value: stac::DataVal::Float(f),
                     data_type: stac::DataType::Float,
                 });
-                self.next_tok();
-                return x;
+                self.next_tok()?;
+                return Ok(x);
+            }
+            Token::Null => {
+                return Err(self.error(ParseErrorKind::Syntax(
+                    "null can only be compared against, with == or !=".into(),
+                )))
             }
             Token::True => {
                 let x = Box::new(ast::Const {
                     value: stac::DataVal::Bool(true),
                     data_type: stac::DataType::Bool,
                 });
-                self.next_tok();
-                return x;
+                self.next_tok()?;
+                return Ok(x);
             }
             Token::False => {
                 let x = Box::new(ast::Const {
                     value: stac::DataVal::Bool(false),
                     data_type: stac::DataType::Bool,
                 });
-                self.next_tok();
-                return x;
+                self.next_tok()?;
+                return Ok(x);
             }
             Token::Word(_) => {
                 let id_tok = self.lookahead.clone();
-                let id = self.cur_scope.get(id_tok.clone());
-                self.next_tok();
-
-                if self.lookahead == Token::C('[') {
-                    // Array index
-                    self.next_tok();
+                self.next_tok()?;
 
-                    let index = self.bool();
-                    self.match_tok(Token::C(']'));
+                if id_tok == Token::Word("map".into()) && self.lookahead == Token::C('[') {
+                    // Typed map literal, eg. map[string]int{}
+                    let val_type = *self.map_type()?.into_map().unwrap();
+                    self.next_tok()?;
+                    return self.map_literal(Some(val_type));
+                }
 
-                    return Box::new(ast::compound::ArrayIndex {
-                        arr: Box::new(id.unwrap()),
-                        index,
-                    });
-                } else if self.lookahead == Token::C('(') {
+                if self.lookahead == Token::C('(') {
                     // Function call as an expression
-                    self.next_tok();
-                    let params: Vec<Box<dyn ast::Expr>> = self.bool_list(Token::C(')'));
-                    self.next_tok();
+                    self.next_tok()?;
+                    if id_tok == Token::Word("make".into()) {
+                        return self.make();
+                    }
+                    let params: Vec<Box<dyn ast::Expr>> = self.bool_list(Token::C(')'))?;
+                    self.next_tok()?;
 
-                    return Box::new(ast::func::FuncCall {
-                        func: id_tok.into_word().unwrap(),
+                    let name = id_tok.as_word().unwrap();
+                    if ast::builtin::is_builtin(name) {
+                        return ast::builtin::call(name, params, &self.prog)
+                            .map_err(|kind| self.error(kind));
+                    }
+
+                    match self
+                        .prog
+                        .user_functions
+                        .get(&**name)
+                        .map(|f| f.returns.len())
+                    {
+                        None => {
+                            return Err(
+                                self.error(ParseErrorKind::UnknownFunction(name.to_string()))
+                            )
+                        }
+                        Some(1) => (),
+                        Some(_) => {
+                            return Err(self.error(ParseErrorKind::Type(format!(
+                                "can only use {} as an expression if it has one return value",
+                                name
+                            ))))
+                        }
+                    }
+                    self.check_args(name, &params)?;
+                    self.note_call(name);
+                    return Ok(Box::new(ast::func::FuncCall {
+                        func: id_tok.into_word().unwrap().to_string(),
                         params,
-                    });
-                } else if self.lookahead == Token::C('{') {
+                    }));
+                } else if self.lookahead == Token::C('{') && !self.no_struct_literal {
                     // Struct literal
-                    self.next_tok();
+                    let strct = id_tok.into_word().unwrap().to_string();
+                    if !self.prog.user_structs.contains_key(&strct) {
+                        return Err(self.error(self.unknown_struct(&strct)));
+                    }
+                    self.next_tok()?;
                     let mut list = vec![];
 
                     while self.lookahead != Token::C('}') {
-                        if self.lookahead == Token::C(',') {
-                            self.next_tok();
+                        let name = self.word()?;
+                        let idx = match self.prog.user_structs[&strct].names.get(&name) {
+                            Some(idx) => *idx,
+                            None => return Err(self.error(self.unknown_field(strct, name))),
+                        };
+                        if list.iter().any(|(n, _)| *n == name) {
+                            return Err(
+                                self.error(ParseErrorKind::DuplicateField { strct, field: name })
+                            );
                         }
+                        self.match_tok(Token::C(':'))?;
 
-                        let name = self.lookahead.clone().into_word().unwrap();
-                        self.next_tok();
-                        self.match_tok(Token::C(':'));
+                        let value = self.bool()?;
 
-                        let value = self.bool();
+                        // Constant values can be checked now, the rest are checked before extern calls
+                        if let Some(v) = value.const_value() {
+                            if let Err(e) =
+                                stac::check_field(&self.prog.user_structs, &strct, idx, &v)
+                            {
+                                return Err(self.error(ParseErrorKind::Constraint(e)));
+                            }
+                        }
 
                         list.push((name, value));
+                        self.separator(&Token::C('}'))?;
                     }
-                    self.next_tok();
+                    self.next_tok()?;
 
-                    return Box::new(ast::compound::StructLiteral {
-                        strct: id_tok.into_word().unwrap(),
+                    return Ok(Box::new(ast::compound::StructLiteral {
+                        strct,
                         values: list,
-                    });
+                    }));
                 } else {
-                    return Box::new(id.unwrap());
+                    let name = id_tok.as_word().unwrap();
+                    let id = match self.lookup(&id_tok) {
+                        Err(_)
+                            if self.lookahead == Token::C('{')
+                                && self.prog.user_structs.contains_key(&**name) =>
+                        {
+                            return Err(self.error(ParseErrorKind::Syntax(format!(
+                                "a {} literal in a condition must be in parentheses",
+                                name
+                            ))))
+                        }
+                        res => res?,
+                    };
+                    if let Some((func, _)) = &self.func {
+                        if self.cur_scope.is_global(&id) {
+                            self.global_reads
+                                .entry(func.clone())
+                                .or_default()
+                                .push(id.clone());
+                        }
+                    }
+                    return Ok(Box::new(id));
                 }
             }
-            _ => panic!("syntax error: token {:?}", self.lookahead),
+            _ => return Err(self.unexpected(vec![])),
+        }
+    }
+}
+
+// A list of types as written in a function signature, eg. "int, string"
+fn type_names(types: &[DataType]) -> String {
+    let names: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+    return names.join(", ");
+}
+
+// The name and signature of a function, from the tokens after the func keyword
+fn signature(tokens: &[Token]) -> Option<(String, stac::Function)> {
+    let mut tokens = tokens.iter().peekable();
+    tokens.next_if_eq(&&Token::Extern);
+    let name = tokens.next()?.as_word()?.to_string();
+
+    tokens.next_if_eq(&&Token::C('('))?;
+    let mut params = vec![];
+    while tokens.next_if_eq(&&Token::C(')')).is_none() {
+        tokens.next_if_eq(&&Token::C(','));
+        tokens.next()?.as_word()?;
+        tokens.next_if_eq(&&Token::C(':'))?;
+        params.push(signature_type(&mut tokens)?);
+    }
+
+    tokens.next_if_eq(&&Token::C('('))?;
+    let mut returns = vec![];
+    while tokens.next_if_eq(&&Token::C(')')).is_none() {
+        tokens.next_if_eq(&&Token::C(','));
+        returns.push(signature_type(&mut tokens)?);
+    }
+
+    return Some((
+        name,
+        stac::Function {
+            label: stac::Label::CONTINUE,
+            params,
+            returns,
+            locals: vec![],
+        },
+    ));
+}
+
+fn signature_type<'a>(
+    tokens: &mut std::iter::Peekable<impl Iterator<Item = &'a Token>>,
+) -> Option<DataType> {
+    match tokens.next()? {
+        Token::Type(t) => return Some(t.clone()),
+        Token::Word(s) if &**s == "map" => {
+            tokens.next_if_eq(&&Token::C('['))?;
+            tokens.next_if_eq(&&Token::Type(DataType::String))?;
+            tokens.next_if_eq(&&Token::C(']'))?;
+            return Some(DataType::Map(Box::new(signature_type(tokens)?)));
+        }
+        Token::Word(s) => return Some(DataType::Struct(s.to_string())),
+        Token::C('[') => {
+            tokens.next_if_eq(&&Token::C(']'))?;
+            return Some(DataType::Array(Box::new(signature_type(tokens)?)));
         }
+        _ => return None,
     }
 }
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stac::{CallId, DataType, DataVal, Struct};
+
+const INDENT: &str = "    ";
+
+// ANSI escapes for colored output
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// An extern call the program would make
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlannedCall {
+    pub id: CallId,
+    pub function: String,
+    pub params: Vec<DataVal>,
+    pub param_types: Vec<DataType>,
+}
+
+impl PlannedCall {
+    // The address of the resource created by the call, eg. "3:1:0"
+    pub fn address(&self) -> String {
+        return self.id.to_string();
+    }
+}
+
+// An extern call the program may make, in a branch that depends on a value
+// that isn't known until another call is made. Its params aren't known either.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PossibleCall {
+    pub id: CallId,
+    pub function: String,
+    pub depends_on: (usize, usize), // the waiting if expr the branch is under
+}
+
+/// The extern calls a dry run of a program found it would make, from
+/// `Prog::plan`, and those it may make once the values its branches depend
+/// on are known. It keeps the program's structs, so it can be rendered on
+/// its own once deserialized.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExecutionPlan {
+    pub calls: Vec<PlannedCall>,
+    // Empty for plans made before they were included
+    #[serde(default)]
+    pub possible_calls: Vec<PossibleCall>,
+    pub user_structs: HashMap<String, Struct>,
+}
+
+impl ExecutionPlan {
+    /// Renders the plan like `render`, with a section per call, including
+    /// those that may be made.
+    pub fn render(&self) -> String {
+        return render_plan(&self.calls, &self.possible_calls, &self.user_structs, false);
+    }
+}
+
+// Lists the calls in the order they would be made, a line each, eg.
+// `would call createDroplet with CreateDropletRequest { name: "web" }`,
+// followed by those that may be made
+impl fmt::Display for ExecutionPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for call in &self.calls {
+            let params: Vec<String> = call
+                .params
+                .iter()
+                .zip(&call.param_types)
+                .map(|(v, t)| v.display_typed(t, &self.user_structs))
+                .collect();
+            if params.is_empty() {
+                writeln!(f, "would call {}", call.function)?;
+            } else {
+                writeln!(f, "would call {} with {}", call.function, params.join(", "))?;
+            }
+        }
+        for call in &self.possible_calls {
+            writeln!(
+                f,
+                "may call {} (depends on if at {:?})",
+                call.function, call.depends_on
+            )?;
+        }
+        return Ok(());
+    }
+}
+
+/// Renders a summary of the calls for an operator to review, with a section
+/// per call ordered by resource address, followed by a count of the calls.
+pub fn render(calls: &[PlannedCall], user_structs: &HashMap<String, Struct>) -> String {
+    return render_with(calls, user_structs, false);
+}
+
+/// Like `render`, optionally colored for a terminal.
+pub fn render_with(
+    calls: &[PlannedCall],
+    user_structs: &HashMap<String, Struct>,
+    color: bool,
+) -> String {
+    return render_plan(calls, &[], user_structs, color);
+}
+
+fn render_plan(
+    calls: &[PlannedCall],
+    possible_calls: &[PossibleCall],
+    user_structs: &HashMap<String, Struct>,
+    color: bool,
+) -> String {
+    let paint = |code: &str, s: &str| {
+        if color {
+            return format!("{}{}{}", code, s, RESET);
+        } else {
+            return s.to_string();
+        }
+    };
+
+    let mut sorted: Vec<&PlannedCall> = calls.iter().collect();
+    sorted.sort_by_key(|c| c.id);
+
+    let mut out = String::new();
+    for call in sorted {
+        writeln!(out, "{}", paint(BOLD, &format!("# {}", call.address()))).unwrap();
+        writeln!(out, "{} {}(", paint(GREEN, "+"), call.function).unwrap();
+        for (val, typ) in call.params.iter().zip(&call.param_types) {
+            out.push_str(INDENT);
+            write_val(&mut out, val, typ, user_structs, 1);
+            out.push_str(",\n");
+        }
+        out.push_str(")\n\n");
+    }
+
+    // Their params aren't known, so only what they depend on is shown
+    let mut sorted: Vec<&PossibleCall> = possible_calls.iter().collect();
+    sorted.sort_by_key(|c| c.id);
+    for call in sorted {
+        writeln!(out, "{}", paint(BOLD, &format!("# {}", call.id))).unwrap();
+        writeln!(
+            out,
+            "{} {}(...) depends on if at {:?}\n",
+            paint(YELLOW, "?"),
+            call.function,
+            call.depends_on
+        )
+        .unwrap();
+    }
+
+    if possible_calls.is_empty() {
+        writeln!(out, "Plan: {} to create.", calls.len()).unwrap();
+    } else {
+        writeln!(
+            out,
+            "Plan: {} to create, {} that may be created.",
+            calls.len(),
+            possible_calls.len()
+        )
+        .unwrap();
+    }
+    return out;
+}
+
+// Writes the value as it would appear in source, with struct fields on their own lines
+fn write_val(
+    out: &mut String,
+    val: &DataVal,
+    typ: &DataType,
+    user_structs: &HashMap<String, Struct>,
+    depth: usize,
+) {
+    match (val, typ) {
+        (DataVal::Waiting, _) => out.push_str("(known after apply)"),
+        (DataVal::Integer(i), _) => write!(out, "{}", i).unwrap(),
+        (DataVal::Float(f), _) => write!(out, "{}f", f).unwrap(),
+        (DataVal::Bool(b), _) => write!(out, "{}", b).unwrap(),
+        (DataVal::String(s), _) => write!(out, "{:?}", s).unwrap(),
+        (DataVal::Null, _) => out.push_str("null"),
+        (DataVal::Compound(els), DataType::Array(el_typ)) => {
+            out.push('[');
+            for (idx, el) in els.iter().enumerate() {
+                if idx > 0 {
+                    out.push_str(", ");
+                }
+                write_val(out, el, el_typ, user_structs, depth);
+            }
+            out.push(']');
+        }
+        (DataVal::Map(vals), DataType::Map(val_typ)) => {
+            out.push('{');
+            for (idx, (key, val)) in vals.iter().enumerate() {
+                if idx > 0 {
+                    out.push_str(", ");
+                }
+                write!(out, "{:?}: ", key).unwrap();
+                write_val(out, val, val_typ, user_structs, depth);
+            }
+            out.push('}');
+        }
+        (DataVal::Compound(fields), DataType::Struct(name)) => {
+            let strct = &user_structs[name];
+            let mut names: Vec<(&String, &usize)> = strct.names.iter().collect();
+            names.sort_by_key(|kv| *kv.1);
+            let width = names.iter().map(|kv| kv.0.len()).max().unwrap_or(0);
+
+            writeln!(out, "{} {{", name).unwrap();
+            for (field, idx) in names {
+                out.push_str(&INDENT.repeat(depth + 1));
+                write!(out, "{:width$} = ", field, width = width).unwrap();
+                write_val(
+                    out,
+                    &fields[*idx],
+                    &strct.types[*idx],
+                    user_structs,
+                    depth + 1,
+                );
+                out.push('\n');
+            }
+            out.push_str(&INDENT.repeat(depth));
+            out.push('}');
+        }
+        _ => write!(out, "{:?}", val).unwrap(),
+    }
+}
@@ -1,24 +1,465 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use serde::{
     de::{self, DeserializeSeed, Unexpected, Visitor},
-    ser::{SerializeMap, SerializeSeq},
+    ser::{Error as _, SerializeMap, SerializeSeq},
     Deserialize, Serialize,
 };
 
-use crate::stac::{DataType, DataVal, Struct};
+use crate::stac::{CallId, DataType, DataVal, Struct};
+
+// How strictly values returned by a provider must match their declared types
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProviderMode {
+    Strict,
+    // Safely coerce drifted values (int <-> float, number <-> numeric string),
+    // recording a warning for each
+    Compatible,
+}
+
+// A value a provider returned that had to be coerced to its declared type
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProviderWarning {
+    pub path: String, // eg. "createDroplet[0].networks.v4[1].port"
+    pub expected: DataType,
+    pub received: String,
+}
+
+pub type Warnings = Mutex<Vec<ProviderWarning>>;
+
+/// How requests to an HTTP provider are made. Failures the provider can't
+/// have acted on are retried with exponential backoff, which are connection
+/// errors and 429, 502 and 503 responses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProviderConfig {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub retries: u32, // after the first attempt
+    // The wait before the first retry, doubling for each after it, unless
+    // the response says how long to wait with Retry-After
+    pub backoff: Duration,
+    // The longest wait before a retry. A response asking to wait longer
+    // fails instead of being retried.
+    pub max_backoff: Duration,
+}
+
+impl Default for ProviderConfig {
+    fn default() -> Self {
+        return Self {
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(60),
+            retries: 3,
+            backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(60),
+        };
+    }
+}
+
+impl ProviderConfig {
+    // The client for every request to the provider at the address
+    pub fn client(&self, addr: &str) -> Result<reqwest::blocking::Client, ProviderError> {
+        return reqwest::blocking::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .build()
+            .map_err(|e| ProviderError::unreachable(addr, e));
+    }
+
+    // Like client, for requests that are awaited
+    pub fn async_client(&self, addr: &str) -> Result<reqwest::Client, ProviderError> {
+        return reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .build()
+            .map_err(|e| ProviderError::unreachable(addr, e));
+    }
+
+    // How long to wait before retrying the failed attempt, recording the retry
+    // when there's somewhere to, or the error if it isn't retried
+    fn retry(
+        &self,
+        attempt: u32,
+        error: ProviderError,
+        retry_after: Option<Duration>,
+        retries: Option<&Retries>,
+    ) -> Result<Duration, ProviderError> {
+        if attempt > self.retries {
+            return Err(error);
+        }
+
+        let delay = match retry_after {
+            Some(delay) if delay > self.max_backoff => return Err(error),
+            Some(delay) => delay,
+            None => self
+                .backoff
+                .saturating_mul(2u32.saturating_pow(attempt - 1))
+                .min(self.max_backoff),
+        };
+        if let Some(retries) = retries {
+            retries.lock().unwrap().push(ProviderRetry {
+                attempt,
+                delay,
+                error,
+            });
+        }
+        return Ok(delay);
+    }
+}
+
+// An attempt at a request that failed, and was retried after the delay
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProviderRetry {
+    pub attempt: u32, // from 1
+    pub delay: Duration,
+    pub error: ProviderError,
+}
+
+pub type Retries = Mutex<Vec<ProviderRetry>>;
+
+// The body of a response, or the error and how long the response said to wait
+// if the request should be retried
+fn check_response(
+    url: &str,
+    status: u16,
+    headers: &reqwest::header::HeaderMap,
+    body: String,
+) -> Result<Result<String, ProviderError>, (ProviderError, Option<Duration>)> {
+    let retry_after = headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs);
+    match check_status(url, status, body) {
+        Err(e) if matches!(status, 429 | 502 | 503) => return Err((e, retry_after)),
+        result => return Ok(result),
+    }
+}
+
+// Sends the request built for each attempt, returning the body of the first
+// response that isn't retried. Retries are recorded when there's somewhere to.
+pub fn send(
+    url: &str,
+    config: &ProviderConfig,
+    retries: Option<&Retries>,
+    request: impl Fn() -> reqwest::blocking::RequestBuilder,
+) -> Result<String, ProviderError> {
+    let mut attempt = 1;
+    loop {
+        let (error, retry_after) = match request().send() {
+            Ok(resp) => {
+                let status = resp.status().as_u16();
+                let headers = resp.headers().clone();
+                let body = resp
+                    .text()
+                    .map_err(|e| ProviderError::unreachable(url, e))?;
+                match check_response(url, status, &headers, body) {
+                    Ok(result) => return result,
+                    Err(retried) => retried,
+                }
+            }
+            // The request may have been acted on if it failed after connecting
+            Err(e) if e.is_connect() => (ProviderError::unreachable(url, e), None),
+            Err(e) => return Err(ProviderError::unreachable(url, e)),
+        };
+        std::thread::sleep(config.retry(attempt, error, retry_after, retries)?);
+        attempt += 1;
+    }
+}
+
+// Like send, awaiting each attempt and the delay before the next
+pub async fn send_async(
+    url: &str,
+    config: &ProviderConfig,
+    retries: Option<&Retries>,
+    request: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<String, ProviderError> {
+    let mut attempt = 1;
+    loop {
+        let (error, retry_after) = match request().send().await {
+            Ok(resp) => {
+                let status = resp.status().as_u16();
+                let headers = resp.headers().clone();
+                let body = resp
+                    .text()
+                    .await
+                    .map_err(|e| ProviderError::unreachable(url, e))?;
+                match check_response(url, status, &headers, body) {
+                    Ok(result) => return result,
+                    Err(retried) => retried,
+                }
+            }
+            Err(e) if e.is_connect() => (ProviderError::unreachable(url, e), None),
+            Err(e) => return Err(ProviderError::unreachable(url, e)),
+        };
+        tokio::time::sleep(config.retry(attempt, error, retry_after, retries)?).await;
+        attempt += 1;
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct ProviderSchema {
-    pub functions: Vec<String>,
+    pub functions: Vec<SchemaFunction>,
+}
+
+// A function of a provider, which older providers only give the name of
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SchemaFunction {
+    Name(String),
+    Typed(FunctionSchema),
+}
+
+impl SchemaFunction {
+    pub fn name(&self) -> &str {
+        match self {
+            SchemaFunction::Name(name) => return name,
+            SchemaFunction::Typed(func) => return &func.name,
+        }
+    }
+}
+
+// The signature of a provider's function, eg.
+// `{"name": "createBucket", "params": [{"struct": "Bucket"}], "returns": ["int"],
+// "structs": {"Bucket": [{"name": "name", "type": "string"}]}}`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FunctionSchema {
+    pub name: String,
+    pub params: Vec<TypeDesc>,
+    pub returns: Vec<TypeDesc>,
+    // The fields of the structs in the signature, by name
+    #[serde(default)]
+    pub structs: BTreeMap<String, Vec<FieldDesc>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypeDesc {
+    Int,
+    Float,
+    Bool,
+    String,
+    Array(Box<TypeDesc>),
+    Struct(String),
+}
+
+impl TypeDesc {
+    pub fn data_type(&self) -> DataType {
+        match self {
+            TypeDesc::Int => return DataType::Integer,
+            TypeDesc::Float => return DataType::Float,
+            TypeDesc::Bool => return DataType::Bool,
+            TypeDesc::String => return DataType::String,
+            TypeDesc::Array(el) => return DataType::Array(Box::new(el.data_type())),
+            TypeDesc::Struct(name) => return DataType::Struct(name.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FieldDesc {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub typ: TypeDesc,
+}
+
+impl ProviderSchema {
+    // Decodes the body of the provider's response to a request for its schema
+    pub fn decode(url: &str, body: &str) -> Result<Self, ProviderError> {
+        let val: serde_json::Value = serde_json::from_str(body).map_err(|e| {
+            return ProviderError::InvalidJson {
+                url: url.to_string(),
+                error: e.to_string(),
+                body: excerpt(body),
+            };
+        })?;
+        return serde_json::from_value(val).map_err(|_| ProviderError::MissingFunctions {
+            url: url.to_string(),
+            body: excerpt(body),
+        });
+    }
+}
+
+/// Why a provider couldn't be added, or a call to one of its functions failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProviderError {
+    // No response was received, eg. as the connection was refused
+    Unreachable {
+        url: String,
+        error: String,
+    },
+    // The provider responded with an error status
+    Status {
+        url: String,
+        status: u16,
+        body: String, // the start of the body, for every variant with one
+    },
+    InvalidJson {
+        url: String,
+        error: String,
+        body: String,
+    },
+    // The schema is JSON, but doesn't list the provider's functions
+    MissingFunctions {
+        url: String,
+        body: String,
+    },
+    // The returns are JSON, but don't match the function's declared returns
+    InvalidReturns {
+        url: String,
+        error: String,
+        body: String,
+    },
+    // The params of a call don't match the function's declared params
+    InvalidParams {
+        url: String,
+        error: String,
+    },
+    // A type in the schema refers to a struct the schema doesn't describe
+    UnknownStruct {
+        url: String,
+        name: String,
+    },
+    // A declaration in the program disagrees with the schema
+    Drift {
+        url: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProviderError::Unreachable { url, error } => {
+                write!(f, "provider error: cannot reach {}: {}", url, error)
+            }
+            ProviderError::Status { url, status, body } => {
+                write!(
+                    f,
+                    "provider error: {} responded with status {}: {}",
+                    url, status, body
+                )
+            }
+            ProviderError::InvalidJson { url, error, body } => {
+                write!(
+                    f,
+                    "provider error: {} responded with invalid JSON ({}): {}",
+                    url, error, body
+                )
+            }
+            ProviderError::MissingFunctions { url, body } => {
+                write!(
+                    f,
+                    "provider error: the schema from {} doesn't list its functions: {}",
+                    url, body
+                )
+            }
+            ProviderError::InvalidReturns { url, error, body } => {
+                write!(
+                    f,
+                    "provider error: {} returned values that don't match the declared returns ({}): {}",
+                    url, error, body
+                )
+            }
+            ProviderError::InvalidParams { url, error } => {
+                write!(
+                    f,
+                    "provider error: cannot send the params to {}: {}",
+                    url, error
+                )
+            }
+            ProviderError::UnknownStruct { url, name } => {
+                write!(
+                    f,
+                    "provider error: the schema from {} uses struct {}, but doesn't describe it",
+                    url, name
+                )
+            }
+            ProviderError::Drift { url, message } => {
+                write!(f, "provider error: schema drift from {}: {}", url, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl ProviderError {
+    // The request failed, with the reasons it did, eg. the connection refused
+    pub fn unreachable(url: &str, e: reqwest::Error) -> Self {
+        let mut error = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(e) = source {
+            error += &format!(": {}", e);
+            source = e.source();
+        }
+        return ProviderError::Unreachable {
+            url: url.to_string(),
+            error,
+        };
+    }
+}
+
+// The body of a response, unless it has an error status
+pub fn check_status(url: &str, status: u16, body: String) -> Result<String, ProviderError> {
+    if !(200..300).contains(&status) {
+        return Err(ProviderError::Status {
+            url: url.to_string(),
+            status,
+            body: excerpt(&body),
+        });
+    }
+    return Ok(body);
+}
+
+// The start of a body to include in an error, as it may be a whole HTML page
+fn excerpt(body: &str) -> String {
+    const LEN: usize = 200;
+    match body.char_indices().nth(LEN) {
+        Some((idx, _)) => return format!("{}...", &body[..idx]),
+        None => return body.to_string(),
+    }
 }
 
 #[derive(Serialize)]
 pub struct DMCLRPC<'a> {
-    pub id: (usize, usize, usize),
+    pub id: CallId,
     pub params: Vec<TypeAndVal<'a>>,
 }
 
+impl<'a> DMCLRPC<'a> {
+    // The request to a provider for an extern call
+    pub fn new(
+        id: CallId,
+        param_types: &[DataType],
+        param_vals: Vec<DataVal>,
+        user_structs: &'a HashMap<String, Struct>,
+    ) -> Self {
+        let params = param_types
+            .iter()
+            .zip(param_vals)
+            .map(|(typ, val)| TypeAndVal {
+                typ: typ.clone(),
+                val,
+                user_structs,
+                compat: None,
+                path: String::new(),
+            })
+            .collect();
+        return Self { id, params };
+    }
+
+    // The body of the request to the url
+    pub fn encode(&self, url: &str) -> Result<Vec<u8>, ProviderError> {
+        return serde_json::to_vec(self).map_err(|e| ProviderError::InvalidParams {
+            url: url.to_string(),
+            error: e.to_string(),
+        });
+    }
+}
+
 pub enum Expecting<T>
 where
     T: Serialize,
@@ -51,6 +492,11 @@ pub struct TypeAndVal<'a> {
     pub val: DataVal,
     pub typ: DataType,
     pub user_structs: &'a HashMap<String, Struct>,
+
+    // Only used while deserializing. Values are coerced when there is
+    // somewhere to record the warnings.
+    pub compat: Option<&'a Warnings>,
+    pub path: String,
 }
 
 impl Serialize for TypeAndVal<'_> {
@@ -58,56 +504,70 @@ impl Serialize for TypeAndVal<'_> {
     where
         S: serde::Serializer,
     {
+        // A value of any type can be absent
+        if self.val == DataVal::Null {
+            return serializer.serialize_none();
+        }
+
+        let mismatch = |v: DataVal| S::Error::custom(format!("expected {}, found {}", self.typ, v));
+        let el = |val: DataVal, typ: &DataType| TypeAndVal {
+            val,
+            typ: typ.clone(),
+            user_structs: self.user_structs,
+            compat: None,
+            path: String::new(),
+        };
+
         match &self.typ {
             DataType::Integer => {
-                let i = self.val.clone().into_integer().unwrap();
+                let i = self.val.clone().into_integer().map_err(mismatch)?;
                 serializer.serialize_i64(i)
             }
             DataType::Float => {
-                let f = self.val.clone().into_float().unwrap();
+                let f = self.val.clone().into_float().map_err(mismatch)?;
                 serializer.serialize_f64(f)
             }
             DataType::Bool => {
-                let b = self.val.clone().into_bool().unwrap();
+                let b = self.val.clone().into_bool().map_err(mismatch)?;
                 serializer.serialize_bool(b)
             }
             DataType::String => {
-                let s = self.val.clone().into_string().unwrap();
+                let s = self.val.clone().into_string().map_err(mismatch)?;
                 serializer.serialize_str(&s)
             }
             DataType::Array(el_typ) => {
-                let arr = self.val.clone().into_compound().unwrap();
+                let arr = self.val.clone().into_compound().map_err(mismatch)?;
 
                 let mut seq = serializer.serialize_seq(Some(arr.len()))?;
-                for el in arr {
-                    seq.serialize_element(&TypeAndVal {
-                        val: el,
-                        typ: *el_typ.clone(),
-                        user_structs: &self.user_structs,
-                    })?;
+                for val in arr {
+                    seq.serialize_element(&el(val, el_typ))?;
                 }
 
                 seq.end()
             }
+            DataType::Map(val_typ) => {
+                let vals = self.val.clone().into_map().map_err(mismatch)?;
+
+                let mut map = serializer.serialize_map(Some(vals.len()))?;
+                for (key, val) in vals {
+                    map.serialize_entry(&key, &el(val, val_typ))?;
+                }
+
+                map.end()
+            }
             DataType::Struct(struct_name) => {
-                let struct_struct = self.user_structs.get(struct_name).unwrap().clone();
-                let arr = self.val.clone().into_compound().unwrap();
+                let strct = self
+                    .user_structs
+                    .get(struct_name)
+                    .ok_or_else(|| S::Error::custom(format!("unknown struct {}", struct_name)))?;
+                let arr = self.val.clone().into_compound().map_err(mismatch)?;
+                if arr.len() != strct.types.len() {
+                    return Err(mismatch(DataVal::Compound(arr)));
+                }
 
                 let mut map = serializer.serialize_map(Some(arr.len()))?;
-                for (idx, val) in arr.iter().enumerate() {
-                    map.serialize_entry(
-                        struct_struct
-                            .names
-                            .iter()
-                            .find(|kv| *kv.1 == idx)
-                            .unwrap()
-                            .0,
-                        &TypeAndVal {
-                            val: val.clone(),
-                            typ: struct_struct.types[idx].clone(),
-                            user_structs: &self.user_structs,
-                        },
-                    )?;
+                for (idx, val) in arr.into_iter().enumerate() {
+                    map.serialize_entry(strct.field_name(idx), &el(val, &strct.types[idx]))?;
                 }
 
                 map.end()
@@ -131,6 +591,8 @@ impl<'de> DeserializeSeed<'de> for TypeAndVal<'_> {
         deserializer.deserialize_any(TypeAndValVisitor {
             user_structs: self.user_structs,
             typ: self.typ,
+            compat: self.compat,
+            path: self.path,
         })
     }
 }
@@ -138,6 +600,35 @@ impl<'de> DeserializeSeed<'de> for TypeAndVal<'_> {
 struct TypeAndValVisitor<'a> {
     user_structs: &'a HashMap<String, Struct>,
     typ: DataType,
+    compat: Option<&'a Warnings>,
+    path: String,
+}
+
+impl<'a> TypeAndValVisitor<'a> {
+    // Records the coercion of a received value, returning whether it's allowed
+    fn coerce(&self, received: String) -> bool {
+        match self.compat {
+            Some(warnings) => {
+                warnings.lock().unwrap().push(ProviderWarning {
+                    path: self.path.clone(),
+                    expected: self.typ.clone(),
+                    received,
+                });
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    fn seed(&self, typ: DataType, path: String) -> TypeAndVal<'a> {
+        return TypeAndVal {
+            val: DataVal::Bool(false),
+            typ,
+            user_structs: self.user_structs,
+            compat: self.compat,
+            path,
+        };
+    }
 }
 
 impl<'de> Visitor<'de> for TypeAndValVisitor<'_> {
@@ -153,7 +644,12 @@ impl<'de> Visitor<'de> for TypeAndValVisitor<'_> {
     {
         match self.typ {
             DataType::Integer => Ok(DataVal::Integer(v)),
-            DataType::Float => Ok(DataVal::Float(v as f64)),
+            DataType::Float if self.compat.is_none() || self.coerce(format!("int {}", v)) => {
+                Ok(DataVal::Float(v as f64))
+            }
+            DataType::String if self.coerce(format!("int {}", v)) => {
+                Ok(DataVal::String(v.to_string().into()))
+            }
             _ => Err(de::Error::invalid_type(Unexpected::Signed(v), &self)),
         }
     }
@@ -164,7 +660,12 @@ impl<'de> Visitor<'de> for TypeAndValVisitor<'_> {
     {
         match self.typ {
             DataType::Integer => Ok(DataVal::Integer(v as i64)),
-            DataType::Float => Ok(DataVal::Float(v as f64)),
+            DataType::Float if self.compat.is_none() || self.coerce(format!("int {}", v)) => {
+                Ok(DataVal::Float(v as f64))
+            }
+            DataType::String if self.coerce(format!("int {}", v)) => {
+                Ok(DataVal::String(v.to_string().into()))
+            }
             _ => Err(de::Error::invalid_type(Unexpected::Unsigned(v), &self)),
         }
     }
@@ -174,8 +675,13 @@ impl<'de> Visitor<'de> for TypeAndValVisitor<'_> {
         E: de::Error,
     {
         match self.typ {
-            DataType::Integer => Ok(DataVal::Integer(v as i64)),
+            DataType::Integer if self.compat.is_none() || self.coerce(format!("float {}", v)) => {
+                Ok(DataVal::Integer(v as i64))
+            }
             DataType::Float => Ok(DataVal::Float(v)),
+            DataType::String if self.coerce(format!("float {}", v)) => {
+                Ok(DataVal::String(v.to_string().into()))
+            }
             _ => Err(de::Error::invalid_type(Unexpected::Float(v), &self)),
         }
     }
@@ -194,24 +700,45 @@ impl<'de> Visitor<'de> for TypeAndValVisitor<'_> {
     where
         E: de::Error,
     {
+        // Numeric strings can only be coerced when they parse
+        let received = || format!("string {:?}", v);
         match self.typ {
-            DataType::String => Ok(DataVal::String(v.to_string())),
+            DataType::String => Ok(DataVal::String(v.into())),
+            DataType::Integer if v.parse::<i64>().is_ok() && self.coerce(received()) => {
+                Ok(DataVal::Integer(v.parse().unwrap()))
+            }
+            DataType::Float if v.parse::<f64>().is_ok() && self.coerce(received()) => {
+                Ok(DataVal::Float(v.parse().unwrap()))
+            }
             _ => Err(de::Error::invalid_type(Unexpected::Str(v), &self)),
         }
     }
 
+    // Any value can be null, as providers leave out optional fields
+    fn visit_unit<E>(self) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(DataVal::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(DataVal::Null)
+    }
+
     fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
     where
         A: de::SeqAccess<'de>,
     {
-        match self.typ {
+        match &self.typ {
             DataType::Array(el_type) => {
                 let mut arr = vec![];
-                while let Some(el) = seq.next_element_seed(TypeAndVal {
-                    val: DataVal::Bool(false),
-                    typ: *el_type.clone(),
-                    user_structs: self.user_structs,
-                })? {
+                while let Some(el) = seq.next_element_seed(
+                    self.seed(*el_type.clone(), format!("{}[{}]", self.path, arr.len())),
+                )? {
                     arr.push(el);
                 }
 
@@ -225,11 +752,11 @@ impl<'de> Visitor<'de> for TypeAndValVisitor<'_> {
     where
         A: serde::de::MapAccess<'de>,
     {
-        match self.typ {
+        match &self.typ {
             DataType::Struct(struct_name) => {
-                let stru = &self.user_structs[&struct_name];
+                let stru = &self.user_structs[struct_name];
 
-                let mut arr = vec![DataVal::Bool(false); stru.types.len()];
+                let mut arr = vec![DataVal::Null; stru.types.len()];
                 while let Some(key) = map.next_key::<String>()? {
                     // If any key in this map is waiting (there should only be one)
                     // then this entire object is a single DataVal::Waiting.
@@ -239,21 +766,37 @@ impl<'de> Visitor<'de> for TypeAndValVisitor<'_> {
                     }
 
                     // Discard unknown keys
-                    if stru.names.get(&key).is_none() {
+                    if !stru.names.contains_key(&key) {
                         map.next_value::<serde::de::IgnoredAny>()?;
                         continue;
                     }
 
-                    let val = map.next_value_seed(TypeAndVal {
-                        val: DataVal::Bool(false),
-                        typ: stru.types[stru.names[&key]].clone(),
-                        user_structs: self.user_structs,
-                    })?;
+                    let val = map.next_value_seed(self.seed(
+                        stru.types[stru.names[&key]].clone(),
+                        format!("{}.{}", self.path, key),
+                    ))?;
                     arr[stru.names[&key]] = val;
                 }
 
                 Ok(DataVal::Compound(arr))
             }
+            DataType::Map(val_typ) => {
+                let mut vals = BTreeMap::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    // Like a struct, the whole map is waiting if it has this key
+                    if key == "$waiting" {
+                        map.next_value::<serde::de::IgnoredAny>()?;
+                        return Ok(DataVal::Waiting);
+                    }
+
+                    let val = map.next_value_seed(
+                        self.seed(*val_typ.clone(), format!("{}[{:?}]", self.path, key)),
+                    )?;
+                    vals.insert(key, val);
+                }
+
+                Ok(DataVal::Map(vals))
+            }
             _ => {
                 // this could be waiting
                 while let Some((k, v)) = map.next_entry::<String, bool>()? {
@@ -273,6 +816,25 @@ impl<'de> Visitor<'de> for TypeAndValVisitor<'_> {
 pub struct ExternReturns<'a> {
     pub user_structs: &'a HashMap<String, Struct>,
     pub types: Vec<DataType>,
+    pub compat: Option<&'a Warnings>,
+    pub function: String, // for the paths of warnings
+}
+
+impl ExternReturns<'_> {
+    // Decodes the body of a provider's response to an extern call
+    pub fn decode(self, url: &str, body: &str) -> Result<Vec<DataVal>, ProviderError> {
+        let mut deserializer = serde_json::Deserializer::from_str(body);
+        return DeserializeSeed::deserialize(self, &mut deserializer)
+            .and_then(|returns| deserializer.end().map(|_| returns))
+            .map_err(|e| {
+                let (url, error, body) = (url.to_string(), e.to_string(), excerpt(body));
+                if e.is_data() {
+                    return ProviderError::InvalidReturns { url, error, body };
+                } else {
+                    return ProviderError::InvalidJson { url, error, body };
+                }
+            });
+    }
 }
 
 impl<'de> DeserializeSeed<'de> for ExternReturns<'_> {
@@ -285,6 +847,8 @@ impl<'de> DeserializeSeed<'de> for ExternReturns<'_> {
         deserializer.deserialize_seq(ExternReturnsVisitor {
             user_structs: self.user_structs,
             types: self.types,
+            compat: self.compat,
+            function: self.function,
         })
     }
 }
@@ -292,6 +856,8 @@ impl<'de> DeserializeSeed<'de> for ExternReturns<'_> {
 struct ExternReturnsVisitor<'a> {
     user_structs: &'a HashMap<String, Struct>,
     types: Vec<DataType>,
+    compat: Option<&'a Warnings>,
+    function: String,
 }
 
 impl<'de> Visitor<'de> for ExternReturnsVisitor<'_> {
@@ -306,17 +872,17 @@ impl<'de> Visitor<'de> for ExternReturnsVisitor<'_> {
         A: serde::de::SeqAccess<'de>,
     {
         let mut arr = vec![];
-        let mut i = 0;
-        while let Some(el) = seq.next_element_seed(TypeAndVal {
-            val: DataVal::Bool(false),
-            typ: self.types[i].clone(),
-            user_structs: self.user_structs,
-        })? {
-            arr.push(el);
-            i += 1;
-
-            if i == self.types.len() {
-                break;
+        for (i, typ) in self.types.iter().enumerate() {
+            let el = seq.next_element_seed(TypeAndVal {
+                val: DataVal::Bool(false),
+                typ: typ.clone(),
+                user_structs: self.user_structs,
+                compat: self.compat,
+                path: format!("{}[{}]", self.function, i),
+            })?;
+            match el {
+                Some(el) => arr.push(el),
+                None => return Err(de::Error::invalid_length(i, &self)),
             }
         }
 
@@ -1,13 +1,14 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use crate::{ast, lexer};
 
 pub struct Scope {
     pub prev: Option<Box<Scope>>,
-    sym_table: HashMap<String, ast::Ident>,
+    sym_table: HashMap<Arc<str>, ast::Ident>,
 }
 
-impl<'a> Scope {
+impl Scope {
     pub fn new(prev: Option<Box<Scope>>) -> Scope {
         return Scope {
             prev,
@@ -16,13 +17,24 @@ impl<'a> Scope {
     }
 
     pub fn put(&mut self, w: lexer::Token, i: ast::Ident) {
-        println!("assigning {:?} to slot {:?}", w, i.addr);
         match w {
             lexer::Token::Word(s) => self.sym_table.insert(s, i),
             _ => panic!("cannot save non-word in symbol table: {:?}", w),
         };
     }
 
+    // Like put, but only if the name isn't already declared in this scope.
+    // Returns whether it was declared.
+    pub fn declare(&mut self, w: lexer::Token, i: ast::Ident) -> bool {
+        if let lexer::Token::Word(s) = &w {
+            if self.sym_table.contains_key(s) {
+                return false;
+            }
+        }
+        self.put(w, i);
+        return true;
+    }
+
     pub fn get(&self, w: lexer::Token) -> Option<ast::Ident> {
         let s = match w.clone() {
             lexer::Token::Word(s) => s,
@@ -41,7 +53,69 @@ impl<'a> Scope {
         }
     }
 
+    // Whether the ident was declared at the top level, rather than in a block
+    pub fn is_global(&self, i: &ast::Ident) -> bool {
+        match &self.prev {
+            Some(s) => return s.is_global(i),
+            None => return self.sym_table.values().any(|g| g.addr == i.addr),
+        }
+    }
+
+    // The number of scopes enclosing this one
+    pub fn depth(&self) -> usize {
+        match &self.prev {
+            Some(s) => return s.depth() + 1,
+            None => return 0,
+        }
+    }
+
+    // The name in this scope or an enclosing one that is closest to a misspelt
+    // one, if any are close enough to be what was meant
+    pub fn closest(&self, name: &str) -> Option<String> {
+        let mut best: Option<(usize, &str)> = None;
+        let mut scope = Some(self);
+        while let Some(s) = scope {
+            for candidate in s.sym_table.keys() {
+                let d = edit_distance(name, candidate);
+                // Ties go to the first name alphabetically, so the suggestion is stable
+                if best.is_none_or(|b| (d, &**candidate) < b) {
+                    best = Some((d, candidate));
+                }
+            }
+            scope = s.prev.as_deref();
+        }
+
+        // Allow one edit for every three characters, and at least one
+        let max = (name.chars().count() / 3).max(1);
+        return best.filter(|(d, _)| *d <= max).map(|(_, s)| s.to_string());
+    }
+
     pub fn take_prev(self) -> Scope {
         return *self.prev.unwrap();
     }
 }
+
+// The number of characters to insert, remove, replace or swap with the one
+// next to it to turn a into b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    d[0] = (0..=b.len()).collect();
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    return d[a.len()][b.len()];
+}
@@ -1,57 +1,527 @@
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
 use enum_as_inner::EnumAsInner;
-use serde::de::DeserializeSeed;
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
+use crate::ast::func::ExternFuncImpl;
+use crate::ast::Stmt;
+use crate::expect::{instr_kind, ExecExpectations, TraceEntry};
+use crate::graph::{self, Lineage};
 use crate::lexer::{self, Token};
-use crate::provider::{ExternReturns, ProviderSchema, TypeAndVal, DMCLRPC};
+use crate::native::NativeFn;
+use crate::optimize;
+use crate::parser::Parser;
+use crate::plan::{ExecutionPlan, PlannedCall, PossibleCall};
+use crate::provider::{
+    self, ExternReturns, ProviderConfig, ProviderError, ProviderMode, ProviderSchema,
+    ProviderWarning, Retries, SchemaFunction, TypeAndVal, Warnings, DMCLRPC,
+};
 use crate::stac;
+use crate::state::{self, RecordedCall, StateStore};
+use crate::stdlib;
+use crate::telemetry::{self, ExternSpan, Phase, PhaseSpan};
+use crate::trace::{TraceEvent, TraceHook};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Addr(pub usize); // Addr of variable in memory
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Label(pub usize); // A label of a block to jump to.
 
 impl Label {
     pub const CONTINUE: Label = Label(usize::MAX); // continue execution. used in if.
 }
 
-#[derive(Clone)]
+// Why a frame was pushed onto the call stack.
+//
+// Every frame resumes at the instruction after the one that pushed it. An if
+// expr that jumps to a branch, a goto, a loop and a call each push one, and the
+// implicit EndBlock at the end of every block pops one. So the statements after
+// an if in the same block run once, after its branch, and a call returns to the
+// statement after it even if its last statement is an if. A waiting if expr
+// pushes a branch frame too, which is popped once its side effects are evaluated.
+//
+// A loop's body ends like a continue, unwinding the frames of the iteration back
+// to the loop's frame before jumping to its condition, so the call stack doesn't
+// grow with each iteration. When the condition is false, the end of its block
+// pops the loop's frame. Break and return unwind straight to the nearest loop or
+// call frame instead, and continue unwinds to the loop's frame but leaves it
+// there, as if the loop had just started.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FrameKind {
+    Branch, // an if expr or goto, resumed at the end of the block
+    Loop,   // the start of a loop, which break can also resume
+    Call,   // a function call, which return also resumes
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    pub ret: (usize, usize), // the instruction that pushed the frame
+    pub kind: FrameKind,
+}
+
+// The most blocks a program may be compiled into
+pub const BLOCK_LIMIT: usize = 1 << 16;
+
+// The most blocks a single side effect evaluation may visit before giving up
+pub const SIDE_EFFECT_BLOCK_LIMIT: usize = 4096;
+
+// The most calls that may be in progress at once, unless the limit is changed
+// with Prog::set_max_call_depth
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+// The longest array make may create, so that a length from an extern can't use
+// up all the memory of the host, unless the limit is changed with
+// Prog::set_max_array_length
+pub const DEFAULT_MAX_ARRAY_LENGTH: usize = 1 << 20;
+
+// The most instructions a single execution may run, unless the limit is
+// changed with Prog::set_cycle_limit
+pub const DEFAULT_CYCLE_LIMIT: usize = 10_000_000;
+
+// A variable that side effect mode marked as waiting, because it may have
+// been assigned in a branch we couldn't decide on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpeculativeStore {
+    pub addr: Addr,
+    pub block: Label,           // the block containing the assignment
+    pub origin: (usize, usize), // the waiting if expr that started side effect mode
+}
+
+// An extern call that side effect mode found in a branch it couldn't decide
+// on. It isn't made, as its params aren't known, but the host may want to
+// plan for the resource it would create.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeferredCall {
+    pub func: String,
+    pub id: CallId, // the id the call takes, as if it were made
+    pub call_site: (usize, usize),
+    pub origin: (usize, usize), // the waiting if expr that started side effect mode
+}
+
+// Information collected while executing a program
+#[derive(Clone, Debug, Default)]
+pub struct ExecReport {
+    pub speculative_stores: Vec<SpeculativeStore>,
+    pub deferred_calls: Vec<DeferredCall>,
+    // Waiting if exprs that were reached again with no new waiting variables,
+    // so evaluating their side effects again was skipped
+    pub converged: Vec<(usize, usize)>,
+
+    // The calls made to each extern, counted by call site
+    pub extern_calls: HashMap<String, HashMap<(usize, usize), usize>>,
+    pub max_eval_stack: usize,
+    pub max_call_stack: usize,
+    pub entered_side_effects: bool,
+}
+
+// What a run of a program did, as returned by Prog::summary
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunSummary {
+    pub extern_calls: HashMap<String, HashMap<(usize, usize), usize>>,
+    pub waiting: Vec<String>, // the variables holding Waiting, in declaration order
+    pub cycles: usize,
+    pub max_eval_stack: usize,
+    pub max_call_stack: usize,
+    pub entered_side_effects: bool,
+    pub near_cycle_limit: bool, // whether 90% of the cycle limit was used
+}
+
+impl RunSummary {
+    // The calls made to an extern from every call site
+    pub fn extern_call_count(&self, func: &str) -> usize {
+        return self
+            .extern_calls
+            .get(func)
+            .map(|sites| sites.values().sum())
+            .unwrap_or(0);
+    }
+}
+
+// The outcome of a test declared in the program
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub failure: Option<String>, // the message of the assertion that failed
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        return self.failure.is_none();
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Struct {
     pub types: Vec<DataType>,
     pub names: HashMap<String, usize>,
+    pub constraints: Vec<Option<Constraint>>, // for each field
+    // For each field, an id that stays the same when fields are reordered
+    pub ids: Vec<u64>,
+}
+
+impl Struct {
+    pub fn field_name(&self, idx: usize) -> &str {
+        return self.names.iter().find(|kv| *kv.1 == idx).unwrap().0;
+    }
+
+    // The current index of a field from its stable id
+    pub fn field_by_id(&self, id: u64) -> Option<usize> {
+        return self.ids.iter().position(|i| *i == id);
+    }
+}
+
+// Whether a declared function has the signature from a provider's schema
+fn schema_drift_function(
+    name: &str,
+    declared: &Function,
+    params: &[DataType],
+    returns: &[DataType],
+) -> Result<(), String> {
+    if declared.params == params && declared.returns == returns {
+        return Ok(());
+    }
+
+    let names = |types: &[DataType]| {
+        let names: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+        return names.join(", ");
+    };
+    return Err(format!(
+        "{} is declared as ({}) ({}), but the provider's schema has ({}) ({})",
+        name,
+        names(&declared.params),
+        names(&declared.returns),
+        names(params),
+        names(returns)
+    ));
+}
+
+// Whether a declared struct has the fields from a provider's schema, in any order
+fn schema_drift_struct(name: &str, declared: &Struct, schema: &Struct) -> Result<(), String> {
+    let fields = |strct: &Struct| {
+        let mut fields: Vec<(String, String)> = strct
+            .names
+            .iter()
+            .map(|(field, idx)| (field.clone(), strct.types[*idx].to_string()))
+            .collect();
+        fields.sort();
+        return fields;
+    };
+    if fields(declared) == fields(schema) {
+        return Ok(());
+    }
+
+    let show = |strct: &Struct| {
+        let fields: Vec<String> = fields(strct)
+            .iter()
+            .map(|(field, typ)| format!("{}: {}", field, typ))
+            .collect();
+        return fields.join(", ");
+    };
+    return Err(format!(
+        "struct {} is declared with {{ {} }}, but the provider's schema has {{ {} }}",
+        name,
+        show(declared),
+        show(schema)
+    ));
+}
+
+// The first struct the type uses that isn't declared
+fn unknown_struct(typ: &DataType, user_structs: &HashMap<String, Struct>) -> Option<String> {
+    match typ {
+        DataType::Struct(name) if !user_structs.contains_key(name) => return Some(name.clone()),
+        DataType::Array(el) | DataType::Map(el) => return unknown_struct(el, user_structs),
+        _ => return None,
+    }
+}
+
+// The stable id of a field, an FNV-1a hash of its name. Unlike the std hashers
+// this is guaranteed to be the same across builds.
+pub fn field_id(name: &str) -> u64 {
+    return fnv1a(name.as_bytes());
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+// A restriction on the values a struct field may take
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Constraint {
+    OneOf(Vec<DataVal>),
+    Range(i64, i64), // inclusive
+    Matches(#[serde(with = "regex_source")] regex::Regex),
+}
+
+// Regexes are serialized as their source, and compiled again when deserialized
+mod regex_source {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(re: &regex::Regex, s: S) -> Result<S::Ok, S::Error> {
+        return s.serialize_str(re.as_str());
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<regex::Regex, D::Error> {
+        let src = String::deserialize(d)?;
+        return regex::Regex::new(&src).map_err(de::Error::custom);
+    }
+}
+
+impl Constraint {
+    pub fn allows(&self, val: &DataVal) -> bool {
+        match (self, val) {
+            (_, DataVal::Waiting) => return true,
+            (Constraint::OneOf(vals), _) => return vals.contains(val),
+            (Constraint::Range(lo, hi), DataVal::Integer(i)) => return lo <= i && i <= hi,
+            (Constraint::Matches(re), DataVal::String(s)) => return re.is_match(s),
+            _ => return false,
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constraint::OneOf(vals) => {
+                let vals: Vec<_> = vals.iter().map(fmt_const).collect();
+                write!(f, "be one of [{}]", vals.join(", "))
+            }
+            Constraint::Range(lo, hi) => write!(f, "be in {}..{}", lo, hi),
+            Constraint::Matches(re) => write!(f, "match {:?}", re.as_str()),
+        }
+    }
+}
+
+// Formats a constant the way it would be written in source
+fn fmt_const(val: &DataVal) -> String {
+    match val {
+        DataVal::Integer(i) => return i.to_string(),
+        DataVal::Float(x) => return format!("{}f", x),
+        DataVal::Bool(b) => return b.to_string(),
+        DataVal::String(s) => return format!("{:?}", s),
+        _ => return format!("{:?}", val),
+    }
+}
+
+// Describes the first constraint on a field of a struct that the value violates
+pub fn check_field(
+    user_structs: &HashMap<String, Struct>,
+    strct: &str,
+    idx: usize,
+    val: &DataVal,
+) -> Result<(), String> {
+    let s = &user_structs[strct];
+    if let Some(c) = &s.constraints[idx] {
+        if !c.allows(val) {
+            return Err(format!(
+                "constraint violated: field {}.{} must {}, but was {}",
+                strct,
+                s.field_name(idx),
+                c,
+                fmt_const(val)
+            ));
+        }
+    }
+
+    return check_constraints(user_structs, &s.types[idx], val);
+}
+
+// Checks every constrained field within a value of the type
+pub fn check_constraints(
+    user_structs: &HashMap<String, Struct>,
+    typ: &DataType,
+    val: &DataVal,
+) -> Result<(), String> {
+    match (typ, val) {
+        (DataType::Struct(name), DataVal::Compound(fields)) => {
+            for (idx, field) in fields.iter().enumerate() {
+                check_field(user_structs, name, idx, field)?;
+            }
+        }
+        (DataType::Array(el_typ), DataVal::Compound(els)) => {
+            for el in els {
+                check_constraints(user_structs, el_typ, el)?;
+            }
+        }
+        (DataType::Map(val_typ), DataVal::Map(vals)) => {
+            for val in vals.values() {
+                check_constraints(user_structs, val_typ, val)?;
+            }
+        }
+        _ => {}
+    }
+
+    return Ok(());
+}
+
+// Whether any value of the type could violate a constraint
+pub fn is_constrained(user_structs: &HashMap<String, Struct>, typ: &DataType) -> bool {
+    match typ {
+        DataType::Struct(name) => {
+            let s = &user_structs[name];
+            return s.constraints.iter().any(|c| c.is_some())
+                || s.types.iter().any(|t| is_constrained(user_structs, t));
+        }
+        DataType::Array(el_typ) | DataType::Map(el_typ) => {
+            return is_constrained(user_structs, el_typ)
+        }
+        _ => return false,
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Function {
     pub label: Label,
     pub params: Vec<DataType>,
     pub returns: Vec<DataType>,
+    // The variables declared in the function, which a recursive call saves
+    pub locals: Vec<Addr>,
 }
 
-#[derive(Clone, Debug, PartialEq, EnumAsInner)]
+#[derive(Clone, Debug, PartialEq, EnumAsInner, Serialize, Deserialize)]
 pub enum DataType {
     Integer,
     Float,
     Bool,
     String,
     Array(Box<DataType>),
-    Struct(String), // the name of struct
-    Waiting,        // this value is waiting on an external resource to be created
+    Map(Box<DataType>), // the type of the values, as keys are always strings
+    Struct(String),     // the name of struct
+    Waiting,            // this value is waiting on an external resource to be created
+}
+
+// Prints the type the way it is written in source
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataType::Integer => write!(f, "int"),
+            DataType::Float => write!(f, "float"),
+            DataType::Bool => write!(f, "bool"),
+            DataType::String => write!(f, "string"),
+            DataType::Array(el) => write!(f, "[]{}", el),
+            DataType::Map(val) => write!(f, "map[string]{}", val),
+            DataType::Struct(name) => write!(f, "{}", name),
+            DataType::Waiting => write!(f, "waiting"),
+        }
+    }
 }
 
-#[derive(Clone, Debug, PartialEq, EnumAsInner)]
+#[derive(Clone, Debug, PartialEq, EnumAsInner, Serialize, Deserialize)]
 pub enum DataVal {
     Integer(i64),
     Float(f64),
     Bool(bool),
-    String(String),
+    String(Arc<str>), // shared, as strings are copied far more often than changed
     Compound(Vec<DataVal>),
+    Map(BTreeMap<String, DataVal>), // sorted by key, so it always serializes the same way
+    // The absence of a value, eg. an optional field a provider returned as null
+    Null,
     Waiting,
+    // A variable that has never been stored to. It never reaches the eval stack.
+    Uninit,
+}
+
+// Prints the value like JSON, eg. `["web", 2, 1.5]`. Without the types,
+// the fields of structs are printed like the elements of arrays.
+impl fmt::Display for DataVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataVal::Integer(i) => write!(f, "{}", i),
+            DataVal::Float(x) => write!(f, "{:?}", x),
+            DataVal::Bool(b) => write!(f, "{}", b),
+            DataVal::String(s) => write!(f, "{:?}", s),
+            DataVal::Compound(vals) => {
+                write!(f, "[")?;
+                for (idx, v) in vals.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            DataVal::Map(vals) => {
+                write!(f, "{{")?;
+                for (idx, (k, v)) in vals.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: {}", k, v)?;
+                }
+                write!(f, "}}")
+            }
+            DataVal::Null => write!(f, "null"),
+            DataVal::Waiting => write!(f, "waiting"),
+            DataVal::Uninit => write!(f, "uninit"),
+        }
+    }
 }
 
 impl DataVal {
+    /// Prints the value of the type, naming the fields of structs, eg.
+    /// `Droplet { name: "web", ports: [80, 443] }`.
+    pub fn display_typed(&self, typ: &DataType, user_structs: &HashMap<String, Struct>) -> String {
+        match (typ, self) {
+            (DataType::Struct(name), DataVal::Compound(fields)) => {
+                let strct = &user_structs[name];
+                if fields.is_empty() {
+                    return format!("{} {{}}", name);
+                }
+                let fields: Vec<String> = fields
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, v)| {
+                        return format!(
+                            "{}: {}",
+                            strct.field_name(idx),
+                            v.display_typed(&strct.types[idx], user_structs)
+                        );
+                    })
+                    .collect();
+                return format!("{} {{ {} }}", name, fields.join(", "));
+            }
+            (DataType::Array(el_typ), DataVal::Compound(els)) => {
+                let els: Vec<String> = els
+                    .iter()
+                    .map(|v| v.display_typed(el_typ, user_structs))
+                    .collect();
+                return format!("[{}]", els.join(", "));
+            }
+            (DataType::Map(val_typ), DataVal::Map(vals)) => {
+                let vals: Vec<String> = vals
+                    .iter()
+                    .map(|(k, v)| format!("{:?}: {}", k, v.display_typed(val_typ, user_structs)))
+                    .collect();
+                return format!("{{{}}}", vals.join(", "));
+            }
+            _ => return self.to_string(),
+        }
+    }
+
+    // Whether the value or any of its elements or fields are waiting
+    pub fn contains_waiting(&self) -> bool {
+        match self {
+            DataVal::Waiting => return true,
+            DataVal::Compound(vals) => return vals.iter().any(|v| v.contains_waiting()),
+            DataVal::Map(vals) => return vals.values().any(|v| v.contains_waiting()),
+            _ => return false,
+        }
+    }
+
     pub fn default_for(ty: DataType, user_structs: &HashMap<String, Struct>) -> Self {
         match ty {
             DataType::Integer => DataVal::Integer(0),
@@ -59,10 +529,11 @@ impl DataVal {
             DataType::Bool => DataVal::Bool(false),
             DataType::String => DataVal::String("".into()),
             DataType::Array(_) => DataVal::Compound(vec![]),
+            DataType::Map(_) => DataVal::Map(BTreeMap::new()),
             DataType::Struct(struct_name) => {
                 let strct = user_structs.get(&struct_name).unwrap();
                 let mut compound = vec![DataVal::Bool(false); strct.names.len()];
-                for (_, idx) in &strct.names {
+                for idx in strct.names.values() {
                     // Get the default value for the type
                     compound[*idx] = DataVal::default_for(strct.types[*idx].clone(), user_structs);
                 }
@@ -72,14 +543,146 @@ impl DataVal {
             DataType::Waiting => panic!("no default value for waiting"),
         }
     }
+
+    // Whether the value could be held by a variable of the type
+    pub fn is_type(&self, ty: &DataType, user_structs: &HashMap<String, Struct>) -> bool {
+        match (self, ty) {
+            (DataVal::Integer(_), DataType::Integer)
+            | (DataVal::Float(_), DataType::Float)
+            | (DataVal::Bool(_), DataType::Bool)
+            | (DataVal::String(_), DataType::String)
+            | (DataVal::Null, _) => return true,
+            (DataVal::Compound(vals), DataType::Array(el)) => {
+                return vals.iter().all(|v| v.is_type(el, user_structs))
+            }
+            (DataVal::Map(vals), DataType::Map(val)) => {
+                return vals.values().all(|v| v.is_type(val, user_structs))
+            }
+            (DataVal::Compound(vals), DataType::Struct(name)) => {
+                let strct = &user_structs[name];
+                return vals.len() == strct.types.len()
+                    && vals
+                        .iter()
+                        .zip(&strct.types)
+                        .all(|(v, t)| v.is_type(t, user_structs));
+            }
+            _ => return false,
+        }
+    }
+
+    /// Converts a Rust value into a value of the type, matching struct fields
+    /// by name the same way as values returned by providers.
+    pub fn from_serde<T: Serialize>(
+        value: &T,
+        typ: &DataType,
+        user_structs: &HashMap<String, Struct>,
+    ) -> Result<DataVal, ConversionError> {
+        let json = serde_json::to_value(value).map_err(|e| ConversionError(e.to_string()))?;
+        let seed = TypeAndVal {
+            val: DataVal::Bool(false),
+            typ: typ.clone(),
+            user_structs,
+            compat: None,
+            path: String::new(),
+        };
+        return seed
+            .deserialize(json)
+            .map_err(|e| ConversionError(e.to_string()));
+    }
+
+    /// Converts a value of the type into a Rust value, the same way as the
+    /// params sent to providers.
+    pub fn to_serde<T: DeserializeOwned>(
+        &self,
+        typ: &DataType,
+        user_structs: &HashMap<String, Struct>,
+    ) -> Result<T, ConversionError> {
+        if !self.is_type(typ, user_structs) {
+            return Err(ConversionError(format!("expected {}, found {}", typ, self)));
+        }
+        let json = serde_json::to_value(TypeAndVal {
+            val: self.clone(),
+            typ: typ.clone(),
+            user_structs,
+            compat: None,
+            path: String::new(),
+        })
+        .map_err(|e| ConversionError(e.to_string()))?;
+        return serde_json::from_value(json).map_err(|e| ConversionError(e.to_string()));
+    }
+}
+
+/// A value that can't be converted to or from a Rust type
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConversionError(pub String);
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "conversion error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+// Converts between values and the Rust types that hold them, eg. in externs
+macro_rules! convert {
+    ($t:ty, $variant:ident, $name:literal) => {
+        impl From<$t> for DataVal {
+            fn from(v: $t) -> Self {
+                return DataVal::$variant(v);
+            }
+        }
+
+        impl TryFrom<DataVal> for $t {
+            type Error = ConversionError;
+
+            fn try_from(v: DataVal) -> Result<Self, Self::Error> {
+                match v {
+                    DataVal::$variant(v) => return Ok(v),
+                    v => return Err(ConversionError(format!("expected {}, found {}", $name, v))),
+                }
+            }
+        }
+    };
+}
+
+convert!(i64, Integer, "int");
+convert!(f64, Float, "float");
+convert!(bool, Bool, "bool");
+convert!(Arc<str>, String, "string");
+convert!(Vec<DataVal>, Compound, "an array or struct");
+
+impl From<&str> for DataVal {
+    fn from(v: &str) -> Self {
+        return DataVal::String(v.into());
+    }
 }
 
-#[derive(Debug, Clone)]
+impl From<String> for DataVal {
+    fn from(v: String) -> Self {
+        return DataVal::String(v.into());
+    }
+}
+
+impl TryFrom<DataVal> for String {
+    type Error = ConversionError;
+
+    fn try_from(v: DataVal) -> Result<Self, Self::Error> {
+        return Arc::<str>::try_from(v).map(|s| s.to_string());
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Instr {
     BinaryExpr {
         op: lexer::Token,
     },
     Concat,
+    // Converts a value to a string, naming the fields of structs by the type
+    ToString {
+        typ: DataType,
+    },
+    FloorDiv, // integer division, rounding towards negative infinity
     UnaryExpr {
         op: lexer::Token,
     },
@@ -103,10 +706,21 @@ pub enum Instr {
     },
 
     Discard, // discards an element from the eval_stack
+    DupPair, // pushes copies of the top two elements of the eval_stack
 
     CompoundGet,    // arr, index
     CompoundSet,    // arr, index, value
+    FieldGet,       // struct, field index
+    FieldSet,       // struct, field index, value
     CompoundCreate, // length
+    CompoundFill,   // arr, value
+    CompoundPush,   // arr, value
+    CompoundRemove, // arr, index
+    CompoundLen,    // arr or string
+
+    MapGet, // map, key
+    MapSet, // map, key, value
+    MapHas, // map, key
 
     Goto {
         label: Label,
@@ -115,31 +729,119 @@ pub enum Instr {
         // Adds the return label to the call stack, then does a goto to the function
         label: Label,
     },
-    Return, // Pop the previous label on the callstack and goto it
+    Return,   // Unwind the callstack to the frame of the function call, and resume after it
+    EndBlock, // Implicitly at the end of every block, pops a single frame and resumes after it
+
+    Loop {
+        // Like goto, but marks the frame so break can return to it
+        label: Label,
+    },
+    Break {
+        // The number of enclosing loops to exit
+        loops: usize,
+    },
+    Continue {
+        // Unwinds to the frame of the loop to continue, then jumps to the label
+        loops: usize,
+        label: Label,
+    },
 
     ExternCall {
         param_types: Vec<DataType>,
         return_types: Vec<DataType>,
     },
+
+    // Pops a bool, failing the running test (or panicking outside of tests) when it's false
+    Assert {
+        msg: String,
+    },
+
+    // Checks the constraints of the values on top of the stack, leaving them there
+    Validate {
+        types: Vec<DataType>,
+    },
+
+    TypeOf {
+        // The static type of the value, as values don't know their struct
+        typ: DataType,
+    },
+    IsWaiting,
+    IsNull,
+    // Converts a number or a numeric string to another number type
+    Cast {
+        to: DataType,
+    },
+}
+
+impl Instr {
+    // The values the instruction takes from the top of the eval stack
+    pub fn operands(&self) -> usize {
+        match self {
+            Instr::BinaryExpr { .. }
+            | Instr::Concat
+            | Instr::FloorDiv
+            | Instr::DupPair
+            | Instr::CompoundGet
+            | Instr::FieldGet
+            | Instr::MapGet
+            | Instr::MapHas
+            | Instr::CompoundFill
+            | Instr::CompoundPush
+            | Instr::CompoundRemove => return 2,
+            Instr::CompoundSet | Instr::FieldSet | Instr::MapSet => return 3,
+            Instr::ToString { .. }
+            | Instr::UnaryExpr { .. }
+            | Instr::StoreIdent { .. }
+            | Instr::IfExpr { .. }
+            | Instr::Discard
+            | Instr::CompoundCreate
+            | Instr::CompoundLen
+            | Instr::Assert { .. }
+            | Instr::TypeOf { .. }
+            | Instr::IsWaiting
+            | Instr::IsNull
+            | Instr::Cast { .. } => return 1,
+            // The name of the extern is above its params
+            Instr::ExternCall { param_types, .. } => return param_types.len() + 1,
+            Instr::Validate { types } => return types.len(),
+            Instr::LoadConst { .. }
+            | Instr::LoadIdent { .. }
+            | Instr::Goto { .. }
+            | Instr::Call { .. }
+            | Instr::Return
+            | Instr::EndBlock
+            | Instr::Loop { .. }
+            | Instr::Break { .. }
+            | Instr::Continue { .. } => return 0,
+        }
+    }
 }
 
 macro_rules! arith {
-    ($self:ident, $op:expr) => {{
-        let x = $self.eval_stack.pop().unwrap();
-        let y = $self.eval_stack.pop().unwrap();
-        if (x.is_waiting() || y.is_waiting()) {
-            $self.eval_stack.push(DataVal::Waiting);
-        } else {
-            match x {
-                DataVal::Integer(_) => $self.eval_stack.push(DataVal::Integer($op(
-                    x.into_integer().unwrap(),
-                    y.into_integer().unwrap(),
-                ))),
-                DataVal::Float(_) => $self.eval_stack.push(DataVal::Float($op(
-                    x.into_float().unwrap(),
-                    y.into_float().unwrap(),
-                ))),
-                _ => panic!("cannot use arithmetic on those types"),
+    ($self:ident, $sym:literal, $int_op:expr, $op:expr) => {{
+        let x = $self.pop()?;
+        let y = $self.pop()?;
+        match (x, y) {
+            (DataVal::Waiting, _) | (_, DataVal::Waiting) => {
+                $self.eval_stack.push(DataVal::Waiting)
+            }
+            (DataVal::Integer(x), DataVal::Integer(y)) => match $int_op(x, y) {
+                Some(v) => $self.eval_stack.push(DataVal::Integer(v)),
+                None => {
+                    return Err($self.error(
+                        ExecErrorKind::IntegerOverflow,
+                        format!("integer overflow: {} {} {}", x, $sym, y),
+                    ))
+                }
+            },
+            (DataVal::Float(x), DataVal::Float(y)) => {
+                $self.eval_stack.push(DataVal::Float($op(x, y)))
+            }
+            (x, y) => {
+                return Err($self.error(
+                    ExecErrorKind::Type,
+                    format!("cannot use arithmetic on {} and {}", x, y),
+                ))
             }
         }
     }};
@@ -147,26 +849,35 @@ macro_rules! arith {
 
 macro_rules! rel {
     ($self:ident, $op:expr) => {{
-        let x = $self.eval_stack.pop().unwrap();
-        let y = $self.eval_stack.pop().unwrap();
-        if (x.is_waiting() || y.is_waiting()) {
-            $self.eval_stack.push(DataVal::Waiting);
-        } else {
-            match x {
-                DataVal::Integer(_) => $self.eval_stack.push(DataVal::Bool($op(
-                    &x.into_integer().unwrap(),
-                    &y.into_integer().unwrap(),
-                ))),
-                DataVal::Float(_) => $self.eval_stack.push(DataVal::Bool($op(
-                    &x.into_float().unwrap(),
-                    &y.into_float().unwrap(),
-                ))),
-                _ => panic!("cannot compare those types"),
+        let x = $self.pop()?;
+        let y = $self.pop()?;
+        match (x, y) {
+            (DataVal::Waiting, _) | (_, DataVal::Waiting) => {
+                $self.eval_stack.push(DataVal::Waiting)
+            }
+            (DataVal::Integer(x), DataVal::Integer(y)) => {
+                $self.eval_stack.push(DataVal::Bool($op(&x, &y)))
+            }
+            (DataVal::Float(x), DataVal::Float(y)) => {
+                $self.eval_stack.push(DataVal::Bool($op(&x, &y)))
+            }
+            (DataVal::String(x), DataVal::String(y)) => {
+                $self.eval_stack.push(DataVal::Bool($op(&x, &y)))
+            }
+            (DataVal::Bool(x), DataVal::Bool(y)) => {
+                $self.eval_stack.push(DataVal::Bool($op(&x, &y)))
+            }
+            (x, y) => {
+                return Err($self.error(
+                    ExecErrorKind::Type,
+                    format!("cannot compare {} and {}", x, y),
+                ))
             }
         }
     }};
 }
 
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Block {
     pub code: Vec<Instr>,
 }
@@ -181,351 +892,2730 @@ impl Block {
     }
 }
 
-pub struct Prog {
-    pub code: Vec<Block>,
-    pub entrypoint: Label,
-
-    pub eval_stack: Vec<DataVal>,
-    pub variables: Vec<DataVal>,
-    pub user_structs: HashMap<String, Struct>,
-    pub user_functions: HashMap<String, Function>,
+// Identifies an extern call across runs of a program, so a host can map it to
+// the resource the call created. The ordinal only counts calls from the same
+// site, so calls elsewhere in the program don't change it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(from = "(usize, usize, usize)", into = "(usize, usize, usize)")]
+pub struct CallId {
+    pub block: usize, // the block and instr of the call instruction
+    pub instr: usize,
+    pub ordinal: usize, // the calls made from the site before this one
+}
 
-    ip: (usize, usize), // instruction pointer (block, instr)
-    call_stack: Vec<(usize, usize)>,
-    cycles: usize,
+impl CallId {
+    /// A key for the call that uses a name the host gave it in place of the
+    /// ordinal, so that it doesn't change when the calls from a loop are made
+    /// in a different order.
+    pub fn with_name(&self, name: &str) -> String {
+        return format!("{}:{}:{}", self.block, self.instr, name);
+    }
+}
 
-    evaluating_side_effects: bool,
-    blocks_to_eval: Vec<Label>,
-    pub external_functions: HashMap<
-        String,
-        Box<
-            dyn Fn(
-                (usize, usize, usize),
-                Vec<DataType>,
-                Vec<DataType>,
-                Vec<DataVal>,
-                &HashMap<String, Struct>,
-            ) -> Vec<DataVal>,
-        >,
-    >,
-    extern_func_call_count: HashMap<String, usize>,
+// Prints the id as the address of the call, eg. "3:1:0"
+impl fmt::Display for CallId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}:{}:{}", self.block, self.instr, self.ordinal);
+    }
 }
 
-impl Prog {
-    pub fn new() -> Prog {
-        Prog {
-            code: vec![],
-            entrypoint: Label(0),
-            eval_stack: vec![],
-            variables: vec![],
-            ip: (0, 0),
-            cycles: 0,
-            call_stack: vec![],
-            user_structs: HashMap::new(),
-            user_functions: HashMap::new(),
-            evaluating_side_effects: false,
-            blocks_to_eval: vec![],
-            external_functions: HashMap::new(),
-            extern_func_call_count: HashMap::new(),
-        }
+impl From<(usize, usize, usize)> for CallId {
+    fn from((block, instr, ordinal): (usize, usize, usize)) -> Self {
+        return CallId {
+            block,
+            instr,
+            ordinal,
+        };
     }
+}
 
-    pub fn allocate_var(&mut self) -> Addr {
-        // Doesn't matter what we set it to, just return the address
-        self.variables.push(DataVal::Bool(false));
-        return Addr(self.variables.len() - 1);
+impl From<CallId> for (usize, usize, usize) {
+    fn from(id: CallId) -> Self {
+        return (id.block, id.instr, id.ordinal);
     }
+}
 
-    pub fn add_block(&mut self, block: Block) -> Label {
-        self.code.push(block);
-        return Label(self.code.len() - 1);
+// An external function receives the call id, the param types, the return
+// types, the param values, the user structs and the host's context (see
+// Prog::set_context). If it fails, execution stops with the error.
+pub type ExternFn = Box<
+    dyn Fn(
+            CallId,
+            Vec<DataType>,
+            Vec<DataType>,
+            Vec<DataVal>,
+            &HashMap<String, Struct>,
+            &mut dyn Any,
+        ) -> Result<Vec<DataVal>, ExternError>
+        + Send
+        + Sync,
+>;
+
+// What a middleware knows about the extern call it is intercepting
+pub struct ExternCtx<'a> {
+    pub id: CallId,
+    pub param_types: &'a [DataType],
+    pub return_types: &'a [DataType],
+    pub user_structs: &'a HashMap<String, Struct>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExternError {
+    pub message: String,
+}
+
+impl ExternError {
+    pub fn new(message: impl Into<String>) -> Self {
+        return Self {
+            message: message.into(),
+        };
     }
+}
 
-    pub fn add_temp_block(&mut self) -> Label {
-        self.code.push(stac::Block::new());
-        return Label(self.code.len() - 1);
+// A provider's function failed, eg. as the provider responded with an error
+impl From<ProviderError> for ExternError {
+    fn from(e: ProviderError) -> Self {
+        return ExternError::new(e.to_string());
     }
+}
 
-    pub fn mod_block(&mut self, block: Block, label: Label) {
-        self.code[label.0] = block;
+impl fmt::Display for ExternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}", self.message);
     }
+}
 
-    pub fn add_http_provider(&mut self, addr: String) {
-        let schema: ProviderSchema = reqwest::blocking::get(addr.clone() + "/provider_schema")
-            .unwrap()
-            .json()
-            .unwrap();
+/// Why execution stopped before the end of the program
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecError {
+    pub kind: ExecErrorKind,
+    pub ip: (usize, usize), // the instruction that failed
+    pub message: String,
+}
 
-        for func in schema.functions {
-            println!("adding {} from {}", &addr, &func);
-            self.add_http_extern(addr.clone(), func);
-        }
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExecErrorKind {
+    // An operation on values of types it doesn't support
+    Type,
+    DivisionByZero,
+    // An integer operation whose result doesn't fit in an int
+    IntegerOverflow,
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    // A value couldn't be converted by int() or float(), eg. NaN or "abc"
+    Conversion,
+    // The state store couldn't keep the returns of a call
+    State,
+    // A map was indexed by a key it doesn't have
+    MissingKey {
+        key: String,
+    },
+    // An array was created with a length less than zero
+    NegativeLength {
+        len: i64,
+    },
+    // An array was created longer than the program's max array length
+    LengthLimitExceeded {
+        len: i64,
+    },
+    UseBeforeInit,
+    // An instruction needed more values than were on the eval stack
+    StackUnderflow {
+        needed: usize,
+        available: usize,
+        instr: String,
+    },
+    // An instruction needed a frame, but the call stack was empty
+    CallStackUnderflow {
+        instr: String,
+    },
+    AssertionFailed,
+    Constraint,
+    Input,
+    SideEffectLimit,
+    // The execution ran more instructions than the cycle limit allows,
+    // counting the elements of arrays it made as instructions too
+    CycleLimitExceeded {
+        cycles: usize,
+    },
+    // More calls were in progress than the max call depth allows
+    StackOverflow {
+        depth: usize,
+    },
+    UnknownExtern,
+    // A middleware refused to let the extern be called
+    Rejected {
+        func: String,
+        error: ExternError,
+    },
+    // The extern was called, but failed
+    Extern {
+        func: String,
+        id: CallId,
+        error: ExternError,
+    },
+    // An instruction the compiler shouldn't have emitted, eg. a break outside of a loop
+    Internal,
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{} at {:?}", self.message, self.ip);
     }
+}
 
-    pub fn add_http_extern(&mut self, addr: String, name: String) {
-        self.external_functions.insert(
-            name.clone(),
-            Box::new(
-                move |id, param_types, return_types, param_vals, user_structs| {
-                    let to_ser: Vec<_> = param_types
-                        .iter()
-                        .enumerate()
-                        .map(|(idx, dtype)| TypeAndVal {
-                            typ: dtype.clone(),
-                            val: param_vals[idx].clone(),
-                            user_structs,
-                        })
-                        .collect();
+impl std::error::Error for ExecError {}
 
-                    let client = reqwest::blocking::Client::new();
-                    let resp = client
-                        .post(format!("{}/{}", &addr, &name))
-                        .json(&DMCLRPC { id, params: to_ser })
-                        .send()
-                        .unwrap();
+// Like an ExternFn, but the call is awaited by Prog::execute_async. It doesn't
+// have access to the host's context, as the call may outlive the borrow. The
+// future it returns doesn't need to be Send, eg. to hold a RefCell.
+pub type AsyncExternFn = Box<
+    dyn Fn(
+            CallId,
+            Vec<DataType>,
+            Vec<DataType>,
+            Vec<DataVal>,
+            &HashMap<String, Struct>,
+        ) -> Pin<Box<dyn Future<Output = Result<Vec<DataVal>, ExternError>>>>
+        + Send
+        + Sync,
+>;
 
-                    let s = resp.text().unwrap();
-                    let mut deserializer = serde_json::Deserializer::from_str(&s);
-                    println!("received from provider: {}", s);
+// What running a single instruction did
+enum Advance {
+    Continued,
+    Call(PendingCall), // it was an extern call, which must be made before continuing
+    Finished,
+}
 
-                    let ext_ret = ExternReturns {
+/// What a step of execution did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StepResult {
+    Continued,
+    Finished,
+    // The next instruction has a breakpoint
+    Breakpoint,
+}
+
+// An extern call that execution stopped at
+struct PendingCall {
+    func_name: String,
+    id: CallId,
+    call_site: (usize, usize),
+    param_types: Vec<DataType>,
+    return_types: Vec<DataType>,
+    param_vals: Vec<DataVal>,
+}
+
+// Rewrites the params of an extern call before it is made, or rejects it
+pub type ExternMiddleware =
+    Box<dyn Fn(&str, Vec<DataVal>, &ExternCtx) -> Result<Vec<DataVal>, ExternError> + Send + Sync>;
+
+// A program and the state of executing it. It is Send and Sync, so it can be
+// moved to or shared between threads, which is why the externs, middleware,
+// context and trace hook it holds must be as well.
+pub struct Prog {
+    pub code: Vec<Block>,
+    pub entrypoint: Label,
+
+    pub eval_stack: Vec<DataVal>,
+    pub variables: Vec<DataVal>,
+    var_names: Vec<String>, // the name each variable was declared with
+    // The address of each variable by name, qualified by the function it's
+    // declared in if any, eg. `clamp.x`
+    var_addrs: HashMap<String, Addr>,
+    pub user_structs: HashMap<String, Struct>,
+    pub user_functions: HashMap<String, Function>,
+    pub tests: Vec<(String, Label)>,
+    // The inputs declared by the program, and the values given by the host
+    inputs: Vec<(String, DataType)>,
+    input_values: HashMap<String, DataVal>,
+    // Where continue jumps to in each loop being compiled, innermost last
+    pub continue_labels: Vec<Label>,
+    // Whether more blocks were added than a program may have, which the
+    // parser reports once the program is emitted
+    blocks_exceeded: bool,
+    // What each extern returns while running tests, instead of waiting
+    pub test_stubs: HashMap<String, Vec<DataVal>>,
+
+    ip: (usize, usize), // instruction pointer (block, instr)
+    call_stack: Vec<Frame>,
+    cycles: usize,
+    cycle_limit: Option<usize>,
+    breakpoints: HashSet<(usize, usize)>,
+    // The calls in progress of each function, by its label
+    active_calls: HashMap<usize, usize>,
+    call_depth: usize,
+    max_call_depth: usize,
+    max_array_length: usize,
+    // The locals of functions that were called again before returning
+    saved_locals: Vec<Vec<DataVal>>,
+
+    testing: bool,
+    assert_failure: Option<String>,
+
+    // Whether extern calls are recorded rather than made, by plan
+    planning: bool,
+    planned_calls: Vec<PlannedCall>,
+    // Which calls the values were derived from, for the dependency graph
+    lineage: Lineage,
+
+    evaluating_side_effects: bool,
+    blocks_to_eval: Vec<Label>,
+    side_effect_origin: (usize, usize),
+    side_effect_visited: HashSet<usize>,
+    // The waiting variables after the side effects of each if expr were last evaluated
+    side_effect_results: HashMap<(usize, usize), HashSet<usize>>,
+    pub report: ExecReport,
+
+    // Only recorded while there are expectations to verify
+    expectations: Option<ExecExpectations>,
+    trace: Vec<TraceEntry>,
+
+    // The W3C trace id that spans for this program belong to, if tracing is enabled
+    pub trace_id: u128,
+    trace_hook: Option<TraceHook>,
+    pub external_functions: HashMap<String, ExternFn>,
+    pub async_external_functions: HashMap<String, AsyncExternFn>,
+    // Shared by the externs, eg. for a client handle or a ledger of resources
+    context: Box<dyn Any + Send + Sync>,
+    extern_middleware: Vec<ExternMiddleware>,
+    // The calls made from each call site, which give the calls their ordinals
+    extern_site_calls: HashMap<(usize, usize), usize>,
+    // The values of extern calls that waited, once the host knows them
+    resolved_externs: HashMap<CallId, Vec<DataVal>>,
+    // The returns of the calls made by earlier executions
+    state: Option<Box<dyn StateStore>>,
+    // The signatures and structs from the typed schemas of providers, which
+    // the program's declarations must agree with
+    schema_functions: HashMap<String, (Vec<DataType>, Vec<DataType>)>,
+    schema_structs: HashMap<String, Struct>,
+    // Shared with the externs of providers in compatibility mode
+    provider_warnings: Arc<Warnings>,
+    // The retries of the HTTP extern being called, for the trace hook
+    provider_retries: Arc<Retries>,
+    // Whether the stdlib was compiled in, and where its print writes to
+    stdlib: bool,
+    output: Arc<stdlib::Output>,
+}
+
+// What Prog::to_bytes saves: the parts of a program that were compiled
+#[derive(Serialize, Deserialize)]
+struct Compiled {
+    code: Vec<Block>,
+    entrypoint: Label,
+    var_names: Vec<String>,
+    var_addrs: HashMap<String, Addr>,
+    user_structs: HashMap<String, Struct>,
+    user_functions: HashMap<String, Function>,
+    tests: Vec<(String, Label)>,
+    inputs: Vec<(String, DataType)>,
+    stdlib: bool,
+}
+
+/// The state of an execution part way through, as taken by `Prog::snapshot`,
+/// which can be serialized and restored into another copy of the program.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExecutionSnapshot {
+    code_hash: u64, // of the program it was taken from
+    variables: Vec<DataVal>,
+    eval_stack: Vec<DataVal>,
+    call_stack: Vec<Frame>,
+    ip: (usize, usize),
+    cycles: usize,
+    extern_site_calls: Vec<((usize, usize), usize)>,
+
+    evaluating_side_effects: bool,
+    blocks_to_eval: Vec<Label>,
+    side_effect_origin: (usize, usize),
+    side_effect_visited: Vec<usize>,
+    // Keyed by the if expr, which can't be the key of a JSON object
+    side_effect_results: Vec<((usize, usize), Vec<usize>)>,
+    resolved_externs: Vec<(CallId, Vec<DataVal>)>,
+    active_calls: Vec<(usize, usize)>,
+    saved_locals: Vec<Vec<DataVal>>,
+}
+
+// Why a snapshot couldn't be restored
+#[derive(Clone, Debug, PartialEq)]
+pub struct RestoreError(pub String);
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "restore error: {}", self.0)
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+impl Default for Prog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// DMCL functions available to every program
+const PRELUDE: &str = include_str!("prelude.dmcl");
+
+impl Prog {
+    /// A program containing the prelude, ready for a unit to be parsed into.
+    pub fn new() -> Prog {
+        let l = lexer::Lexer::new(PRELUDE.chars().collect());
+        let mut par = Parser::with_prog(l, Prog::without_prelude());
+        par.program().expect("the prelude should compile");
+
+        // The locals of the prelude's functions aren't the user's to look up
+        let mut prog = par.into_prog();
+        prog.var_addrs.clear();
+        return prog;
+    }
+
+    /// A program containing the prelude and the stdlib, whose functions (such
+    /// as print, split and max) are implemented as externs by the host.
+    pub fn with_stdlib() -> Prog {
+        let l = lexer::Lexer::new(stdlib::SOURCE.chars().collect());
+        let mut par = Parser::with_prog(l, Prog::new());
+        par.program().expect("the stdlib should compile");
+
+        let mut prog = par.into_prog();
+        prog.stdlib = true;
+        let output = prog.output.clone();
+        stdlib::register(&mut prog, output);
+        return prog;
+    }
+
+    /// An empty program, where the prelude's functions can't be called.
+    pub fn without_prelude() -> Prog {
+        Prog {
+            code: vec![],
+            entrypoint: Label(0),
+            eval_stack: vec![],
+            variables: vec![],
+            var_names: vec![],
+            var_addrs: HashMap::new(),
+            ip: (0, 0),
+            cycles: 0,
+            cycle_limit: Some(DEFAULT_CYCLE_LIMIT),
+            breakpoints: HashSet::new(),
+            active_calls: HashMap::new(),
+            call_depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_array_length: DEFAULT_MAX_ARRAY_LENGTH,
+            saved_locals: vec![],
+            call_stack: vec![],
+            user_structs: HashMap::new(),
+            user_functions: HashMap::new(),
+            tests: vec![],
+            inputs: vec![],
+            input_values: HashMap::new(),
+            continue_labels: vec![],
+            blocks_exceeded: false,
+            test_stubs: HashMap::new(),
+            testing: false,
+            assert_failure: None,
+            planning: false,
+            planned_calls: vec![],
+            lineage: Lineage::default(),
+            state: None,
+            evaluating_side_effects: false,
+            blocks_to_eval: vec![],
+            side_effect_origin: (0, 0),
+            side_effect_visited: HashSet::new(),
+            side_effect_results: HashMap::new(),
+            report: ExecReport::default(),
+            expectations: None,
+            trace: vec![],
+            trace_id: telemetry::new_trace_id(),
+            trace_hook: None,
+            external_functions: HashMap::new(),
+            async_external_functions: HashMap::new(),
+            context: Box::new(()),
+            extern_middleware: vec![],
+            extern_site_calls: HashMap::new(),
+            resolved_externs: HashMap::new(),
+            schema_functions: HashMap::new(),
+            schema_structs: HashMap::new(),
+            provider_warnings: Arc::new(Mutex::new(vec![])),
+            provider_retries: Arc::new(Mutex::new(vec![])),
+            stdlib: false,
+            output: Arc::new(Mutex::new(Box::new(std::io::stdout()))),
+        }
+    }
+
+    /// Copies the compiled program, so it can be executed again with other
+    /// variable bindings without re-parsing.
+    ///
+    /// The copy starts with fresh variables and no runtime state. External
+    /// functions and the context can't be copied, so they (and any providers)
+    /// must be registered again on the copy before it is executed, except for
+    /// those of the stdlib.
+    pub fn duplicate(&self) -> Prog {
+        let mut prog = Prog::without_prelude();
+        prog.code = self.code.clone();
+        prog.entrypoint = self.entrypoint;
+        prog.variables = vec![DataVal::Uninit; self.variables.len()];
+        prog.var_names = self.var_names.clone();
+        prog.var_addrs = self.var_addrs.clone();
+        prog.user_structs = self.user_structs.clone();
+        prog.user_functions = self.user_functions.clone();
+        prog.tests = self.tests.clone();
+        prog.inputs = self.inputs.clone();
+        prog.input_values = self.input_values.clone();
+        prog.test_stubs = self.test_stubs.clone();
+        prog.cycle_limit = self.cycle_limit;
+        prog.max_call_depth = self.max_call_depth;
+        prog.max_array_length = self.max_array_length;
+        prog.output = self.output.clone();
+        if self.stdlib {
+            prog.stdlib = true;
+            stdlib::register(&mut prog, self.output.clone());
+        }
+        return prog;
+    }
+
+    /// Takes a snapshot of the execution, eg. between steps or after it ended
+    /// waiting on an extern.
+    pub fn snapshot(&self) -> ExecutionSnapshot {
+        return ExecutionSnapshot {
+            code_hash: self.code_hash(),
+            variables: self.variables.clone(),
+            eval_stack: self.eval_stack.clone(),
+            call_stack: self.call_stack.clone(),
+            ip: self.ip,
+            cycles: self.cycles,
+            extern_site_calls: self
+                .extern_site_calls
+                .iter()
+                .map(|(site, calls)| (*site, *calls))
+                .collect(),
+            evaluating_side_effects: self.evaluating_side_effects,
+            blocks_to_eval: self.blocks_to_eval.clone(),
+            side_effect_origin: self.side_effect_origin,
+            side_effect_visited: self.side_effect_visited.iter().copied().collect(),
+            side_effect_results: self
+                .side_effect_results
+                .iter()
+                .map(|(ip, vars)| (*ip, vars.iter().copied().collect()))
+                .collect(),
+            resolved_externs: self
+                .resolved_externs
+                .iter()
+                .map(|(id, vals)| (*id, vals.clone()))
+                .collect(),
+            active_calls: self.active_calls.iter().map(|(l, n)| (*l, *n)).collect(),
+            saved_locals: self.saved_locals.clone(),
+        };
+    }
+
+    /// Restores a snapshot taken of this program, or a copy of it, so that
+    /// execution can be continued with `step` or `run_until_complete`.
+    pub fn restore(&mut self, snapshot: ExecutionSnapshot) -> Result<(), RestoreError> {
+        if snapshot.code_hash != self.code_hash() {
+            return Err(RestoreError(
+                "the snapshot was taken of a different program".into(),
+            ));
+        }
+        if snapshot.variables.len() != self.variables.len() {
+            return Err(RestoreError(format!(
+                "the snapshot has {} variables, but the program has {}",
+                snapshot.variables.len(),
+                self.variables.len()
+            )));
+        }
+
+        self.variables = snapshot.variables;
+        self.eval_stack = snapshot.eval_stack;
+        self.call_stack = snapshot.call_stack;
+        self.ip = snapshot.ip;
+        self.cycles = snapshot.cycles;
+        self.extern_site_calls = snapshot.extern_site_calls.into_iter().collect();
+        self.evaluating_side_effects = snapshot.evaluating_side_effects;
+        self.blocks_to_eval = snapshot.blocks_to_eval;
+        self.side_effect_origin = snapshot.side_effect_origin;
+        self.side_effect_visited = snapshot.side_effect_visited.into_iter().collect();
+        self.side_effect_results = snapshot
+            .side_effect_results
+            .into_iter()
+            .map(|(ip, vars)| (ip, vars.into_iter().collect()))
+            .collect();
+        self.resolved_externs = snapshot.resolved_externs.into_iter().collect();
+        self.call_depth = snapshot.active_calls.iter().map(|(_, n)| n).sum();
+        self.active_calls = snapshot.active_calls.into_iter().collect();
+        self.saved_locals = snapshot.saved_locals;
+        return Ok(());
+    }
+
+    // Identifies the compiled code, so that snapshots aren't restored into
+    // another program
+    fn code_hash(&self) -> u64 {
+        let code = serde_json::to_vec(&self.code).expect("the code should serialize");
+        return fnv1a(&code);
+    }
+
+    /// Serializes the compiled program, so it can be executed later without
+    /// parsing it again. Like `duplicate`, the externs (other than those of
+    /// the stdlib) aren't saved, and must be registered again once loaded.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let compiled = Compiled {
+            code: self.code.clone(),
+            entrypoint: self.entrypoint,
+            var_names: self.var_names.clone(),
+            var_addrs: self.var_addrs.clone(),
+            user_structs: self.user_structs.clone(),
+            user_functions: self.user_functions.clone(),
+            tests: self.tests.clone(),
+            inputs: self.inputs.clone(),
+            stdlib: self.stdlib,
+        };
+        return serde_json::to_vec(&compiled).expect("a compiled program should serialize");
+    }
+
+    /// Loads a program saved with `to_bytes`, with fresh variables and no
+    /// runtime state.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Prog, serde_json::Error> {
+        let compiled: Compiled = serde_json::from_slice(bytes)?;
+
+        let mut prog = Prog::without_prelude();
+        prog.code = compiled.code;
+        prog.entrypoint = compiled.entrypoint;
+        prog.variables = vec![DataVal::Uninit; compiled.var_names.len()];
+        prog.var_names = compiled.var_names;
+        prog.var_addrs = compiled.var_addrs;
+        prog.user_structs = compiled.user_structs;
+        prog.user_functions = compiled.user_functions;
+        prog.tests = compiled.tests;
+        prog.inputs = compiled.inputs;
+        if compiled.stdlib {
+            let output = prog.output.clone();
+            prog.stdlib = true;
+            stdlib::register(&mut prog, output);
+        }
+        return Ok(prog);
+    }
+
+    /// Runs each test declared in the program, after running the program itself.
+    ///
+    /// Every test gets its own copy of the program, where externs aren't called.
+    /// Instead they return the values in `test_stubs`, or wait if there are none.
+    pub fn run_tests(&self) -> Vec<TestResult> {
+        let mut results = vec![];
+        for (name, label) in &self.tests {
+            let mut prog = self.duplicate();
+            prog.testing = true;
+            let mut result = prog.execute();
+
+            // The test runs after the program, with its variables
+            if result.is_ok() && prog.assert_failure.is_none() {
+                prog.entrypoint = *label;
+                prog.ip = (label.0, 0);
+                prog.cycles = 0;
+                result = prog.run_until_complete();
+            }
+
+            // An error fails the test just as an assertion does
+            let failure = match result {
+                Ok(()) => prog.assert_failure,
+                Err(e) => Some(e.message),
+            };
+            results.push(TestResult {
+                name: name.clone(),
+                failure,
+            });
+        }
+
+        return results;
+    }
+
+    // Moves the first `vars` variables and `blocks` blocks after the rest, so
+    // that those of the most recently compiled unit start from 0.
+    pub fn move_to_end(&mut self, vars: usize, blocks: usize) {
+        let (var_count, block_count) = (self.variables.len(), self.code.len());
+        let addr = |a: &mut Addr| {
+            a.0 = if a.0 < vars {
+                a.0 + var_count - vars
+            } else {
+                a.0 - vars
+            };
+        };
+        for block in &mut self.code {
+            for instr in &mut block.code {
+                if let Instr::LoadIdent { i } | Instr::StoreIdent { i } = instr {
+                    addr(i);
+                }
+            }
+        }
+        self.var_addrs.values_mut().for_each(addr);
+        for f in self.user_functions.values_mut() {
+            f.locals.iter_mut().for_each(addr);
+        }
+
+        self.variables.rotate_left(vars);
+        self.var_names.rotate_left(vars);
+        self.code.rotate_left(blocks);
+        self.relabel(|l| {
+            if l < blocks {
+                l + block_count - blocks
+            } else {
+                l - blocks
+            }
+        });
+    }
+
+    // Renumbers every label of a block in the program
+    pub fn relabel(&mut self, f: impl Fn(usize) -> usize) {
+        let label = |l: &mut Label| {
+            if *l != Label::CONTINUE {
+                l.0 = f(l.0);
+            }
+        };
+
+        for block in &mut self.code {
+            for instr in &mut block.code {
+                match instr {
+                    Instr::IfExpr { if_true, if_false } => {
+                        label(if_true);
+                        label(if_false);
+                    }
+                    Instr::Goto { label: l }
+                    | Instr::Call { label: l }
+                    | Instr::Loop { label: l }
+                    | Instr::Continue { label: l, .. } => label(l),
+                    _ => {}
+                }
+            }
+        }
+
+        label(&mut self.entrypoint);
+        for f in self.user_functions.values_mut() {
+            label(&mut f.label);
+        }
+        for test in &mut self.tests {
+            label(&mut test.1);
+        }
+    }
+
+    /// Specializes the program for known values of some of its inputs or
+    /// variables, such as the environment being deployed to. An input is no
+    /// longer set by the host, while a variable must be declared with a
+    /// constant and never assigned again.
+    ///
+    /// Comparisons with the values are folded and the branches they rule out
+    /// removed, while everything else (such as extern results) is left to be
+    /// evaluated at runtime. Like a duplicate, externs must be registered again.
+    pub fn specialize(&self, values: &HashMap<String, DataVal>) -> Result<Prog, ExecError> {
+        let mut prog = self.duplicate();
+        let fail = |name: &str, reason: String| ExecError {
+            kind: ExecErrorKind::Input,
+            ip: (self.entrypoint.0, 0),
+            message: format!("input error: cannot specialize {}: {}", name, reason),
+        };
+
+        let mut names: Vec<&String> = values.keys().collect();
+        names.sort();
+        for name in names {
+            let val = &values[name];
+            let addr = match self.var_addrs.get(name.as_str()) {
+                Some(addr) => *addr,
+                None => return Err(fail(name, "there is no such input or variable".into())),
+            };
+
+            match self.inputs.iter().position(|(n, _)| n == name) {
+                Some(idx) => {
+                    let typ = &self.inputs[idx].1;
+                    if !val.is_type(typ, &self.user_structs) {
+                        return Err(fail(name, format!("it must be {}, found {}", typ, val)));
+                    }
+                    prog.inputs.retain(|(n, _)| n != name);
+                    prog.input_values.remove(name.as_str());
+                    optimize::bind_input(&mut prog, addr, val);
+                }
+                None => optimize::bind_constant(&mut prog, addr, val).map_err(|r| fail(name, r))?,
+            }
+        }
+
+        optimize::fold_constants(&mut prog);
+        optimize::fold_branches(&mut prog);
+        optimize::remove_unreachable(&mut prog);
+        return Ok(prog);
+    }
+
+    /// Simplifies the compiled program without changing what it does, to be
+    /// called after parsing. Operations on constants are evaluated, branches
+    /// on constants become gotos, jumps skip the blocks that only jump
+    /// elsewhere, and the blocks nothing reaches any more are removed,
+    /// renumbering the rest.
+    pub fn optimize(&mut self) {
+        optimize::fold_constants(self);
+        optimize::fold_branches(self);
+        optimize::collapse_gotos(self);
+        optimize::remove_unreachable(self);
+    }
+
+    /// The value of a variable by name. Those declared in a function are
+    /// qualified by its name, eg. `double.y`, while those of the prelude's
+    /// functions can't be looked up.
+    pub fn get_var(&self, name: &str) -> Option<&DataVal> {
+        return self.var_addrs.get(name).map(|a| &self.variables[a.0]);
+    }
+
+    /// Every named variable and its value, in the order they were declared.
+    /// The variables of the most recently compiled unit come first.
+    pub fn vars(&self) -> impl Iterator<Item = (&str, &DataVal)> {
+        let mut vars: Vec<_> = self.var_addrs.iter().collect();
+        vars.sort_by_key(|(_, a)| a.0);
+        return vars
+            .into_iter()
+            .map(|(name, a)| (name.as_str(), &self.variables[a.0]));
+    }
+
+    pub fn declare_input(&mut self, name: String, typ: DataType) {
+        self.inputs.push((name, typ));
+    }
+
+    /// Sets the value of an input declared by the program, eg. `input region: string;`.
+    /// Every input must be set before the program is executed.
+    pub fn set_input(&mut self, name: &str, val: DataVal) -> Result<(), ExecError> {
+        if !self.inputs.iter().any(|(n, _)| n == name) {
+            return Err(self.error(
+                ExecErrorKind::Input,
+                format!("input error: the program has no input {}", name),
+            ));
+        }
+        self.input_values.insert(name.to_string(), val);
+        return Ok(());
+    }
+
+    /// The value of a struct with the given fields, and the default for the
+    /// rest, eg. to be given as an input.
+    pub fn struct_val(&self, name: &str, fields: &[(&str, DataVal)]) -> Result<DataVal, ExecError> {
+        let strct = match self.user_structs.get(name) {
+            Some(strct) => strct,
+            None => {
+                return Err(self.error(
+                    ExecErrorKind::Input,
+                    format!("input error: unknown struct {}", name),
+                ))
+            }
+        };
+        let mut compound = DataVal::default_for(DataType::Struct(name.into()), &self.user_structs)
+            .into_compound()
+            .unwrap();
+        for (field, val) in fields {
+            match strct.names.get(*field) {
+                Some(idx) => compound[*idx] = val.clone(),
+                None => {
+                    return Err(self.error(
+                        ExecErrorKind::Input,
+                        format!("input error: struct {} has no field {}", name, field),
+                    ))
+                }
+            }
+        }
+        return Ok(DataVal::Compound(compound));
+    }
+
+    // Stores the value of each input, which must match its declared type
+    fn load_inputs(&mut self) -> Result<(), ExecError> {
+        for (name, typ) in &self.inputs {
+            let val = match self.input_values.get(name) {
+                Some(val) => val,
+                None => {
+                    return Err(self.error(
+                        ExecErrorKind::Input,
+                        format!("input error: input {} was not set", name),
+                    ))
+                }
+            };
+            if !val.is_type(typ, &self.user_structs) {
+                return Err(self.error(
+                    ExecErrorKind::Input,
+                    format!("input error: input {} must be {}, found {}", name, typ, val),
+                ));
+            }
+            self.variables[self.var_addrs[name].0] = val.clone();
+        }
+        return Ok(());
+    }
+
+    // Gives a variable a name to be looked up by, replacing any variable of an
+    // earlier unit with the same name
+    pub fn name_var(&mut self, name: String, addr: Addr) {
+        self.var_addrs.insert(name, addr);
+    }
+
+    pub fn allocate_var(&mut self, name: &str) -> Addr {
+        self.variables.push(DataVal::Uninit);
+        self.var_names.push(name.to_string());
+        return Addr(self.variables.len() - 1);
+    }
+
+    // Past the block limit, blocks are still added so the program can be
+    // emitted to the end, but the parser fails it
+    pub fn add_block(&mut self, block: Block) -> Label {
+        if self.code.len() >= BLOCK_LIMIT {
+            self.blocks_exceeded = true;
+        }
+
+        self.code.push(block);
+        return Label(self.code.len() - 1);
+    }
+
+    // Whether more blocks were added than a program may have
+    pub fn blocks_exceeded(&self) -> bool {
+        return self.blocks_exceeded;
+    }
+
+    pub fn add_temp_block(&mut self) -> Label {
+        return self.add_block(stac::Block::new());
+    }
+
+    pub fn mod_block(&mut self, block: Block, label: Label) {
+        self.code[label.0] = block;
+    }
+
+    /// Adds every function of the provider at the address, failing if its
+    /// schema can't be fetched. Requests are made and retried as configured,
+    /// and calls that still fail stop execution with an extern error.
+    ///
+    /// Functions the schema gives the signature of are declared as externs,
+    /// along with the structs they use, so when the provider is added before
+    /// the program is parsed they can be called without a `func extern`.
+    /// Declarations that disagree with the schema are errors, whichever is
+    /// first.
+    pub fn add_http_provider(
+        &mut self,
+        addr: String,
+        config: ProviderConfig,
+    ) -> Result<(), ProviderError> {
+        return self.add_http_provider_with(addr, config, ProviderMode::Strict);
+    }
+
+    /// Adds every function of a provider, in compatibility mode if its
+    /// returns may have drifted from their declared types.
+    pub fn add_http_provider_with(
+        &mut self,
+        addr: String,
+        config: ProviderConfig,
+        mode: ProviderMode,
+    ) -> Result<(), ProviderError> {
+        // Every function shares the client, and so its connections
+        let client = config.client(&addr)?;
+        let url = addr.clone() + "/provider_schema";
+        let body = provider::send(&url, &config, None, || client.get(&url))?;
+        let schema = ProviderSchema::decode(&url, &body)?;
+        self.declare_schema(&url, &schema)?;
+
+        for func in schema.functions {
+            let name = func.name().to_string();
+            self.add_http_extern_client(addr.clone(), name, client.clone(), config.clone(), mode);
+        }
+        return Ok(());
+    }
+
+    // Declares the typed functions of the schema and their structs, checking
+    // any the program already declares agree with them
+    fn declare_schema(&mut self, url: &str, schema: &ProviderSchema) -> Result<(), ProviderError> {
+        let drift = |message| ProviderError::Drift {
+            url: url.to_string(),
+            message,
+        };
+
+        for func in &schema.functions {
+            let func = match func {
+                SchemaFunction::Typed(func) => func,
+                SchemaFunction::Name(_) => continue,
+            };
+
+            for (name, fields) in &func.structs {
+                let mut strct = Struct {
+                    types: vec![],
+                    names: HashMap::new(),
+                    constraints: vec![None; fields.len()],
+                    ids: vec![],
+                };
+                for field in fields {
+                    strct.names.insert(field.name.clone(), strct.types.len());
+                    strct.types.push(field.typ.data_type());
+                    strct.ids.push(field_id(&field.name));
+                }
+
+                // The program's declaration keeps its order and constraints
+                match self.user_structs.get(name) {
+                    Some(declared) => schema_drift_struct(name, declared, &strct).map_err(drift)?,
+                    None => {
+                        self.user_structs.insert(name.clone(), strct.clone());
+                    }
+                }
+                self.schema_structs.insert(name.clone(), strct);
+            }
+
+            let params: Vec<DataType> = func.params.iter().map(|t| t.data_type()).collect();
+            let returns: Vec<DataType> = func.returns.iter().map(|t| t.data_type()).collect();
+            let fields = func.structs.values().flatten().map(|f| f.typ.data_type());
+            for typ in params.iter().cloned().chain(returns.clone()).chain(fields) {
+                if let Some(name) = unknown_struct(&typ, &self.user_structs) {
+                    return Err(ProviderError::UnknownStruct {
+                        url: url.to_string(),
+                        name,
+                    });
+                }
+            }
+
+            match self.user_functions.get(&func.name) {
+                Some(declared) => {
+                    schema_drift_function(&func.name, declared, &params, &returns).map_err(drift)?
+                }
+                None => {
+                    self.user_functions.insert(
+                        func.name.clone(),
+                        Function {
+                            label: Label::CONTINUE,
+                            params: params.clone(),
+                            returns: returns.clone(),
+                            locals: vec![],
+                        },
+                    );
+                    let decl = Box::new(ExternFuncImpl {
+                        name: func.name.clone(),
+                        param_types: params.clone(),
+                        return_types: returns.clone(),
+                    });
+                    decl.emit(self, &mut Block::new());
+                }
+            }
+            self.schema_functions
+                .insert(func.name.clone(), (params, returns));
+        }
+        return Ok(());
+    }
+
+    /// Checks the declaration of an extern agrees with the schema of any
+    /// provider added that has it, returning what differs if it doesn't.
+    pub fn check_schema_function(
+        &self,
+        name: &str,
+        params: &[DataType],
+        returns: &[DataType],
+    ) -> Result<(), String> {
+        match self.schema_functions.get(name) {
+            Some((schema_params, schema_returns)) => {
+                let declared = Function {
+                    label: Label::CONTINUE,
+                    params: params.to_vec(),
+                    returns: returns.to_vec(),
+                    locals: vec![],
+                };
+                return schema_drift_function(name, &declared, schema_params, schema_returns);
+            }
+            None => return Ok(()),
+        }
+    }
+
+    /// Checks the declaration of a struct agrees with the schema of any
+    /// provider added that uses it, returning what differs if it doesn't.
+    pub fn check_schema_struct(&self, name: &str, strct: &Struct) -> Result<(), String> {
+        match self.schema_structs.get(name) {
+            Some(schema) => return schema_drift_struct(name, strct, schema),
+            None => return Ok(()),
+        }
+    }
+
+    /// Registers a Rust closure as the declared extern with the name, eg.
+    /// `prog.register_extern("createResource", |name: String| -> i64 { 6 })`.
+    /// A closure returning an Option returns waiting values for None, and one
+    /// returning a Result stops execution with the error.
+    ///
+    /// The closure's params and returns must match the declaration. It isn't
+    /// called if any of the params are waiting, and its returns wait instead.
+    pub fn register_extern<A, F: NativeFn<A> + Send + Sync + 'static>(&mut self, name: &str, f: F) {
+        let func = self
+            .user_functions
+            .get(name)
+            .unwrap_or_else(|| panic!("cannot register extern {}, as it isn't declared", name));
+
+        let names = |types: &[DataType]| {
+            let names: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+            return names.join(", ");
+        };
+        if func.params != F::param_types() || func.returns != F::return_types() {
+            panic!(
+                "extern {} is declared as ({}) ({}), but registered as ({}) ({})",
+                name,
+                names(&func.params),
+                names(&func.returns),
+                names(&F::param_types()),
+                names(&F::return_types())
+            );
+        }
+
+        let returns = func.returns.len();
+        self.external_functions.insert(
+            name.to_string(),
+            Box::new(move |_, _, _, params, _, _| {
+                if params.iter().any(|p| p.contains_waiting()) {
+                    return Ok(vec![DataVal::Waiting; returns]);
+                }
+                return f.call(params);
+            }),
+        );
+    }
+
+    pub fn add_http_extern(
+        &mut self,
+        addr: String,
+        name: String,
+        config: ProviderConfig,
+    ) -> Result<(), ProviderError> {
+        return self.add_http_extern_with(addr, name, config, ProviderMode::Strict);
+    }
+
+    pub fn add_http_extern_with(
+        &mut self,
+        addr: String,
+        name: String,
+        config: ProviderConfig,
+        mode: ProviderMode,
+    ) -> Result<(), ProviderError> {
+        let client = config.client(&addr)?;
+        self.add_http_extern_client(addr, name, client, config, mode);
+        return Ok(());
+    }
+
+    fn add_http_extern_client(
+        &mut self,
+        addr: String,
+        name: String,
+        client: reqwest::blocking::Client,
+        config: ProviderConfig,
+        mode: ProviderMode,
+    ) {
+        let warnings = match mode {
+            ProviderMode::Strict => None,
+            ProviderMode::Compatible => Some(self.provider_warnings.clone()),
+        };
+        let retries = self.provider_retries.clone();
+        self.external_functions.insert(
+            name.clone(),
+            Box::new(
+                move |id, param_types, return_types, param_vals, user_structs, _ctx| {
+                    // A call can't be made until its params are known
+                    if param_vals.iter().any(|p| p.contains_waiting()) {
+                        return Ok(vec![DataVal::Waiting; return_types.len()]);
+                    }
+
+                    let url = format!("{}/{}", &addr, &name);
+                    let rpc = DMCLRPC::new(id, &param_types, param_vals, user_structs);
+                    let req_body = rpc.encode(&url)?;
+                    let traceparent = telemetry::traceparent();
+                    let body = provider::send(&url, &config, Some(&retries), || {
+                        let req = client
+                            .post(&url)
+                            .header(reqwest::header::CONTENT_TYPE, "application/json")
+                            .body(req_body.clone());
+                        match &traceparent {
+                            Some(traceparent) => return req.header("traceparent", traceparent),
+                            None => return req,
+                        }
+                    })?;
+
+                    let ext_ret = ExternReturns {
                         user_structs,
                         types: return_types,
+                        compat: warnings.as_deref(),
+                        function: name.clone(),
+                    };
+                    return Ok(ext_ret.decode(&url, &body)?);
+                },
+            ),
+        );
+    }
+
+    /// Adds every function of a provider as an async extern, which is only
+    /// called by `execute_async`. Unlike `add_http_provider`, it doesn't block,
+    /// so it can be used inside an async runtime.
+    pub async fn add_http_provider_async(
+        &mut self,
+        addr: String,
+        config: ProviderConfig,
+    ) -> Result<(), ProviderError> {
+        let client = config.async_client(&addr)?;
+        let url = addr.clone() + "/provider_schema";
+        let body = provider::send_async(&url, &config, None, || client.get(&url)).await?;
+        let schema = ProviderSchema::decode(&url, &body)?;
+        self.declare_schema(&url, &schema)?;
+
+        for func in schema.functions {
+            let name = func.name().to_string();
+            self.add_http_extern_async_client(
+                addr.clone(),
+                name,
+                client.clone(),
+                config.clone(),
+                ProviderMode::Strict,
+            );
+        }
+        return Ok(());
+    }
+
+    pub fn add_http_extern_async(
+        &mut self,
+        addr: String,
+        name: String,
+        config: ProviderConfig,
+        mode: ProviderMode,
+    ) -> Result<(), ProviderError> {
+        let client = config.async_client(&addr)?;
+        self.add_http_extern_async_client(addr, name, client, config, mode);
+        return Ok(());
+    }
+
+    fn add_http_extern_async_client(
+        &mut self,
+        addr: String,
+        name: String,
+        client: reqwest::Client,
+        config: ProviderConfig,
+        mode: ProviderMode,
+    ) {
+        let warnings = match mode {
+            ProviderMode::Strict => None,
+            ProviderMode::Compatible => Some(self.provider_warnings.clone()),
+        };
+        let retries = self.provider_retries.clone();
+        self.async_external_functions.insert(
+            name.clone(),
+            Box::new(
+                move |id, param_types, return_types, param_vals, user_structs| {
+                    // A call can't be made until its params are known
+                    if param_vals.iter().any(|p| p.contains_waiting()) {
+                        let returns = vec![DataVal::Waiting; return_types.len()];
+                        return Box::pin(async move { Ok(returns) });
+                    }
+
+                    // The request is built before the call is awaited, while the
+                    // params and the current span are still available
+                    let url = format!("{}/{}", &addr, &name);
+                    let rpc = DMCLRPC::new(id, &param_types, param_vals, user_structs);
+                    let req_body = match rpc.encode(&url) {
+                        Ok(body) => body,
+                        Err(e) => return Box::pin(async move { Err(e.into()) }),
+                    };
+                    let traceparent = telemetry::traceparent();
+
+                    let client = client.clone();
+                    let config = config.clone();
+                    let retries = retries.clone();
+                    let user_structs = user_structs.clone();
+                    let warnings = warnings.clone();
+                    let name = name.clone();
+                    return Box::pin(async move {
+                        let body = provider::send_async(&url, &config, Some(&retries), || {
+                            let req = client
+                                .post(&url)
+                                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                                .body(req_body.clone());
+                            match &traceparent {
+                                Some(traceparent) => return req.header("traceparent", traceparent),
+                                None => return req,
+                            }
+                        })
+                        .await?;
+
+                        let ext_ret = ExternReturns {
+                            user_structs: &user_structs,
+                            types: return_types,
+                            compat: warnings.as_deref(),
+                            function: name,
+                        };
+                        return Ok(ext_ret.decode(&url, &body)?);
+                    });
+                },
+            ),
+        );
+    }
+
+    /// Adds a middleware to run before every extern call, after those already
+    /// added. Each is given the params returned by the last.
+    pub fn add_extern_middleware(&mut self, middleware: ExternMiddleware) {
+        self.extern_middleware.push(middleware);
+    }
+
+    /// Sets the context given to every extern call, replacing any set before.
+    /// Externs can downcast it to T to share state, eg. a client handle.
+    pub fn set_context<T: Any + Send + Sync>(&mut self, ctx: T) {
+        self.context = Box::new(ctx);
+    }
+
+    /// The context set by the host, if it was set to a T.
+    pub fn context<T: Any>(&self) -> Option<&T> {
+        return self.context.downcast_ref();
+    }
+
+    pub fn context_mut<T: Any>(&mut self) -> Option<&mut T> {
+        return self.context.downcast_mut();
+    }
+
+    /// Sets the most calls that may be in progress at once, eg. of a recursive
+    /// function, before execution fails.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Sets the longest array make may create before execution fails. Each
+    /// element created also counts as a cycle toward the cycle limit.
+    pub fn set_max_array_length(&mut self, len: usize) {
+        self.max_array_length = len;
+    }
+
+    /// Calls the hook with each event of every later execution, such as the
+    /// instructions run and the externs called. It isn't copied by `duplicate`.
+    pub fn set_trace(&mut self, hook: TraceHook) {
+        self.trace_hook = Some(hook);
+    }
+
+    /// Sets the most instructions an execution may run before it fails, or
+    /// removes the limit with None.
+    pub fn set_cycle_limit(&mut self, limit: Option<usize>) {
+        self.cycle_limit = limit;
+    }
+
+    /// Sets where the stdlib's print writes to, instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        *self.output.lock().unwrap() = output;
+    }
+
+    /// The values providers in compatibility mode returned that had to be
+    /// coerced, so that drift from their schemas can be flagged.
+    pub fn provider_warnings(&self) -> Vec<ProviderWarning> {
+        return self.provider_warnings.lock().unwrap().clone();
+    }
+
+    // Record the execution trace so the expectations can be verified afterwards
+    pub fn expect(&mut self, expectations: ExecExpectations) {
+        self.expectations = Some(expectations);
+    }
+
+    pub fn verify_expectations(&self) -> Result<(), String> {
+        return match &self.expectations {
+            Some(e) => e.verify(&self.trace),
+            None => Err("no expectations attached".into()),
+        };
+    }
+
+    /// Summarizes the last execution, such as the externs it called and the
+    /// variables it left waiting.
+    pub fn summary(&self) -> RunSummary {
+        return RunSummary {
+            extern_calls: self.report.extern_calls.clone(),
+            waiting: (0..self.variables.len())
+                .filter(|&i| self.variables[i].is_waiting())
+                .map(|i| self.var_names[i].clone())
+                .collect(),
+            cycles: self.cycles,
+            max_eval_stack: self.report.max_eval_stack,
+            max_call_stack: self.report.max_call_stack,
+            entered_side_effects: self.report.entered_side_effects,
+            near_cycle_limit: match self.cycle_limit {
+                Some(limit) => self.cycles * 10 >= limit * 9,
+                None => false,
+            },
+        };
+    }
+
+    fn waiting_vars(&self) -> HashSet<usize> {
+        return (0..self.variables.len())
+            .filter(|&i| self.variables[i].is_waiting())
+            .collect();
+    }
+
+    fn push_frame(&mut self, kind: FrameKind) {
+        self.call_stack.push(Frame { ret: self.ip, kind });
+    }
+
+    fn locals(&self, label: usize) -> Vec<Addr> {
+        match self.user_functions.values().find(|f| f.label.0 == label) {
+            Some(f) => return f.locals.clone(),
+            None => return vec![],
+        }
+    }
+
+    // Calls the function, saving its locals if it's already in progress so
+    // that the new call doesn't overwrite them
+    fn enter_call(&mut self, label: Label) -> Result<(), ExecError> {
+        if self.call_depth >= self.max_call_depth {
+            return Err(self.error(
+                ExecErrorKind::StackOverflow {
+                    depth: self.call_depth,
+                },
+                format!(
+                    "stack overflow: more than {} calls in progress",
+                    self.max_call_depth
+                ),
+            ));
+        }
+
+        let active = self.active_calls.entry(label.0).or_insert(0);
+        *active += 1;
+        if *active > 1 {
+            let saved = self
+                .locals(label.0)
+                .iter()
+                .map(|a| self.variables[a.0].clone())
+                .collect();
+            self.saved_locals.push(saved);
+        }
+        self.call_depth += 1;
+
+        self.push_frame(FrameKind::Call);
+        self.ip = (label.0, 0);
+        return Ok(());
+    }
+
+    // Returns from the call that pushed the frame, restoring the locals of the
+    // call it interrupted, if any
+    fn leave_call(&mut self, frame: Frame) {
+        let label = match &self.code[frame.ret.0].code[frame.ret.1] {
+            Instr::Call { label } => label.0,
+            _ => return,
+        };
+        self.call_depth -= 1;
+
+        let active = self.active_calls.entry(label).or_insert(1);
+        *active -= 1;
+        if *active > 0 {
+            let saved = self.saved_locals.pop().unwrap_or_default();
+            for (addr, val) in self.locals(label).into_iter().zip(saved) {
+                self.variables[addr.0] = val;
+            }
+        }
+        self.ip = frame.ret;
+    }
+
+    pub fn execute(&mut self) -> Result<(), ExecError> {
+        let _phase = PhaseSpan::enter(Phase::Execute, self.trace_id);
+        self.start()?;
+        return self.run_until_complete();
+    }
+
+    /// Keeps the returns of extern calls in the store, using them rather than
+    /// making a call again when it has the call's id. Returns that are waiting
+    /// aren't kept, so those calls are made again by the next execution.
+    pub fn with_state(&mut self, store: Box<dyn StateStore>) -> &mut Self {
+        self.state = Some(store);
+        return self;
+    }
+
+    /// The dependencies between the extern calls made by the last execution or
+    /// plan, as (from, to) pairs where a return of from was derived into the
+    /// params of to. Calls to the stdlib aren't included, though values are
+    /// still derived through them.
+    pub fn dependency_graph(&self) -> Vec<(CallId, CallId)> {
+        return self.lineage.edges();
+    }
+
+    /// Renders the dependency graph in the DOT language, with every call made
+    /// as a node.
+    pub fn dependency_dot(&self) -> String {
+        return graph::render_dot(self.lineage.calls(), &self.lineage.edges());
+    }
+
+    /// Executes the program as a dry run, recording the extern calls it would
+    /// make instead of making them. Each call returns waiting values, so the
+    /// program continues as it would before the resources exist, and calls in
+    /// branches that depend on them are recorded as possible calls. Only the
+    /// stdlib is still called.
+    pub fn plan(&mut self) -> Result<ExecutionPlan, ExecError> {
+        self.planning = true;
+        let result = self.execute();
+        self.planning = false;
+        result?;
+
+        // Calls under a branch that depends on a call's results aren't made,
+        // even as a dry run, but may be once the branch is decided
+        let possible_calls = self
+            .report
+            .deferred_calls
+            .iter()
+            .map(|c| PossibleCall {
+                id: c.id,
+                function: c.func.clone(),
+                depends_on: c.origin,
+            })
+            .collect();
+
+        return Ok(ExecutionPlan {
+            calls: std::mem::take(&mut self.planned_calls),
+            possible_calls,
+            user_structs: self.user_structs.clone(),
+        });
+    }
+
+    /// Records the values an extern call returns, once those it waited on are
+    /// known. The id is the one the extern was called with, and the values are
+    /// used instead of calling the extern when the program is re-executed.
+    pub fn resolve_extern(&mut self, call_id: CallId, values: Vec<DataVal>) {
+        self.resolved_externs.insert(call_id, values);
+    }
+
+    /// Executes the program again from the start, with the values of every
+    /// resolved extern call. Unresolved calls are made again.
+    pub fn reexecute(&mut self) -> Result<(), ExecError> {
+        return self.execute();
+    }
+
+    /// Puts the program back in the state it was compiled in, so it can be
+    /// executed again. Every variable is uninitialized, as it was when it was
+    /// declared. The externs, inputs, breakpoints and resolved extern calls
+    /// are kept.
+    pub fn reset(&mut self) {
+        self.variables = vec![DataVal::Uninit; self.variables.len()];
+        self.eval_stack.clear();
+        self.call_stack.clear();
+        self.ip = (self.entrypoint.0, 0);
+        self.cycles = 0;
+        self.extern_site_calls.clear();
+        self.assert_failure = None;
+        self.evaluating_side_effects = false;
+        self.blocks_to_eval.clear();
+        self.side_effect_visited.clear();
+        self.side_effect_results.clear();
+        self.active_calls.clear();
+        self.call_depth = 0;
+        self.saved_locals.clear();
+        self.report = ExecReport::default();
+        self.trace.clear();
+        self.planned_calls.clear();
+        self.lineage = Lineage::default();
+    }
+
+    /// Prepares the program to be executed a step at a time with `step`,
+    /// resetting it if it has been executed before.
+    pub fn start(&mut self) -> Result<(), ExecError> {
+        self.reset();
+        return self.load_inputs();
+    }
+
+    /// Runs the next instruction, making the extern call if it is one.
+    pub fn step(&mut self) -> Result<StepResult, ExecError> {
+        match self.advance()? {
+            Advance::Continued => {}
+            Advance::Call(call) => self.call_blocking(call)?,
+            Advance::Finished => return Ok(StepResult::Finished),
+        }
+
+        if self.breakpoints.contains(&self.ip) {
+            return Ok(StepResult::Breakpoint);
+        }
+        return Ok(StepResult::Continued);
+    }
+
+    /// Steps until the program ends, without stopping at breakpoints.
+    pub fn run_until_complete(&mut self) -> Result<(), ExecError> {
+        while self.step()? != StepResult::Finished {}
+        return Ok(());
+    }
+
+    /// Makes `step` report a breakpoint when it reaches the instruction.
+    pub fn add_breakpoint(&mut self, ip: (usize, usize)) {
+        self.breakpoints.insert(ip);
+    }
+
+    pub fn remove_breakpoint(&mut self, ip: (usize, usize)) {
+        self.breakpoints.remove(&ip);
+    }
+
+    /// The instruction that will run next, as (block, instr).
+    pub fn ip(&self) -> (usize, usize) {
+        return self.ip;
+    }
+
+    pub fn eval_stack(&self) -> &[DataVal] {
+        return &self.eval_stack;
+    }
+
+    /// The frames of the blocks execution is in, innermost last.
+    pub fn call_stack(&self) -> &[Frame] {
+        return &self.call_stack;
+    }
+
+    /// The waiting if expr whose side effects are being evaluated, if any.
+    pub fn side_effect_origin(&self) -> Option<(usize, usize)> {
+        if self.evaluating_side_effects {
+            return Some(self.side_effect_origin);
+        }
+        return None;
+    }
+
+    // Makes an extern call, blocking until it returns
+    fn call_blocking(&mut self, mut call: PendingCall) -> Result<(), ExecError> {
+        let span = ExternSpan::enter(
+            self.trace_id,
+            &call.func_name,
+            call.id,
+            call.param_vals.len(),
+        );
+        let stubbed = self.before_call(&mut call)?;
+        self.emit(TraceEvent::ExternStart {
+            func: &call.func_name,
+            id: call.id,
+            params: &call.param_vals,
+        });
+        let result = match stubbed {
+            Some(stubbed) => Ok(stubbed),
+            None => {
+                let recorded = self.record(&call);
+                let result = self.call_extern(&mut call)?;
+                self.persist(recorded, &result)?;
+                result
+            }
+        };
+        return self.after_call(call, result, span);
+    }
+
+    /// Executes the program like `execute`, but awaits the externs in
+    /// `async_external_functions` rather than blocking on them. Externs that
+    /// are only in `external_functions` are still called directly.
+    ///
+    /// As the futures of async externs needn't be Send, neither is the future
+    /// this returns, so it must be awaited rather than spawned onto another
+    /// thread. The program itself can still be moved between executions.
+    pub async fn execute_async(&mut self) -> Result<(), ExecError> {
+        let _phase = PhaseSpan::enter(Phase::Execute, self.trace_id);
+        self.start()?;
+
+        while let Some(mut call) = self.run()? {
+            let span = ExternSpan::enter(
+                self.trace_id,
+                &call.func_name,
+                call.id,
+                call.param_vals.len(),
+            );
+            let stubbed = self.before_call(&mut call)?;
+            self.emit(TraceEvent::ExternStart {
+                func: &call.func_name,
+                id: call.id,
+                params: &call.param_vals,
+            });
+            let result = match stubbed {
+                Some(stubbed) => Ok(stubbed),
+                None => {
+                    let recorded = self.record(&call);
+                    let result = match self.async_external_functions.get(&call.func_name) {
+                        Some(f) => {
+                            f(
+                                call.id,
+                                std::mem::take(&mut call.param_types),
+                                std::mem::take(&mut call.return_types),
+                                std::mem::take(&mut call.param_vals),
+                                &self.user_structs,
+                            )
+                            .await
+                        }
+                        None => self.call_extern(&mut call)?,
                     };
-                    DeserializeSeed::deserialize(ext_ret, &mut deserializer).unwrap()
-                },
+                    self.persist(recorded, &result)?;
+                    result
+                }
+            };
+            self.after_call(call, result, span)?;
+        }
+        return Ok(());
+    }
+
+    // The resolved returns of the call, its stubbed returns while testing, or
+    // its returns from an earlier execution. Otherwise the middleware rewrites
+    // its params, and the call must be made, unless it's only being planned.
+    fn before_call(&mut self, call: &mut PendingCall) -> Result<Option<Vec<DataVal>>, ExecError> {
+        if let Some(vals) = self.resolved_externs.get(&call.id) {
+            return Ok(Some(vals.clone()));
+        }
+
+        // The stdlib doesn't create anything, so it's called even in tests
+        let in_stdlib = self.in_stdlib(&call.func_name);
+        if self.testing && !in_stdlib {
+            match self.test_stubs.get(&call.func_name) {
+                Some(vals) => return Ok(Some(vals.clone())),
+                None => return Ok(Some(vec![DataVal::Waiting; call.return_types.len()])),
+            }
+        }
+
+        // Planned calls are still recorded when they were made before, so the
+        // plan can be diffed against the state
+        let stored = self.state.as_ref().and_then(|s| s.get(call.id));
+        if !self.planning {
+            if let Some(vals) = stored {
+                return Ok(Some(vals));
+            }
+        }
+
+        let ctx = ExternCtx {
+            id: call.id,
+            param_types: &call.param_types,
+            return_types: &call.return_types,
+            user_structs: &self.user_structs,
+        };
+        let mut param_vals = std::mem::take(&mut call.param_vals);
+        for middleware in &self.extern_middleware {
+            param_vals = match middleware(&call.func_name, param_vals, &ctx) {
+                Ok(vals) => vals,
+                Err(error) => {
+                    return Err(self.error(
+                        ExecErrorKind::Rejected {
+                            func: call.func_name.clone(),
+                            error: error.clone(),
+                        },
+                        format!("extern call to {} rejected: {}", call.func_name, error),
+                    ))
+                }
+            };
+        }
+        call.param_vals = param_vals;
+
+        if self.planning && !in_stdlib {
+            self.planned_calls.push(PlannedCall {
+                id: call.id,
+                function: call.func_name.clone(),
+                params: call.param_vals.clone(),
+                param_types: call.param_types.clone(),
+            });
+            return Ok(Some(stored.unwrap_or_else(|| {
+                return vec![DataVal::Waiting; call.return_types.len()];
+            })));
+        }
+        return Ok(None);
+    }
+
+    fn in_stdlib(&self, func: &str) -> bool {
+        return self.stdlib && stdlib::FUNCTIONS.contains(&func);
+    }
+
+    // The call as the state store keeps it, if there's a store to keep it in.
+    // It must be recorded before it's made, which takes its params.
+    fn record(&self, call: &PendingCall) -> Option<RecordedCall> {
+        if self.state.is_none() || self.in_stdlib(&call.func_name) {
+            return None;
+        }
+        let params = call
+            .param_vals
+            .iter()
+            .zip(&call.param_types)
+            .map(|(v, t)| state::encode(v, t, &self.user_structs))
+            .collect();
+        return Some(RecordedCall {
+            id: call.id,
+            function: call.func_name.clone(),
+            params,
+        });
+    }
+
+    // Keeps the returns of a call that was made, once they are all known
+    fn persist(
+        &mut self,
+        recorded: Option<RecordedCall>,
+        result: &Result<Vec<DataVal>, ExternError>,
+    ) -> Result<(), ExecError> {
+        let returns = match result {
+            Ok(returns) if !returns.iter().any(|v| v.contains_waiting()) => returns,
+            _ => return Ok(()),
+        };
+        let (store, call) = match (&mut self.state, recorded) {
+            (Some(store), Some(call)) => (store, call),
+            _ => return Ok(()),
+        };
+
+        let message = format!(
+            "state error: cannot keep the returns of {} {}",
+            call.function, call.id
+        );
+        if let Err(e) = store.put_call(call, returns.clone()) {
+            return Err(self.error(ExecErrorKind::State, format!("{}: {}", message, e)));
+        }
+        return Ok(());
+    }
+
+    // Makes the call, which may still fail
+    fn call_extern(
+        &mut self,
+        call: &mut PendingCall,
+    ) -> Result<Result<Vec<DataVal>, ExternError>, ExecError> {
+        let func = match self.external_functions.get(&call.func_name) {
+            Some(func) => func,
+            None => {
+                return Err(self.error(
+                    ExecErrorKind::UnknownExtern,
+                    format!("unknown external function: {}", call.func_name),
+                ))
+            }
+        };
+        return Ok(func(
+            call.id,
+            std::mem::take(&mut call.param_types),
+            std::mem::take(&mut call.return_types),
+            std::mem::take(&mut call.param_vals),
+            &self.user_structs,
+            &mut *self.context,
+        ));
+    }
+
+    // Pushes the returns of the call and resumes after it, unless it failed
+    fn after_call(
+        &mut self,
+        call: PendingCall,
+        result: Result<Vec<DataVal>, ExternError>,
+        span: ExternSpan,
+    ) -> Result<(), ExecError> {
+        let retries = std::mem::take(&mut *self.provider_retries.lock().unwrap());
+        for retry in &retries {
+            self.emit(TraceEvent::ExternRetry {
+                func: &call.func_name,
+                id: call.id,
+                retry,
+            });
+        }
+
+        let mut returns = match result {
+            Ok(returns) => returns,
+            Err(error) => {
+                let message = format!("extern error: {} failed: {}", call.func_name, error);
+                return Err(self.error(
+                    ExecErrorKind::Extern {
+                        func: call.func_name,
+                        id: call.id,
+                        error,
+                    },
+                    message,
+                ));
+            }
+        };
+        span.finish(&returns);
+        self.emit(TraceEvent::ExternFinish {
+            func: &call.func_name,
+            id: call.id,
+            returns: &returns,
+        });
+        self.lineage.returned(returns.len());
+        self.eval_stack.append(&mut returns);
+
+        *self
+            .report
+            .extern_calls
+            .entry(call.func_name.clone())
+            .or_default()
+            .entry(call.call_site)
+            .or_insert(0) += 1;
+        self.extern_site_calls
+            .insert(call.call_site, call.id.ordinal + 1);
+
+        self.ip.1 += 1;
+        return Ok(());
+    }
+
+    fn emit(&mut self, event: TraceEvent) {
+        if let Some(hook) = &mut self.trace_hook {
+            hook(event);
+        }
+    }
+
+    fn emit_store(&mut self, addr: Addr) {
+        if let Some(hook) = &mut self.trace_hook {
+            hook(TraceEvent::Store {
+                addr,
+                name: &self.var_names[addr.0],
+                val: &self.variables[addr.0],
+            });
+        }
+    }
+
+    // An error at the current instruction
+    fn error(&self, kind: ExecErrorKind, message: impl Into<String>) -> ExecError {
+        return ExecError {
+            kind,
+            ip: self.ip,
+            message: message.into(),
+        };
+    }
+
+    // Counts the cycles toward the cycle limit, failing once it's exceeded.
+    // Each instruction is a cycle, as is each element of an array made.
+    fn use_cycles(&mut self, n: usize) -> Result<(), ExecError> {
+        let cycles = self.cycles;
+        self.cycles = self.cycles.saturating_add(n);
+        if let Some(limit) = self.cycle_limit {
+            if self.cycles > limit {
+                return Err(self.error(
+                    ExecErrorKind::CycleLimitExceeded { cycles },
+                    format!("cycle limit exceeded: ran more than {} instructions", limit),
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    pub(crate) fn pop(&mut self) -> Result<DataVal, ExecError> {
+        match self.eval_stack.pop() {
+            Some(v) => return Ok(v),
+            None => return Err(self.underflow(1)),
+        }
+    }
+
+    fn pop_frame(&mut self) -> Result<Frame, ExecError> {
+        match self.call_stack.pop() {
+            Some(frame) => return Ok(frame),
+            None => return Err(self.frame_underflow()),
+        }
+    }
+
+    // The name of the instruction at the ip, for errors
+    fn instr_kind(&self) -> String {
+        match self.code[self.ip.0].code.get(self.ip.1) {
+            Some(instr) => return instr_kind(instr),
+            None => return instr_kind(&Instr::EndBlock),
+        }
+    }
+
+    // The error for an instruction that needed more values than the eval stack has
+    fn underflow(&self, needed: usize) -> ExecError {
+        let available = self.eval_stack.len();
+        let instr = self.instr_kind();
+        return self.error(
+            ExecErrorKind::StackUnderflow {
+                needed,
+                available,
+                instr: instr.clone(),
+            },
+            format!(
+                "stack underflow: {} needs {} values on the eval stack, but there are {}",
+                instr, needed, available
             ),
         );
     }
 
-    pub fn execute(&mut self) {
-        self.ip = (self.entrypoint.0, 0);
+    fn frame_underflow(&self) -> ExecError {
+        let instr = self.instr_kind();
+        return self.error(
+            ExecErrorKind::CallStackUnderflow {
+                instr: instr.clone(),
+            },
+            format!(
+                "stack underflow: {} needs a frame, but the call stack is empty",
+                instr
+            ),
+        );
+    }
+
+    // The error for a value that isn't of the type the instruction needs
+    fn mismatch(&self, expected: &str, found: DataVal) -> ExecError {
+        return self.error(
+            ExecErrorKind::Type,
+            format!("expected {}, found {}", expected, found),
+        );
+    }
 
-        'outer: loop {
-            let instr;
-            if self.ip.1 >= self.code[self.ip.0].code.len() {
-                if self.ip.0 == self.entrypoint.0 {
-                    break;
+    // The value converted to an int or float. Floats are truncated, and only
+    // converted when they are in range, as are ints that a float holds exactly.
+    fn cast(&self, v: DataVal, to: &DataType) -> Result<DataVal, ExecError> {
+        let fail = |v: &DataVal| {
+            return self.error(
+                ExecErrorKind::Conversion,
+                format!("cannot convert {} to {}", v, to),
+            );
+        };
+        match (&v, to) {
+            (DataVal::Waiting, _) => return Ok(DataVal::Waiting),
+            (DataVal::Integer(_), DataType::Integer) | (DataVal::Float(_), DataType::Float) => {
+                return Ok(v)
+            }
+            (DataVal::Float(f), DataType::Integer) => {
+                // i64::MAX as f64 rounds up to 2^63, which is out of range
+                if f.is_finite() && *f >= i64::MIN as f64 && *f < i64::MAX as f64 {
+                    return Ok(DataVal::Integer(*f as i64));
+                }
+                return Err(fail(&v));
+            }
+            (DataVal::Integer(i), DataType::Float) => {
+                let f = *i as f64;
+                if f as i128 == *i as i128 {
+                    return Ok(DataVal::Float(f));
                 }
+                return Err(fail(&v));
+            }
+            (DataVal::String(s), DataType::Integer) => {
+                return s.parse().map(DataVal::Integer).map_err(|_| fail(&v));
+            }
+            (DataVal::String(s), DataType::Float) => match s.parse::<f64>() {
+                Ok(f) if f.is_finite() => return Ok(DataVal::Float(f)),
+                _ => return Err(fail(&v)),
+            },
+            _ => return Err(self.mismatch("a number or string", v)),
+        }
+    }
 
-                instr = Instr::Return
-            } else {
-                instr = self.code[self.ip.0].code[self.ip.1].clone()
+    // The index into an array of the length, if it is in range
+    fn checked_index(&self, index: DataVal, len: usize) -> Result<usize, ExecError> {
+        let index = index.into_integer().map_err(|v| self.mismatch("int", v))?;
+        if index < 0 || index as usize >= len {
+            return Err(self.error(
+                ExecErrorKind::IndexOutOfBounds { index, len },
+                format!(
+                    "index out of range: {} is not an index of an array of length {}",
+                    index, len
+                ),
+            ));
+        }
+        return Ok(index as usize);
+    }
+
+    // The extern a function calls, if the function at the label is an extern
+    fn extern_name(&self, label: Label) -> Option<String> {
+        return self.code[label.0].code.windows(2).find_map(|w| match w {
+            [Instr::LoadConst {
+                v: DataVal::String(name),
+            }, Instr::ExternCall { .. }] => Some(name.to_string()),
+            _ => None,
+        });
+    }
+
+    // The index of a field in a struct. Fields are resolved by the compiler,
+    // so one that doesn't exist is a bug rather than an error in the program.
+    fn checked_field(&self, index: DataVal, fields: usize) -> Result<usize, ExecError> {
+        let index = index.into_integer().map_err(|v| self.mismatch("int", v))?;
+        if index < 0 || index as usize >= fields {
+            return Err(self.error(
+                ExecErrorKind::Internal,
+                format!("struct with {} fields has no field {}", fields, index),
+            ));
+        }
+        return Ok(index as usize);
+    }
+
+    // Compares the top two values, deeply if they are compounds, pushing
+    // whether they are equal (or unequal)
+    fn equality(&mut self, equal: bool) -> Result<(), ExecError> {
+        let x = self.pop()?;
+        let y = self.pop()?;
+        if x.contains_waiting() || y.contains_waiting() {
+            self.eval_stack.push(DataVal::Waiting);
+        } else if std::mem::discriminant(&x) == std::mem::discriminant(&y) {
+            self.eval_stack.push(DataVal::Bool((x == y) == equal));
+        } else {
+            return Err(self.error(
+                ExecErrorKind::Type,
+                format!("cannot compare {} and {}", x, y),
+            ));
+        }
+        return Ok(());
+    }
+
+    // The value of a number as a float
+    fn as_float(&self, v: DataVal) -> Result<f64, ExecError> {
+        match v {
+            DataVal::Integer(i) => return Ok(i as f64),
+            DataVal::Float(f) => return Ok(f),
+            v => return Err(self.mismatch("a number", v)),
+        }
+    }
+
+    // Runs until the next extern call, which the caller must make before
+    // running again, or until the program ends
+    fn run(&mut self) -> Result<Option<PendingCall>, ExecError> {
+        loop {
+            match self.advance()? {
+                Advance::Continued => {}
+                Advance::Call(call) => return Ok(Some(call)),
+                Advance::Finished => return Ok(None),
             }
+        }
+    }
+
+    // Runs a single instruction, stopping before it if it's an extern call
+    fn advance(&mut self) -> Result<Advance, ExecError> {
+        let ip = self.ip;
+        let before = self.eval_stack.len();
+        let side_effect = self.evaluating_side_effects;
+        let advance = self.run_instr()?;
 
-            println!("executing @ {:?} : {:?}", self.ip, instr);
+        // Instrs only scanned for side effects don't change any values
+        let instr = match self.code[ip.0].code.get(ip.1) {
+            Some(instr) if !side_effect => instr,
+            _ => return Ok(advance),
+        };
+        let origins = self.lineage.step(instr, before, self.eval_stack.len());
+        if let Advance::Call(call) = &advance {
+            let in_stdlib = self.in_stdlib(&call.func_name);
+            self.lineage
+                .call(call.id, &call.func_name, origins, in_stdlib);
+        }
+        return Ok(advance);
+    }
 
-            self.cycles += 1;
-            if self.cycles > 1000 {
-                break;
+    fn run_instr(&mut self) -> Result<Advance, ExecError> {
+        let instr = if self.ip.1 >= self.code[self.ip.0].code.len() {
+            if self.ip.0 == self.entrypoint.0 {
+                return Ok(Advance::Finished);
             }
 
-            if self.evaluating_side_effects {
-                while self.ip.1 >= self.code[self.ip.0].code.len() {
-                    match self.blocks_to_eval.pop() {
-                        Some(next) => {
-                            if next != Label::CONTINUE {
-                                self.ip = (next.0, 0);
-                                continue 'outer;
+            Instr::EndBlock
+        } else {
+            self.code[self.ip.0].code[self.ip.1].clone()
+        };
+
+        self.emit(TraceEvent::Instr {
+            ip: self.ip,
+            instr: &instr,
+        });
+        if self.expectations.is_some() {
+            self.trace.push(TraceEntry {
+                ip: self.ip,
+                instr: instr.clone(),
+                side_effect: self.evaluating_side_effects,
+            });
+        }
+
+        self.use_cycles(1)?;
+
+        let report = &mut self.report;
+        report.max_eval_stack = report.max_eval_stack.max(self.eval_stack.len());
+        report.max_call_stack = report.max_call_stack.max(self.call_stack.len());
+
+        if self.evaluating_side_effects {
+            while self.ip.1 >= self.code[self.ip.0].code.len() {
+                match self.blocks_to_eval.pop() {
+                    Some(next) => {
+                        // Each block only needs to be scanned once, which also stops
+                        // us from following loops and recursive functions forever
+                        if next != Label::CONTINUE && self.side_effect_visited.insert(next.0) {
+                            if self.side_effect_visited.len() > SIDE_EFFECT_BLOCK_LIMIT {
+                                return Err(ExecError {
+                                    kind: ExecErrorKind::SideEffectLimit,
+                                    ip: self.side_effect_origin,
+                                    message: format!(
+                                        "side effect evaluation of if expr at {:?} visited more than {} blocks",
+                                        self.side_effect_origin, SIDE_EFFECT_BLOCK_LIMIT
+                                    ),
+                                });
                             }
+
+                            self.ip = (next.0, 0);
+                            return Ok(Advance::Continued);
                         }
-                        None => {
-                            // Stop evaluating side effects
-                            self.evaluating_side_effects = false;
-                            println!("EXITING side effect mode");
-                            self.ip = self.call_stack.pop().unwrap();
-                        }
+                    }
+                    None => {
+                        // Stop evaluating side effects
+                        self.evaluating_side_effects = false;
+                        self.emit(TraceEvent::ExitSideEffects {
+                            origin: self.side_effect_origin,
+                        });
+                        self.side_effect_results
+                            .insert(self.side_effect_origin, self.waiting_vars());
+                        self.ip = self.pop_frame()?.ret;
                     }
                 }
+            }
 
-                match instr {
-                    Instr::StoreIdent { i } => {
-                        self.variables[i.0] = DataVal::Waiting;
+            match instr {
+                Instr::StoreIdent { i } => {
+                    self.variables[i.0] = DataVal::Waiting;
+                    self.emit_store(i);
+                    self.report.speculative_stores.push(SpeculativeStore {
+                        addr: i,
+                        block: Label(self.ip.0),
+                        origin: self.side_effect_origin,
+                    });
+                }
+                Instr::IfExpr { if_true, if_false } => {
+                    self.blocks_to_eval.push(if_true);
+                    self.blocks_to_eval.push(if_false);
+                }
+                Instr::Goto { label } => {
+                    self.blocks_to_eval.push(label);
+                }
+                Instr::Call { label } => {
+                    if let Some(func) = self.extern_name(label) {
+                        // Reserve the id, so later calls from the same site have the
+                        // same ids whichever way the branch goes
+                        let count = self.extern_site_calls.entry(self.ip).or_insert(0);
+                        self.report.deferred_calls.push(DeferredCall {
+                            func,
+                            id: CallId {
+                                block: self.ip.0,
+                                instr: self.ip.1,
+                                ordinal: *count,
+                            },
+                            call_site: self.ip,
+                            origin: self.side_effect_origin,
+                        });
+                        *count += 1;
                     }
-                    Instr::IfExpr { if_true, if_false } => {
-                        self.blocks_to_eval.push(if_true);
-                        self.blocks_to_eval.push(if_false);
+                    self.blocks_to_eval.push(label);
+                }
+                Instr::Loop { label } => {
+                    self.blocks_to_eval.push(label);
+                }
+                Instr::Continue { label, .. } => {
+                    self.blocks_to_eval.push(label);
+                }
+                _ => {}
+            }
+        } else {
+            // Checking first means the error has the values the instr needed in total
+            let needed = instr.operands();
+            if self.eval_stack.len() < needed {
+                return Err(self.underflow(needed));
+            }
+
+            match instr {
+                Instr::BinaryExpr { op } => match op {
+                    Token::C('+') => arith!(self, "+", i64::checked_add, std::ops::Add::add),
+                    Token::C('-') => arith!(self, "-", i64::checked_sub, std::ops::Sub::sub),
+                    Token::C('*') => arith!(self, "*", i64::checked_mul, std::ops::Mul::mul),
+                    Token::C('%') => {
+                        // The divisor is below the dividend
+                        if self.eval_stack.iter().rev().nth(1) == Some(&DataVal::Integer(0)) {
+                            return Err(self.error(ExecErrorKind::DivisionByZero, "modulo by zero"));
+                        }
+                        arith!(self, "%", i64::checked_rem, std::ops::Rem::rem)
                     }
-                    Instr::Goto { label } => {
-                        self.blocks_to_eval.push(label);
+                    Token::C('/') => {
+                        // Division always produces a float, FloorDiv is for integers
+                        let x = self.pop()?;
+                        let y = self.pop()?;
+                        if x.is_waiting() || y.is_waiting() {
+                            self.eval_stack.push(DataVal::Waiting);
+                        } else {
+                            let q = self.as_float(x)? / self.as_float(y)?;
+                            self.eval_stack.push(DataVal::Float(q));
+                        }
                     }
-                    Instr::Call { label } => {
-                        self.blocks_to_eval.push(label);
+
+                    Token::Eq => self.equality(true)?,
+                    Token::Ne => self.equality(false)?,
+
+                    Token::C('<') => rel!(self, std::cmp::PartialOrd::lt),
+                    Token::Le => rel!(self, std::cmp::PartialOrd::le),
+                    Token::C('>') => rel!(self, std::cmp::PartialOrd::gt),
+                    Token::Ge => rel!(self, std::cmp::PartialOrd::ge),
+                    _ => {
+                        return Err(self.error(
+                            ExecErrorKind::Internal,
+                            format!("unimplemented operator '{op}' for binary expression"),
+                        ))
                     }
-                    _ => {}
-                }
-            } else {
-                match instr {
-                    Instr::BinaryExpr { op } => match op {
-                        Token::C('+') => arith!(self, std::ops::Add::add),
-                        Token::C('-') => arith!(self, std::ops::Sub::sub),
-                        Token::C('*') => arith!(self, std::ops::Mul::mul),
-                        Token::C('/') => arith!(self, std::ops::Div::div),
-
-                        Token::Eq => rel!(self, std::cmp::PartialEq::eq),
-                        Token::Ne => rel!(self, std::cmp::PartialEq::ne),
-
-                        Token::C('<') => rel!(self, std::cmp::PartialOrd::lt),
-                        Token::Le => rel!(self, std::cmp::PartialOrd::le),
-                        Token::C('>') => rel!(self, std::cmp::PartialOrd::gt),
-                        Token::Ge => rel!(self, std::cmp::PartialOrd::ge),
-                        _ => panic!("unimplemented operator for binary expression"),
-                    },
-                    Instr::Concat => {
-                        let mut x = self.eval_stack.pop().unwrap().into_string().unwrap();
-                        let y = self.eval_stack.pop().unwrap().into_string().unwrap();
-                        x.push_str(&y);
-                        self.eval_stack.push(DataVal::String(x));
-                    }
-                    Instr::UnaryExpr { op } => match op {
-                        Token::C('-') => {
-                            let top = self.eval_stack.pop().unwrap();
-                            match top {
-                                DataVal::Integer(i) => {
-                                    self.eval_stack.push(DataVal::Integer(-i));
-                                }
-                                DataVal::Float(f) => {
-                                    self.eval_stack.push(DataVal::Float(-f));
-                                }
-                                DataVal::Waiting => self.eval_stack.push(DataVal::Waiting),
-                                _ => panic!("'{op}' operator unimplemented for data type"),
+                },
+                Instr::FloorDiv => {
+                    let x = self.pop()?;
+                    let y = self.pop()?;
+                    if x.is_waiting() || y.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else {
+                        let x = x.into_integer().map_err(|v| self.mismatch("int", v))?;
+                        let y = y.into_integer().map_err(|v| self.mismatch("int", v))?;
+                        if y == 0 {
+                            return Err(
+                                self.error(ExecErrorKind::DivisionByZero, "division by zero")
+                            );
+                        }
+
+                        let q = match x.checked_div(y) {
+                            Some(q) => q,
+                            None => {
+                                return Err(self.error(
+                                    ExecErrorKind::IntegerOverflow,
+                                    format!("integer overflow: div({}, {})", x, y),
+                                ))
                             }
+                        };
+                        if x % y != 0 && (x < 0) != (y < 0) {
+                            self.eval_stack.push(DataVal::Integer(q - 1));
+                        } else {
+                            self.eval_stack.push(DataVal::Integer(q));
+                        }
+                    }
+                }
+                Instr::Concat => {
+                    let x = self.pop()?;
+                    let y = self.pop()?;
+                    if x.is_waiting() || y.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else {
+                        let x = x.into_string().map_err(|v| self.mismatch("string", v))?;
+                        let y = y.into_string().map_err(|v| self.mismatch("string", v))?;
+                        self.eval_stack
+                            .push(DataVal::String([x, y].concat().into()));
+                    }
+                }
+                Instr::ToString { typ } => {
+                    let s = match self.pop()? {
+                        DataVal::Integer(i) => DataVal::String(i.to_string().into()),
+                        DataVal::Float(f) => DataVal::String(f.to_string().into()),
+                        DataVal::Bool(b) => DataVal::String(b.to_string().into()),
+                        DataVal::Null => DataVal::String("null".into()),
+                        v @ DataVal::String(_) => v,
+                        // The string of a compound is only known once all of it is
+                        v if v.contains_waiting() => DataVal::Waiting,
+                        v @ (DataVal::Compound(_) | DataVal::Map(_)) => {
+                            DataVal::String(v.display_typed(&typ, &self.user_structs).into())
                         }
-                        Token::C('!') => {
-                            let top = self.eval_stack.pop().unwrap();
-                            match top {
-                                DataVal::Bool(b) => {
-                                    self.eval_stack.push(DataVal::Bool(!b));
-                                }
-                                _ => panic!("'{op}' operator unimplemented for data type"),
+                        v => {
+                            return Err(self.error(
+                                ExecErrorKind::Type,
+                                format!("cannot convert {} to a string", v),
+                            ))
+                        }
+                    };
+                    self.eval_stack.push(s);
+                }
+                Instr::UnaryExpr { op } => match op {
+                    Token::C('-') => match self.pop()? {
+                        DataVal::Integer(i) => match i.checked_neg() {
+                            Some(n) => self.eval_stack.push(DataVal::Integer(n)),
+                            None => {
+                                return Err(self.error(
+                                    ExecErrorKind::IntegerOverflow,
+                                    format!("integer overflow: -({})", i),
+                                ))
                             }
+                        },
+                        DataVal::Float(f) => {
+                            self.eval_stack.push(DataVal::Float(-f));
                         }
-                        _ => panic!("unimplemented operator '{op}' for unary expression"),
+                        DataVal::Waiting => self.eval_stack.push(DataVal::Waiting),
+                        v => return Err(self.mismatch("a number", v)),
                     },
-                    Instr::LoadConst { v } => self.eval_stack.push(v),
-                    Instr::LoadIdent { i } => self.eval_stack.push(self.variables[i.0].clone()),
-                    Instr::StoreIdent { i } => self.variables[i.0] = self.eval_stack.pop().unwrap(),
-                    Instr::IfExpr { if_true, if_false } => match self.eval_stack.pop().unwrap() {
+                    Token::C('!') => match self.pop()? {
                         DataVal::Bool(b) => {
-                            if b {
-                                if if_true != Label::CONTINUE {
-                                    self.call_stack.push(self.ip);
-                                    self.ip = (if_true.0, 0);
-                                    continue;
-                                }
-                            } else {
-                                if if_false != Label::CONTINUE {
-                                    self.call_stack.push(self.ip);
-                                    self.ip = (if_false.0, 0);
-                                    continue;
-                                }
-                            }
+                            self.eval_stack.push(DataVal::Bool(!b));
                         }
-                        DataVal::Waiting => {
-                            println!(
-                                "if expr at {:?} is waiting, going to side effect mode",
-                                self.ip
-                            );
-
-                            // Evaluate side effects of both paths
-                            self.evaluating_side_effects = true;
-                            self.call_stack.push(self.ip);
-                            self.ip = (if_true.0, 0);
-                            self.blocks_to_eval.push(if_false);
-                            continue;
-                        }
-                        _ => panic!("can only if on bool"),
+                        DataVal::Waiting => self.eval_stack.push(DataVal::Waiting),
+                        v => return Err(self.mismatch("bool", v)),
                     },
-                    Instr::CompoundGet => {
-                        let index = self.eval_stack.pop().unwrap();
-                        let arr = self.eval_stack.pop().unwrap();
-                        if index.is_waiting() || arr.is_waiting() {
-                            self.eval_stack.push(DataVal::Waiting);
+                    _ => {
+                        return Err(self.error(
+                            ExecErrorKind::Internal,
+                            format!("unimplemented operator '{op}' for unary expression"),
+                        ))
+                    }
+                },
+                Instr::LoadConst { v } => self.eval_stack.push(v),
+                Instr::LoadIdent { i } => {
+                    if self.variables[i.0] == DataVal::Uninit {
+                        return Err(self.error(
+                            ExecErrorKind::UseBeforeInit,
+                            format!(
+                                "use before init: variable {} was read before it was assigned",
+                                self.var_names[i.0]
+                            ),
+                        ));
+                    }
+                    self.eval_stack.push(self.variables[i.0].clone());
+                }
+                Instr::StoreIdent { i } => {
+                    self.variables[i.0] = self.pop()?;
+                    self.emit_store(i);
+                }
+                Instr::IfExpr { if_true, if_false } => match self.pop()? {
+                    DataVal::Bool(b) => {
+                        if b {
+                            if if_true != Label::CONTINUE {
+                                self.push_frame(FrameKind::Branch);
+                                self.ip = (if_true.0, 0);
+                                return Ok(Advance::Continued);
+                            }
                         } else {
-                            let val = arr.into_compound().unwrap()
-                                [index.into_integer().unwrap() as usize]
-                                .clone();
-                            self.eval_stack.push(val);
+                            if if_false != Label::CONTINUE {
+                                self.push_frame(FrameKind::Branch);
+                                self.ip = (if_false.0, 0);
+                                return Ok(Advance::Continued);
+                            }
                         }
                     }
-                    Instr::CompoundSet => {
-                        let val = self.eval_stack.pop().unwrap();
-                        let index = self.eval_stack.pop().unwrap();
-                        let arr = self.eval_stack.pop().unwrap();
+                    // Neither branch has side effects, eg. once optimize has skipped
+                    // the empty blocks
+                    DataVal::Waiting
+                        if if_true == Label::CONTINUE && if_false == Label::CONTINUE => {}
+                    DataVal::Waiting => {
+                        // Evaluating the side effects again can only mark the
+                        // same variables as waiting, eg. on each iteration of a loop
+                        if self.side_effect_results.get(&self.ip) == Some(&self.waiting_vars()) {
+                            if !self.report.converged.contains(&self.ip) {
+                                self.report.converged.push(self.ip);
+                            }
+                            self.ip.1 += 1;
+                            return Ok(Advance::Continued);
+                        }
 
-                        if index.is_waiting() || arr.is_waiting() {
-                            self.eval_stack.push(DataVal::Waiting);
+                        self.emit(TraceEvent::EnterSideEffects { origin: self.ip });
+
+                        // Evaluate side effects of both paths
+                        self.evaluating_side_effects = true;
+                        self.report.entered_side_effects = true;
+                        self.side_effect_origin = self.ip;
+                        let (first, second) = if if_true == Label::CONTINUE {
+                            (if_false, if_true)
                         } else {
-                            let mut a = arr.into_compound().unwrap();
-                            a[index.into_integer().unwrap() as usize] = val;
-                            self.eval_stack.push(DataVal::Compound(a));
+                            (if_true, if_false)
+                        };
+                        self.side_effect_visited = HashSet::from([first.0]);
+                        self.push_frame(FrameKind::Branch);
+                        self.ip = (first.0, 0);
+                        self.blocks_to_eval.push(second);
+                        return Ok(Advance::Continued);
+                    }
+                    v => return Err(self.mismatch("bool", v)),
+                },
+                Instr::CompoundGet => {
+                    let index = self.pop()?;
+                    let arr = self.pop()?;
+                    if index.is_waiting() || arr.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else {
+                        let arr = arr.into_compound().map_err(|v| self.mismatch("array", v))?;
+                        let idx = self.checked_index(index, arr.len())?;
+                        self.eval_stack.push(arr[idx].clone());
+                    }
+                }
+                Instr::CompoundSet => {
+                    let val = self.pop()?;
+                    let index = self.pop()?;
+                    let arr = self.pop()?;
+
+                    // A waiting value is stored as a waiting element. When the index
+                    // is waiting, any element could have been set, so every one waits.
+                    if arr.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else if index.is_waiting() {
+                        let a = arr.into_compound().map_err(|v| self.mismatch("array", v))?;
+                        self.eval_stack
+                            .push(DataVal::Compound(vec![DataVal::Waiting; a.len()]));
+                    } else {
+                        let mut a = arr.into_compound().map_err(|v| self.mismatch("array", v))?;
+                        let idx = self.checked_index(index, a.len())?;
+                        a[idx] = val;
+                        self.eval_stack.push(DataVal::Compound(a));
+                    }
+                }
+                Instr::MapGet => {
+                    let key = self.pop()?;
+                    let map = self.pop()?;
+                    if key.is_waiting() || map.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else {
+                        let key = key.into_string().map_err(|v| self.mismatch("string", v))?;
+                        let mut map = map.into_map().map_err(|v| self.mismatch("map", v))?;
+                        match map.remove(&*key) {
+                            Some(v) => self.eval_stack.push(v),
+                            None => {
+                                return Err(self.error(
+                                    ExecErrorKind::MissingKey {
+                                        key: key.to_string(),
+                                    },
+                                    format!("missing key: the map has no key {:?}", key),
+                                ))
+                            }
                         }
                     }
-                    Instr::CompoundCreate => {
-                        let len = self.eval_stack.pop().unwrap();
-                        if len.is_waiting() {
-                            self.eval_stack.push(DataVal::Waiting);
-                        } else {
-                            let arr =
-                                vec![DataVal::Bool(false); len.into_integer().unwrap() as usize];
+                }
+                Instr::MapSet => {
+                    let val = self.pop()?;
+                    let key = self.pop()?;
+                    let map = self.pop()?;
+
+                    // When the key is waiting, any key could have been set, so the
+                    // whole map waits
+                    if map.is_waiting() || key.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else {
+                        let key = key.into_string().map_err(|v| self.mismatch("string", v))?;
+                        let mut map = map.into_map().map_err(|v| self.mismatch("map", v))?;
+                        map.insert(key.to_string(), val);
+                        self.eval_stack.push(DataVal::Map(map));
+                    }
+                }
+                Instr::MapHas => {
+                    let key = self.pop()?;
+                    let map = self.pop()?;
+                    if key.is_waiting() || map.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else {
+                        let key = key.into_string().map_err(|v| self.mismatch("string", v))?;
+                        let map = map.into_map().map_err(|v| self.mismatch("map", v))?;
+                        self.eval_stack.push(DataVal::Bool(map.contains_key(&*key)));
+                    }
+                }
+                Instr::FieldGet => {
+                    let index = self.pop()?;
+                    let strct = self.pop()?;
+                    if strct.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else {
+                        let strct = strct
+                            .into_compound()
+                            .map_err(|v| self.mismatch("struct", v))?;
+                        let idx = self.checked_field(index, strct.len())?;
+                        self.eval_stack.push(strct[idx].clone());
+                    }
+                }
+                Instr::FieldSet => {
+                    let val = self.pop()?;
+                    let index = self.pop()?;
+                    let strct = self.pop()?;
+
+                    if strct.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else {
+                        let mut s = strct
+                            .into_compound()
+                            .map_err(|v| self.mismatch("struct", v))?;
+                        let idx = self.checked_field(index, s.len())?;
+                        s[idx] = val;
+                        self.eval_stack.push(DataVal::Compound(s));
+                    }
+                }
+                Instr::CompoundCreate => {
+                    let len = self.pop()?;
+                    if len.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else {
+                        let len = len.into_integer().map_err(|v| self.mismatch("int", v))?;
+                        if len < 0 {
+                            return Err(self.error(
+                                ExecErrorKind::NegativeLength { len },
+                                format!("cannot create an array of negative length {}", len),
+                            ));
+                        }
+                        if len as u64 > self.max_array_length as u64 {
+                            return Err(self.error(
+                                ExecErrorKind::LengthLimitExceeded { len },
+                                format!(
+                                    "cannot create an array of length {}: arrays may have at most {} elements",
+                                    len, self.max_array_length
+                                ),
+                            ));
+                        }
+
+                        // Filling the array is work like running instructions, so
+                        // making many of them is bounded too
+                        self.use_cycles(len as usize)?;
+                        let arr = vec![DataVal::Bool(false); len as usize];
+                        self.eval_stack.push(DataVal::Compound(arr));
+                    }
+                }
+                Instr::CompoundFill => {
+                    let val = self.pop()?;
+                    match self.pop()? {
+                        DataVal::Compound(arr) => self
+                            .eval_stack
+                            .push(DataVal::Compound(vec![val; arr.len()])),
+                        _ => self.eval_stack.push(DataVal::Waiting),
+                    }
+                }
+                Instr::CompoundPush => {
+                    let val = self.pop()?;
+                    match self.pop()? {
+                        DataVal::Compound(mut arr) => {
+                            arr.push(val);
                             self.eval_stack.push(DataVal::Compound(arr));
                         }
+                        _ => self.eval_stack.push(DataVal::Waiting),
                     }
-                    Instr::Goto { label } => {
-                        self.call_stack.push(self.ip);
-                        self.ip = (label.0, 0);
-                        continue;
+                }
+                Instr::CompoundRemove => {
+                    let index = self.pop()?;
+                    let arr = self.pop()?;
+                    if index.is_waiting() || arr.is_waiting() {
+                        self.eval_stack.push(DataVal::Waiting);
+                    } else {
+                        let mut arr = arr.into_compound().map_err(|v| self.mismatch("array", v))?;
+                        let idx = self.checked_index(index, arr.len())?;
+                        arr.remove(idx);
+                        self.eval_stack.push(DataVal::Compound(arr));
                     }
-                    Instr::Call { label } => {
-                        self.call_stack.push(self.ip);
-                        self.ip = (label.0, 0);
-                        continue;
+                }
+                Instr::CompoundLen => match self.pop()? {
+                    DataVal::Compound(arr) => {
+                        self.eval_stack.push(DataVal::Integer(arr.len() as i64))
                     }
-                    Instr::Return {} => match self.call_stack.pop() {
-                        Some(label) => {
-                            self.ip = label;
+                    DataVal::String(s) => self
+                        .eval_stack
+                        .push(DataVal::Integer(s.chars().count() as i64)),
+                    _ => self.eval_stack.push(DataVal::Waiting),
+                },
+                Instr::Goto { label } => {
+                    self.push_frame(FrameKind::Branch);
+                    self.ip = (label.0, 0);
+                    return Ok(Advance::Continued);
+                }
+                Instr::Call { label } => {
+                    self.enter_call(label)?;
+                    return Ok(Advance::Continued);
+                }
+                Instr::Return => loop {
+                    // Leave every block entered since the function was called
+                    match self.call_stack.pop() {
+                        Some(frame) if frame.kind == FrameKind::Call => {
+                            self.leave_call(frame);
                             // don't continue, increment past the origin label
+                            break;
                         }
+                        Some(_) => {}
                         None => {
                             // Return in main function
-                            return;
+                            return Ok(Advance::Finished);
                         }
-                    },
-                    Instr::Discard => {
-                        self.eval_stack.pop();
                     }
-                    Instr::ExternCall {
-                        param_types,
-                        return_types,
-                    } => {
-                        let func_name = self.eval_stack.pop().unwrap().into_string().unwrap();
+                },
+                Instr::EndBlock => match self.call_stack.pop() {
+                    Some(frame) if frame.kind == FrameKind::Call => {
+                        self.leave_call(frame);
+                        // don't continue, increment past the origin label
+                    }
+                    Some(frame) => {
+                        self.ip = frame.ret;
+                        // don't continue, increment past the origin label
+                    }
+                    None => {
+                        // End of main function
+                        return Ok(Advance::Finished);
+                    }
+                },
+                Instr::Loop { label } => {
+                    self.push_frame(FrameKind::Loop);
+                    self.ip = (label.0, 0);
+                    return Ok(Advance::Continued);
+                }
+                Instr::Break { mut loops } => {
+                    // Unwind every frame up to and including the loop being exited
+                    while loops > 0 {
+                        let frame = match self.call_stack.pop() {
+                            Some(frame) => frame,
+                            None => {
+                                return Err(
+                                    self.error(ExecErrorKind::Internal, "break outside of loop")
+                                )
+                            }
+                        };
+                        if frame.kind == FrameKind::Loop {
+                            loops -= 1;
+                            self.ip = frame.ret;
+                        }
+                    }
+                }
+                Instr::Continue { mut loops, label } => {
+                    // Unwind up to the frame of the loop being continued, leaving the
+                    // stack as it was when the loop started
+                    loop {
+                        let frame = match self.call_stack.last() {
+                            Some(frame) => frame,
+                            None => {
+                                return Err(
+                                    self.error(ExecErrorKind::Internal, "continue outside of loop")
+                                )
+                            }
+                        };
+                        if frame.kind == FrameKind::Loop {
+                            loops -= 1;
+                            if loops == 0 {
+                                break;
+                            }
+                        }
+                        self.call_stack.pop();
+                    }
 
-                        let param_vals = self
-                            .eval_stack
-                            .split_off(self.eval_stack.len() - param_types.len());
-
-                        let call_site = *self.call_stack.last().unwrap();
-                        let call_count = *self.extern_func_call_count.get(&func_name).unwrap_or(&0);
-
-                        let mut returns = self
-                            .external_functions
-                            .get(&func_name)
-                            .expect("unknown external function")(
-                            (call_site.0, call_site.1, call_count),
-                            param_types,
-                            return_types,
-                            param_vals,
-                            &self.user_structs,
-                        );
+                    self.ip = (label.0, 0);
+                    return Ok(Advance::Continued);
+                }
+                Instr::Discard => {
+                    self.pop()?;
+                }
+                Instr::DupPair => {
+                    let len = self.eval_stack.len();
+                    self.eval_stack.extend_from_within(len - 2..);
+                }
+                Instr::ExternCall {
+                    param_types,
+                    return_types,
+                } => {
+                    let func_name = self.pop()?;
+                    let func_name = func_name
+                        .into_string()
+                        .map_err(|v| self.mismatch("the name of an extern", v))?;
+
+                    let param_vals = self
+                        .eval_stack
+                        .split_off(self.eval_stack.len() - param_types.len());
 
-                        self.eval_stack.append(&mut returns);
+                    // The call site is the Call instr the function was entered from
+                    let call_site = match self.call_stack.last() {
+                        Some(frame) => frame.ret,
+                        None => return Err(self.frame_underflow()),
+                    };
+                    let ordinal = *self.extern_site_calls.get(&call_site).unwrap_or(&0);
 
-                        self.extern_func_call_count
-                            .insert(func_name, call_count + 1);
+                    // Execution resumes once the caller has made the call
+                    return Ok(Advance::Call(PendingCall {
+                        func_name: func_name.to_string(),
+                        id: CallId {
+                            block: call_site.0,
+                            instr: call_site.1,
+                            ordinal,
+                        },
+                        call_site,
+                        param_types,
+                        return_types,
+                        param_vals,
+                    }));
+                }
+                Instr::Assert { msg } => match self.pop()? {
+                    DataVal::Bool(true) => {}
+                    DataVal::Bool(false) if self.testing => {
+                        self.assert_failure = Some(format!("{} failed", msg));
+                        return Ok(Advance::Finished);
+                    }
+                    DataVal::Bool(false) => {
+                        return Err(
+                            self.error(ExecErrorKind::AssertionFailed, format!("{} failed", msg))
+                        )
+                    }
+                    DataVal::Waiting if self.testing => {
+                        self.assert_failure = Some(format!("{} is waiting", msg));
+                        return Ok(Advance::Finished);
+                    }
+                    // It may still hold once the value is known
+                    DataVal::Waiting => {}
+                    v => return Err(self.mismatch("bool", v)),
+                },
+                Instr::Validate { types } => {
+                    let vals = &self.eval_stack[self.eval_stack.len() - types.len()..];
+                    for (typ, val) in types.iter().zip(vals) {
+                        if let Err(e) = check_constraints(&self.user_structs, typ, val) {
+                            return Err(self.error(ExecErrorKind::Constraint, e.to_string()));
+                        }
                     }
                 }
-            };
-            self.ip.1 += 1;
-        }
+                Instr::Cast { to } => {
+                    let v = self.pop()?;
+                    let cast = self.cast(v, &to)?;
+                    self.eval_stack.push(cast);
+                }
+                Instr::TypeOf { typ } => match self.pop()? {
+                    DataVal::Waiting => self.eval_stack.push(DataVal::Waiting),
+                    _ => self
+                        .eval_stack
+                        .push(DataVal::String(typ.to_string().into())),
+                },
+                Instr::IsWaiting => {
+                    // The only operation that doesn't propagate waiting
+                    let top = self.pop()?;
+                    self.eval_stack.push(DataVal::Bool(top.is_waiting()));
+                }
+                Instr::IsNull => match self.pop()? {
+                    DataVal::Waiting => self.eval_stack.push(DataVal::Waiting),
+                    v => self.eval_stack.push(DataVal::Bool(v.is_null())),
+                },
+            }
+        };
+        self.ip.1 += 1;
+        return Ok(Advance::Continued);
     }
 }
@@ -0,0 +1,297 @@
+// The representation of values persisted between runs, and the stores that
+// persist the returns of extern calls.
+//
+// Compiled code refers to struct fields by their index, which changes when the
+// fields of a declaration are reordered. Persisted structs are keyed by field
+// name instead, and are translated into the current layout when loaded.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::stac::{field_id, CallId, DataType, DataVal, Struct};
+
+/// Where the returns of extern calls are kept between executions, so that a
+/// call made before, such as one creating a droplet, isn't made again. It's
+/// set with `Prog::with_state`.
+pub trait StateStore: Send + Sync {
+    fn get(&self, id: CallId) -> Option<Vec<DataVal>>;
+    fn put(&mut self, id: CallId, returns: Vec<DataVal>) -> io::Result<()>;
+    // Every call in the store, ordered by id
+    fn all(&self) -> Vec<(CallId, Vec<DataVal>)>;
+
+    // Keeps the returns along with the call they came from, so that a later
+    // plan can be diffed against it. Stores that only keep returns needn't
+    // implement this or snapshot.
+    fn put_call(&mut self, call: RecordedCall, returns: Vec<DataVal>) -> io::Result<()> {
+        return self.put(call.id, returns);
+    }
+
+    // The calls kept with put_call, ordered by id
+    fn snapshot(&self) -> StateSnapshot {
+        return StateSnapshot::default();
+    }
+}
+
+// An extern call that was made, with its params encoded like persisted state
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedCall {
+    pub id: CallId,
+    pub function: String,
+    pub params: Vec<Value>,
+}
+
+/// The calls recorded in a state store, to diff a plan against with `diff`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub calls: Vec<RecordedCall>,
+}
+
+/// A store kept in a JSON file, which is rewritten after every call.
+pub struct JsonFileStore {
+    path: PathBuf,
+    calls: BTreeMap<CallId, StoredCall>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredCall {
+    id: CallId,
+    // Empty for calls kept with only their returns
+    #[serde(default)]
+    function: String,
+    #[serde(default)]
+    params: Vec<Value>,
+    returns: Vec<DataVal>,
+}
+
+impl JsonFileStore {
+    /// Loads the store from the file, or starts an empty one if the file
+    /// doesn't exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let calls = match std::fs::read(&path) {
+            Ok(data) => {
+                let stored: Vec<StoredCall> = serde_json::from_slice(&data)?;
+                stored.into_iter().map(|c| (c.id, c)).collect()
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        return Ok(Self { path, calls });
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let stored: Vec<&StoredCall> = self.calls.values().collect();
+        return std::fs::write(&self.path, serde_json::to_vec_pretty(&stored)?);
+    }
+}
+
+impl StateStore for JsonFileStore {
+    fn get(&self, id: CallId) -> Option<Vec<DataVal>> {
+        return self.calls.get(&id).map(|c| c.returns.clone());
+    }
+
+    fn put(&mut self, id: CallId, returns: Vec<DataVal>) -> io::Result<()> {
+        match self.calls.get_mut(&id) {
+            Some(call) => call.returns = returns,
+            None => {
+                self.calls.insert(
+                    id,
+                    StoredCall {
+                        id,
+                        function: String::new(),
+                        params: vec![],
+                        returns,
+                    },
+                );
+            }
+        }
+        return self.write();
+    }
+
+    fn all(&self) -> Vec<(CallId, Vec<DataVal>)> {
+        return self
+            .calls
+            .values()
+            .map(|c| (c.id, c.returns.clone()))
+            .collect();
+    }
+
+    fn put_call(&mut self, call: RecordedCall, returns: Vec<DataVal>) -> io::Result<()> {
+        self.calls.insert(
+            call.id,
+            StoredCall {
+                id: call.id,
+                function: call.function,
+                params: call.params,
+                returns,
+            },
+        );
+        return self.write();
+    }
+
+    fn snapshot(&self) -> StateSnapshot {
+        // Calls kept without their params can't be diffed
+        let calls = self
+            .calls
+            .values()
+            .filter(|c| !c.function.is_empty())
+            .map(|c| RecordedCall {
+                id: c.id,
+                function: c.function.clone(),
+                params: c.params.clone(),
+            })
+            .collect();
+        return StateSnapshot { calls };
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MigrationError {
+    // A field holding data no longer exists in the struct
+    RemovedField { strct: String, field: String },
+    // A persisted value doesn't fit the type it is loaded as
+    WrongType { expected: DataType, found: Value },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::RemovedField { strct, field } => write!(
+                f,
+                "migration error: field {}.{} was removed, but the state has data for it",
+                strct, field
+            ),
+            MigrationError::WrongType { expected, found } => write!(
+                f,
+                "migration error: expected a value of type {}, found {}",
+                expected, found
+            ),
+        }
+    }
+}
+
+// Converts a value into its persisted form
+pub fn encode(val: &DataVal, typ: &DataType, user_structs: &HashMap<String, Struct>) -> Value {
+    match (val, typ) {
+        (DataVal::Waiting, _) => return serde_json::json!({"$waiting": true}),
+        (DataVal::Null, _) => return Value::Null,
+        (DataVal::Integer(i), _) => return Value::from(*i),
+        (DataVal::Float(f), _) => return Value::from(*f),
+        (DataVal::Bool(b), _) => return Value::from(*b),
+        (DataVal::String(s), _) => return Value::from(&**s),
+        (DataVal::Compound(els), DataType::Array(el_typ)) => {
+            return Value::Array(
+                els.iter()
+                    .map(|el| encode(el, el_typ, user_structs))
+                    .collect(),
+            );
+        }
+        (DataVal::Map(vals), DataType::Map(val_typ)) => {
+            let mut map = Map::new();
+            for (key, val) in vals {
+                map.insert(key.clone(), encode(val, val_typ, user_structs));
+            }
+            return Value::Object(map);
+        }
+        (DataVal::Compound(fields), DataType::Struct(name)) => {
+            let strct = &user_structs[name];
+            let mut map = Map::new();
+            for (idx, field) in fields.iter().enumerate() {
+                map.insert(
+                    strct.field_name(idx).to_string(),
+                    encode(field, &strct.types[idx], user_structs),
+                );
+            }
+            return Value::Object(map);
+        }
+        _ => panic!("cannot persist {:?} as {}", val, typ),
+    }
+}
+
+// Converts a persisted value into a value of the type, in the current layout
+pub fn decode(
+    val: &Value,
+    typ: &DataType,
+    user_structs: &HashMap<String, Struct>,
+) -> Result<DataVal, MigrationError> {
+    let wrong_type = || MigrationError::WrongType {
+        expected: typ.clone(),
+        found: val.clone(),
+    };
+
+    if val.get("$waiting") == Some(&Value::Bool(true)) {
+        return Ok(DataVal::Waiting);
+    }
+    if val.is_null() {
+        return Ok(DataVal::Null);
+    }
+
+    match typ {
+        DataType::Integer => return val.as_i64().map(DataVal::Integer).ok_or_else(wrong_type),
+        DataType::Float => return val.as_f64().map(DataVal::Float).ok_or_else(wrong_type),
+        DataType::Bool => return val.as_bool().map(DataVal::Bool).ok_or_else(wrong_type),
+        DataType::String => {
+            return val
+                .as_str()
+                .map(|s| DataVal::String(s.into()))
+                .ok_or_else(wrong_type)
+        }
+        DataType::Array(el_typ) => {
+            let mut arr = vec![];
+            for el in val.as_array().ok_or_else(wrong_type)? {
+                arr.push(decode(el, el_typ, user_structs)?);
+            }
+            return Ok(DataVal::Compound(arr));
+        }
+        DataType::Map(val_typ) => {
+            let mut vals = BTreeMap::new();
+            for (key, val) in val.as_object().ok_or_else(wrong_type)? {
+                vals.insert(key.clone(), decode(val, val_typ, user_structs)?);
+            }
+            return Ok(DataVal::Map(vals));
+        }
+        DataType::Struct(name) => {
+            let strct = &user_structs[name];
+            let map = val.as_object().ok_or_else(wrong_type)?;
+
+            // Fields added since the state was persisted get their default value
+            let mut fields = match DataVal::default_for(typ.clone(), user_structs) {
+                DataVal::Compound(fields) => fields,
+                _ => unreachable!(),
+            };
+
+            for (key, field) in map {
+                match strct.field_by_id(field_id(key)) {
+                    Some(idx) => fields[idx] = decode(field, &strct.types[idx], user_structs)?,
+                    None if is_default(field) => {}
+                    None => {
+                        return Err(MigrationError::RemovedField {
+                            strct: name.clone(),
+                            field: key.clone(),
+                        })
+                    }
+                }
+            }
+
+            return Ok(DataVal::Compound(fields));
+        }
+        DataType::Waiting => return Err(wrong_type()),
+    }
+}
+
+// Whether a persisted value holds no data, so it can be dropped safely
+fn is_default(val: &Value) -> bool {
+    match val {
+        Value::Null => return true,
+        Value::Bool(b) => return !b,
+        Value::Number(n) => return n.as_f64() == Some(0.0),
+        Value::String(s) => return s.is_empty(),
+        Value::Array(arr) => return arr.is_empty(),
+        Value::Object(map) => return map.values().all(is_default),
+    }
+}
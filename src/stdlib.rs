@@ -0,0 +1,45 @@
+// The standard library, which programs only get with Prog::with_stdlib. The
+// functions are declared as externs in stdlib.dmcl and implemented here.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::stac::{ExternError, Prog};
+
+pub const SOURCE: &str = include_str!("stdlib.dmcl");
+
+pub const FUNCTIONS: &[&str] = &[
+    "print", "contains", "split", "join", "to_upper", "abs", "min", "max", "floor", "ceil",
+];
+
+// Where print writes to, shared by the program and its extern
+pub type Output = Mutex<Box<dyn Write + Send>>;
+
+// Registers every function, after the source has been compiled into the program
+pub fn register(prog: &mut Prog, output: Arc<Output>) {
+    prog.register_extern("print", move |s: String| {
+        return writeln!(output.lock().unwrap(), "{}", s)
+            .map_err(|e| ExternError::new(format!("cannot print: {}", e)));
+    });
+
+    prog.register_extern("contains", |s: String, sub: String| s.contains(&sub));
+    prog.register_extern("split", |s: String, sep: String| -> Vec<String> {
+        // Splitting on nothing separates every character
+        if sep.is_empty() {
+            return s.chars().map(String::from).collect();
+        }
+        return s.split(&sep).map(String::from).collect();
+    });
+    prog.register_extern("join", |parts: Vec<String>, sep: String| parts.join(&sep));
+    prog.register_extern("to_upper", |s: String| s.to_uppercase());
+
+    prog.register_extern("abs", |n: i64| {
+        return n
+            .checked_abs()
+            .ok_or_else(|| ExternError::new(format!("abs of {} overflows", n)));
+    });
+    prog.register_extern("min", |a: i64, b: i64| a.min(b));
+    prog.register_extern("max", |a: i64, b: i64| a.max(b));
+    prog.register_extern("floor", |x: f64| x.floor() as i64);
+    prog.register_extern("ceil", |x: f64| x.ceil() as i64);
+}
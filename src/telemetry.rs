@@ -0,0 +1,149 @@
+// Spans for the phases of a run and for every extern call, emitted through the
+// tracing crate when the otel feature is enabled. Without the feature all of
+// this compiles down to nothing.
+//
+// Each Prog gets its own W3C trace id, and each extern call its own span id, so
+// that the spans can be correlated with the provider's through the traceparent
+// header sent with HTTP provider requests.
+
+#[cfg(feature = "otel")]
+use std::cell::Cell;
+
+use crate::stac::{CallId, DataVal};
+
+#[cfg(feature = "otel")]
+thread_local! {
+    // The (trace id, span id) of the extern call currently being made
+    static CONTEXT: Cell<Option<(u128, u64)>> = const { Cell::new(None) };
+}
+
+// A new random trace id, or 0 when tracing is disabled
+pub fn new_trace_id() -> u128 {
+    #[cfg(feature = "otel")]
+    {
+        return ((random_u64() as u128) << 64) | random_u64() as u128;
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        return 0;
+    }
+}
+
+#[cfg(feature = "otel")]
+fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // RandomState is seeded randomly per process, the counter makes sure every
+    // call within it differs. W3C ids must not be all zeroes.
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    return hasher.finish().max(1);
+}
+
+// The traceparent header for requests made during the current extern call
+pub fn traceparent() -> Option<String> {
+    #[cfg(feature = "otel")]
+    {
+        return CONTEXT
+            .get()
+            .map(|(trace, span)| format!("00-{:032x}-{:016x}-01", trace, span));
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        return None;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Phase {
+    Parse,
+    Execute,
+}
+
+// Covers a phase until it is dropped
+pub struct PhaseSpan {
+    #[cfg(feature = "otel")]
+    _span: tracing::span::EnteredSpan,
+}
+
+impl PhaseSpan {
+    pub fn enter(phase: Phase, trace_id: u128) -> Self {
+        #[cfg(feature = "otel")]
+        {
+            let trace_id = format!("{:032x}", trace_id);
+            let span = match phase {
+                Phase::Parse => tracing::info_span!("dmcl.parse", trace_id),
+                Phase::Execute => tracing::info_span!("dmcl.execute", trace_id),
+            };
+            return Self {
+                _span: span.entered(),
+            };
+        }
+
+        #[cfg(not(feature = "otel"))]
+        {
+            let _ = (phase, trace_id);
+            return Self {};
+        }
+    }
+}
+
+// Covers a single extern call, finished with what it returned
+pub struct ExternSpan {
+    #[cfg(feature = "otel")]
+    span: tracing::span::EnteredSpan,
+    #[cfg(feature = "otel")]
+    prev: Option<(u128, u64)>,
+}
+
+impl ExternSpan {
+    pub fn enter(trace_id: u128, function: &str, id: CallId, params_size: usize) -> Self {
+        #[cfg(feature = "otel")]
+        {
+            let span_id = random_u64();
+            let span = tracing::info_span!(
+                "dmcl.extern_call",
+                function,
+                resource = id.to_string(),
+                params_size,
+                outcome = tracing::field::Empty,
+                trace_id = format!("{:032x}", trace_id),
+                span_id = format!("{:016x}", span_id),
+            );
+            let prev = CONTEXT.replace(Some((trace_id, span_id)));
+            return Self {
+                span: span.entered(),
+                prev,
+            };
+        }
+
+        #[cfg(not(feature = "otel"))]
+        {
+            let _ = (trace_id, function, id, params_size);
+            return Self {};
+        }
+    }
+
+    pub fn finish(self, returns: &[DataVal]) {
+        #[cfg(feature = "otel")]
+        {
+            let outcome = if returns.iter().any(|r| r.is_waiting()) {
+                "waiting"
+            } else {
+                "ok"
+            };
+            self.span.record("outcome", outcome);
+            CONTEXT.set(self.prev);
+        }
+
+        #[cfg(not(feature = "otel"))]
+        {
+            let _ = returns;
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// Events a program reports to the hook set with Prog::set_trace as it
+// executes. Nothing is reported when no hook is set.
+
+use std::fmt;
+
+use crate::provider::ProviderRetry;
+use crate::stac::{Addr, CallId, DataVal, Instr};
+
+pub enum TraceEvent<'a> {
+    // An instruction about to be run, including in side effect mode
+    Instr {
+        ip: (usize, usize),
+        instr: &'a Instr,
+    },
+    // An extern about to be called, after any middleware
+    ExternStart {
+        func: &'a str,
+        id: CallId,
+        params: &'a [DataVal],
+    },
+    // A request to a provider for the extern failed, and was retried
+    ExternRetry {
+        func: &'a str,
+        id: CallId,
+        retry: &'a ProviderRetry,
+    },
+    ExternFinish {
+        func: &'a str,
+        id: CallId,
+        returns: &'a [DataVal],
+    },
+    Store {
+        addr: Addr,
+        name: &'a str,
+        val: &'a DataVal,
+    },
+    // A waiting if expr at origin started or finished evaluating its side effects
+    EnterSideEffects {
+        origin: (usize, usize),
+    },
+    ExitSideEffects {
+        origin: (usize, usize),
+    },
+}
+
+pub type TraceHook = Box<dyn FnMut(TraceEvent) + Send + Sync>;
+
+impl fmt::Display for TraceEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceEvent::Instr { ip, instr } => write!(f, "executing @ {:?} : {:?}", ip, instr),
+            TraceEvent::ExternStart { func, id, params } => {
+                write!(
+                    f,
+                    "calling {} {} with {}",
+                    func,
+                    id,
+                    DataVal::Compound(params.to_vec())
+                )
+            }
+            TraceEvent::ExternRetry { func, id, retry } => {
+                write!(
+                    f,
+                    "retrying {} {} in {:?}, as attempt {} failed: {}",
+                    func, id, retry.delay, retry.attempt, retry.error
+                )
+            }
+            TraceEvent::ExternFinish { func, id, returns } => {
+                write!(
+                    f,
+                    "{} {} returned {}",
+                    func,
+                    id,
+                    DataVal::Compound(returns.to_vec())
+                )
+            }
+            TraceEvent::Store { addr, name, val } => {
+                write!(f, "assigning {} to {} in slot {:?}", val, name, addr)
+            }
+            TraceEvent::EnterSideEffects { origin } => {
+                write!(
+                    f,
+                    "if expr at {:?} is waiting, going to side effect mode",
+                    origin
+                )
+            }
+            TraceEvent::ExitSideEffects { .. } => write!(f, "EXITING side effect mode"),
+        }
+    }
+}
+
+/// A hook that prints every event to stdout, in the format execution used to
+/// log in.
+pub fn stdout_hook() -> TraceHook {
+    return Box::new(|event| println!("{}", event));
+}